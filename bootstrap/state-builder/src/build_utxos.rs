@@ -85,6 +85,7 @@ fn main() {
             let vout: u32 = parts[2].parse().unwrap();
             let amount: u64 = parts[3].parse().unwrap();
             let script = parts[6];
+            let is_coinbase = parts[7] == "1";
             let height: u32 = parts[9].parse().unwrap();
             let address_str = parts[5];
 
@@ -108,12 +109,20 @@ fn main() {
                     script_pubkey: script,
                 };
 
-                let found = s.utxos.utxos.insert(outpoint, (txout, height));
+                let found = s.utxos.utxos.insert(outpoint.clone(), (txout, height));
                 assert!(!found); // A UTXO cannot be seen more than once.
+
+                // The coinbase UTXOs are needed to compute the UTXO set's hash.
+                if is_coinbase {
+                    s.utxos.insert_coinbase_outpoint(outpoint);
+                }
             }
         }
     });
 
+    println!("Computing the UTXO set's hash...");
+    let muhash = with_state(|s| s.utxos.compute_muhash());
+
     // Write the memories corresponding to the small and medium UTXOs.
     // These are stable structures so we write the memory as-is.
     println!("Writing small UTXOs...");
@@ -126,6 +135,23 @@ fn main() {
     p.push("medium_utxos");
     write_memory_to_file(&p, MemoryId::new(3));
 
+    println!("Writing coinbase UTXOs...");
+    let mut p = args.output.clone();
+    p.push("coinbase_utxos");
+    write_memory_to_file(&p, MemoryId::new(7));
+
+    // Write the UTXO set's hash, which is part of the serialized `UtxoSet` and is to be set
+    // with `UtxoSet::set_muhash`.
+    println!("Writing the UTXO set's hash...");
+    let mut p = args.output.clone();
+    p.push("muhash");
+    let mut bytes = vec![];
+    ciborium::ser::into_writer(&muhash, &mut bytes).expect("failed to encode the hash");
+    match File::create(&p).and_then(|mut file| file.write_all(&bytes)) {
+        Err(err) => panic!("couldn't write to {}: {}", p.display(), err),
+        Ok(_) => println!("successfully wrote the hash to {}", p.display()),
+    };
+
     // Write the large UTXOs, which is a standard BTreeMap so it needs to
     // be serialized.
     println!("Writing large UTXOs...");
@@ -0,0 +1,199 @@
+use crate::types::{Admin, Flag, Role, SetConfigRequest};
+use ic_cdk::export::Principal;
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, BTreeSet};
+
+/// The principals allowed to change the config.
+///
+/// Controllers are allowed to change the entire config, admins only the parts that their roles
+/// allow.
+///
+/// The controllers are the principal that installed or last upgraded the canister, along with
+/// the configured controllers. The canister cannot ask the management canister for its
+/// controllers, so the configured controllers should list all of them.
+#[derive(Serialize, Deserialize, PartialEq, Eq, Debug, Default)]
+pub struct AccessControl {
+    admins: BTreeMap<Principal, BTreeSet<Role>>,
+
+    // The controller that installed or last upgraded the canister. Only controllers can install
+    // and upgrade canisters, so the principal is known to be a controller.
+    #[serde(default)]
+    controller: Option<Principal>,
+
+    // The controllers given in the config.
+    #[serde(default)]
+    controllers: BTreeSet<Principal>,
+}
+
+impl AccessControl {
+    /// Sets the controller that installed or last upgraded the canister. It must be the caller
+    /// of `init` or `post_upgrade`, which replaces the installer of the previous version.
+    pub fn set_controller(&mut self, controller: Principal) {
+        self.controller = Some(controller);
+    }
+
+    /// Replaces the configured controllers.
+    pub fn set_controllers(&mut self, controllers: Vec<Principal>) {
+        self.controllers = controllers.into_iter().collect();
+    }
+
+    /// Returns the configured controllers.
+    pub fn controllers(&self) -> Vec<Principal> {
+        self.controllers.iter().copied().collect()
+    }
+
+    /// Returns true if the given principal is the controller that installed or last upgraded
+    /// the canister, or one of the configured controllers.
+    pub fn is_controller(&self, principal: Principal) -> bool {
+        self.controller == Some(principal) || self.controllers.contains(&principal)
+    }
+
+    /// Replaces the admins. The roles of a principal that's listed several times are merged.
+    pub fn set_admins(&mut self, admins: Vec<Admin>) {
+        self.admins.clear();
+        for admin in admins {
+            self.admins
+                .entry(admin.principal)
+                .or_default()
+                .extend(admin.roles);
+        }
+    }
+
+    pub fn admins(&self) -> Vec<Admin> {
+        self.admins
+            .iter()
+            .map(|(principal, roles)| Admin {
+                principal: *principal,
+                roles: roles.iter().copied().collect(),
+            })
+            .collect()
+    }
+
+    /// Returns true if the roles of the given principal allow it to make all the changes in the
+    /// request.
+    pub fn is_authorized(&self, principal: Principal, request: &SetConfigRequest) -> bool {
+        match self.admins.get(&principal) {
+            Some(roles) => roles_allow(roles, request),
+            None => false,
+        }
+    }
+}
+
+// Returns true if the given roles allow making all the changes in the request.
+fn roles_allow(roles: &BTreeSet<Role>, request: &SetConfigRequest) -> bool {
+    let SetConfigRequest {
+        stability_threshold,
+        stability_mode,
+        syncing,
+        fees,
+        max_lag,
+        blocks_source,
+        backup_blocks_sources,
+        cross_check_blocks_sources,
+        fast_sync,
+        heartbeat_limits,
+        admins,
+        controllers,
+        http_api,
+        log_level,
+        max_rollback_depth,
+    } = request;
+
+    let fee_admin = roles.contains(&Role::FeeAdmin);
+    let sync_admin = roles.contains(&Role::SyncAdmin);
+    let emergency_stop = roles.contains(&Role::EmergencyStop);
+
+    let syncing_allowed = match syncing {
+        None => true,
+        Some(Flag::Enabled) => sync_admin,
+        Some(Flag::Disabled) => sync_admin || emergency_stop,
+    };
+
+    let changes_sync_settings = stability_threshold.is_some()
+        || stability_mode.is_some()
+        || max_lag.is_some()
+        || blocks_source.is_some()
+        || backup_blocks_sources.is_some()
+        || cross_check_blocks_sources.is_some()
+        || fast_sync.is_some()
+        || heartbeat_limits.is_some()
+        || max_rollback_depth.is_some();
+
+    // Only controllers can change the admins, the controllers, the HTTP API and the log level.
+    admins.is_none()
+        && controllers.is_none()
+        && http_api.is_none()
+        && log_level.is_none()
+        && (fees.is_none() || fee_admin)
+        && syncing_allowed
+        && (!changes_sync_settings || sync_admin)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::types::Fees;
+
+    fn principal(id: u8) -> Principal {
+        Principal::from_slice(&[id])
+    }
+
+    #[test]
+    fn roles_allow_only_their_changes() {
+        let mut access_control = AccessControl::default();
+        access_control.set_admins(vec![
+            Admin {
+                principal: principal(0),
+                roles: vec![Role::FeeAdmin],
+            },
+            Admin {
+                principal: principal(1),
+                roles: vec![Role::EmergencyStop],
+            },
+            Admin {
+                principal: principal(1),
+                roles: vec![Role::SyncAdmin],
+            },
+            Admin {
+                principal: principal(2),
+                roles: vec![Role::EmergencyStop],
+            },
+        ]);
+
+        let set_fees = SetConfigRequest {
+            fees: Some(Fees::default()),
+            ..Default::default()
+        };
+        let set_syncing = |flag| SetConfigRequest {
+            syncing: Some(flag),
+            ..Default::default()
+        };
+        let set_stability_threshold = SetConfigRequest {
+            stability_threshold: Some(6),
+            ..Default::default()
+        };
+
+        assert!(access_control.is_authorized(principal(0), &set_fees));
+        assert!(!access_control.is_authorized(principal(0), &set_syncing(Flag::Disabled)));
+
+        // The roles of principal 1 are merged.
+        assert!(access_control.is_authorized(principal(1), &set_syncing(Flag::Enabled)));
+        assert!(access_control.is_authorized(principal(1), &set_stability_threshold));
+        assert!(!access_control.is_authorized(principal(1), &set_fees));
+
+        // The emergency stop can only disable syncing.
+        assert!(access_control.is_authorized(principal(2), &set_syncing(Flag::Disabled)));
+        assert!(!access_control.is_authorized(principal(2), &set_syncing(Flag::Enabled)));
+        assert!(!access_control.is_authorized(principal(2), &set_stability_threshold));
+
+        // Principals that aren't admins, and admins changing the admins, aren't authorized.
+        assert!(!access_control.is_authorized(principal(3), &SetConfigRequest::default()));
+        assert!(!access_control.is_authorized(
+            principal(1),
+            &SetConfigRequest {
+                admins: Some(vec![]),
+                ..Default::default()
+            }
+        ));
+    }
+}
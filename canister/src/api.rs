@@ -1,12 +1,26 @@
+mod esplora;
 mod fee_percentiles;
 mod get_balance;
 mod get_utxos;
+mod http_api;
+mod logs;
 mod metrics;
 mod send_transaction;
 mod set_config;
-pub use fee_percentiles::get_current_fee_percentiles;
-pub use get_balance::get_balance;
-pub use get_utxos::get_utxos;
+mod snapshot;
+mod sync_status;
+mod utxo_set_hash;
+pub use esplora::serve_esplora;
+pub use fee_percentiles::{get_current_fee_percentiles, get_current_fee_percentiles_query};
+pub(crate) use get_balance::ENDPOINT as GET_BALANCE_ENDPOINT;
+pub use get_balance::{get_balance, get_balance_query, try_get_balance, try_get_balance_query};
+pub(crate) use get_utxos::ENDPOINT as GET_UTXOS_ENDPOINT;
+pub use get_utxos::{get_utxos, get_utxos_query, try_get_utxos, try_get_utxos_query};
+pub use http_api::serve_http_api;
+pub use logs::get_logs;
 pub use metrics::get_metrics;
 pub use send_transaction::send_transaction;
 pub use set_config::set_config;
+pub use snapshot::get_snapshot;
+pub use sync_status::get_sync_status;
+pub use utxo_set_hash::get_utxo_set_hash;
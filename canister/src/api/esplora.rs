@@ -0,0 +1,560 @@
+//! A subset of the Esplora REST API served over HTTP, so that wallets and libraries that speak
+//! it can use the canister as a backend. Like the JSON API, it's only served if `http_api` is
+//! enabled in the config.
+//!
+//! The routes are:
+//!
+//! * `/address/:address/utxo`
+//! * `/address/:address`
+//! * `/block-height/:height`
+//! * `/block/:hash/header`
+//! * `/blocks/tip/height`
+//! * `/blocks/tip/hash`
+//! * `/fee-estimates`
+//!
+//! The canister doesn't have a mempool, so all the UTXOs are confirmed and the mempool stats of
+//! addresses are always zero. It also doesn't keep the outputs that have been spent, so the chain
+//! stats of addresses only cover their unspent outputs. Addresses with more than
+//! `MAX_ADDRESS_UTXOS` UTXOs are refused, like Esplora refuses addresses with too long a history.
+use crate::{
+    api::{get_current_fee_percentiles_query, try_get_balance_query, try_get_utxos_query},
+    runtime::time,
+    state::{self, State},
+    types::{BlockHash, Flag, GetBalanceRequest, HttpResponse},
+    unstable_blocks, with_state,
+};
+use bitcoin::BlockHeader;
+use ic_btc_types::{Height, MillisatoshiPerByte, Satoshi, Utxo as PublicUtxo};
+use serde::Serialize;
+use serde_bytes::ByteBuf;
+use std::{
+    collections::{BTreeMap, BTreeSet},
+    str::FromStr,
+};
+
+// The confirmation targets (in blocks) of the fee estimates, along with the fee percentile that
+// is used as the estimate for each target.
+const FEE_ESTIMATE_PERCENTILES: &[(u32, usize)] = &[
+    (1, 90),
+    (2, 75),
+    (3, 60),
+    (6, 50),
+    (12, 40),
+    (24, 30),
+    (144, 20),
+    (504, 10),
+    (1008, 5),
+];
+
+// The maximum number of UTXOs of an address that are returned, or counted in its stats. The
+// UTXOs are retrieved page by page in a single query, whose instructions are limited.
+const MAX_ADDRESS_UTXOS: usize = 50_000;
+
+// An error returned by a route, along with its status code.
+type Error = (u16, String);
+
+// The routes of the API.
+enum Route<'a> {
+    AddressUtxos(&'a str),
+    Address(&'a str),
+    BlockHeight(&'a str),
+    BlockHeader(&'a str),
+    TipHeight,
+    TipHash,
+    FeeEstimates,
+}
+
+impl<'a> Route<'a> {
+    fn parse(path: &'a str) -> Option<Self> {
+        let segments: Vec<&str> = path.strip_prefix('/')?.split('/').collect();
+        match segments.as_slice() {
+            ["address", address, "utxo"] => Some(Self::AddressUtxos(*address)),
+            ["address", address] => Some(Self::Address(*address)),
+            ["block-height", height] => Some(Self::BlockHeight(*height)),
+            ["block", hash, "header"] => Some(Self::BlockHeader(*hash)),
+            ["blocks", "tip", "height"] => Some(Self::TipHeight),
+            ["blocks", "tip", "hash"] => Some(Self::TipHash),
+            ["fee-estimates"] => Some(Self::FeeEstimates),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Serialize)]
+struct UtxoStatus {
+    confirmed: bool,
+    block_height: Height,
+    block_hash: String,
+    block_time: u32,
+}
+
+#[derive(Serialize)]
+struct Utxo {
+    txid: String,
+    vout: u32,
+    status: UtxoStatus,
+    value: Satoshi,
+}
+
+#[derive(Serialize, Default)]
+struct Stats {
+    funded_txo_count: u64,
+    funded_txo_sum: Satoshi,
+    spent_txo_count: u64,
+    spent_txo_sum: Satoshi,
+    tx_count: u64,
+}
+
+#[derive(Serialize)]
+struct AddressInfo {
+    address: String,
+    chain_stats: Stats,
+    mempool_stats: Stats,
+}
+
+/// Returns the response to a request to the Esplora API, or `None` if the path isn't one of its
+/// routes.
+pub fn serve_esplora(method: &str, path: &str) -> Option<HttpResponse> {
+    let route = Route::parse(path)?;
+
+    if with_state(|s| s.http_api) == Flag::Disabled {
+        return Some(error_response((
+            403,
+            String::from("The Esplora API is disabled."),
+        )));
+    }
+
+    if method != "GET" {
+        return Some(error_response((
+            405,
+            format!("Method {} is not allowed.", method),
+        )));
+    }
+
+    let response = match route {
+        Route::AddressUtxos(address) => get_address_utxos(address),
+        Route::Address(address) => get_address(address),
+        Route::BlockHeight(height) => get_block_hash_at_height(height),
+        Route::BlockHeader(hash) => get_block_header(hash),
+        Route::TipHeight => Ok(text_response(
+            with_state(state::main_chain_height).to_string(),
+        )),
+        Route::TipHash => Ok(text_response(with_state(|s| {
+            unstable_blocks::get_main_chain(&s.unstable_blocks)
+                .tip()
+                .block_hash()
+                .to_string()
+        }))),
+        Route::FeeEstimates => Ok(json_response(&fee_estimates(
+            &get_current_fee_percentiles_query(),
+        ))),
+    };
+
+    Some(response.unwrap_or_else(error_response))
+}
+
+fn get_address_utxos(address: &str) -> Result<HttpResponse, Error> {
+    verify_synced()?;
+    let utxos = get_all_utxos(address, MAX_ADDRESS_UTXOS)?;
+
+    let utxos = with_state(|s| {
+        // The headers of the blocks containing the UTXOs, by height.
+        let mut headers = BTreeMap::new();
+        utxos
+            .into_iter()
+            .map(|utxo| {
+                let header = match headers.get(&utxo.height) {
+                    Some(header) => *header,
+                    None => {
+                        let header = header_at_height(s, utxo.height)?;
+                        headers.insert(utxo.height, header);
+                        header
+                    }
+                };
+                Ok(Utxo {
+                    txid: display_hash(&utxo.outpoint.txid),
+                    vout: utxo.outpoint.vout,
+                    status: UtxoStatus {
+                        confirmed: true,
+                        block_height: utxo.height,
+                        block_hash: header.block_hash().to_string(),
+                        block_time: header.time,
+                    },
+                    value: utxo.value,
+                })
+            })
+            .collect::<Result<Vec<Utxo>, Error>>()
+    })?;
+
+    Ok(json_response(&utxos))
+}
+
+fn get_address(address: &str) -> Result<HttpResponse, Error> {
+    verify_synced()?;
+    let balance = try_get_balance_query(GetBalanceRequest {
+        address: address.to_string(),
+        min_confirmations: None,
+    })
+    .map_err(|err| (400, format!("{:?}", err)))?;
+    let utxos = get_all_utxos(address, MAX_ADDRESS_UTXOS)?;
+
+    let txids: BTreeSet<_> = utxos.iter().map(|utxo| &utxo.outpoint.txid).collect();
+    Ok(json_response(&AddressInfo {
+        address: address.to_string(),
+        chain_stats: Stats {
+            funded_txo_count: utxos.len() as u64,
+            funded_txo_sum: balance,
+            spent_txo_count: 0,
+            spent_txo_sum: 0,
+            tx_count: txids.len() as u64,
+        },
+        mempool_stats: Stats::default(),
+    }))
+}
+
+fn get_block_hash_at_height(height: &str) -> Result<HttpResponse, Error> {
+    let height: Height = height
+        .parse()
+        .map_err(|_| (400, format!("Invalid block height: {}", height)))?;
+
+    with_state(|s| state::header_at_height(s, height))
+        .map(|header| text_response(header.block_hash().to_string()))
+        .ok_or_else(|| (404, String::from("Block not found")))
+}
+
+fn get_block_header(hash: &str) -> Result<HttpResponse, Error> {
+    let block_hash =
+        BlockHash::from_str(hash).map_err(|_| (400, format!("Invalid block hash: {}", hash)))?;
+
+    with_state(|s| header_with_block_hash(s, &block_hash))
+        .map(|header| text_response(hex::encode(bitcoin::consensus::serialize(&header))))
+        .ok_or_else(|| (404, String::from("Block not found")))
+}
+
+// Returns the header of the block with the given hash, if the block is known.
+fn header_with_block_hash(state: &State, block_hash: &BlockHash) -> Option<BlockHeader> {
+    state
+        .stable_block_headers
+        .get_with_block_hash(block_hash)
+        .or_else(|| {
+            state::get_unstable_blocks(state)
+                .into_iter()
+                .find(|block| block.block_hash() == *block_hash)
+                .map(|block| *block.header())
+        })
+}
+
+// Returns all the UTXOs of the given address, following the pages of `get_utxos`, or an error if
+// there are more than `max_utxos` of them.
+fn get_all_utxos(address: &str, max_utxos: usize) -> Result<Vec<PublicUtxo>, Error> {
+    let mut utxos = vec![];
+    let mut page = None;
+    loop {
+        let response = try_get_utxos_query(address, 0, page.take())
+            .map_err(|err| (400, format!("{:?}", err)))?;
+        utxos.extend(response.utxos);
+        if utxos.len() > max_utxos {
+            return Err((
+                400,
+                format!("Too many UTXOs: the address has more than {}.", max_utxos),
+            ));
+        }
+
+        match response.next_page {
+            Some(next_page) => page = Some(next_page.into_vec()),
+            None => return Ok(utxos),
+        }
+    }
+}
+
+// Returns the header of the block of a UTXO at the given height. The header is expected to be
+// known, but isn't for the stable blocks of a state built offline, which only has their UTXOs.
+fn header_at_height(state: &State, height: Height) -> Result<BlockHeader, Error> {
+    state::header_at_height(state, height).ok_or_else(|| {
+        (
+            500,
+            format!("The header at height {} is unavailable.", height),
+        )
+    })
+}
+
+// Returns the fee estimates in satoshi per byte, keyed by their confirmation target.
+fn fee_estimates(fee_percentiles: &[MillisatoshiPerByte]) -> BTreeMap<u32, f64> {
+    if fee_percentiles.is_empty() {
+        return BTreeMap::new();
+    }
+
+    FEE_ESTIMATE_PERCENTILES
+        .iter()
+        .map(|(target, percentile)| (*target, fee_percentiles[*percentile] as f64 / 1000.0))
+        .collect()
+}
+
+fn verify_synced() -> Result<(), Error> {
+    with_state(|s| state::check_synced(s, time() / 1_000_000_000))
+        .map_err(|err| (503, format!("NotSynced: {:?}", err)))
+}
+
+// Returns the hex encoding of a hash in the byte order that's used to display it.
+fn display_hash(hash: &[u8]) -> String {
+    let mut bytes = hash.to_vec();
+    bytes.reverse();
+    hex::encode(bytes)
+}
+
+fn json_response(body: &impl Serialize) -> HttpResponse {
+    response(
+        200,
+        "application/json",
+        serde_json::to_vec(body).expect("JSON encoding must succeed"),
+    )
+}
+
+fn text_response(body: String) -> HttpResponse {
+    response(200, "text/plain", body.into_bytes())
+}
+
+fn error_response((status_code, error): Error) -> HttpResponse {
+    response(status_code, "text/plain", error.into_bytes())
+}
+
+fn response(status_code: u16, content_type: &str, body: Vec<u8>) -> HttpResponse {
+    HttpResponse {
+        status_code,
+        headers: vec![
+            ("Content-Type".to_string(), content_type.to_string()),
+            ("Content-Length".to_string(), body.len().to_string()),
+        ],
+        body: ByteBuf::from(body),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::{
+        genesis_block,
+        test_utils::{random_p2pkh_address, BlockBuilder, TransactionBuilder},
+        types::{Block, Config, HttpRequest, Network},
+        with_state_mut,
+    };
+    use serde_json::{json, Value};
+
+    fn init() {
+        crate::init(Config {
+            stability_threshold: 2,
+            network: Network::Regtest,
+            http_api: Some(Flag::Enabled),
+            ..Default::default()
+        });
+    }
+
+    fn get(url: &str) -> (u16, String) {
+        let response = crate::http_request(HttpRequest {
+            method: String::from("GET"),
+            url: url.to_string(),
+            headers: vec![],
+            body: ByteBuf::new(),
+        });
+        (
+            response.status_code,
+            String::from_utf8(response.body.into_vec()).unwrap(),
+        )
+    }
+
+    fn get_json(url: &str) -> Value {
+        let (status_code, body) = get(url);
+        assert_eq!(status_code, 200, "{}", body);
+        serde_json::from_str(&body).unwrap()
+    }
+
+    // Inserts two blocks on top of genesis, the first of which gives two outputs to the returned
+    // address in the same transaction, and ingests the genesis block, which is the only stable
+    // block.
+    fn insert_blocks() -> (String, Vec<Block>) {
+        let network = Network::Regtest;
+        let address = random_p2pkh_address(network);
+        let block_1 = BlockBuilder::with_prev_header(genesis_block(network).header())
+            .with_transaction(
+                TransactionBuilder::coinbase()
+                    .with_output(&address, 1000)
+                    .with_output(&address, 2000)
+                    .build(),
+            )
+            .build();
+        let block_2 = BlockBuilder::with_prev_header(block_1.header()).build();
+
+        with_state_mut(|s| {
+            state::insert_block(s, block_1.clone()).unwrap();
+            state::insert_block(s, block_2.clone()).unwrap();
+            state::ingest_stable_blocks_into_utxoset(s);
+        });
+
+        (address.to_string(), vec![block_1, block_2])
+    }
+
+    #[test]
+    fn returns_the_utxos_and_stats_of_addresses() {
+        init();
+        let (address, blocks) = insert_blocks();
+        let txid = blocks[0].txdata()[0].txid().to_string();
+
+        let mut utxos = get_json(&format!("/address/{}/utxo", address));
+        utxos
+            .as_array_mut()
+            .unwrap()
+            .sort_by_key(|utxo| utxo["vout"].as_u64());
+        let status = json!({
+            "confirmed": true,
+            "block_height": 1,
+            "block_hash": blocks[0].block_hash().to_string(),
+            "block_time": blocks[0].header().time,
+        });
+        assert_eq!(
+            utxos,
+            json!([
+                { "txid": txid, "vout": 0, "status": status, "value": 1000 },
+                { "txid": txid, "vout": 1, "status": status, "value": 2000 },
+            ])
+        );
+
+        let stats = json!({
+            "funded_txo_count": 2,
+            "funded_txo_sum": 3000,
+            "spent_txo_count": 0,
+            "spent_txo_sum": 0,
+            "tx_count": 1,
+        });
+        let empty_stats = json!({
+            "funded_txo_count": 0,
+            "funded_txo_sum": 0,
+            "spent_txo_count": 0,
+            "spent_txo_sum": 0,
+            "tx_count": 0,
+        });
+        assert_eq!(
+            get_json(&format!("/address/{}", address)),
+            json!({
+                "address": address,
+                "chain_stats": stats,
+                "mempool_stats": empty_stats,
+            })
+        );
+
+        assert_eq!(get("/address/not-an-address/utxo").0, 400);
+    }
+
+    #[test]
+    fn follows_the_pages_of_utxos() {
+        init();
+        let network = Network::Regtest;
+        let address = random_p2pkh_address(network);
+
+        // More UTXOs than fit in a page of `get_utxos`.
+        let num_utxos = 10_001;
+        let mut transaction = TransactionBuilder::coinbase();
+        for value in 1..=num_utxos {
+            transaction = transaction.with_output(&address, value);
+        }
+        let block = BlockBuilder::with_prev_header(genesis_block(network).header())
+            .with_transaction(transaction.build())
+            .build();
+        with_state_mut(|s| state::insert_block(s, block).unwrap());
+
+        let address = address.to_string();
+        let utxos = get_json(&format!("/address/{}/utxo", address));
+        assert_eq!(utxos.as_array().unwrap().len(), num_utxos as usize);
+        let stats = &get_json(&format!("/address/{}", address))["chain_stats"];
+        assert_eq!(stats["funded_txo_count"], num_utxos);
+        assert_eq!(stats["funded_txo_sum"], num_utxos * (num_utxos + 1) / 2);
+
+        // Addresses with too many UTXOs are refused.
+        assert_eq!(
+            get_all_utxos(&address, 10_000).map(|utxos| utxos.len()),
+            Err((
+                400,
+                String::from("Too many UTXOs: the address has more than 10000.")
+            ))
+        );
+    }
+
+    #[test]
+    fn returns_an_error_if_the_header_of_a_utxo_is_unavailable() {
+        init();
+        let (address, blocks) = insert_blocks();
+
+        // The block with the UTXOs becomes stable, and its header is dropped, as in a state
+        // built offline.
+        let block_3 = BlockBuilder::with_prev_header(blocks[1].header()).build();
+        with_state_mut(|s| {
+            state::insert_block(s, block_3).unwrap();
+            state::ingest_stable_blocks_into_utxoset(s);
+            assert_eq!(s.utxos.next_height(), 2);
+            s.stable_block_headers.remove(1);
+        });
+
+        assert_eq!(
+            get(&format!("/address/{}/utxo", address)),
+            (500, String::from("The header at height 1 is unavailable."))
+        );
+    }
+
+    #[test]
+    fn returns_blocks_and_the_tip() {
+        init();
+        let (_, blocks) = insert_blocks();
+
+        assert_eq!(get("/blocks/tip/height"), (200, String::from("2")));
+        assert_eq!(
+            get("/blocks/tip/hash"),
+            (200, blocks[1].block_hash().to_string())
+        );
+
+        // Both stable and unstable blocks are found.
+        let genesis = genesis_block(Network::Regtest);
+        for (height, block) in [&genesis, &blocks[0], &blocks[1]].iter().enumerate() {
+            let block_hash = block.block_hash().to_string();
+            assert_eq!(
+                get(&format!("/block-height/{}", height)),
+                (200, block_hash.clone())
+            );
+            assert_eq!(
+                get(&format!("/block/{}/header", block_hash)),
+                (
+                    200,
+                    hex::encode(bitcoin::consensus::serialize(block.header()))
+                )
+            );
+        }
+
+        assert_eq!(get("/block-height/3").0, 404);
+        assert_eq!(get("/block-height/abc").0, 400);
+        assert_eq!(get(&format!("/block/{}/header", "00".repeat(32))).0, 404);
+        assert_eq!(get("/block/abc/header").0, 400);
+    }
+
+    #[test]
+    fn fee_estimates_use_higher_percentiles_for_lower_targets() {
+        let fee_percentiles: Vec<_> = (0..=100).map(|p| p * 1000).collect();
+        let estimates = fee_estimates(&fee_percentiles);
+
+        assert_eq!(estimates.len(), FEE_ESTIMATE_PERCENTILES.len());
+        assert_eq!(estimates[&1], 90.0);
+        assert_eq!(estimates[&1008], 5.0);
+        assert!(estimates
+            .values()
+            .zip(estimates.values().skip(1))
+            .all(|(a, b)| a >= b));
+
+        assert_eq!(fee_estimates(&[]), BTreeMap::new());
+    }
+
+    #[test]
+    fn is_only_served_if_enabled() {
+        crate::init(Config {
+            network: Network::Regtest,
+            ..Default::default()
+        });
+        assert_eq!(get("/blocks/tip/height").0, 403);
+    }
+}
@@ -1,6 +1,6 @@
 use crate::{
     charge_cycles,
-    runtime::{performance_counter, print},
+    runtime::performance_counter,
     state::{FeePercentilesCache, State},
     types::{Block, Transaction},
     unstable_blocks::{self, UnstableBlocks},
@@ -11,9 +11,12 @@ use ic_btc_types::MillisatoshiPerByte;
 /// The number of transactions to include in the percentiles calculation.
 const NUM_TRANSACTIONS: u32 = 10_000;
 
+const ENDPOINT: &str = "bitcoin_get_current_fee_percentiles";
+
 /// Returns the 100 fee percentiles of the chain's 10,000 most recent transactions.
 pub fn get_current_fee_percentiles() -> Vec<MillisatoshiPerByte> {
-    charge_cycles(with_state(|s| s.fees.get_current_fee_percentiles));
+    with_state_mut(|s| s.metrics.observe_call(ENDPOINT));
+    charge_cycles(ENDPOINT, with_state(|s| s.fees.get_current_fee_percentiles));
 
     let res = with_state_mut(|s| get_current_fee_percentiles_internal(s, NUM_TRANSACTIONS));
 
@@ -22,15 +25,21 @@ pub fn get_current_fee_percentiles() -> Vec<MillisatoshiPerByte> {
     with_state_mut(|s| {
         s.metrics
             .get_current_fee_percentiles_total
-            .observe(ins_total)
+            .observe_instructions(ins_total);
+        s.logs.debug(
+            "get_current_fee_percentiles",
+            &[("instructions", &ins_total)],
+        );
     });
-    print(&format!(
-        "[INSTRUCTION COUNT] get_current_fee_percentiles: {}",
-        ins_total
-    ));
     res
 }
 
+/// Same as `get_current_fee_percentiles`, but without charging cycles, which queries cannot
+/// accept.
+pub fn get_current_fee_percentiles_query() -> Vec<MillisatoshiPerByte> {
+    with_state_mut(|s| get_current_fee_percentiles_internal(s, NUM_TRANSACTIONS))
+}
+
 fn get_current_fee_percentiles_internal(
     state: &mut State,
     number_of_transactions: u32,
@@ -1,12 +1,14 @@
 use crate::{
     charge_cycles,
-    runtime::{performance_counter, print},
+    runtime::performance_counter,
     types::{Address, GetBalanceRequest},
-    unstable_blocks, with_state, with_state_mut,
+    unstable_blocks, verify_cycles_available, with_state, with_state_mut,
 };
 use ic_btc_types::{GetBalanceError, Satoshi};
 use std::str::FromStr;
 
+pub(crate) const ENDPOINT: &str = "bitcoin_get_balance";
+
 // Various profiling stats for tracking the performance of `get_balance`.
 #[derive(Debug, Default)]
 struct Stats {
@@ -15,16 +17,54 @@ struct Stats {
 
     // The number of instructions used to apply the unstable blocks.
     ins_apply_unstable_blocks: u64,
+
+    // The number of unstable blocks applied.
+    num_unstable_blocks: u64,
 }
 
 /// Retrieves the balance of the given Bitcoin address.
+///
+/// The fee depends on the number of unstable blocks applied if usage-based fees are set. The fee
+/// of applying all the unstable blocks of the main chain is verified before processing the
+/// request, and the cycles that aren't charged are refunded.
 pub fn get_balance(request: GetBalanceRequest) -> Satoshi {
-    charge_cycles(with_state(|s| s.fees.get_balance));
+    try_get_balance(request).expect("get_balance failed")
+}
+
+/// Same as `get_balance`, but returns an error instead of panicking if the request is invalid,
+/// so that the error is kept in the metrics.
+pub fn try_get_balance(request: GetBalanceRequest) -> Result<Satoshi, GetBalanceError> {
+    with_state_mut(|s| s.metrics.observe_call(ENDPOINT));
+    verify_cycles_available(with_state(|s| {
+        let num_unstable_blocks = unstable_blocks::get_main_chain(&s.unstable_blocks).len();
+        s.fees.get_balance_fee(num_unstable_blocks as u64)
+    }));
+
+    let (balance, stats) = get_balance_internal(request).map_err(|err| {
+        with_state_mut(|s| s.metrics.observe_error(ENDPOINT, &err));
+        err
+    })?;
+
+    charge_cycles(
+        ENDPOINT,
+        with_state(|s| s.fees.get_balance_fee(stats.num_unstable_blocks)),
+    );
+    Ok(balance)
+}
 
-    get_balance_internal(request).expect("get_balance failed")
+/// Retrieves the balance of the given Bitcoin address without charging cycles, which queries
+/// cannot accept.
+pub fn get_balance_query(request: GetBalanceRequest) -> Satoshi {
+    try_get_balance_query(request).expect("get_balance failed")
 }
 
-fn get_balance_internal(request: GetBalanceRequest) -> Result<Satoshi, GetBalanceError> {
+/// Same as `get_balance_query`, but returns an error instead of panicking if the request is
+/// invalid.
+pub fn try_get_balance_query(request: GetBalanceRequest) -> Result<Satoshi, GetBalanceError> {
+    get_balance_internal(request).map(|(balance, _)| balance)
+}
+
+fn get_balance_internal(request: GetBalanceRequest) -> Result<(Satoshi, Stats), GetBalanceError> {
     let min_confirmations = request.min_confirmations.unwrap_or(0);
     let address =
         Address::from_str(&request.address).map_err(|_| GetBalanceError::MalformedAddress)?;
@@ -46,6 +86,7 @@ fn get_balance_internal(request: GetBalanceRequest) -> Result<Satoshi, GetBalanc
 
         // Apply all the unstable blocks.
         let ins_start = performance_counter();
+        let mut num_unstable_blocks = 0;
         let chain_height = state.utxos.next_height() + (main_chain.len() as u32) - 1;
         for (i, block) in main_chain.into_chain().iter().enumerate() {
             let block_height = state.utxos.next_height() + (i as u32);
@@ -72,11 +113,14 @@ fn get_balance_internal(request: GetBalanceRequest) -> Result<Satoshi, GetBalanc
                 let (txout, _) = state.unstable_blocks.get_tx_out(outpoint).unwrap();
                 balance -= txout.value;
             }
+
+            num_unstable_blocks += 1;
         }
 
         let stats = Stats {
             ins_apply_unstable_blocks: performance_counter() - ins_start,
             ins_total: performance_counter(),
+            num_unstable_blocks,
         };
 
         Ok((balance, stats))
@@ -84,16 +128,17 @@ fn get_balance_internal(request: GetBalanceRequest) -> Result<Satoshi, GetBalanc
 
     // Observe metrics
     with_state_mut(|s| {
-        s.metrics.get_balance_total.observe(stats.ins_total);
+        s.metrics
+            .get_balance_total
+            .observe_instructions(stats.ins_total);
         s.metrics
             .get_balance_apply_unstable_blocks
-            .observe(stats.ins_apply_unstable_blocks);
+            .observe_instructions(stats.ins_apply_unstable_blocks);
+        s.logs
+            .debug("get_balance", &[("request", &request), ("stats", &stats)]);
     });
 
-    // Print the number of instructions it took to process this request.
-    print(&format!("[INSTRUCTION COUNT] {:?}: {:?}", request, stats));
-
-    Ok(balance)
+    Ok((balance, stats))
 }
 
 #[cfg(test)]
@@ -102,7 +147,7 @@ mod test {
     use crate::{
         genesis_block, state,
         test_utils::{random_p2pkh_address, BlockBuilder, TransactionBuilder},
-        types::{Config, Fees, Network, OutPoint},
+        types::{Config, Fees, Network, OutPoint, UsageBasedFees},
         with_state_mut,
     };
 
@@ -288,4 +333,79 @@ mod test {
 
         assert_eq!(crate::runtime::get_cycles_balance(), 10);
     }
+
+    #[test]
+    fn records_calls_cycles_and_errors_in_metrics() {
+        crate::init(Config {
+            fees: Fees {
+                get_balance: 10,
+                ..Default::default()
+            },
+            ..Default::default()
+        });
+
+        try_get_balance(GetBalanceRequest {
+            address: random_p2pkh_address(Network::Regtest).to_string(),
+            min_confirmations: None,
+        })
+        .unwrap();
+        assert!(matches!(
+            try_get_balance(GetBalanceRequest {
+                address: String::from("not an address"),
+                min_confirmations: None,
+            }),
+            Err(GetBalanceError::MalformedAddress)
+        ));
+
+        crate::with_state(|s| {
+            assert_eq!(s.metrics.num_calls.get(ENDPOINT), Some(&2));
+            assert_eq!(s.metrics.cycles_charged.get(ENDPOINT), Some(&10));
+            assert_eq!(
+                s.metrics
+                    .num_errors
+                    .get(&(ENDPOINT.to_string(), "MalformedAddress".to_string())),
+                Some(&1)
+            );
+        });
+    }
+
+    #[test]
+    fn charges_usage_based_fees() {
+        let network = Network::Regtest;
+        crate::init(Config {
+            stability_threshold: 2,
+            network,
+            fees: Fees {
+                get_balance: 10,
+                usage_based: Some(UsageBasedFees {
+                    get_utxos_per_utxo: 3,
+                    per_unstable_block: 5,
+                }),
+                ..Default::default()
+            },
+            ..Default::default()
+        });
+
+        let address = random_p2pkh_address(network);
+        let block = BlockBuilder::with_prev_header(genesis_block(network).header())
+            .with_transaction(
+                TransactionBuilder::coinbase()
+                    .with_output(&address, 1000)
+                    .build(),
+            )
+            .build();
+        with_state_mut(|state| {
+            state::insert_block(state, block).unwrap();
+        });
+
+        // Only the block with enough confirmations is applied.
+        assert_eq!(
+            get_balance(GetBalanceRequest {
+                address: address.to_string(),
+                min_confirmations: Some(2),
+            }),
+            0
+        );
+        assert_eq!(crate::runtime::get_cycles_balance(), 10 + 5);
+    }
 }
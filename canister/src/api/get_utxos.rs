@@ -1,9 +1,9 @@
 use crate::{
     blocktree::BlockChain,
     charge_cycles,
-    runtime::{performance_counter, print},
+    runtime::performance_counter,
     types::{Address, GetUtxosRequest, OutPoint, Page, Txid, Utxo},
-    unstable_blocks, with_state, with_state_mut, State,
+    unstable_blocks, verify_cycles_available, with_state, with_state_mut, State,
 };
 use ic_btc_types::{GetUtxosError, GetUtxosResponse, Utxo as PublicUtxo, UtxosFilter};
 use serde_bytes::ByteBuf;
@@ -21,6 +21,8 @@ use std::str::FromStr;
 // than 100_000 `Utxo`s are returned in a single response.
 const MAX_UTXOS_PER_RESPONSE: usize = 10_000;
 
+pub(crate) const ENDPOINT: &str = "bitcoin_get_utxos";
+
 // Various profiling stats for tracking the performance of `get_utxos`.
 #[derive(Default, Debug)]
 struct Stats {
@@ -32,12 +34,77 @@ struct Stats {
 
     // The number of instructions used to build the utxos vec.
     ins_build_utxos_vec: u64,
+
+    // The number of unstable blocks applied.
+    num_unstable_blocks: u64,
 }
 
 /// Retrieves the UTXOs of the given Bitcoin address.
+///
+/// The fee depends on the number of UTXOs returned and unstable blocks applied if usage-based
+/// fees are set. The fee of returning a full page after applying all the unstable blocks of the
+/// main chain is verified before processing the request, and the cycles that aren't charged are
+/// refunded.
 pub fn get_utxos(request: GetUtxosRequest) -> GetUtxosResponse {
-    charge_cycles(with_state(|s| s.fees.get_utxos));
+    try_get_utxos(request).expect("get_utxos failed")
+}
+
+/// Same as `get_utxos`, but returns an error instead of panicking if the request is invalid,
+/// so that the error is kept in the metrics.
+pub fn try_get_utxos(request: GetUtxosRequest) -> Result<GetUtxosResponse, GetUtxosError> {
+    with_state_mut(|s| s.metrics.observe_call(ENDPOINT));
+    verify_cycles_available(with_state(|s| {
+        let num_unstable_blocks = unstable_blocks::get_main_chain(&s.unstable_blocks).len();
+        s.fees
+            .get_utxos_fee(MAX_UTXOS_PER_RESPONSE as u64, num_unstable_blocks as u64)
+    }));
+
+    let (res, stats) = get_utxos_with_stats(request).map_err(|err| {
+        with_state_mut(|s| s.metrics.observe_error(ENDPOINT, &err));
+        err
+    })?;
+
+    charge_cycles(
+        ENDPOINT,
+        with_state(|s| {
+            s.fees
+                .get_utxos_fee(res.utxos.len() as u64, stats.num_unstable_blocks)
+        }),
+    );
+    Ok(res)
+}
+
+/// Retrieves the UTXOs of the given Bitcoin address without charging cycles, which queries
+/// cannot accept.
+pub fn get_utxos_query(request: GetUtxosRequest) -> GetUtxosResponse {
+    get_utxos_with_stats(request).expect("get_utxos failed").0
+}
+
+/// Retrieves the UTXOs of the given Bitcoin address without charging cycles, returning an error
+/// instead of panicking if the request is invalid.
+///
+/// Unlike a `GetUtxosRequest`, both a number of confirmations and a page can be given.
+pub fn try_get_utxos_query(
+    address: &str,
+    min_confirmations: u32,
+    page: Option<Vec<u8>>,
+) -> Result<GetUtxosResponse, GetUtxosError> {
+    with_state(|state| {
+        get_utxos_internal(
+            state,
+            address,
+            min_confirmations,
+            page,
+            MAX_UTXOS_PER_RESPONSE,
+        )
+    })
+    .map(|(res, _)| res)
+}
 
+// Retrieves the UTXOs of the given Bitcoin address and observes the metrics of the request.
+fn get_utxos_with_stats(
+    request: GetUtxosRequest,
+) -> Result<(GetUtxosResponse, Stats), GetUtxosError> {
     let (res, stats) = with_state(|state| {
         match &request.filter {
             None => {
@@ -62,23 +129,26 @@ pub fn get_utxos(request: GetUtxosRequest) -> GetUtxosResponse {
                 MAX_UTXOS_PER_RESPONSE,
             ),
         }
-    })
-    .expect("get_utxos failed");
+    })?;
 
     // Observe metrics
     with_state_mut(|s| {
-        s.metrics.get_utxos_total.observe(stats.ins_total);
+        s.metrics
+            .get_utxos_total
+            .observe_instructions(stats.ins_total);
         s.metrics
             .get_utxos_apply_unstable_blocks
-            .observe(stats.ins_apply_unstable_blocks);
+            .observe_instructions(stats.ins_apply_unstable_blocks);
         s.metrics
             .get_utxos_build_utxos_vec
-            .observe(stats.ins_build_utxos_vec);
+            .observe_instructions(stats.ins_build_utxos_vec);
+        s.metrics
+            .get_utxos_num_utxos
+            .observe(res.utxos.len() as f64);
+        s.logs
+            .debug("get_utxos", &[("request", &request), ("stats", &stats)]);
     });
-
-    // Print the number of instructions it took to process this request.
-    print(&format!("[INSTRUCTION COUNT] {:?}: {:?}", request, stats));
-    res
+    Ok((res, stats))
 }
 
 // Returns the set of UTXOs for a given bitcoin address.
@@ -173,6 +243,7 @@ fn get_utxos_from_chain(
         }
 
         address_utxos.apply_block(block);
+        stats.num_unstable_blocks += 1;
 
         tip_block_hash = block.block_hash();
         tip_block_height = block_height;
@@ -235,7 +306,7 @@ mod test {
     use crate::{
         genesis_block, state,
         test_utils::{random_p2pkh_address, random_p2tr_address, BlockBuilder, TransactionBuilder},
-        types::{Block, Config, Fees, Network},
+        types::{Block, Config, Fees, Network, UsageBasedFees},
         with_state_mut,
     };
     use ic_btc_types::{OutPoint, Utxo};
@@ -1136,4 +1207,72 @@ mod test {
 
         assert_eq!(crate::runtime::get_cycles_balance(), 10);
     }
+
+    #[test]
+    fn charges_usage_based_fees() {
+        let network = Network::Regtest;
+        crate::init(Config {
+            stability_threshold: 2,
+            network,
+            fees: Fees {
+                get_utxos: 10,
+                usage_based: Some(UsageBasedFees {
+                    get_utxos_per_utxo: 3,
+                    per_unstable_block: 5,
+                }),
+                ..Default::default()
+            },
+            ..Default::default()
+        });
+
+        // Two UTXOs are given to the address in an unstable block.
+        let address = random_p2pkh_address(network);
+        let block = BlockBuilder::with_prev_header(genesis_block(network).header())
+            .with_transaction(
+                TransactionBuilder::coinbase()
+                    .with_output(&address, 1000)
+                    .with_output(&address, 2000)
+                    .build(),
+            )
+            .build();
+        with_state_mut(|state| {
+            state::insert_block(state, block).unwrap();
+        });
+
+        let response = get_utxos(GetUtxosRequest {
+            address: address.to_string(),
+            filter: None,
+        });
+        assert_eq!(response.utxos.len(), 2);
+
+        // The genesis block and the new block are both unstable, and are applied. The rest of
+        // the cycles that were received are refunded.
+        assert_eq!(crate::runtime::get_cycles_balance(), 10 + 3 * 2 + 5 * 2);
+    }
+
+    #[test]
+    #[should_panic(expected = "cycles are required")]
+    fn verifies_the_maximum_usage_based_fee_before_processing_the_request() {
+        let network = Network::Regtest;
+        crate::init(Config {
+            network,
+            fees: Fees {
+                get_utxos: 10,
+                usage_based: Some(UsageBasedFees {
+                    get_utxos_per_utxo: 3,
+                    per_unstable_block: 5,
+                }),
+                ..Default::default()
+            },
+            ..Default::default()
+        });
+
+        // The cycles would cover the fee of the request, which returns no UTXOs, but not the
+        // fee of a full page.
+        crate::runtime::set_msg_cycles_available(10 + 5);
+        get_utxos(GetUtxosRequest {
+            address: random_p2pkh_address(network).to_string(),
+            filter: None,
+        });
+    }
 }
@@ -0,0 +1,542 @@
+//! A read-only JSON API served over HTTP, mirroring the candid endpoints for dashboards and
+//! scripts. It's only served if `http_api` is enabled in the config, as no cycles are charged.
+//!
+//! The routes are:
+//!
+//! * `/balance/{address}?min_confirmations=N`
+//! * `/utxos/{address}?min_confirmations=N&page=P`, where `P` is the `next_page` of a previous
+//!   response.
+//! * `/fee_percentiles`
+//! * `/tip`
+//! * `/headers/{height}`
+//! * `/config`
+//!
+//! Hashes are hex-encoded in the byte order that's used to display them, e.g. by block explorers.
+//! Errors are returned as `{"error": "..."}` along with the corresponding status code.
+use crate::{
+    api::{get_current_fee_percentiles_query, try_get_balance_query, try_get_utxos_query},
+    certification,
+    runtime::time,
+    state,
+    types::{
+        Admin, Config, Fees, Flag, GetBalanceRequest, HeartbeatLimits, HttpResponse, LogLevel,
+        MaxLag, Network, Role, StabilityMode,
+    },
+    with_state,
+};
+use ic_btc_types::{GetUtxosResponse, Height, Satoshi};
+use serde::Serialize;
+use serde_bytes::ByteBuf;
+use std::collections::BTreeMap;
+
+// An error returned by a route, along with its status code.
+type Error = (u16, String);
+
+// The routes of the API.
+enum Route<'a> {
+    Balance(&'a str),
+    Utxos(&'a str),
+    FeePercentiles,
+    Tip,
+    Header(&'a str),
+    Config,
+}
+
+impl<'a> Route<'a> {
+    fn parse(path: &'a str) -> Option<Self> {
+        match path {
+            "/fee_percentiles" => Some(Self::FeePercentiles),
+            "/tip" => Some(Self::Tip),
+            "/config" => Some(Self::Config),
+            _ => {
+                let (prefix, arg) = path.strip_prefix('/')?.split_once('/')?;
+                if arg.is_empty() || arg.contains('/') {
+                    return None;
+                }
+                match prefix {
+                    "balance" => Some(Self::Balance(arg)),
+                    "utxos" => Some(Self::Utxos(arg)),
+                    "headers" => Some(Self::Header(arg)),
+                    _ => None,
+                }
+            }
+        }
+    }
+
+    // The query parameters accepted by the route.
+    fn params(&self) -> &'static [&'static str] {
+        match self {
+            Self::Balance(_) => &["min_confirmations"],
+            Self::Utxos(_) => &["min_confirmations", "page"],
+            Self::FeePercentiles | Self::Tip | Self::Header(_) | Self::Config => &[],
+        }
+    }
+}
+
+#[derive(Serialize)]
+struct ErrorJson {
+    error: String,
+}
+
+#[derive(Serialize)]
+struct BalanceJson {
+    balance: Satoshi,
+}
+
+#[derive(Serialize)]
+struct UtxoJson {
+    txid: String,
+    vout: u32,
+    value: Satoshi,
+    height: Height,
+}
+
+#[derive(Serialize)]
+struct UtxosJson {
+    utxos: Vec<UtxoJson>,
+    tip_block_hash: String,
+    tip_height: Height,
+    next_page: Option<String>,
+}
+
+impl From<GetUtxosResponse> for UtxosJson {
+    fn from(response: GetUtxosResponse) -> Self {
+        Self {
+            utxos: response
+                .utxos
+                .into_iter()
+                .map(|utxo| UtxoJson {
+                    txid: display_hash(&utxo.outpoint.txid),
+                    vout: utxo.outpoint.vout,
+                    value: utxo.value,
+                    height: utxo.height,
+                })
+                .collect(),
+            tip_block_hash: display_hash(&response.tip_block_hash),
+            tip_height: response.tip_height,
+            next_page: response.next_page.map(hex::encode),
+        }
+    }
+}
+
+#[derive(Serialize)]
+struct TipJson {
+    block_hash: String,
+    height: Height,
+    stable_height: Height,
+    utxos_length: u64,
+}
+
+#[derive(Serialize)]
+struct HeaderJson {
+    block_hash: String,
+    height: Height,
+    version: i32,
+    prev_block_hash: String,
+    merkle_root: String,
+    time: u32,
+    bits: u32,
+    nonce: u32,
+}
+
+#[derive(Serialize)]
+struct AdminJson {
+    principal: String,
+    roles: Vec<Role>,
+}
+
+// The config, with principals in their textual representation.
+#[derive(Serialize)]
+struct ConfigJson {
+    stability_threshold: u128,
+    network: Network,
+    stability_mode: Option<StabilityMode>,
+    blocks_source: String,
+    backup_blocks_sources: Option<Vec<String>>,
+    cross_check_blocks_sources: Option<Flag>,
+    fast_sync: Option<Flag>,
+    syncing: Flag,
+    fees: Fees,
+    max_lag: Option<MaxLag>,
+    best_header_height: Option<Height>,
+    heartbeat_limits: Option<HeartbeatLimits>,
+    admins: Option<Vec<AdminJson>>,
+    controllers: Option<Vec<String>>,
+    http_api: Option<Flag>,
+    log_level: Option<LogLevel>,
+    max_rollback_depth: Option<u32>,
+}
+
+impl From<Config> for ConfigJson {
+    fn from(config: Config) -> Self {
+        Self {
+            stability_threshold: config.stability_threshold,
+            network: config.network,
+            stability_mode: config.stability_mode,
+            blocks_source: config.blocks_source.to_text(),
+            backup_blocks_sources: config
+                .backup_blocks_sources
+                .map(|sources| sources.iter().map(|source| source.to_text()).collect()),
+            cross_check_blocks_sources: config.cross_check_blocks_sources,
+            fast_sync: config.fast_sync,
+            syncing: config.syncing,
+            fees: config.fees,
+            max_lag: config.max_lag,
+            best_header_height: config.best_header_height,
+            heartbeat_limits: config.heartbeat_limits,
+            admins: config.admins.map(|admins| {
+                admins
+                    .into_iter()
+                    .map(|Admin { principal, roles }| AdminJson {
+                        principal: principal.to_text(),
+                        roles,
+                    })
+                    .collect()
+            }),
+            controllers: config.controllers.map(|controllers| {
+                controllers
+                    .iter()
+                    .map(|controller| controller.to_text())
+                    .collect()
+            }),
+            http_api: config.http_api,
+            log_level: config.log_level,
+            max_rollback_depth: config.max_rollback_depth,
+        }
+    }
+}
+
+/// Returns the response to a request to the JSON API, or `None` if the path isn't one of its
+/// routes.
+pub fn serve_http_api(method: &str, path: &str, query: Option<&str>) -> Option<HttpResponse> {
+    let route = Route::parse(path)?;
+
+    if with_state(|s| s.http_api) == Flag::Disabled {
+        return Some(error_response((
+            403,
+            String::from("The JSON API is disabled."),
+        )));
+    }
+
+    if method != "GET" {
+        return Some(error_response((
+            405,
+            format!("Method {} is not allowed.", method),
+        )));
+    }
+
+    let response =
+        parse_query(query.unwrap_or_default(), route.params()).and_then(|params| match route {
+            Route::Balance(address) => get_balance(address, &params),
+            Route::Utxos(address) => get_utxos(address, &params),
+            Route::FeePercentiles => Ok(json_response(&get_current_fee_percentiles_query())),
+            Route::Tip => Ok(get_tip()),
+            Route::Header(height) => get_header(height),
+            Route::Config => Ok(json_response(&ConfigJson::from(crate::get_config()))),
+        });
+
+    Some(response.unwrap_or_else(error_response))
+}
+
+fn get_balance(address: &str, params: &BTreeMap<&str, &str>) -> Result<HttpResponse, Error> {
+    let min_confirmations = parse_param(params, "min_confirmations")?;
+    verify_synced()?;
+
+    let balance = try_get_balance_query(GetBalanceRequest {
+        address: address.to_string(),
+        min_confirmations,
+    })
+    .map_err(|err| (400, format!("{:?}", err)))?;
+
+    Ok(json_response(&BalanceJson { balance }))
+}
+
+fn get_utxos(address: &str, params: &BTreeMap<&str, &str>) -> Result<HttpResponse, Error> {
+    let min_confirmations = parse_param(params, "min_confirmations")?;
+    let page = params
+        .get("page")
+        .map(|page| hex::decode(page).map_err(|_| (400, format!("Malformed page: {}", page))))
+        .transpose()?;
+    verify_synced()?;
+
+    let response = try_get_utxos_query(address, min_confirmations.unwrap_or(0), page)
+        .map_err(|err| (400, format!("{:?}", err)))?;
+
+    Ok(json_response(&UtxosJson::from(response)))
+}
+
+fn get_tip() -> HttpResponse {
+    let tip = with_state(certification::certified_tip);
+    json_response(&TipJson {
+        block_hash: display_hash(&tip.block_hash),
+        height: tip.height,
+        stable_height: tip.stable_height,
+        utxos_length: tip.utxos_length,
+    })
+}
+
+fn get_header(height: &str) -> Result<HttpResponse, Error> {
+    let height: Height = height
+        .parse()
+        .map_err(|_| (400, format!("Malformed height: {}", height)))?;
+
+    let header = with_state(|s| state::header_at_height(s, height))
+        .ok_or_else(|| (404, format!("No block at height {}.", height)))?;
+
+    Ok(json_response(&HeaderJson {
+        block_hash: header.block_hash().to_string(),
+        height,
+        version: header.version,
+        prev_block_hash: header.prev_blockhash.to_string(),
+        merkle_root: header.merkle_root.to_string(),
+        time: header.time,
+        bits: header.bits,
+        nonce: header.nonce,
+    }))
+}
+
+fn verify_synced() -> Result<(), Error> {
+    with_state(|s| state::check_synced(s, time() / 1_000_000_000))
+        .map_err(|err| (503, format!("NotSynced: {:?}", err)))
+}
+
+// Parses a query string, accepting only the given parameters.
+fn parse_query<'a>(query: &'a str, accepted: &[&str]) -> Result<BTreeMap<&'a str, &'a str>, Error> {
+    let mut params = BTreeMap::new();
+    for param in query.split('&').filter(|p| !p.is_empty()) {
+        match param.split_once('=') {
+            Some((name, value)) if accepted.contains(&name) => {
+                params.insert(name, value);
+            }
+            _ => return Err((400, format!("Unknown parameter: {}", param))),
+        }
+    }
+    Ok(params)
+}
+
+fn parse_param(params: &BTreeMap<&str, &str>, name: &str) -> Result<Option<u32>, Error> {
+    params
+        .get(name)
+        .map(|value| {
+            value
+                .parse()
+                .map_err(|_| (400, format!("Malformed {}: {}", name, value)))
+        })
+        .transpose()
+}
+
+// Returns the hex encoding of a hash in the byte order that's used to display it.
+fn display_hash(hash: &[u8]) -> String {
+    let mut bytes = hash.to_vec();
+    bytes.reverse();
+    hex::encode(bytes)
+}
+
+fn json_response(body: &impl Serialize) -> HttpResponse {
+    response(
+        200,
+        serde_json::to_vec(body).expect("JSON encoding must succeed"),
+    )
+}
+
+fn error_response((status_code, error): Error) -> HttpResponse {
+    response(
+        status_code,
+        serde_json::to_vec(&ErrorJson { error }).expect("JSON encoding must succeed"),
+    )
+}
+
+fn response(status_code: u16, body: Vec<u8>) -> HttpResponse {
+    HttpResponse {
+        status_code,
+        headers: vec![
+            ("Content-Type".to_string(), "application/json".to_string()),
+            ("Content-Length".to_string(), body.len().to_string()),
+        ],
+        body: ByteBuf::from(body),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::{
+        genesis_block,
+        test_utils::{random_p2pkh_address, BlockBuilder, TransactionBuilder},
+        types::{Block, HttpRequest},
+        with_state_mut,
+    };
+    use ic_cdk::export::Principal;
+    use serde_json::{json, Value};
+
+    fn init(http_api: Flag) {
+        crate::init(Config {
+            stability_threshold: 2,
+            network: Network::Regtest,
+            http_api: Some(http_api),
+            ..Default::default()
+        });
+    }
+
+    fn get(url: &str) -> (u16, Value) {
+        let response = crate::http_request(HttpRequest {
+            method: String::from("GET"),
+            url: url.to_string(),
+            headers: vec![],
+            body: ByteBuf::new(),
+        });
+        assert!(response
+            .headers
+            .contains(&("Content-Type".to_string(), "application/json".to_string())));
+        (
+            response.status_code,
+            serde_json::from_slice(&response.body).unwrap(),
+        )
+    }
+
+    // Inserts two blocks on top of genesis, the first of which gives 1000 satoshis to the
+    // returned address, and ingests the genesis block, which is the only stable block.
+    fn insert_blocks() -> (String, Vec<Block>) {
+        let network = Network::Regtest;
+        let address = random_p2pkh_address(network);
+        let block_1 = BlockBuilder::with_prev_header(genesis_block(network).header())
+            .with_transaction(
+                TransactionBuilder::coinbase()
+                    .with_output(&address, 1000)
+                    .build(),
+            )
+            .build();
+        let block_2 = BlockBuilder::with_prev_header(block_1.header()).build();
+
+        with_state_mut(|s| {
+            state::insert_block(s, block_1.clone()).unwrap();
+            state::insert_block(s, block_2.clone()).unwrap();
+            state::ingest_stable_blocks_into_utxoset(s);
+        });
+
+        (address.to_string(), vec![block_1, block_2])
+    }
+
+    #[test]
+    fn is_only_served_if_enabled() {
+        init(Flag::Disabled);
+        assert_eq!(get("/tip").0, 403);
+
+        // Other routes aren't affected.
+        let response = crate::http_request(HttpRequest {
+            method: String::from("GET"),
+            url: String::from("/unknown"),
+            headers: vec![],
+            body: ByteBuf::new(),
+        });
+        assert_eq!(response.status_code, 404);
+    }
+
+    #[test]
+    fn returns_balances_and_utxos() {
+        init(Flag::Enabled);
+        let (address, blocks) = insert_blocks();
+
+        assert_eq!(
+            get(&format!("/balance/{}", address)),
+            (200, json!({ "balance": 1000 }))
+        );
+        assert_eq!(
+            get(&format!("/balance/{}?min_confirmations=2", address)),
+            (200, json!({ "balance": 1000 }))
+        );
+
+        let (status_code, utxos) = get(&format!("/utxos/{}?min_confirmations=2", address));
+        assert_eq!(status_code, 200);
+        assert_eq!(
+            utxos,
+            json!({
+                "utxos": [{
+                    "txid": blocks[0].txdata()[0].txid().to_string(),
+                    "vout": 0,
+                    "value": 1000,
+                    "height": 1,
+                }],
+                "tip_block_hash": blocks[0].block_hash().to_string(),
+                "tip_height": 1,
+                "next_page": null,
+            })
+        );
+    }
+
+    #[test]
+    fn returns_tips_and_headers() {
+        init(Flag::Enabled);
+        let (_, blocks) = insert_blocks();
+
+        assert_eq!(
+            get("/tip"),
+            (
+                200,
+                json!({
+                    "block_hash": blocks[1].block_hash().to_string(),
+                    "height": 2,
+                    "stable_height": 1,
+                    "utxos_length": with_state(|s| s.utxos.utxos_len()),
+                })
+            )
+        );
+
+        // Headers of both stable and unstable blocks are returned.
+        for height in 0..=2 {
+            let (status_code, header) = get(&format!("/headers/{}", height));
+            assert_eq!(status_code, 200);
+            let expected_hash = match height {
+                0 => genesis_block(Network::Regtest).block_hash(),
+                _ => blocks[height - 1].block_hash(),
+            };
+            assert_eq!(header["block_hash"], json!(expected_hash.to_string()));
+            assert_eq!(header["height"], json!(height));
+        }
+
+        assert_eq!(get("/headers/3").0, 404);
+    }
+
+    #[test]
+    fn returns_the_config() {
+        init(Flag::Enabled);
+
+        let (status_code, config) = get("/config");
+        assert_eq!(status_code, 200);
+        assert_eq!(config["network"], json!("regtest"));
+        assert_eq!(config["http_api"], json!("enabled"));
+        assert_eq!(
+            config["blocks_source"],
+            json!(Principal::management_canister().to_text())
+        );
+    }
+
+    #[test]
+    fn rejects_invalid_requests() {
+        init(Flag::Enabled);
+        let (address, _) = insert_blocks();
+
+        for url in [
+            format!("/balance/{}?page=00", address),
+            format!("/balance/{}?min_confirmations=abc", address),
+            format!("/balance/{}?min_confirmations=10", address),
+            format!("/utxos/{}?page=xyz", address),
+            format!("/utxos/{}?page=00", address),
+            String::from("/balance/not-an-address"),
+            String::from("/headers/abc"),
+            String::from("/tip?foo=bar"),
+        ] {
+            let (status_code, body) = get(&url);
+            assert_eq!(status_code, 400, "{}", url);
+            assert!(body["error"].is_string());
+        }
+
+        let response = crate::http_request(HttpRequest {
+            method: String::from("POST"),
+            url: String::from("/tip"),
+            headers: vec![],
+            body: ByteBuf::new(),
+        });
+        assert_eq!(response.status_code, 405);
+    }
+}
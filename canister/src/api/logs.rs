@@ -0,0 +1,183 @@
+//! Serves the most recent log entries as JSON at `/logs?level=L&since=T`, where `L` is the
+//! minimum level of the entries (`debug`, `info`, `warn` or `error`) and `T` a time in
+//! nanoseconds since the epoch. Entries are returned the oldest first.
+//!
+//! Like the JSON API, the logs are only served if `http_api` is enabled in the config, as no
+//! cycles are charged.
+use crate::{
+    logs::LogEntry,
+    types::{Flag, HttpResponse, LogLevel},
+    with_state,
+};
+use serde::Serialize;
+use serde_bytes::ByteBuf;
+use std::collections::BTreeMap;
+
+#[derive(Serialize)]
+struct ErrorJson {
+    error: String,
+}
+
+#[derive(Serialize)]
+struct LogsJson<'a> {
+    entries: Vec<LogEntryJson<'a>>,
+}
+
+#[derive(Serialize)]
+struct LogEntryJson<'a> {
+    timestamp: u64,
+    level: LogLevel,
+    message: &'a str,
+    fields: BTreeMap<&'a str, &'a str>,
+}
+
+impl<'a> From<&'a LogEntry> for LogEntryJson<'a> {
+    fn from(entry: &'a LogEntry) -> Self {
+        Self {
+            timestamp: entry.timestamp,
+            level: entry.level,
+            message: &entry.message,
+            fields: entry
+                .fields
+                .iter()
+                .map(|(name, value)| (name.as_str(), value.as_str()))
+                .collect(),
+        }
+    }
+}
+
+/// Returns the log entries matching the query string of the request.
+pub fn get_logs(query: Option<&str>) -> HttpResponse {
+    if with_state(|s| s.http_api) == Flag::Disabled {
+        return error_response(403, String::from("The logs are disabled."));
+    }
+
+    let (level, since) = match parse_query(query.unwrap_or_default()) {
+        Ok(params) => params,
+        Err(error) => return error_response(400, error),
+    };
+
+    with_state(|state| {
+        let logs = LogsJson {
+            entries: state
+                .logs
+                .entries(level, since)
+                .map(LogEntryJson::from)
+                .collect(),
+        };
+        response(
+            200,
+            serde_json::to_vec(&logs).expect("JSON encoding must succeed"),
+        )
+    })
+}
+
+// Parses the query string of a logs request into a level and a time.
+fn parse_query(query: &str) -> Result<(LogLevel, u64), String> {
+    let mut level = LogLevel::Debug;
+    let mut since = 0;
+
+    for param in query.split('&').filter(|p| !p.is_empty()) {
+        match param.split_once('=') {
+            Some(("level", value)) => {
+                level = match value {
+                    "debug" => LogLevel::Debug,
+                    "info" => LogLevel::Info,
+                    "warn" => LogLevel::Warn,
+                    "error" => LogLevel::Error,
+                    _ => return Err(format!("Malformed level: {}", value)),
+                };
+            }
+            Some(("since", value)) => {
+                since = value
+                    .parse()
+                    .map_err(|_| format!("Malformed since: {}", value))?;
+            }
+            _ => return Err(format!("Unknown parameter: {}", param)),
+        }
+    }
+
+    Ok((level, since))
+}
+
+fn error_response(status_code: u16, error: String) -> HttpResponse {
+    response(
+        status_code,
+        serde_json::to_vec(&ErrorJson { error }).expect("JSON encoding must succeed"),
+    )
+}
+
+fn response(status_code: u16, body: Vec<u8>) -> HttpResponse {
+    HttpResponse {
+        status_code,
+        headers: vec![
+            ("Content-Type".to_string(), "application/json".to_string()),
+            ("Content-Length".to_string(), body.len().to_string()),
+        ],
+        body: ByteBuf::from(body),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::{init, runtime::set_time, types::Config, with_state_mut};
+    use serde_json::{json, Value};
+
+    fn init_with_http_api() {
+        init(Config {
+            http_api: Some(Flag::Enabled),
+            ..Default::default()
+        });
+    }
+
+    fn get(query: &str) -> (u16, Value) {
+        let response = get_logs(Some(query));
+        let body = serde_json::from_slice(&response.body).unwrap_or(Value::Null);
+        (response.status_code, body)
+    }
+
+    #[test]
+    fn filters_entries_by_level_and_time() {
+        init_with_http_api();
+        with_state_mut(|s| {
+            set_time(10);
+            s.logs.info("synced", &[("height", &5)]);
+            set_time(20);
+            s.logs.error("failed", &[]);
+        });
+
+        assert_eq!(
+            get(""),
+            (
+                200,
+                json!({"entries": [
+                    {"timestamp": 10, "level": "info", "message": "synced", "fields": {"height": "5"}},
+                    {"timestamp": 20, "level": "error", "message": "failed", "fields": {}},
+                ]})
+            )
+        );
+        assert_eq!(get("level=error").1["entries"].as_array().unwrap().len(), 1);
+        assert_eq!(get("since=11").1["entries"][0]["message"], json!("failed"));
+        assert_eq!(get("since=21").1, json!({"entries": []}));
+    }
+
+    #[test]
+    fn malformed_queries_are_rejected() {
+        init_with_http_api();
+
+        assert_eq!(
+            get("level=trace"),
+            (400, json!({"error": "Malformed level: trace"}))
+        );
+        assert_eq!(get("since=yesterday").0, 400);
+        assert_eq!(get("page=1").0, 400);
+    }
+
+    #[test]
+    fn arent_served_if_the_http_api_is_disabled() {
+        init(Config::default());
+
+        assert_eq!(get(""), (403, json!({"error": "The logs are disabled."})));
+    }
+}
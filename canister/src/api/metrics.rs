@@ -1,4 +1,7 @@
-use crate::{metrics::InstructionHistogram, state, types::HttpResponse, with_state};
+use crate::{
+    block_sources::BlockSourceStats, metrics::Histogram, state, types::HttpResponse,
+    unstable_blocks, with_state,
+};
 use ic_cdk::api::time;
 use serde_bytes::ByteBuf;
 use std::{fmt::Display, io};
@@ -38,11 +41,25 @@ fn encode_metrics(w: &mut MetricsEncoder<Vec<u8>>) -> std::io::Result<()> {
             state::main_chain_height(state) as f64,
             "Height of the main chain.",
         )?;
+        w.encode_gauge(
+            "best_header_height",
+            state::best_header_height(state) as f64,
+            "Height of the best known header chain.",
+        )?;
         w.encode_gauge(
             "stable_height",
             state.stable_height() as f64,
             "The height of the latest stable block.",
         )?;
+        w.encode_gauge(
+            "fast_sync",
+            if state.unstable_blocks.is_fast_sync() {
+                1.0
+            } else {
+                0.0
+            },
+            "Whether blocks are inserted in fast sync mode.",
+        )?;
         w.encode_gauge(
             "utxos_length",
             state.utxos.utxos_len() as f64,
@@ -53,6 +70,41 @@ fn encode_metrics(w: &mut MetricsEncoder<Vec<u8>>) -> std::io::Result<()> {
             state.utxos.address_utxos_len() as f64,
             "The number of UTXOs that are owned by supported addresses.",
         )?;
+        w.encode_gauge(
+            "unstable_blocks_length",
+            unstable_blocks::num_blocks(&state.unstable_blocks) as f64,
+            "The number of unstable blocks, including the anchor.",
+        )?;
+        w.encode_gauge(
+            "outpoints_cache_length",
+            state.unstable_blocks.outpoints_cache_len() as f64,
+            "The number of transaction outputs cached for the unstable blocks.",
+        )?;
+        w.encode_gauge(
+            "num_forks",
+            unstable_blocks::num_forks(&state.unstable_blocks) as f64,
+            "The number of forks among the unstable blocks.",
+        )?;
+        w.encode_counter(
+            "num_reorgs",
+            state.metrics.num_reorgs,
+            "The number of times the main chain was reorganized.",
+        )?;
+        w.encode_gauge(
+            "max_reorg_depth",
+            state.metrics.max_reorg_depth as f64,
+            "The number of blocks removed from the main chain by the deepest reorg.",
+        )?;
+        if let Some(muhash) = state.utxos.muhash_hex() {
+            w.encode_info(
+                "utxo_set_muhash",
+                &[
+                    ("height", &(state.utxos.next_height() - 1).to_string()),
+                    ("muhash", &muhash),
+                ],
+                "The MuHash3072 of the UTXO set at the latest fully ingested block.",
+            )?;
+        }
 
         // Memory
         w.encode_gauge(
@@ -82,14 +134,161 @@ fn encode_metrics(w: &mut MetricsEncoder<Vec<u8>>) -> std::io::Result<()> {
             state.syncing_state.num_insert_block_errors,
             "The number of errors occurred when inserting a block.",
         )?;
+        w.encode_counter(
+            "num_invalid_headers",
+            state.syncing_state.num_invalid_headers,
+            "The number of block headers that failed to be deserialized or validated.",
+        )?;
+        w.encode_counter(
+            "num_unexpected_responses",
+            state.syncing_state.num_unexpected_responses,
+            "The number of GetSuccessors responses that didn't match the request that was sent.",
+        )?;
+        w.encode_gauge(
+            "num_consecutive_failures",
+            state.syncing_state.num_consecutive_failures as f64,
+            "The number of consecutive failures to fetch blocks.",
+        )?;
+
+        // Block sources
+        let sources: Vec<(String, &BlockSourceStats)> = state
+            .block_sources
+            .iter()
+            .map(|source| (source.principal.to_text(), &source.stats))
+            .collect();
+        let samples = |value: fn(&BlockSourceStats) -> u64| -> Vec<(Vec<(&str, &str)>, u64)> {
+            sources
+                .iter()
+                .map(|(principal, stats)| (vec![("source", principal.as_str())], value(stats)))
+                .collect()
+        };
+        w.encode_labeled_counter(
+            "block_source_num_requests",
+            &samples(|stats| stats.num_requests),
+            "The number of GetSuccessors requests sent to each block source.",
+        )?;
+        w.encode_labeled_counter(
+            "block_source_num_rejects",
+            &samples(|stats| stats.num_rejects),
+            "The number of rejects received from each block source.",
+        )?;
+        w.encode_labeled_counter(
+            "block_source_num_invalid_responses",
+            &samples(|stats| stats.num_invalid_responses),
+            "The number of invalid responses received from each block source.",
+        )?;
+        w.encode_labeled_counter(
+            "block_source_num_conflicts",
+            &samples(|stats| stats.num_conflicts),
+            "The number of responses of each block source that conflicted with another source.",
+        )?;
+        let current = state.block_sources.current().to_text();
+        w.encode_labeled_gauge(
+            "block_source_demoted",
+            &sources
+                .iter()
+                .map(|(principal, stats)| {
+                    (
+                        vec![("source", principal.as_str())],
+                        if stats.demoted { 1.0 } else { 0.0 },
+                    )
+                })
+                .collect::<Vec<_>>(),
+            "Whether each block source is demoted.",
+        )?;
+        w.encode_info(
+            "block_source_current",
+            &[("source", current.as_str())],
+            "The block source that requests are currently sent to.",
+        )?;
+
+        // Config
+        w.encode_labeled_counter(
+            "num_config_changes",
+            &state
+                .config_history
+                .num_changes_by_field()
+                .map(|(field, count)| (vec![("field", field)], count))
+                .collect::<Vec<_>>(),
+            "The number of times each config field has been changed with set_config.",
+        )?;
+
+        // Endpoints
+        w.encode_labeled_counter(
+            "num_calls",
+            &state
+                .metrics
+                .num_calls
+                .iter()
+                .map(|(endpoint, count)| (vec![("endpoint", endpoint.as_str())], *count))
+                .collect::<Vec<_>>(),
+            "The number of calls to each update endpoint that didn't trap.",
+        )?;
+        w.encode_labeled_counter(
+            "cycles_charged",
+            &state
+                .metrics
+                .cycles_charged
+                .iter()
+                .map(|(endpoint, cycles)| (vec![("endpoint", endpoint.as_str())], *cycles))
+                .collect::<Vec<_>>(),
+            "The number of cycles charged by each endpoint.",
+        )?;
+        w.encode_labeled_counter(
+            "num_errors",
+            &state
+                .metrics
+                .num_errors
+                .iter()
+                .map(|((endpoint, error), count)| {
+                    (
+                        vec![("endpoint", endpoint.as_str()), ("error", error.as_str())],
+                        *count,
+                    )
+                })
+                .collect::<Vec<_>>(),
+            "The number of errors returned by each endpoint, by error.",
+        )?;
+
+        // Block ingestion
+        if let Some(stats) = state.utxos.last_ingestion_stats() {
+            w.encode_gauge(
+                "last_block_ingestion_num_rounds",
+                stats.num_rounds as f64,
+                "The number of rounds it took to ingest the latest block.",
+            )?;
+            w.encode_labeled_gauge(
+                "last_block_ingestion_instructions",
+                &[
+                    (vec![("step", "total")], stats.ins_total as f64),
+                    (
+                        vec![("step", "remove_inputs")],
+                        stats.ins_remove_inputs as f64,
+                    ),
+                    (
+                        vec![("step", "insert_outputs")],
+                        stats.ins_insert_outputs as f64,
+                    ),
+                    (vec![("step", "txids")], stats.ins_txids as f64),
+                    (
+                        vec![("step", "insert_utxos")],
+                        stats.ins_insert_utxos as f64,
+                    ),
+                ],
+                "The instructions used by each step of the ingestion of the latest block.",
+            )?;
+        }
 
         // Profiling
-        w.encode_instruction_histogram(&state.metrics.get_utxos_total)?;
-        w.encode_instruction_histogram(&state.metrics.get_utxos_apply_unstable_blocks)?;
-        w.encode_instruction_histogram(&state.metrics.get_utxos_build_utxos_vec)?;
-        w.encode_instruction_histogram(&state.metrics.get_balance_total)?;
-        w.encode_instruction_histogram(&state.metrics.get_balance_apply_unstable_blocks)?;
-        w.encode_instruction_histogram(&state.metrics.get_current_fee_percentiles_total)?;
+        w.encode_histogram_metric(&state.metrics.get_utxos_total)?;
+        w.encode_histogram_metric(&state.metrics.get_utxos_apply_unstable_blocks)?;
+        w.encode_histogram_metric(&state.metrics.get_utxos_build_utxos_vec)?;
+        w.encode_histogram_metric(&state.metrics.get_utxos_num_utxos)?;
+        w.encode_histogram_metric(&state.metrics.get_balance_total)?;
+        w.encode_histogram_metric(&state.metrics.get_balance_apply_unstable_blocks)?;
+        w.encode_histogram_metric(&state.metrics.get_current_fee_percentiles_total)?;
+        w.encode_histogram_metric(&state.metrics.block_ingestion_total)?;
+        w.encode_histogram_metric(&state.metrics.block_ingestion_rounds)?;
 
         Ok(())
     })
@@ -147,6 +346,52 @@ impl<W: io::Write> MetricsEncoder<W> {
         self.encode_single_value("counter", name, value, help)
     }
 
+    fn encode_labeled<T: Display>(
+        &mut self,
+        typ: &str,
+        name: &str,
+        samples: &[(Vec<(&str, &str)>, T)],
+        help: &str,
+    ) -> io::Result<()> {
+        self.encode_header(name, help, typ)?;
+        for (labels, value) in samples {
+            writeln!(
+                self.writer,
+                "{}{{{}}} {} {}",
+                name,
+                format_labels(labels),
+                value,
+                self.now_millis
+            )?;
+        }
+        Ok(())
+    }
+
+    /// Encodes the metadata of a counter, and its value for each set of labels.
+    fn encode_labeled_counter<T: Display>(
+        &mut self,
+        name: &str,
+        samples: &[(Vec<(&str, &str)>, T)],
+        help: &str,
+    ) -> io::Result<()> {
+        self.encode_labeled("counter", name, samples, help)
+    }
+
+    /// Encodes the metadata of a gauge, and its value for each set of labels.
+    fn encode_labeled_gauge(
+        &mut self,
+        name: &str,
+        samples: &[(Vec<(&str, &str)>, f64)],
+        help: &str,
+    ) -> io::Result<()> {
+        self.encode_labeled("gauge", name, samples, help)
+    }
+
+    /// Encodes a gauge that's always set to 1 and whose labels carry the information.
+    fn encode_info(&mut self, name: &str, labels: &[(&str, &str)], help: &str) -> io::Result<()> {
+        self.encode_labeled_gauge(name, &[(labels.to_vec(), 1.0)], help)
+    }
+
     /// Encodes the metadata and the value of a histogram.
     ///
     /// SUM is the sum of all observed values, before they were put
@@ -193,12 +438,29 @@ impl<W: io::Write> MetricsEncoder<W> {
         writeln!(self.writer, "{}_count {} {}", name, total, self.now_millis)
     }
 
-    /// Encodes an `InstructionHistogram`.
-    pub fn encode_instruction_histogram(&mut self, h: &InstructionHistogram) -> io::Result<()> {
+    /// Encodes a `Histogram`.
+    pub fn encode_histogram_metric(&mut self, h: &Histogram) -> io::Result<()> {
         self.encode_histogram(&h.name, h.buckets(), h.sum, &h.help)
     }
 }
 
+// Formats labels as `key1="value1",key2="value2"`, escaping the values.
+fn format_labels(labels: &[(&str, &str)]) -> String {
+    labels
+        .iter()
+        .map(|(k, v)| format!("{}=\"{}\"", k, escape_label_value(v)))
+        .collect::<Vec<_>>()
+        .join(",")
+}
+
+// Escapes backslashes, double quotes and line feeds, as required by the text format.
+fn escape_label_value(value: &str) -> String {
+    value
+        .replace('\\', "\\\\")
+        .replace('"', "\\\"")
+        .replace('\n', "\\n")
+}
+
 // Returns the size of the heap in pages.
 fn get_heap_size() -> u64 {
     #[cfg(target_arch = "wasm32")]
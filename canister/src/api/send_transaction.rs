@@ -1,19 +1,26 @@
 use crate::{
     charge_cycles, runtime, types::SendTransactionInternalRequest, verify_network, with_state,
+    with_state_mut,
 };
 use ic_btc_types::SendTransactionRequest;
 
+const ENDPOINT: &str = "bitcoin_send_transaction";
+
 pub async fn send_transaction(request: SendTransactionRequest) {
     verify_network(request.network.into());
+    with_state_mut(|s| s.metrics.observe_call(ENDPOINT));
 
-    charge_cycles(with_state(|s| {
-        s.fees.send_transaction_base
-            + s.fees.send_transaction_per_byte * request.transaction.len() as u128
-    }));
+    charge_cycles(
+        ENDPOINT,
+        with_state(|s| {
+            s.fees.send_transaction_base
+                + s.fees.send_transaction_per_byte * request.transaction.len() as u128
+        }),
+    );
 
     // Use the internal endpoint to send the transaction to the bitcoin network.
     runtime::call_send_transaction_internal(
-        with_state(|s| s.blocks_source),
+        with_state(|s| s.block_sources.current()),
         SendTransactionInternalRequest {
             network: request.network.into(),
             transaction: request.transaction,
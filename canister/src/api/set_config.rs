@@ -1,44 +1,307 @@
-use crate::SetConfigRequest;
-use std::convert::TryInto;
+use crate::{
+    block_sources::BlockSources,
+    runtime::{self, time},
+    state::{self, ResponseToProcess},
+    types::{
+        Admin, ConfigChange, FieldChange, MaxLag, Network, Role, SetConfigError,
+        MAX_HEARTBEAT_INSTRUCTIONS,
+    },
+    unstable_blocks,
+    utxo_set::MAX_UNDO_LOG_SIZE,
+    with_state, SetConfigRequest, State,
+};
+use ic_cdk::export::Principal;
+use std::{convert::TryInto, fmt::Debug};
 
-pub fn set_config(request: SetConfigRequest) {
-    verify_caller();
+// The minimum stability threshold on the networks where forks happen in practice.
+const MIN_STABILITY_THRESHOLD: u128 = 1;
+
+// The minimum lag in seconds. Blocks are mined every ten minutes on average, so a smaller lag
+// would refuse requests most of the time, even when the canister is synced.
+const MIN_MAX_LAG_SECS: u64 = 600;
+
+/// Updates the config, or returns an error, leaving the config unchanged, if the request
+/// contains invalid values.
+///
+/// Panics if the caller isn't allowed to make the changes in the request.
+pub fn set_config(request: SetConfigRequest) -> Result<(), SetConfigError> {
+    let caller = runtime::caller();
+    verify_caller(caller, &request);
+    with_state(|s| validate(s, &request))?;
 
     crate::with_state_mut(|s| {
+        let mut changes = vec![];
+
         if let Some(syncing) = request.syncing {
+            changes.push(field_change("syncing", s.syncing_state.syncing, syncing));
             s.syncing_state.syncing = syncing;
         }
 
         if let Some(fees) = request.fees {
+            changes.push(field_change("fees", &s.fees, &fees));
             s.fees = fees;
         }
 
         if let Some(stability_threshold) = request.stability_threshold {
+            changes.push(field_change(
+                "stability_threshold",
+                s.unstable_blocks.stability_threshold(),
+                stability_threshold,
+            ));
             s.unstable_blocks.set_stability_threshold(
                 stability_threshold
                     .try_into()
                     .expect("stability threshold too large"),
             );
         }
+
+        if let Some(stability_mode) = request.stability_mode {
+            changes.push(field_change(
+                "stability_mode",
+                s.unstable_blocks.stability_mode(),
+                stability_mode,
+            ));
+            s.unstable_blocks.set_stability_mode(stability_mode);
+        }
+
+        if let Some(max_lag) = request.max_lag {
+            let max_lag = state::max_lag(max_lag);
+            changes.push(field_change("max_lag", s.max_lag, max_lag));
+            s.max_lag = max_lag;
+        }
+
+        if request.blocks_source.is_some() || request.backup_blocks_sources.is_some() {
+            if let Some(blocks_source) = request.blocks_source {
+                changes.push(field_change(
+                    "blocks_source",
+                    s.block_sources.primary().to_text(),
+                    blocks_source.to_text(),
+                ));
+            }
+
+            if let Some(backup_blocks_sources) = &request.backup_blocks_sources {
+                changes.push(field_change(
+                    "backup_blocks_sources",
+                    display_principals(&s.block_sources.backups()),
+                    display_principals(backup_blocks_sources),
+                ));
+            }
+
+            let current = s.block_sources.current();
+            let cross_check = s.block_sources.cross_check;
+            s.block_sources = BlockSources::new(
+                request
+                    .blocks_source
+                    .unwrap_or_else(|| s.block_sources.primary()),
+                request
+                    .backup_blocks_sources
+                    .unwrap_or_else(|| s.block_sources.backups()),
+            );
+            s.block_sources.cross_check = cross_check;
+
+            // Requests are sent to the primary source again. A partial response received from
+            // another source cannot be followed up on.
+            if current != s.block_sources.current() {
+                if let Some(ResponseToProcess::Partial(..)) = s.syncing_state.response_to_process {
+                    s.syncing_state.response_to_process = None;
+                }
+            }
+        }
+
+        if let Some(cross_check) = request.cross_check_blocks_sources {
+            changes.push(field_change(
+                "cross_check_blocks_sources",
+                s.block_sources.cross_check,
+                cross_check,
+            ));
+            s.block_sources.cross_check = cross_check;
+        }
+
+        if let Some(fast_sync) = request.fast_sync {
+            changes.push(field_change(
+                "fast_sync",
+                s.syncing_state.fast_sync,
+                fast_sync,
+            ));
+            s.syncing_state.fast_sync = fast_sync;
+        }
+
+        if let Some(heartbeat_limits) = request.heartbeat_limits {
+            changes.push(field_change(
+                "heartbeat_limits",
+                s.heartbeat_limits,
+                heartbeat_limits,
+            ));
+            state::set_heartbeat_limits(s, heartbeat_limits);
+        }
+
+        if let Some(admins) = request.admins {
+            changes.push(field_change(
+                "admins",
+                display_admins(&s.access_control.admins()),
+                display_admins(&admins),
+            ));
+            s.access_control.set_admins(admins);
+        }
+
+        if let Some(controllers) = request.controllers {
+            changes.push(field_change(
+                "controllers",
+                display_principals(&s.access_control.controllers()),
+                display_principals(&controllers),
+            ));
+            s.access_control.set_controllers(controllers);
+        }
+
+        if let Some(http_api) = request.http_api {
+            changes.push(field_change("http_api", s.http_api, http_api));
+            s.http_api = http_api;
+        }
+
+        if let Some(log_level) = request.log_level {
+            changes.push(field_change("log_level", s.logs.level(), log_level));
+            s.logs.set_level(log_level);
+        }
+
+        if let Some(max_rollback_depth) = request.max_rollback_depth {
+            changes.push(field_change(
+                "max_rollback_depth",
+                s.utxos.max_undo_log_size(),
+                max_rollback_depth,
+            ));
+            s.utxos.set_max_undo_log_size(max_rollback_depth);
+        }
+
+        if !changes.is_empty() {
+            let height = state::main_chain_height(s);
+            s.config_history.record(ConfigChange {
+                timestamp: time(),
+                caller,
+                height,
+                changes,
+            });
+        }
     });
+
+    Ok(())
 }
 
-fn verify_caller() {
-    #[cfg(target_arch = "wasm32")]
-    {
-        use ic_cdk::export::Principal;
-        use std::str::FromStr;
+// Returns an error if any of the values in the request is invalid.
+fn validate(state: &State, request: &SetConfigRequest) -> Result<(), SetConfigError> {
+    if let Some(stability_threshold) = request.stability_threshold {
+        let min = match state.network() {
+            Network::Mainnet | Network::Testnet => MIN_STABILITY_THRESHOLD,
+            Network::Regtest => 0,
+        };
 
-        // TODO(EXC-1279): Instead of hard-coding a principal, check that the caller is a canister controller.
-        if ic_cdk::api::caller()
-            != Principal::from_str(
-                "5kqj4-ymytp-ozksm-u62pb-po22y-zqqzf-2o4th-5shdt-m5j6r-kgyfi-2qe",
-            )
-            .unwrap()
-        {
-            panic!("Unauthorized sender");
+        if stability_threshold < min {
+            return Err(SetConfigError::StabilityThresholdTooSmall {
+                given: stability_threshold,
+                min,
+            });
+        }
+
+        // No block becomes stable until the unstable blocks are deeper than the threshold, so
+        // the threshold can only be raised up to their current depth. Keeping or lowering the
+        // current threshold is always allowed.
+        let max = unstable_blocks::depth(&state.unstable_blocks)
+            .max(state.unstable_blocks.stability_threshold()) as u128;
+        if stability_threshold > max {
+            return Err(SetConfigError::StabilityThresholdTooLarge {
+                given: stability_threshold,
+                max,
+            });
+        }
+    }
+
+    let block_sources = request
+        .blocks_source
+        .iter()
+        .chain(request.backup_blocks_sources.iter().flatten());
+    for source in block_sources {
+        if *source == Principal::anonymous() {
+            return Err(SetConfigError::InvalidBlocksSource { source: *source });
         }
     }
+
+    if let Some(MaxLag::Seconds(seconds)) = request.max_lag {
+        if seconds < MIN_MAX_LAG_SECS {
+            return Err(SetConfigError::MaxLagTooSmall {
+                given: MaxLag::Seconds(seconds),
+                min: MaxLag::Seconds(MIN_MAX_LAG_SECS),
+            });
+        }
+    }
+
+    if let Some(limits) = request.heartbeat_limits {
+        let budgets = [
+            ("ingestion_instructions", limits.ingestion_instructions),
+            ("insertion_instructions", limits.insertion_instructions),
+            ("validation_instructions", limits.validation_instructions),
+        ];
+        for (limit, given) in budgets.iter() {
+            if *given == 0 || *given > MAX_HEARTBEAT_INSTRUCTIONS {
+                return Err(SetConfigError::HeartbeatLimitOutOfRange {
+                    limit: limit.to_string(),
+                    given: *given,
+                    min: 1,
+                    max: MAX_HEARTBEAT_INSTRUCTIONS,
+                });
+            }
+        }
+
+        if limits.max_blocks_per_heartbeat == 0 {
+            return Err(SetConfigError::HeartbeatLimitOutOfRange {
+                limit: String::from("max_blocks_per_heartbeat"),
+                given: 0,
+                min: 1,
+                max: u32::MAX as u64,
+            });
+        }
+    }
+
+    if let Some(max_rollback_depth) = request.max_rollback_depth {
+        if max_rollback_depth > MAX_UNDO_LOG_SIZE {
+            return Err(SetConfigError::MaxRollbackDepthTooLarge {
+                given: max_rollback_depth,
+                max: MAX_UNDO_LOG_SIZE,
+            });
+        }
+    }
+
+    Ok(())
+}
+
+fn field_change(field: &str, old_value: impl Debug, new_value: impl Debug) -> FieldChange {
+    FieldChange {
+        field: field.to_string(),
+        old_value: format!("{:?}", old_value),
+        new_value: format!("{:?}", new_value),
+    }
+}
+
+// Principals are displayed in their textual representation.
+fn display_principals(principals: &[Principal]) -> Vec<String> {
+    principals.iter().map(Principal::to_text).collect()
+}
+
+fn display_admins(admins: &[Admin]) -> Vec<(String, &[Role])> {
+    admins
+        .iter()
+        .map(|admin| (admin.principal.to_text(), admin.roles.as_slice()))
+        .collect()
+}
+
+// Panics unless the caller is allowed to make the changes in the request, i.e. unless the
+// caller is the controller that installed or last upgraded the canister, or an admin with the
+// required roles.
+fn verify_caller(caller: Principal, request: &SetConfigRequest) {
+    let authorized = with_state(|s| {
+        s.access_control.is_controller(caller) || s.access_control.is_authorized(caller, request)
+    });
+    if !authorized {
+        panic!("Unauthorized sender");
+    }
 }
 
 #[cfg(test)]
@@ -46,22 +309,32 @@ mod test {
     use super::*;
     use crate::{
         init,
-        types::{Config, Fees, Flag},
+        test_utils::build_regtest_chain,
+        types::{
+            Admin, Config, ConfigHistoryPage, Fees, Flag, HeartbeatLimits, LogLevel, MaxLag,
+            Network, Role, StabilityMode,
+        },
+        utxo_set::DEFAULT_MAX_UNDO_LOG_SIZE,
         with_state,
     };
+    use ic_cdk::export::Principal;
     use proptest::prelude::*;
 
     #[test]
     fn set_stability_threshold() {
-        init(Config::default());
-
         proptest!(|(
             stability_threshold in 0..150u128,
         )| {
+            init(Config {
+                stability_threshold: 150,
+                ..Default::default()
+            });
+
             set_config(SetConfigRequest {
                 stability_threshold: Some(stability_threshold),
                 ..Default::default()
-            });
+            })
+            .unwrap();
 
             assert_eq!(
                 with_state(|s| s.unstable_blocks.stability_threshold()),
@@ -70,6 +343,258 @@ mod test {
         });
     }
 
+    #[test]
+    fn stability_threshold_is_bounded_by_the_depth_of_the_unstable_blocks() {
+        init(Config::default());
+        for block in build_regtest_chain(5, 1).into_iter().skip(1) {
+            crate::with_state_mut(|s| state::insert_block(s, block)).unwrap();
+        }
+
+        // The unstable blocks are 4 blocks deep.
+        set_config(SetConfigRequest {
+            stability_threshold: Some(4),
+            ..Default::default()
+        })
+        .unwrap();
+        assert_eq!(
+            set_config(SetConfigRequest {
+                stability_threshold: Some(5),
+                ..Default::default()
+            }),
+            Err(SetConfigError::StabilityThresholdTooLarge { given: 5, max: 4 })
+        );
+    }
+
+    #[test]
+    fn set_stability_mode() {
+        // The block count is used if no stability mode is given.
+        init(Config::default());
+        assert_eq!(
+            crate::get_config().stability_mode,
+            Some(StabilityMode::BlockCount)
+        );
+
+        for mode in &[StabilityMode::Chainwork, StabilityMode::BlockCount] {
+            set_config(SetConfigRequest {
+                stability_mode: Some(*mode),
+                ..Default::default()
+            })
+            .unwrap();
+
+            assert_eq!(with_state(|s| s.unstable_blocks.stability_mode()), *mode);
+        }
+    }
+
+    #[test]
+    fn set_max_lag() {
+        init(Config::default());
+        assert_eq!(with_state(|s| s.max_lag), None);
+
+        set_config(SetConfigRequest {
+            max_lag: Some(MaxLag::Blocks(6)),
+            ..Default::default()
+        })
+        .unwrap();
+
+        assert_eq!(with_state(|s| s.max_lag), Some(MaxLag::Blocks(6)));
+
+        // The maximum lag can be cleared.
+        set_config(SetConfigRequest {
+            max_lag: Some(MaxLag::Disabled),
+            ..Default::default()
+        })
+        .unwrap();
+
+        assert_eq!(with_state(|s| s.max_lag), None);
+    }
+
+    #[test]
+    fn set_log_level() {
+        init(Config::default());
+        assert_eq!(with_state(|s| s.logs.level()), LogLevel::Info);
+
+        set_config(SetConfigRequest {
+            log_level: Some(LogLevel::Debug),
+            ..Default::default()
+        })
+        .unwrap();
+
+        assert_eq!(with_state(|s| s.logs.level()), LogLevel::Debug);
+        assert_eq!(crate::get_config().log_level, Some(LogLevel::Debug));
+    }
+
+    #[test]
+    fn set_backup_blocks_sources() {
+        init(Config::default());
+        let backup = Principal::from_slice(&[1]);
+
+        set_config(SetConfigRequest {
+            backup_blocks_sources: Some(vec![backup]),
+            cross_check_blocks_sources: Some(Flag::Enabled),
+            ..Default::default()
+        })
+        .unwrap();
+
+        with_state(|s| {
+            assert_eq!(s.block_sources.primary(), Principal::management_canister());
+            assert_eq!(s.block_sources.backups(), vec![backup]);
+            assert_eq!(s.block_sources.witness(), Some(backup));
+        });
+    }
+
+    #[test]
+    fn set_blocks_source() {
+        init(Config::default());
+        let source = Principal::from_slice(&[1]);
+        let backup = Principal::from_slice(&[2]);
+
+        set_config(SetConfigRequest {
+            backup_blocks_sources: Some(vec![backup]),
+            ..Default::default()
+        })
+        .unwrap();
+        set_config(SetConfigRequest {
+            blocks_source: Some(source),
+            ..Default::default()
+        })
+        .unwrap();
+
+        with_state(|s| {
+            assert_eq!(s.block_sources.primary(), source);
+            assert_eq!(s.block_sources.current(), source);
+            assert_eq!(s.block_sources.backups(), vec![backup]);
+        });
+        assert_eq!(crate::get_config().blocks_source, source);
+    }
+
+    #[test]
+    fn rejects_invalid_values() {
+        init(Config {
+            stability_threshold: 6,
+            network: Network::Mainnet,
+            ..Default::default()
+        });
+
+        let invalid_requests = vec![
+            (
+                SetConfigRequest {
+                    stability_threshold: Some(0),
+                    ..Default::default()
+                },
+                SetConfigError::StabilityThresholdTooSmall { given: 0, min: 1 },
+            ),
+            (
+                SetConfigRequest {
+                    stability_threshold: Some(7),
+                    ..Default::default()
+                },
+                SetConfigError::StabilityThresholdTooLarge { given: 7, max: 6 },
+            ),
+            (
+                SetConfigRequest {
+                    backup_blocks_sources: Some(vec![Principal::anonymous()]),
+                    ..Default::default()
+                },
+                SetConfigError::InvalidBlocksSource {
+                    source: Principal::anonymous(),
+                },
+            ),
+            (
+                SetConfigRequest {
+                    max_lag: Some(MaxLag::Seconds(60)),
+                    ..Default::default()
+                },
+                SetConfigError::MaxLagTooSmall {
+                    given: MaxLag::Seconds(60),
+                    min: MaxLag::Seconds(MIN_MAX_LAG_SECS),
+                },
+            ),
+            (
+                SetConfigRequest {
+                    heartbeat_limits: Some(HeartbeatLimits {
+                        insertion_instructions: 0,
+                        ..Default::default()
+                    }),
+                    ..Default::default()
+                },
+                SetConfigError::HeartbeatLimitOutOfRange {
+                    limit: String::from("insertion_instructions"),
+                    given: 0,
+                    min: 1,
+                    max: MAX_HEARTBEAT_INSTRUCTIONS,
+                },
+            ),
+            (
+                SetConfigRequest {
+                    max_rollback_depth: Some(MAX_UNDO_LOG_SIZE + 1),
+                    ..Default::default()
+                },
+                SetConfigError::MaxRollbackDepthTooLarge {
+                    given: MAX_UNDO_LOG_SIZE + 1,
+                    max: MAX_UNDO_LOG_SIZE,
+                },
+            ),
+        ];
+
+        for (request, error) in invalid_requests {
+            // Valid values in the same request aren't applied either.
+            let request = SetConfigRequest {
+                syncing: Some(Flag::Disabled),
+                ..request
+            };
+            assert_eq!(set_config(request), Err(error));
+        }
+
+        with_state(|s| {
+            assert_eq!(s.unstable_blocks.stability_threshold(), 6);
+            assert_eq!(s.syncing_state.syncing, Flag::Enabled);
+        });
+        assert_eq!(crate::get_config_history(0).changes, vec![]);
+    }
+
+    #[test]
+    fn set_max_rollback_depth() {
+        init(Config::default());
+        assert_eq!(
+            crate::get_config().max_rollback_depth,
+            Some(DEFAULT_MAX_UNDO_LOG_SIZE)
+        );
+
+        set_config(SetConfigRequest {
+            max_rollback_depth: Some(MAX_UNDO_LOG_SIZE),
+            ..Default::default()
+        })
+        .unwrap();
+
+        assert_eq!(
+            crate::get_config().max_rollback_depth,
+            Some(MAX_UNDO_LOG_SIZE)
+        );
+    }
+
+    #[test]
+    fn set_heartbeat_limits() {
+        init(Config::default());
+        assert_eq!(
+            with_state(|s| s.heartbeat_limits),
+            HeartbeatLimits::default()
+        );
+
+        let heartbeat_limits = HeartbeatLimits {
+            ingestion_instructions: 1_000_000_000,
+            insertion_instructions: 2_000_000_000,
+            validation_instructions: 3_000_000_000,
+            max_blocks_per_heartbeat: 10,
+        };
+        set_config(SetConfigRequest {
+            heartbeat_limits: Some(heartbeat_limits),
+            ..Default::default()
+        })
+        .unwrap();
+
+        assert_eq!(with_state(|s| s.heartbeat_limits), heartbeat_limits);
+    }
+
     #[test]
     fn set_syncing() {
         init(Config::default());
@@ -78,12 +603,10 @@ mod test {
             set_config(SetConfigRequest {
                 syncing: Some(*flag),
                 ..Default::default()
-            });
+            })
+            .unwrap();
 
-            assert_eq!(
-                with_state(|s| s.syncing_state.syncing),
-                *flag
-            );
+            assert_eq!(with_state(|s| s.syncing_state.syncing), *flag);
         }
     }
 
@@ -103,15 +626,191 @@ mod test {
                 get_balance,
                 get_current_fee_percentiles,
                 send_transaction_base,
-                send_transaction_per_byte
+                send_transaction_per_byte,
+                usage_based: None,
             };
 
             set_config(SetConfigRequest {
                 fees: Some(fees.clone()),
                 ..Default::default()
-            });
+            })
+            .unwrap();
 
             with_state(|s| assert_eq!(s.fees, fees));
         });
     }
+
+    fn fee_admin() -> Principal {
+        Principal::from_slice(&[1])
+    }
+
+    fn init_with_fee_admin() {
+        init(Config {
+            admins: Some(vec![Admin {
+                principal: fee_admin(),
+                roles: vec![Role::FeeAdmin],
+            }]),
+            ..Default::default()
+        });
+    }
+
+    #[test]
+    fn admins_can_make_the_changes_their_roles_allow() {
+        init_with_fee_admin();
+        runtime::set_caller(fee_admin());
+
+        let fees = Fees {
+            get_utxos: 1,
+            ..Default::default()
+        };
+        set_config(SetConfigRequest {
+            fees: Some(fees.clone()),
+            ..Default::default()
+        })
+        .unwrap();
+
+        with_state(|s| assert_eq!(s.fees, fees));
+    }
+
+    #[test]
+    #[should_panic(expected = "Unauthorized sender")]
+    fn admins_cannot_make_changes_their_roles_dont_allow() {
+        init_with_fee_admin();
+        runtime::set_caller(fee_admin());
+
+        set_config(SetConfigRequest {
+            syncing: Some(Flag::Disabled),
+            ..Default::default()
+        })
+        .unwrap();
+    }
+
+    #[test]
+    #[should_panic(expected = "Unauthorized sender")]
+    fn callers_that_arent_admins_or_controllers_are_unauthorized() {
+        init(Config::default());
+        runtime::set_caller(Principal::from_slice(&[2]));
+
+        set_config(SetConfigRequest::default()).unwrap();
+    }
+
+    #[test]
+    fn controllers_can_change_the_admins() {
+        init_with_fee_admin();
+        let controller = Principal::from_slice(&[2]);
+        runtime::set_caller(controller);
+        crate::pre_upgrade();
+        crate::post_upgrade();
+
+        let admins = vec![Admin {
+            principal: fee_admin(),
+            roles: vec![Role::SyncAdmin, Role::EmergencyStop],
+        }];
+        set_config(SetConfigRequest {
+            admins: Some(admins.clone()),
+            ..Default::default()
+        })
+        .unwrap();
+
+        assert_eq!(with_state(|s| s.access_control.admins()), admins);
+    }
+
+    #[test]
+    fn configured_controllers_can_change_the_entire_config() {
+        let (installer, controller, upgrader) = (
+            Principal::from_slice(&[2]),
+            Principal::from_slice(&[3]),
+            Principal::from_slice(&[4]),
+        );
+        runtime::set_caller(installer);
+        init(Config {
+            controllers: Some(vec![controller]),
+            ..Default::default()
+        });
+
+        // The configured controllers remain controllers after an upgrade by another controller,
+        // which replaces the installer.
+        runtime::set_caller(upgrader);
+        crate::pre_upgrade();
+        crate::post_upgrade();
+        with_state(|s| {
+            assert!(s.access_control.is_controller(controller));
+            assert!(s.access_control.is_controller(upgrader));
+            assert!(!s.access_control.is_controller(installer));
+        });
+
+        runtime::set_caller(controller);
+        set_config(SetConfigRequest {
+            http_api: Some(Flag::Enabled),
+            controllers: Some(vec![controller, installer]),
+            ..Default::default()
+        })
+        .unwrap();
+        assert_eq!(
+            crate::get_config().controllers,
+            Some(vec![installer, controller])
+        );
+    }
+
+    #[test]
+    fn records_changes_in_the_config_history() {
+        init_with_fee_admin();
+        assert_eq!(crate::get_config_history(0).changes, vec![]);
+
+        runtime::set_time(1);
+        set_config(SetConfigRequest {
+            syncing: Some(Flag::Disabled),
+            max_lag: Some(MaxLag::Blocks(6)),
+            ..Default::default()
+        })
+        .unwrap();
+
+        runtime::set_time(2);
+        runtime::set_caller(fee_admin());
+        let fees = Fees {
+            get_utxos: 1,
+            ..Default::default()
+        };
+        set_config(SetConfigRequest {
+            fees: Some(fees.clone()),
+            ..Default::default()
+        })
+        .unwrap();
+
+        // Requests that don't change anything aren't recorded.
+        set_config(SetConfigRequest::default()).unwrap();
+
+        let field_change = |field: &str, old_value: &str, new_value: &str| FieldChange {
+            field: field.to_string(),
+            old_value: old_value.to_string(),
+            new_value: new_value.to_string(),
+        };
+        assert_eq!(
+            crate::get_config_history(0),
+            ConfigHistoryPage {
+                changes: vec![
+                    ConfigChange {
+                        timestamp: 2,
+                        caller: fee_admin(),
+                        height: 0,
+                        changes: vec![field_change(
+                            "fees",
+                            &format!("{:?}", Fees::default()),
+                            &format!("{:?}", fees)
+                        )],
+                    },
+                    ConfigChange {
+                        timestamp: 1,
+                        caller: Principal::anonymous(),
+                        height: 0,
+                        changes: vec![
+                            field_change("syncing", "Enabled", "Disabled"),
+                            field_change("max_lag", "None", "Some(Blocks(6))"),
+                        ],
+                    },
+                ],
+                next_page: None,
+            }
+        );
+    }
 }
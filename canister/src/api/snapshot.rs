@@ -0,0 +1,332 @@
+//! Exports the stable UTXO set over HTTP in chunks.
+//!
+//! Each chunk is a CSV in the same format as the `utxos-dump.csv` consumed by
+//! `bootstrap/state-builder`, i.e. with the columns:
+//!
+//! count,txid,vout,amount,type,address,script,coinbase,nsize,height
+//!
+//! Scripts aren't stored compressed in the canister, so `nsize` is set to the length of the script.
+//!
+//! A snapshot is retrieved by requesting `/snapshot`, followed by `/snapshot?height=H&cursor=C`
+//! using the values returned in the `x-snapshot-height` and `x-snapshot-next-cursor` headers.
+//! The last chunk doesn't have an `x-snapshot-next-cursor` header.
+//!
+//! Like the JSON API, snapshots are only served if `http_api` is enabled in the config, as no
+//! cycles are charged.
+use crate::{
+    types::{Address, Flag, HttpResponse, OutPoint},
+    utxo_set::UtxoSet,
+    with_state,
+};
+use bitcoin::Script;
+use ic_btc_types::Height;
+use ic_stable_structures::Storable;
+use serde_bytes::ByteBuf;
+use std::convert::TryInto;
+
+// The maximum number of UTXOs to include in a single chunk.
+const MAX_UTXOS_PER_CHUNK: usize = 5_000;
+
+// The size in bytes of a chunk after which no more UTXOs are added to it.
+// Kept well below the maximum size of a response.
+const MAX_CHUNK_SIZE: usize = 1_000_000;
+
+// The size of a cursor in bytes: the count (8 bytes) followed by an outpoint (36 bytes).
+const CURSOR_SIZE: usize = 44;
+
+/// A position in the snapshot.
+#[derive(Debug, PartialEq)]
+struct Cursor {
+    // The number of UTXOs that have been returned so far.
+    count: u64,
+
+    // The last outpoint that was returned.
+    outpoint: OutPoint,
+}
+
+impl Cursor {
+    fn to_hex(&self) -> String {
+        let mut bytes = self.count.to_be_bytes().to_vec();
+        bytes.extend_from_slice(&self.outpoint.to_bytes());
+        hex::encode(bytes)
+    }
+
+    fn from_hex(cursor: &str) -> Result<Self, String> {
+        let bytes = hex::decode(cursor).map_err(|err| format!("Malformed cursor: {}", err))?;
+        if bytes.len() != CURSOR_SIZE {
+            return Err(String::from("Malformed cursor: invalid length"));
+        }
+
+        Ok(Self {
+            count: u64::from_be_bytes(bytes[..8].try_into().unwrap()),
+            outpoint: OutPoint::from_bytes(bytes[8..].to_vec()),
+        })
+    }
+}
+
+/// Returns a chunk of the UTXO set snapshot given the query string of the request.
+pub fn get_snapshot(query: Option<&str>) -> HttpResponse {
+    if with_state(|s| s.http_api) == Flag::Disabled {
+        return response(403, vec![], String::from("Snapshots are disabled."));
+    }
+
+    let (height, cursor) = match parse_query(query.unwrap_or_default()) {
+        Ok(params) => params,
+        Err(err) => return response(400, vec![], err),
+    };
+
+    with_state(|state| {
+        // The UTXO set must not change while a snapshot is being retrieved, as a snapshot
+        // mixing UTXOs from different heights would be inconsistent.
+        if state.utxos.has_partial_block() {
+            return response(
+                409,
+                vec![],
+                String::from("A block is being ingested. Try again later."),
+            );
+        }
+
+        if state.utxos.next_height() == 0 {
+            return response(409, vec![], String::from("The UTXO set is empty."));
+        }
+
+        let current_height = state.utxos.next_height() - 1;
+        match height {
+            Some(height) if height != current_height => {
+                return response(
+                    409,
+                    vec![],
+                    format!(
+                        "The UTXO set is at height {}, but the snapshot is at height {}.",
+                        current_height, height
+                    ),
+                );
+            }
+            None if cursor.is_some() => {
+                return response(
+                    400,
+                    vec![],
+                    String::from("A cursor must be accompanied by a height."),
+                );
+            }
+            _ => {}
+        }
+
+        let (body, next_cursor) = get_chunk(&state.utxos, cursor, MAX_UTXOS_PER_CHUNK);
+
+        let mut headers = vec![
+            ("Content-Type".to_string(), "text/csv".to_string()),
+            ("x-snapshot-height".to_string(), current_height.to_string()),
+        ];
+        if let Some(next_cursor) = next_cursor {
+            headers.push(("x-snapshot-next-cursor".to_string(), next_cursor.to_hex()));
+        }
+        response(200, headers, body)
+    })
+}
+
+// Returns the CSV rows of (at most `max_utxos`) UTXOs following the given cursor, along with
+// the cursor of the next chunk, if any.
+fn get_chunk(
+    utxo_set: &UtxoSet,
+    cursor: Option<Cursor>,
+    max_utxos: usize,
+) -> (String, Option<Cursor>) {
+    let (mut count, iter) = match cursor {
+        Some(cursor) => (cursor.count, utxo_set.utxos.iter_after(&cursor.outpoint)),
+        None => (0, utxo_set.utxos.iter()),
+    };
+    let mut iter = iter.peekable();
+
+    let mut body = String::new();
+    let mut num_utxos = 0;
+    while let Some((outpoint, (txout, height))) = iter.next() {
+        let script = Script::from(txout.script_pubkey);
+        let address = Address::from_script(&script, utxo_set.network())
+            .map(|a| a.to_string())
+            .unwrap_or_default();
+
+        body.push_str(&format!(
+            "{},{},{},{},{},{},{},{},{},{}\n",
+            count,
+            outpoint.txid,
+            outpoint.vout,
+            txout.value,
+            script_type(&script),
+            address,
+            hex::encode(script.as_bytes()),
+            utxo_set.is_coinbase(&outpoint) as u8,
+            script.len(),
+            height
+        ));
+
+        count += 1;
+        num_utxos += 1;
+
+        let chunk_is_full = num_utxos == max_utxos || body.len() >= MAX_CHUNK_SIZE;
+        if chunk_is_full && iter.peek().is_some() {
+            return (body, Some(Cursor { count, outpoint }));
+        }
+    }
+
+    (body, None)
+}
+
+// Returns the type of the script, using the same names as bitcoin-utxo-dump.
+fn script_type(script: &Script) -> &'static str {
+    if script.is_p2pkh() {
+        "p2pkh"
+    } else if script.is_p2sh() {
+        "p2sh"
+    } else if script.is_v0_p2wpkh() {
+        "p2wpkh"
+    } else if script.is_v0_p2wsh() {
+        "p2wsh"
+    } else if script.is_v1_p2tr() {
+        "p2tr"
+    } else if script.is_p2pk() {
+        "p2pk"
+    } else {
+        "non-standard"
+    }
+}
+
+// Parses the query string of a snapshot request into a height and cursor.
+fn parse_query(query: &str) -> Result<(Option<Height>, Option<Cursor>), String> {
+    let mut height = None;
+    let mut cursor = None;
+
+    for param in query.split('&').filter(|p| !p.is_empty()) {
+        match param.split_once('=') {
+            Some(("height", value)) => {
+                height = Some(
+                    value
+                        .parse()
+                        .map_err(|_| format!("Malformed height: {}", value))?,
+                );
+            }
+            Some(("cursor", value)) => cursor = Some(Cursor::from_hex(value)?),
+            _ => return Err(format!("Unknown parameter: {}", param)),
+        }
+    }
+
+    Ok((height, cursor))
+}
+
+fn response(status_code: u16, mut headers: Vec<(String, String)>, body: String) -> HttpResponse {
+    headers.push(("Content-Length".to_string(), body.len().to_string()));
+    HttpResponse {
+        status_code,
+        headers,
+        body: ByteBuf::from(body),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::{
+        state,
+        test_utils::build_regtest_chain,
+        types::{Config, HttpRequest, Network, Txid},
+        with_state_mut,
+    };
+    use std::str::FromStr;
+
+    fn init_with_chain(num_blocks: u32, num_transactions_per_block: u32) {
+        crate::init(Config {
+            stability_threshold: 0,
+            network: Network::Regtest,
+            http_api: Some(Flag::Enabled),
+            ..Default::default()
+        });
+
+        let blocks = build_regtest_chain(num_blocks, num_transactions_per_block);
+        with_state_mut(|s| {
+            for block in blocks[1..].iter() {
+                state::insert_block(s, block.clone()).unwrap();
+            }
+            state::ingest_stable_blocks_into_utxoset(s);
+        });
+    }
+
+    fn get_header<'a>(response: &'a HttpResponse, name: &str) -> Option<&'a str> {
+        response
+            .headers
+            .iter()
+            .find(|(k, _)| k == name)
+            .map(|(_, v)| v.as_str())
+    }
+
+    #[test]
+    fn chunks_cover_the_utxo_set() {
+        init_with_chain(10, 3);
+
+        with_state(|s| {
+            let mut rows = vec![];
+            let mut cursor = None;
+            loop {
+                let (body, next_cursor) = get_chunk(&s.utxos, cursor, 4);
+                rows.extend(body.lines().map(|l| l.to_string()));
+                match next_cursor {
+                    Some(next_cursor) => cursor = Some(next_cursor),
+                    None => break,
+                }
+            }
+
+            // Parse the rows the same way `state-builder` does.
+            let mut utxos: Vec<_> = s.utxos.utxos.iter().collect();
+            utxos.sort_by_key(|(outpoint, _)| outpoint.to_bytes().to_vec());
+            assert_eq!(rows.len(), utxos.len());
+            for (i, (row, (outpoint, (txout, height)))) in rows.iter().zip(utxos).enumerate() {
+                let parts: Vec<_> = row.split(',').collect();
+                assert_eq!(parts[0], i.to_string());
+                assert_eq!(Txid::from_str(parts[1]).unwrap(), outpoint.txid);
+                assert_eq!(parts[2], outpoint.vout.to_string());
+                assert_eq!(parts[3], txout.value.to_string());
+                assert_eq!(hex::decode(parts[6]).unwrap(), txout.script_pubkey);
+                assert_eq!(parts[9], height.to_string());
+            }
+        });
+    }
+
+    #[test]
+    fn returns_conflict_if_height_changed() {
+        init_with_chain(5, 1);
+
+        let response = crate::http_request(HttpRequest {
+            method: String::from("GET"),
+            url: String::from("/snapshot"),
+            headers: vec![],
+            body: ByteBuf::new(),
+        });
+        assert_eq!(response.status_code, 200);
+        let height: Height = get_header(&response, "x-snapshot-height")
+            .unwrap()
+            .parse()
+            .unwrap();
+
+        let response = get_snapshot(Some(&format!("height={}", height + 1)));
+        assert_eq!(response.status_code, 409);
+
+        let response = get_snapshot(Some(&format!("height={}", height)));
+        assert_eq!(response.status_code, 200);
+    }
+
+    #[test]
+    fn rejects_malformed_queries() {
+        init_with_chain(2, 1);
+
+        for query in ["height=abc", "cursor=00", "cursor=", "foo=bar"] {
+            assert_eq!(get_snapshot(Some(query)).status_code, 400);
+        }
+    }
+
+    #[test]
+    fn isnt_served_if_the_http_api_is_disabled() {
+        init_with_chain(2, 1);
+        with_state_mut(|s| s.http_api = Flag::Disabled);
+
+        assert_eq!(get_snapshot(None).status_code, 403);
+    }
+}
@@ -0,0 +1,77 @@
+use crate::{
+    state::{self, ResponseToProcess},
+    types::SyncStatus,
+    unstable_blocks, with_state,
+};
+
+/// Returns the progress of the canister in syncing with the bitcoin network.
+pub fn get_sync_status() -> SyncStatus {
+    with_state(|state| {
+        let main_chain_height = state::main_chain_height(state);
+        let best_header_height = state::best_header_height(state);
+
+        let partial_response_follow_up_index = match &state.syncing_state.response_to_process {
+            Some(ResponseToProcess::Partial(_, follow_up_index)) => Some(*follow_up_index),
+            _ => None,
+        };
+
+        SyncStatus {
+            stable_height: state.stable_height(),
+            main_chain_height,
+            main_chain_tip_hash: unstable_blocks::get_main_chain(&state.unstable_blocks)
+                .tip()
+                .block_hash()
+                .to_vec(),
+            best_header_height,
+            is_fetching_blocks: state.syncing_state.is_fetching_blocks,
+            partial_response_follow_up_index,
+            num_get_successors_rejects: state.syncing_state.num_get_successors_rejects,
+            num_block_deserialize_errors: state.syncing_state.num_block_deserialize_errors,
+            num_insert_block_errors: state.syncing_state.num_insert_block_errors,
+            num_invalid_headers: state.syncing_state.num_invalid_headers,
+            num_unexpected_responses: state.syncing_state.num_unexpected_responses,
+            blocks_behind: state::blocks_behind(state),
+        }
+    })
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::{
+        test_utils::build_regtest_chain,
+        types::{Config, Network},
+        with_state_mut,
+    };
+
+    #[test]
+    fn reports_the_blocks_behind_the_best_header() {
+        crate::init(Config {
+            stability_threshold: 1,
+            network: Network::Regtest,
+            ..Default::default()
+        });
+
+        let blocks = build_regtest_chain(5, 1);
+        with_state_mut(|s| {
+            for block in blocks[1..3].iter() {
+                state::insert_block(s, block.clone()).unwrap();
+            }
+            state::ingest_stable_blocks_into_utxoset(s);
+
+            let headers: Vec<_> = blocks[3..].iter().map(|b| *b.header()).collect();
+            state::insert_next_headers(s, &headers).unwrap();
+        });
+
+        let sync_status = get_sync_status();
+        assert_eq!(sync_status.stable_height, 1);
+        assert_eq!(sync_status.main_chain_height, 2);
+        assert_eq!(
+            sync_status.main_chain_tip_hash,
+            blocks[2].block_hash().to_vec()
+        );
+        assert_eq!(sync_status.best_header_height, 4);
+        assert_eq!(sync_status.blocks_behind, 2);
+        assert_eq!(sync_status.partial_response_follow_up_index, None);
+    }
+}
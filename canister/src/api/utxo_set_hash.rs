@@ -0,0 +1,98 @@
+use crate::{types::UtxoSetHash, with_state};
+
+/// Returns a commitment to the UTXO set, as of the latest block that was fully ingested.
+///
+/// Returns `None` if no blocks have been ingested yet, or if a block is currently being
+/// ingested or undone, as the number of UTXOs in the set would then be inconsistent with the hash.
+/// Also returns `None` if the hash is unknown because the UTXO set was created by a previous
+/// version of the canister that didn't maintain it.
+pub fn get_utxo_set_hash() -> Option<UtxoSetHash> {
+    with_state(|state| {
+        if state.utxos.has_partial_block() {
+            return None;
+        }
+        let muhash = state.utxos.muhash_hex()?;
+
+        let height = state.utxos.next_height() - 1;
+        let block_hash = state
+            .stable_block_headers
+            .get_with_height(height)
+            .expect("header of an ingested block must exist")
+            .block_hash();
+
+        Some(UtxoSetHash {
+            height,
+            block_hash: block_hash.to_string(),
+            // The outputs of the genesis block aren't part of bitcoind's UTXO set.
+            txouts: state.utxos.utxos_len() - genesis_outputs_len(state.network()),
+            muhash,
+        })
+    })
+}
+
+// Returns the number of (spendable) outputs in the genesis block of the given network.
+fn genesis_outputs_len(network: crate::types::Network) -> u64 {
+    crate::genesis_block(network)
+        .txdata()
+        .iter()
+        .map(|tx| {
+            tx.output()
+                .iter()
+                .filter(|o| !o.script_pubkey.is_provably_unspendable())
+                .count() as u64
+        })
+        .sum()
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::{
+        state,
+        test_utils::build_regtest_chain,
+        types::{Config, Network},
+        with_state_mut,
+    };
+
+    #[test]
+    fn returns_none_if_nothing_is_ingested() {
+        crate::init(Config {
+            stability_threshold: 1,
+            network: Network::Regtest,
+            ..Default::default()
+        });
+
+        assert_eq!(get_utxo_set_hash(), None);
+    }
+
+    #[test]
+    fn returns_hash_of_latest_ingested_block() {
+        crate::init(Config {
+            stability_threshold: 0,
+            network: Network::Regtest,
+            ..Default::default()
+        });
+
+        let blocks = build_regtest_chain(5, 2);
+        with_state_mut(|s| {
+            for block in blocks[1..].iter() {
+                state::insert_block(s, block.clone()).unwrap();
+            }
+            state::ingest_stable_blocks_into_utxoset(s);
+        });
+
+        let utxo_set_hash = get_utxo_set_hash().unwrap();
+        let height = with_state(|s| s.utxos.next_height()) - 1;
+        assert_eq!(utxo_set_hash.height, height);
+        assert_eq!(
+            utxo_set_hash.block_hash,
+            blocks[height as usize].block_hash().to_string()
+        );
+        // Two UTXOs are created in every block except the genesis block.
+        assert_eq!(utxo_set_hash.txouts, 2 * height as u64);
+        assert_eq!(
+            utxo_set_hash.muhash,
+            with_state(|s| crate::muhash::to_hex(&s.utxos.muhash().finalize()))
+        );
+    }
+}
@@ -58,6 +58,13 @@ impl BlockHeaderStore {
             .expect("block height insertion must succeed");
     }
 
+    /// Removes the header of the block at the given height.
+    pub fn remove(&mut self, height: Height) {
+        if let Some(block_hash) = self.block_heights.remove(&height) {
+            self.block_headers.remove(&block_hash);
+        }
+    }
+
     pub fn get_with_block_hash(&self, block_hash: &BlockHash) -> Option<BlockHeader> {
         self.block_headers
             .get(block_hash)
@@ -0,0 +1,246 @@
+use crate::types::Flag;
+use ic_cdk::export::Principal;
+use serde::{Deserialize, Serialize};
+
+/// The canisters from which blocks are retrieved.
+///
+/// Blocks are requested from one source at a time, starting with the primary source. Upon
+/// failure, requests move on to the next source, skipping the sources that have been demoted
+/// for returning invalid or conflicting data.
+#[derive(Serialize, Deserialize, PartialEq, Eq, Debug)]
+#[serde(from = "BlockSourcesRepr")]
+pub struct BlockSources {
+    // The sources, the primary source being the first.
+    sources: Vec<BlockSource>,
+
+    // The index of the source that requests are currently sent to.
+    current: usize,
+
+    /// Whether complete responses are cross-checked against the response of another source.
+    pub cross_check: Flag,
+}
+
+#[derive(Serialize, Deserialize, PartialEq, Eq, Debug)]
+pub struct BlockSource {
+    pub principal: Principal,
+    pub stats: BlockSourceStats,
+}
+
+#[derive(Serialize, Deserialize, PartialEq, Eq, Debug, Clone, Default)]
+pub struct BlockSourceStats {
+    /// The number of GetSuccessors requests sent to the source.
+    pub num_requests: u64,
+
+    /// The number of rejects received from the source.
+    pub num_rejects: u64,
+
+    /// The number of responses that were unexpected, or contained blocks that couldn't be
+    /// deserialized or had invalid headers.
+    pub num_invalid_responses: u64,
+
+    /// The number of responses that conflicted with the response of another source.
+    pub num_conflicts: u64,
+
+    /// Whether the source is demoted, i.e. only used once all the other sources are demoted.
+    pub demoted: bool,
+}
+
+impl BlockSources {
+    pub fn new(primary: Principal, backups: Vec<Principal>) -> Self {
+        let mut principals = vec![primary];
+        for backup in backups {
+            if !principals.contains(&backup) {
+                principals.push(backup);
+            }
+        }
+
+        Self {
+            sources: principals
+                .into_iter()
+                .map(|principal| BlockSource {
+                    principal,
+                    stats: BlockSourceStats::default(),
+                })
+                .collect(),
+            current: 0,
+            cross_check: Flag::Disabled,
+        }
+    }
+
+    /// The source that requests are currently sent to.
+    pub fn current(&self) -> Principal {
+        self.sources[self.current].principal
+    }
+
+    pub fn primary(&self) -> Principal {
+        self.sources[0].principal
+    }
+
+    pub fn backups(&self) -> Vec<Principal> {
+        self.sources[1..].iter().map(|s| s.principal).collect()
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = &BlockSource> {
+        self.sources.iter()
+    }
+
+    /// Returns true if the given principal is one of the sources.
+    pub fn contains(&self, principal: Principal) -> bool {
+        self.sources.iter().any(|s| s.principal == principal)
+    }
+
+    /// Returns the stats of the given source, or `None` if the principal isn't one of the
+    /// sources, e.g. because the sources changed while a request to it was in flight.
+    pub fn stats_mut(&mut self, principal: Principal) -> Option<&mut BlockSourceStats> {
+        self.sources
+            .iter_mut()
+            .find(|s| s.principal == principal)
+            .map(|s| &mut s.stats)
+    }
+
+    /// Returns a source other than the current one to cross-check responses with, if
+    /// cross-checking is enabled and such a source is available.
+    pub fn witness(&self) -> Option<Principal> {
+        if self.cross_check == Flag::Disabled {
+            return None;
+        }
+
+        self.next_index()
+            .filter(|i| *i != self.current)
+            .map(|i| self.sources[i].principal)
+    }
+
+    /// Moves on to the next source that isn't demoted. If all the sources are demoted, they
+    /// are given another chance.
+    pub fn rotate(&mut self) {
+        if self.sources.iter().all(|s| s.stats.demoted) {
+            for source in self.sources.iter_mut() {
+                source.stats.demoted = false;
+            }
+        }
+
+        self.current = self.next_index().unwrap_or(self.current);
+    }
+
+    /// Demotes the given source, moving on to the next source if it's the current one. Does
+    /// nothing if the principal isn't one of the sources.
+    pub fn demote(&mut self, principal: Principal) {
+        match self.stats_mut(principal) {
+            Some(stats) => stats.demoted = true,
+            None => return,
+        }
+
+        if self.current() == principal {
+            self.rotate();
+        }
+    }
+
+    // The index of the next source after the current one that isn't demoted, if any.
+    fn next_index(&self) -> Option<usize> {
+        let n = self.sources.len();
+        (1..=n)
+            .map(|i| (self.current + i) % n)
+            .find(|i| !self.sources[*i].stats.demoted)
+    }
+}
+
+// The serialized representations of the block sources, which allow migrating the single
+// block source of previous versions of the canister.
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum BlockSourcesRepr {
+    Current {
+        sources: Vec<BlockSource>,
+        current: usize,
+        cross_check: Flag,
+    },
+
+    // The principal of the only source.
+    Principal(Principal),
+}
+
+impl From<BlockSourcesRepr> for BlockSources {
+    fn from(repr: BlockSourcesRepr) -> Self {
+        match repr {
+            BlockSourcesRepr::Current {
+                sources,
+                current,
+                cross_check,
+            } => Self {
+                sources,
+                current,
+                cross_check,
+            },
+            BlockSourcesRepr::Principal(principal) => Self::new(principal, vec![]),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn principal(id: u8) -> Principal {
+        Principal::from_slice(&[id])
+    }
+
+    #[test]
+    fn rotates_and_skips_demoted_sources() {
+        let mut sources = BlockSources::new(principal(0), vec![principal(1), principal(2)]);
+        assert_eq!(sources.current(), principal(0));
+        assert_eq!(sources.backups(), vec![principal(1), principal(2)]);
+
+        sources.rotate();
+        assert_eq!(sources.current(), principal(1));
+
+        // Demoting a source that isn't the current one doesn't change the current source, but
+        // the demoted source is skipped afterwards.
+        sources.demote(principal(2));
+        assert_eq!(sources.current(), principal(1));
+        sources.rotate();
+        assert_eq!(sources.current(), principal(0));
+
+        // Demoting the current source moves on to the next one.
+        sources.demote(principal(0));
+        assert_eq!(sources.current(), principal(1));
+
+        // Once all the sources are demoted, they're given another chance.
+        sources.demote(principal(1));
+        assert_eq!(sources.current(), principal(2));
+        assert!(sources.iter().all(|s| !s.stats.demoted));
+    }
+
+    #[test]
+    fn witness_is_only_available_when_cross_checking() {
+        let mut sources = BlockSources::new(principal(0), vec![principal(1)]);
+        assert_eq!(sources.witness(), None);
+
+        sources.cross_check = Flag::Enabled;
+        assert_eq!(sources.witness(), Some(principal(1)));
+
+        sources.demote(principal(1));
+        assert_eq!(sources.witness(), None);
+
+        // A single source cannot be cross-checked.
+        let mut sources = BlockSources::new(principal(0), vec![principal(0)]);
+        sources.cross_check = Flag::Enabled;
+        assert_eq!(sources.witness(), None);
+    }
+
+    #[test]
+    fn decodes_the_single_source_of_previous_versions() {
+        let mut bytes = vec![];
+        ciborium::ser::into_writer(&principal(3), &mut bytes).unwrap();
+        let sources: BlockSources = ciborium::de::from_reader(&*bytes).unwrap();
+        assert_eq!(sources, BlockSources::new(principal(3), vec![]));
+
+        let mut bytes = vec![];
+        let mut sources = BlockSources::new(principal(0), vec![principal(1)]);
+        sources.demote(principal(0));
+        ciborium::ser::into_writer(&sources, &mut bytes).unwrap();
+        assert_eq!(
+            ciborium::de::from_reader::<BlockSources, _>(&*bytes).unwrap(),
+            sources
+        );
+    }
+}
@@ -1,4 +1,5 @@
 use crate::types::{Block, BlockHash};
+use bitcoin::util::uint::Uint256;
 use std::fmt;
 mod serde;
 
@@ -78,16 +79,37 @@ impl fmt::Display for EmptyChainError {
 pub struct BlockTree {
     pub root: Block,
     pub children: Vec<BlockTree>,
+
+    // The cumulative work of the heaviest chain starting at (and including) the root.
+    chainwork: Uint256,
 }
 
 impl BlockTree {
     /// Creates a new `BlockTree` with the given block as its root.
     pub fn new(root: Block) -> Self {
+        let chainwork = root.header().work();
         Self {
             root,
             children: vec![],
+            chainwork,
         }
     }
+
+    /// Returns the cumulative work of the heaviest chain starting at the root of the tree.
+    pub fn chainwork(&self) -> Uint256 {
+        self.chainwork
+    }
+
+    // Recomputes the chainwork of the tree from the chainwork of its children.
+    fn update_chainwork(&mut self) {
+        self.chainwork = self.root.header().work()
+            + self
+                .children
+                .iter()
+                .map(|child| child.chainwork)
+                .max()
+                .unwrap_or_default();
+    }
 }
 
 /// Extends the tree with the given block.
@@ -102,18 +124,30 @@ pub fn extend(block_tree: &mut BlockTree, block: Block) -> Result<(), BlockDoesN
     }
 
     // Check if the block is a successor to any of the blocks in the tree.
-    match find_mut(block_tree, &block.header().prev_blockhash.into()) {
-        Some((block_subtree, _)) => {
-            assert_eq!(
-                block_subtree.root.block_hash().to_vec(),
-                block.header().prev_blockhash.to_vec()
-            );
-            // Add the block as a successor.
-            block_subtree.children.push(BlockTree::new(block));
-            Ok(())
+    add_successor(block_tree, block).map_err(BlockDoesNotExtendTree)
+}
+
+// Adds the block as a child of its parent, updating the chainwork of all the parent's
+// ancestors along the way. The block is returned if its parent isn't in the tree.
+fn add_successor(block_tree: &mut BlockTree, mut block: Block) -> Result<(), Block> {
+    let parent_hash: BlockHash = block.header().prev_blockhash.into();
+    if block_tree.root.block_hash() == parent_hash {
+        block_tree.children.push(BlockTree::new(block));
+        block_tree.update_chainwork();
+        return Ok(());
+    }
+
+    for i in 0..block_tree.children.len() {
+        match add_successor(&mut block_tree.children[i], block) {
+            Ok(()) => {
+                block_tree.update_chainwork();
+                return Ok(());
+            }
+            Err(b) => block = b,
         }
-        None => Err(BlockDoesNotExtendTree(block)),
     }
+
+    Err(block)
 }
 
 /// Returns all the blockchains in the tree.
@@ -141,6 +175,15 @@ pub fn blockchains(block_tree: &BlockTree) -> Vec<BlockChain> {
     tips
 }
 
+/// Returns all the blocks in the tree, where every block comes after its parent.
+pub fn blocks(block_tree: &BlockTree) -> Vec<&Block> {
+    let mut blocks = vec![&block_tree.root];
+    for child in block_tree.children.iter() {
+        blocks.extend(self::blocks(child));
+    }
+    blocks
+}
+
 /// Returns a `BlockChain` starting from the anchor and ending with the `tip`.
 ///
 /// If the `tip` doesn't exist in the tree, `None` is returned.
@@ -227,8 +270,8 @@ pub fn find_mut<'a>(
     find_mut_helper(block_tree, blockhash, 0)
 }
 
-// Returns true if a block exists in the tree, false otherwise.
-fn contains(block_tree: &BlockTree, block: &Block) -> bool {
+/// Returns true if a block exists in the tree, false otherwise.
+pub fn contains(block_tree: &BlockTree, block: &Block) -> bool {
     if block_tree.root.block_hash() == block.block_hash() {
         return true;
     }
@@ -364,4 +407,33 @@ mod test {
             blocks = vec![blocks[0].clone()];
         }
     }
+
+    // Creating the following tree, where `x` has 4 times the work of the other blocks:
+    //
+    // * -> 1 -> 2
+    //   -> x
+    #[test]
+    fn chainwork_is_that_of_the_heaviest_chain() {
+        let block_0 = BlockBuilder::genesis().build();
+        let block_1 = BlockBuilder::with_prev_header(block_0.header()).build();
+        let block_2 = BlockBuilder::with_prev_header(block_1.header()).build();
+        let block_x = BlockBuilder::with_prev_header(block_0.header())
+            .with_bits(0x201fffff)
+            .build();
+        let work = block_0.header().work();
+        let mut block_tree = BlockTree::new(block_0);
+
+        extend(&mut block_tree, block_1).unwrap();
+        extend(&mut block_tree, block_2).unwrap();
+        assert_eq!(block_tree.chainwork(), work.mul_u32(3));
+
+        extend(&mut block_tree, block_x.clone()).unwrap();
+        assert_eq!(block_tree.chainwork(), work + block_x.header().work());
+
+        // The chainwork is restored when deserializing the tree.
+        let mut bytes = vec![];
+        ciborium::ser::into_writer(&block_tree, &mut bytes).unwrap();
+        let new_block_tree: BlockTree = ciborium::de::from_reader(&bytes[..]).unwrap();
+        assert_eq!(new_block_tree, block_tree);
+    }
 }
@@ -64,6 +64,9 @@ impl<'de> Visitor<'de> for BlockTreeDeserializer {
                 block_tree.children.push(build_tree(seq));
             }
 
+            // The chainwork isn't serialized, as it can be derived from the blocks.
+            block_tree.update_chainwork();
+
             block_tree
         }
 
@@ -0,0 +1,126 @@
+//! Certification of the canister's view of the chain, i.e. of the tip that the responses of
+//! queries, which don't go through consensus, are computed from.
+//!
+//! The canister's certified data is set to the SHA-256 hash of the certified tip's block hash,
+//! followed by its height and its stable height as 4 little-endian bytes each, followed by the
+//! number of UTXOs as 8 little-endian bytes. Clients verify the certified tip returned alongside
+//! a query response by checking that the certificate is valid and that its certified data matches
+//! the hash of the certified tip.
+//!
+//! Only the certified tip is covered by the certificate. The balances and UTXOs in the responses
+//! aren't, so clients can verify which chain a response claims to be computed from, but not that
+//! the balances and UTXOs are those of that chain. Clients that need the latter must make an
+//! update call instead.
+//!
+//! The certified data is updated by the heartbeat after every phase that changes the main chain
+//! tip or the stable tip, and before every call to a block source, so that the certified tip
+//! computed by queries while the call is awaited matches the certified data.
+use crate::{
+    runtime,
+    state::{self, State},
+    types::CertifiedTip,
+    unstable_blocks,
+};
+use bitcoin::hashes::{sha256, Hash};
+
+/// Returns the tip of the main chain, along with the state of the UTXO set.
+pub fn certified_tip(state: &State) -> CertifiedTip {
+    CertifiedTip {
+        block_hash: unstable_blocks::get_main_chain(&state.unstable_blocks)
+            .tip()
+            .block_hash()
+            .to_vec(),
+        height: state::main_chain_height(state),
+        stable_height: state.stable_height(),
+        utxos_length: state.utxos.utxos_len(),
+    }
+}
+
+/// Sets the certified data to commit to the certified tip.
+pub fn certify_tip(state: &State) {
+    runtime::set_certified_data(&commitment(&certified_tip(state)));
+}
+
+/// Returns the commitment to the given tip that's used as the certified data.
+pub fn commitment(tip: &CertifiedTip) -> [u8; 32] {
+    let mut bytes = tip.block_hash.clone();
+    bytes.extend_from_slice(&tip.height.to_le_bytes());
+    bytes.extend_from_slice(&tip.stable_height.to_le_bytes());
+    bytes.extend_from_slice(&tip.utxos_length.to_le_bytes());
+    sha256::Hash::hash(&bytes).into_inner()
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::{
+        genesis_block, heartbeat, init,
+        runtime::GetSuccessorsReply,
+        test_utils::BlockBuilder,
+        types::{Config, GetSuccessorsCompleteResponse, GetSuccessorsResponse, Network},
+        with_state,
+    };
+    use bitcoin::consensus::Encodable;
+
+    #[async_std::test]
+    async fn certifies_the_tips_and_the_utxo_set() {
+        let network = Network::Regtest;
+        init(Config {
+            stability_threshold: 0,
+            network,
+            ..Default::default()
+        });
+
+        let genesis_tip = CertifiedTip {
+            block_hash: genesis_block(network).block_hash().to_vec(),
+            height: 0,
+            stable_height: 0,
+            utxos_length: 0,
+        };
+        assert_eq!(
+            runtime::data_certificate(),
+            Some(commitment(&genesis_tip).to_vec())
+        );
+
+        let block = BlockBuilder::with_prev_header(genesis_block(network).header()).build();
+        let mut block_bytes = vec![];
+        block.consensus_encode(&mut block_bytes).unwrap();
+        runtime::set_successors_response(GetSuccessorsReply::Ok(GetSuccessorsResponse::Complete(
+            GetSuccessorsCompleteResponse {
+                blocks: vec![block_bytes],
+                next: vec![],
+            },
+        )));
+
+        // Fetch and process the block. The main chain tip changes.
+        heartbeat().await;
+        heartbeat().await;
+
+        let tip = with_state(certified_tip);
+        assert_eq!(
+            tip,
+            CertifiedTip {
+                block_hash: block.block_hash().to_vec(),
+                height: 1,
+                stable_height: 0,
+                utxos_length: 0,
+            }
+        );
+        assert_eq!(runtime::data_certificate(), Some(commitment(&tip).to_vec()));
+
+        // Ingest the genesis block into the UTXO set. The stable tip changes.
+        runtime::set_successors_responses(vec![]);
+        heartbeat().await;
+
+        let tip = with_state(certified_tip);
+        assert_eq!(tip.stable_height, 1);
+        assert_eq!(tip.utxos_length, with_state(|s| s.utxos.utxos_len()));
+        assert_eq!(runtime::data_certificate(), Some(commitment(&tip).to_vec()));
+
+        // The new tip was already certified while blocks were being fetched in the same round.
+        assert_eq!(
+            runtime::get_successors_calls_certified_data(),
+            vec![commitment(&tip).to_vec()]
+        );
+    }
+}
@@ -0,0 +1,132 @@
+use crate::types::{ConfigChange, ConfigHistoryPage};
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::{BTreeMap, VecDeque},
+    convert::TryFrom,
+};
+
+/// The maximum number of config changes kept in the history. Older changes are dropped.
+const MAX_CONFIG_CHANGES: usize = 1000;
+
+/// The number of config changes in a page of the history.
+const PAGE_SIZE: usize = 20;
+
+/// A bounded history of the changes made to the config.
+#[derive(Serialize, Deserialize, PartialEq, Eq, Debug, Default)]
+pub struct ConfigHistory {
+    // The most recent changes, the oldest first.
+    changes: VecDeque<ConfigChange>,
+
+    // The number of times each field has been changed, including by the changes that have been
+    // dropped from the history.
+    num_changes_by_field: BTreeMap<String, u64>,
+}
+
+impl ConfigHistory {
+    /// Records a change, dropping the oldest change if the history is full.
+    pub fn record(&mut self, change: ConfigChange) {
+        for field_change in change.changes.iter() {
+            *self
+                .num_changes_by_field
+                .entry(field_change.field.clone())
+                .or_default() += 1;
+        }
+
+        if self.changes.len() == MAX_CONFIG_CHANGES {
+            self.changes.pop_front();
+        }
+        self.changes.push_back(change);
+    }
+
+    /// Returns the given page of the history, the most recent changes first.
+    pub fn page(&self, page: u64) -> ConfigHistoryPage {
+        let start = usize::try_from(page)
+            .unwrap_or(usize::MAX)
+            .saturating_mul(PAGE_SIZE);
+        let end = start.saturating_add(PAGE_SIZE);
+
+        ConfigHistoryPage {
+            changes: self
+                .changes
+                .iter()
+                .rev()
+                .skip(start)
+                .take(PAGE_SIZE)
+                .cloned()
+                .collect(),
+            next_page: if end < self.changes.len() {
+                Some(page + 1)
+            } else {
+                None
+            },
+        }
+    }
+
+    /// Returns the number of times each field has been changed.
+    pub fn num_changes_by_field(&self) -> impl Iterator<Item = (&str, u64)> {
+        self.num_changes_by_field
+            .iter()
+            .map(|(field, count)| (field.as_str(), *count))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::types::FieldChange;
+    use ic_cdk::export::Principal;
+
+    fn change(timestamp: u64, fields: &[&str]) -> ConfigChange {
+        ConfigChange {
+            timestamp,
+            caller: Principal::anonymous(),
+            height: 0,
+            changes: fields
+                .iter()
+                .map(|field| FieldChange {
+                    field: field.to_string(),
+                    old_value: String::from("old"),
+                    new_value: String::from("new"),
+                })
+                .collect(),
+        }
+    }
+
+    #[test]
+    fn pages_start_with_the_most_recent_changes() {
+        let mut history = ConfigHistory::default();
+        for timestamp in 0..(PAGE_SIZE + 1) as u64 {
+            history.record(change(timestamp, &["fees"]));
+        }
+
+        let first_page = history.page(0);
+        assert_eq!(first_page.changes.len(), PAGE_SIZE);
+        assert_eq!(first_page.changes[0].timestamp, PAGE_SIZE as u64);
+        assert_eq!(first_page.next_page, Some(1));
+
+        let second_page = history.page(1);
+        assert_eq!(second_page.changes, vec![change(0, &["fees"])]);
+        assert_eq!(second_page.next_page, None);
+
+        assert_eq!(history.page(u64::MAX).changes, vec![]);
+    }
+
+    #[test]
+    fn drops_the_oldest_changes_but_keeps_counting() {
+        let mut history = ConfigHistory::default();
+        for timestamp in 0..MAX_CONFIG_CHANGES as u64 {
+            history.record(change(timestamp, &["fees", "syncing"]));
+        }
+        history.record(change(MAX_CONFIG_CHANGES as u64, &["fees"]));
+
+        assert_eq!(history.changes.len(), MAX_CONFIG_CHANGES);
+        assert_eq!(history.changes.front().unwrap().timestamp, 1);
+        assert_eq!(
+            history.num_changes_by_field().collect::<Vec<_>>(),
+            vec![
+                ("fees", MAX_CONFIG_CHANGES as u64 + 1),
+                ("syncing", MAX_CONFIG_CHANGES as u64)
+            ]
+        );
+    }
+}
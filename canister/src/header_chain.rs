@@ -0,0 +1,222 @@
+use crate::types::BlockHash;
+use bitcoin::{util::uint::Uint256, BlockHeader};
+use ic_btc_types::Height;
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+
+/// The maximum number of headers in the header chain, and in the competing branch.
+const MAX_HEADERS: usize = 10_000;
+
+/// Validated headers of blocks that are known to the network, but haven't necessarily been
+/// received by the canister yet.
+///
+/// The headers are announced in the `next` field of `GetSuccessors` responses and allow the
+/// canister to know the height of the best known chain before the blocks themselves arrive.
+///
+/// Headers that compete with the chain are kept in a separate branch, which replaces the chain
+/// from the fork point once it has more work. See `insert`.
+#[derive(Serialize, Deserialize, PartialEq, Eq, Debug, Default)]
+pub struct HeaderChain {
+    // The headers of the chain, indexed by height.
+    headers: BTreeMap<Height, BlockHeader>,
+
+    // The heights of the headers of the chain, indexed by block hash.
+    heights: BTreeMap<BlockHash, Height>,
+
+    // The headers of the branch that competes with the chain, if any, ordered by height.
+    branch: Vec<BlockHeader>,
+
+    // The height of the first header of the branch.
+    branch_height: Height,
+}
+
+impl HeaderChain {
+    /// Inserts a header that has already been validated. See `validation::validate_header`.
+    ///
+    /// The header extends the chain if its parent is the header at the previous height, or if
+    /// there's no such header. Otherwise, it extends the competing branch, or starts a new one,
+    /// and the branch replaces the headers of the chain from its first height if it has more
+    /// work than them.
+    pub fn insert(&mut self, header: BlockHeader, height: Height) {
+        let block_hash = BlockHash::from(header.block_hash());
+        if self.get(&block_hash).is_some() {
+            return;
+        }
+
+        // The header fits in the chain if there's no header at its height, and if the headers
+        // around it, if any, are its parent and its child.
+        let prev_hash = BlockHash::from(header.prev_blockhash);
+        let extends_chain = self.headers.get(&height).is_none()
+            && height
+                .checked_sub(1)
+                .and_then(|prev_height| self.headers.get(&prev_height))
+                .map_or(true, |prev| BlockHash::from(prev.block_hash()) == prev_hash)
+            && self.headers.get(&(height + 1)).map_or(true, |next| {
+                BlockHash::from(next.prev_blockhash) == block_hash
+            });
+
+        if extends_chain {
+            // Headers beyond the limit are dropped, as they're also part of subsequent responses.
+            if self.headers.len() < MAX_HEADERS {
+                self.headers.insert(height, header);
+                self.heights.insert(block_hash, height);
+            }
+            return;
+        }
+
+        // The header competes with the chain. It extends the branch from its parent if the
+        // parent is in the branch, or starts a new branch otherwise.
+        match self
+            .branch
+            .iter()
+            .position(|h| BlockHash::from(h.block_hash()) == prev_hash)
+        {
+            Some(prev_idx) => self.branch.truncate(prev_idx + 1),
+            None => {
+                self.branch.clear();
+                self.branch_height = height;
+            }
+        }
+
+        if self.branch.len() < MAX_HEADERS {
+            self.branch.push(header);
+        }
+
+        let chain_work = work(self.headers.range(self.branch_height..).map(|(_, h)| h));
+        if work(self.branch.iter()) > chain_work {
+            let branch_height = self.branch_height;
+            for (_, header) in self.headers.split_off(&branch_height) {
+                self.heights.remove(&BlockHash::from(header.block_hash()));
+            }
+
+            for (i, header) in std::mem::take(&mut self.branch).into_iter().enumerate() {
+                let height = branch_height + i as Height;
+                self.heights
+                    .insert(BlockHash::from(header.block_hash()), height);
+                self.headers.insert(height, header);
+            }
+        }
+    }
+
+    /// Returns the header with the given hash along with its height, if it exists, either in
+    /// the chain or in the competing branch.
+    pub fn get(&self, block_hash: &BlockHash) -> Option<(&BlockHeader, Height)> {
+        if let Some(height) = self.heights.get(block_hash) {
+            return self.headers.get(height).map(|header| (header, *height));
+        }
+
+        self.branch
+            .iter()
+            .enumerate()
+            .find(|(_, header)| BlockHash::from(header.block_hash()) == *block_hash)
+            .map(|(i, header)| (header, self.branch_height + i as Height))
+    }
+
+    /// Returns the header of the chain at the given height, if any.
+    pub fn header_at(&self, height: Height) -> Option<&BlockHeader> {
+        self.headers.get(&height)
+    }
+
+    /// Returns true if the header with the given hash is in the chain, rather than in the
+    /// competing branch.
+    pub fn contains(&self, block_hash: &BlockHash) -> bool {
+        self.heights.contains_key(block_hash)
+    }
+
+    /// Returns the height of the highest header of the chain, if any.
+    pub fn tip_height(&self) -> Option<Height> {
+        self.headers.keys().next_back().copied()
+    }
+
+    /// Removes all the headers with a height lower than the given height. The competing branch
+    /// is dropped if it starts below it, as it competes with stable blocks.
+    pub fn remove_below(&mut self, height: Height) {
+        let headers = self.headers.split_off(&height);
+        for (_, header) in std::mem::replace(&mut self.headers, headers) {
+            self.heights.remove(&BlockHash::from(header.block_hash()));
+        }
+
+        if self.branch_height < height {
+            self.branch.clear();
+        }
+    }
+}
+
+// Returns the cumulative work of the given headers.
+fn work<'a>(headers: impl Iterator<Item = &'a BlockHeader>) -> Uint256 {
+    headers.fold(Uint256::from_u64(0).unwrap(), |work, header| {
+        work + header.work()
+    })
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::{
+        genesis_block,
+        test_utils::{random_p2pkh_address, BlockBuilder, TransactionBuilder},
+        types::Network,
+    };
+
+    #[test]
+    fn tip_height_and_pruning() {
+        let block_1 =
+            BlockBuilder::with_prev_header(genesis_block(Network::Regtest).header()).build();
+        let block_2 = BlockBuilder::with_prev_header(block_1.header()).build();
+
+        let mut header_chain = HeaderChain::default();
+        assert_eq!(header_chain.tip_height(), None);
+
+        header_chain.insert(*block_1.header(), 1);
+        header_chain.insert(*block_2.header(), 2);
+        assert_eq!(header_chain.tip_height(), Some(2));
+        assert_eq!(header_chain.header_at(1), Some(block_1.header()));
+        assert_eq!(
+            header_chain.get(&block_1.block_hash()),
+            Some((block_1.header(), 1))
+        );
+
+        header_chain.remove_below(2);
+        assert_eq!(header_chain.get(&block_1.block_hash()), None);
+        assert_eq!(header_chain.header_at(1), None);
+        assert_eq!(header_chain.tip_height(), Some(2));
+    }
+
+    #[test]
+    fn switches_to_a_competing_branch_with_more_work() {
+        let genesis = genesis_block(Network::Regtest);
+        let block_1 = BlockBuilder::with_prev_header(genesis.header()).build();
+        let block_2 = BlockBuilder::with_prev_header(block_1.header()).build();
+        let fork_1 = BlockBuilder::with_prev_header(genesis.header())
+            .with_transaction(
+                TransactionBuilder::coinbase()
+                    .with_output(&random_p2pkh_address(Network::Regtest), 1)
+                    .build(),
+            )
+            .build();
+        let fork_2 = BlockBuilder::with_prev_header(fork_1.header()).build();
+        let fork_3 = BlockBuilder::with_prev_header(fork_2.header()).build();
+
+        let mut header_chain = HeaderChain::default();
+        header_chain.insert(*block_1.header(), 1);
+        header_chain.insert(*block_2.header(), 2);
+
+        // The branch is kept aside as long as it doesn't have more work than the chain.
+        header_chain.insert(*fork_1.header(), 1);
+        header_chain.insert(*fork_2.header(), 2);
+        assert_eq!(header_chain.header_at(2), Some(block_2.header()));
+        assert!(!header_chain.contains(&fork_2.block_hash()));
+        assert_eq!(
+            header_chain.get(&fork_2.block_hash()),
+            Some((fork_2.header(), 2))
+        );
+
+        // Once it has more work, it replaces the chain from the fork point.
+        header_chain.insert(*fork_3.header(), 3);
+        assert_eq!(header_chain.tip_height(), Some(3));
+        assert_eq!(header_chain.header_at(1), Some(fork_1.header()));
+        assert!(header_chain.contains(&fork_3.block_hash()));
+        assert_eq!(header_chain.get(&block_1.block_hash()), None);
+        assert_eq!(header_chain.get(&block_2.block_hash()), None);
+    }
+}
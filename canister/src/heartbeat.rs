@@ -1,41 +1,90 @@
 use crate::{
-    runtime::{call_get_successors, print},
-    state::{self, ResponseToProcess},
+    certification,
+    runtime::{call_get_successors, performance_counter, time},
+    state::{self, ResponseToProcess, State},
     types::{
-        Block, BlockHash, Flag, GetSuccessorsCompleteResponse, GetSuccessorsRequest,
-        GetSuccessorsRequestInitial, GetSuccessorsResponse,
+        Block, BlockBlob, BlockHash, Flag, GetSuccessorsCompleteResponse, GetSuccessorsRequest,
+        GetSuccessorsRequestInitial, GetSuccessorsResponse, Slicing,
     },
+    unstable_blocks,
+    validation::{self, ValidationContext},
 };
 use crate::{with_state, with_state_mut};
 use bitcoin::consensus::Decodable;
-use bitcoin::Block as BitcoinBlock;
+use bitcoin::{Block as BitcoinBlock, BlockHeader};
+use ic_btc_types::Height;
+use ic_btc_validation::{HeaderStore, ValidateHeaderError};
+use ic_cdk::{api::call::CallResult, export::Principal};
+use std::{
+    cmp::Ordering,
+    collections::{BTreeMap, BTreeSet},
+};
 
 /// The heartbeat of the Bitcoin canister.
 ///
 /// The heartbeat fetches new blocks from the bitcoin network and inserts them into the state.
+/// It's run in rounds scheduled by timers (see the `scheduler` module).
+/// It runs the following phases in order, each one within its budget in `HeartbeatLimits`, and
+/// moves on to the next phase as long as the previous one didn't run out of budget:
+///   1. Ingesting stable blocks into the UTXO set.
+///   2. Fetching new blocks.
+///   3. Inserting the blocks of a fetched response, and validating the headers that follow them.
+///
+/// The tip of the main chain is certified after every phase that changes it, and before every
+/// call to a block source, so that the queries answered while awaiting the call match the
+/// certified data.
 pub async fn heartbeat() {
-    if ingest_stable_blocks_into_utxoset() {
-        // Exit the heartbeat if stable blocks had been ingested.
-        // This is a precaution to not exceed the instructions limit.
+    run_phases().await;
+
+    // The insertion of blocks changes the main chain.
+    with_state(certification::certify_tip);
+}
+
+async fn run_phases() {
+    let ingestion_done = ingest_stable_blocks_into_utxoset();
+
+    // The ingestion changes the stable tip and the UTXO set.
+    with_state(certification::certify_tip);
+    if !ingestion_done {
+        // The ingestion of a block is paused. Continue in the next heartbeat.
         return;
     }
 
     if maybe_fetch_blocks().await {
         // Exit the heartbeat if new blocks have been fetched.
-        // This is a precaution to not exceed the instructions limit.
+        // The response is processed in a subsequent heartbeat.
+        return;
+    }
+
+    if with_state(|s| performance_counter() >= s.heartbeat_limits.insertion_instructions) {
+        // The insertion budget has already been used up.
         return;
     }
 
     maybe_process_response();
 }
 
+// The delay before fetching blocks again after a failure. The delay doubles with every
+// consecutive failure, up to `MAX_BACKOFF_SECS`.
+const INITIAL_BACKOFF_SECS: u64 = 1;
+const MAX_BACKOFF_SECS: u64 = 300;
+
+// The number of consecutive failures after which a partial response is dropped and its block is
+// requested again from scratch.
+const MAX_PARTIAL_RESPONSE_FAILURES: u32 = 3;
+
+// The number of bytes logged from the beginning of a block that cannot be deserialized.
+const MAX_LOGGED_BLOCK_BYTES: usize = 80;
+
 // Fetches new blocks if there isn't a request in progress and no complete response to process.
-// Returns true if a call to the `blocks_source` has been made, false otherwise.
+// Returns true if a call to a block source has been made, false otherwise.
 async fn maybe_fetch_blocks() -> bool {
     if with_state(|s| {
-        s.syncing_state.is_fetching_blocks || s.syncing_state.syncing == Flag::Disabled
+        s.syncing_state.is_fetching_blocks
+            || s.syncing_state.syncing == Flag::Disabled
+            || time() < s.syncing_state.backoff_until
     }) {
-        // Already fetching blocks or syncing is disabled.
+        // Already fetching blocks, syncing is disabled, or backing off after a failure.
         return false;
     }
 
@@ -50,59 +99,114 @@ async fn maybe_fetch_blocks() -> bool {
     };
 
     // A lock to ensure the heartbeat only sends one request at a time.
-    with_state_mut(|s| {
+    let source = with_state_mut(|s| {
+        certification::certify_tip(s);
+
         s.syncing_state.is_fetching_blocks = true;
+
+        let source = s.block_sources.current();
+        if let Some(stats) = s.block_sources.stats_mut(source) {
+            stats.num_requests += 1;
+        }
+        s.logs.debug(
+            "Sending request",
+            &[("source", &source.to_text()), ("request", &request)],
+        );
+        source
     });
 
-    print(&format!("Sending request: {:?}", request));
+    let response: CallResult<(GetSuccessorsResponse,)> =
+        call_get_successors(source, request.clone()).await;
 
-    let response: Result<(GetSuccessorsResponse,), _> =
-        call_get_successors(with_state(|s| s.blocks_source), request).await;
+    with_state_mut(|s| {
+        s.logs
+            .debug("Received response", &[("response", &response)])
+    });
 
-    print(&format!("Received response: {:?}", response));
+    // If enabled, send the same request to another source to cross-check a complete response.
+    let witness = with_state_mut(
+        |s| match (&response, &s.syncing_state.response_to_process) {
+            (Ok((GetSuccessorsResponse::Complete(_),)), None) => {
+                let witness = s.block_sources.witness()?;
+                certification::certify_tip(s);
+                if let Some(stats) = s.block_sources.stats_mut(witness) {
+                    stats.num_requests += 1;
+                }
+                s.logs.debug(
+                    "Sending request",
+                    &[("source", &witness.to_text()), ("request", &request)],
+                );
+                Some(witness)
+            }
+            _ => None,
+        },
+    );
+    let witness_response = match witness {
+        Some(witness) => Some((witness, call_get_successors(witness, request).await)),
+        None => None,
+    };
 
     // Release the heartbeat lock and save the response.
     with_state_mut(|s| {
         s.syncing_state.is_fetching_blocks = false;
 
+        // The sources may have changed while the request was in flight. The response of a
+        // source that has been removed is dropped, and the blocks are requested again.
+        if !s.block_sources.contains(source) {
+            s.logs.warn(
+                "Dropping the response of a removed source",
+                &[("source", &source.to_text())],
+            );
+            return;
+        }
+
         let response = match response {
             Ok((response,)) => response,
             Err((code, msg)) => {
                 s.syncing_state.num_get_successors_rejects += 1;
-                print(&format!("Error fetching blocks: [{:?}] {}", code, msg));
-                s.syncing_state.response_to_process = None;
+                if let Some(stats) = s.block_sources.stats_mut(source) {
+                    stats.num_rejects += 1;
+                }
+                s.logs.error(
+                    "Error fetching blocks",
+                    &[
+                        ("source", &source.to_text()),
+                        ("code", &code),
+                        ("message", &msg),
+                    ],
+                );
+
+                // A partial response is kept, so that its follow-up is requested again from
+                // the same source. Otherwise, the next request is sent to the next source.
+                record_failure(s);
+                if s.syncing_state.response_to_process.is_none() {
+                    s.block_sources.rotate();
+                }
                 return;
             }
         };
 
-        match response {
-            GetSuccessorsResponse::Complete(response) => {
+        match (response, s.syncing_state.response_to_process.take()) {
+            (GetSuccessorsResponse::Complete(response), None) => {
                 // Received complete response.
-                assert!(
-                    s.syncing_state.response_to_process.is_none(),
-                    "Received complete response before processing previous response."
-                );
+                if let Some((witness, witness_response)) = witness_response {
+                    if !cross_check(s, source, &response, witness, witness_response) {
+                        record_failure(s);
+                        return;
+                    }
+                }
                 s.syncing_state.response_to_process = Some(ResponseToProcess::Complete(response));
             }
-            GetSuccessorsResponse::Partial(partial_response) => {
+            (GetSuccessorsResponse::Partial(partial_response), None) => {
                 // Received partial response.
-                assert!(
-                    s.syncing_state.response_to_process.is_none(),
-                    "Received partial response before processing previous response."
-                );
                 s.syncing_state.response_to_process =
                     Some(ResponseToProcess::Partial(partial_response, 0));
             }
-            GetSuccessorsResponse::FollowUp(mut block_bytes) => {
-                // Received a follow-up response.
-                // A follow-up response is only expected, and only makes sense, when there's
-                // a partial response to process.
-
-                let (mut partial_response, mut follow_up_index) = match s.syncing_state.response_to_process.take() {
-                    Some(ResponseToProcess::Partial(res, pages)) => (res, pages),
-                    other => unreachable!("Cannot receive follow-up response without a previous partial response. Previous response found: {:?}", other)
-                };
-
+            (
+                GetSuccessorsResponse::FollowUp(mut block_bytes),
+                Some(ResponseToProcess::Partial(mut partial_response, mut follow_up_index)),
+            ) => {
+                // Received a follow-up response to the partial response.
                 // Append block to partial response and increment # pages processed.
                 partial_response.partial_block.append(&mut block_bytes);
                 follow_up_index += 1;
@@ -120,53 +224,370 @@ async fn maybe_fetch_blocks() -> bool {
                     },
                 );
             }
+            (response, previous_response) => {
+                // The response doesn't match the request that was sent, e.g. a follow-up
+                // response without a partial response to complete. Both responses are dropped
+                // and the blocks are requested again from scratch.
+                s.logs.error(
+                    "Unexpected response",
+                    &[
+                        ("source", &source.to_text()),
+                        ("response", &response),
+                        ("previous_response", &previous_response),
+                    ],
+                );
+                s.syncing_state.num_unexpected_responses += 1;
+                record_failure(s);
+                demote_source(s, source);
+                return;
+            }
         };
+
+        s.syncing_state.num_consecutive_failures = 0;
     });
 
     // A request to fetch new blocks has been made.
     true
 }
 
+// Records a failure to fetch blocks, delaying the next request with an exponential backoff.
+fn record_failure(state: &mut State) {
+    let syncing_state = &mut state.syncing_state;
+    syncing_state.num_consecutive_failures += 1;
+
+    let exponent = (syncing_state.num_consecutive_failures - 1).min(16);
+    let backoff_secs = (INITIAL_BACKOFF_SECS << exponent).min(MAX_BACKOFF_SECS);
+    syncing_state.backoff_until = time() + backoff_secs * 1_000_000_000;
+    state.logs.warn(
+        "Failed to fetch blocks",
+        &[
+            (
+                "consecutive_failures",
+                &syncing_state.num_consecutive_failures,
+            ),
+            ("backoff_secs", &backoff_secs),
+        ],
+    );
+
+    // Give up on a partial response whose follow-ups keep failing.
+    if syncing_state.num_consecutive_failures >= MAX_PARTIAL_RESPONSE_FAILURES {
+        if let Some(ResponseToProcess::Partial(..)) = syncing_state.response_to_process {
+            state
+                .logs
+                .warn("Dropping the partial response after too many failures", &[]);
+            syncing_state.response_to_process = None;
+        }
+    }
+}
+
+// Demotes a source that returned invalid data.
+fn demote_source(state: &mut State, source: Principal) {
+    if let Some(stats) = state.block_sources.stats_mut(source) {
+        stats.num_invalid_responses += 1;
+    }
+    state.block_sources.demote(source);
+}
+
+// Cross-checks a complete response with the response of a second source to the same request.
+// Returns false if the response is to be dropped.
+//
+// The responses conflict if, at a height where both contain blocks, they have no block in
+// common. The source whose blocks reach a lower height is then demoted. If both reach the same
+// height, neither can be trusted over the other, and the blocks are requested again from the
+// next source.
+fn cross_check(
+    state: &mut State,
+    source: Principal,
+    response: &GetSuccessorsCompleteResponse,
+    witness: Principal,
+    witness_response: CallResult<(GetSuccessorsResponse,)>,
+) -> bool {
+    if !state.block_sources.contains(witness) {
+        // The witness has been removed while it was called.
+        state.logs.info(
+            "Cannot cross-check with a removed source",
+            &[("witness", &witness.to_text())],
+        );
+        return true;
+    }
+
+    let witness_response = match witness_response {
+        Ok((GetSuccessorsResponse::Complete(witness_response),)) => witness_response,
+        Ok((other,)) => {
+            // Only complete responses can be compared.
+            state.logs.info(
+                "Cannot cross-check with a response that isn't complete",
+                &[("response", &other)],
+            );
+            return true;
+        }
+        Err((code, msg)) => {
+            // Cross-checking is best-effort. A failing witness doesn't prevent syncing.
+            if let Some(stats) = state.block_sources.stats_mut(witness) {
+                stats.num_rejects += 1;
+            }
+            state.logs.warn(
+                "Error cross-checking blocks",
+                &[
+                    ("source", &witness.to_text()),
+                    ("code", &code),
+                    ("message", &msg),
+                ],
+            );
+            return true;
+        }
+    };
+
+    let blocks = match block_hashes_by_height(state, &response.blocks) {
+        Some(blocks) => blocks,
+        // The response is invalid, which is handled when processing it.
+        None => return true,
+    };
+    let witness_blocks = match block_hashes_by_height(state, &witness_response.blocks) {
+        Some(witness_blocks) => witness_blocks,
+        None => {
+            demote_source(state, witness);
+            return true;
+        }
+    };
+
+    let conflict = blocks.iter().any(|(height, hashes)| {
+        witness_blocks
+            .get(height)
+            .map_or(false, |witness_hashes| witness_hashes.is_disjoint(hashes))
+    });
+    if !conflict {
+        return true;
+    }
+
+    state.logs.error(
+        "Conflicting blocks",
+        &[
+            ("source", &source.to_text()),
+            ("witness", &witness.to_text()),
+        ],
+    );
+    if let Some(stats) = state.block_sources.stats_mut(source) {
+        stats.num_conflicts += 1;
+    }
+    if let Some(stats) = state.block_sources.stats_mut(witness) {
+        stats.num_conflicts += 1;
+    }
+
+    match blocks.keys().max().cmp(&witness_blocks.keys().max()) {
+        Ordering::Less => {
+            state.block_sources.demote(source);
+            false
+        }
+        Ordering::Greater => {
+            state.block_sources.demote(witness);
+            true
+        }
+        Ordering::Equal => {
+            state.block_sources.rotate();
+            false
+        }
+    }
+}
+
+// Returns the hashes of the given blocks by height, or `None` if a block header cannot be
+// deserialized. Blocks that don't extend a known block are skipped.
+fn block_hashes_by_height(
+    state: &State,
+    blocks: &[BlockBlob],
+) -> Option<BTreeMap<Height, BTreeSet<BlockHash>>> {
+    let context = ValidationContext::new(state);
+    let mut heights: BTreeMap<BlockHash, Height> = BTreeMap::new();
+    let mut hashes_by_height: BTreeMap<Height, BTreeSet<BlockHash>> = BTreeMap::new();
+
+    for block_bytes in blocks {
+        // A serialized block starts with its header.
+        let header = BlockHeader::consensus_decode(block_bytes.as_slice()).ok()?;
+        let parent_height = heights
+            .get(&BlockHash::from(header.prev_blockhash))
+            .copied()
+            .or_else(|| {
+                context
+                    .get_header(&header.prev_blockhash)
+                    .map(|(_, height)| height)
+            });
+
+        if let Some(parent_height) = parent_height {
+            let block_hash = BlockHash::from(header.block_hash());
+            heights.insert(block_hash.clone(), parent_height + 1);
+            hashes_by_height
+                .entry(parent_height + 1)
+                .or_default()
+                .insert(block_hash);
+        }
+    }
+
+    Some(hashes_by_height)
+}
+
+// Ingests the stable blocks into the UTXO set.
+// Returns true if all the stable blocks are ingested, false if the ingestion of a block or a
+// rollback of the stable blocks is paused.
 fn ingest_stable_blocks_into_utxoset() -> bool {
-    with_state_mut(state::ingest_stable_blocks_into_utxoset)
+    with_state_mut(|s| {
+        state::ingest_stable_blocks_into_utxoset(s);
+        s.utxos.ingesting_block.is_none() && s.rollback.is_none()
+    })
 }
 
 // Process a `GetSuccessorsResponse` if one is available.
 fn maybe_process_response() {
     with_state_mut(|state| {
+        // The response is processed once the rollback in progress, if any, is complete.
+        if state.rollback.is_some() {
+            return;
+        }
+
+        // Finish caching the outpoints of the unstable blocks after leaving the fast sync mode.
+        if let Some(Slicing::Paused(())) =
+            unstable_blocks::set_fast_sync_continue(&mut state.unstable_blocks, &state.utxos)
+        {
+            return;
+        }
+
+        // Finish inserting the block that's partially inserted, if that exists.
+        match state::insert_block_continue(state) {
+            None | Some(Ok(Slicing::Done(_))) => {}
+            Some(Ok(Slicing::Paused(()))) => return,
+            Some(Err(err)) => {
+                state
+                    .logs
+                    .error("Failed to insert block", &[("error", &err)]);
+
+                // The remaining blocks of the response are dropped.
+                state.syncing_state.num_insert_block_errors += 1;
+                if let Some(ResponseToProcess::Complete(_)) =
+                    state.syncing_state.response_to_process
+                {
+                    state.syncing_state.response_to_process = None;
+                }
+                return;
+            }
+        }
+
         let response_to_process = state.syncing_state.response_to_process.take();
 
         match response_to_process {
             Some(ResponseToProcess::Complete(response)) => {
-                for block_bytes in response.blocks.iter() {
+                // The headers of the response are added to the header chain before its blocks
+                // are inserted, so that blocks of a chain with more work than the header chain
+                // aren't refused. The remaining blocks of the response are kept without the
+                // `next` headers, as they're already in the header chain.
+                insert_response_headers(state, &response);
+
+                for (i, block_bytes) in response.blocks.iter().enumerate() {
+                    // At least one block is inserted in every heartbeat to guarantee progress.
+                    let limits = state.heartbeat_limits;
+                    if i > 0
+                        && (i >= limits.max_blocks_per_heartbeat as usize
+                            || performance_counter() >= limits.insertion_instructions)
+                    {
+                        // Keep the remaining blocks to insert them in the next heartbeat.
+                        state.syncing_state.response_to_process =
+                            Some(ResponseToProcess::Complete(GetSuccessorsCompleteResponse {
+                                blocks: response.blocks[i..].to_vec(),
+                                next: vec![],
+                            }));
+                        return;
+                    }
+
                     // Deserialize the block.
                     let block = match BitcoinBlock::consensus_decode(block_bytes.as_slice()) {
                         Ok(block) => block,
                         Err(err) => {
-                            print(&format!(
-                                "ERROR: Cannot deserialize block. Err: {:?}, Block bytes: {:?}. Full Response: {:?}",
-                                err,
-                                block_bytes,
-                                response,
-                            ));
+                            // Only the beginning of the block is logged, as blocks can be
+                            // megabytes large.
+                            let logged_bytes = block_bytes.len().min(MAX_LOGGED_BLOCK_BYTES);
+                            state.logs.error(
+                                "Cannot deserialize block",
+                                &[
+                                    ("error", &err),
+                                    ("block_size", &block_bytes.len()),
+                                    ("block_bytes", &hex::encode(&block_bytes[..logged_bytes])),
+                                ],
+                            );
 
                             // Return, the remaining blocks in the response are dropped.
                             state.syncing_state.num_block_deserialize_errors += 1;
+                            let source = state.block_sources.current();
+                            demote_source(state, source);
                             return;
                         }
                     };
 
+                    // Refuse blocks whose headers are invalid. Blocks that don't extend any
+                    // known block are reported when inserting them.
+                    match validation::validate_header(state, &block.header) {
+                        Ok(()) | Err(ValidateHeaderError::PrevHeaderNotFound) => {}
+                        Err(err) => {
+                            state.logs.error(
+                                "Received an invalid block",
+                                &[
+                                    ("block_hash", &block.block_hash().to_string()),
+                                    ("error", &err),
+                                ],
+                            );
+
+                            // Return, the remaining blocks in the response are dropped.
+                            state.syncing_state.num_invalid_headers += 1;
+                            let source = state.block_sources.current();
+                            demote_source(state, source);
+                            return;
+                        }
+                    }
+
+                    let block_hash = block.block_hash();
+
+                    // Refuse blocks that compete with the validated header chain without having
+                    // more work. They're inserted once their chain has more work, as the header
+                    // chain then switches to it. See `insert_response_headers`.
+                    if validation::is_off_header_chain(state, &block.header) {
+                        state.logs.error(
+                            "Received a block off the header chain",
+                            &[("block_hash", &block_hash.to_string())],
+                        );
+
+                        // Return, the remaining blocks in the response are dropped.
+                        state.syncing_state.num_insert_block_errors += 1;
+                        return;
+                    }
+
                     if let Err(err) = state::insert_block(state, Block::new(block)) {
-                        print(&format!(
-                            "ERROR: Failed to insert block. Err: {:?}, Block bytes: {:?}",
-                            err, block_bytes,
-                        ));
+                        state.logs.error(
+                            "Failed to insert block",
+                            &[("block_hash", &block_hash.to_string()), ("error", &err)],
+                        );
 
                         // Return, the remaining blocks in the response are dropped.
                         state.syncing_state.num_insert_block_errors += 1;
                         return;
                     }
+
+                    if state.unstable_blocks.inserting_block().is_some() {
+                        // The block is too large to be inserted in one round. Keep the remaining
+                        // blocks of the response to insert them once the block is inserted.
+                        state.syncing_state.response_to_process =
+                            Some(ResponseToProcess::Complete(GetSuccessorsCompleteResponse {
+                                blocks: response.blocks[i + 1..].to_vec(),
+                                next: vec![],
+                            }));
+                        return;
+                    }
                 }
+
+                // If there are at least `stability_threshold` validated headers on top of the
+                // blocks in the state, the canister is far behind the tip and its blocks are
+                // already stable. The headers come from a single source, so this is only
+                // trusted if the operator enabled it.
+                let stability_threshold = state.unstable_blocks.stability_threshold().max(1);
+                let fast_sync = state.syncing_state.fast_sync == Flag::Enabled
+                    && state::blocks_behind(state) >= stability_threshold;
+                unstable_blocks::set_fast_sync(&mut state.unstable_blocks, &state.utxos, fast_sync);
             }
             other => {
                 // Not a complete response. Put it back into the state.
@@ -176,6 +597,76 @@ fn maybe_process_response() {
     });
 }
 
+// Validates the headers of the blocks in the response, followed by the headers of the blocks that
+// come after them, and adds them to the header chain. See `HeaderChain::insert`.
+fn insert_response_headers(state: &mut State, response: &GetSuccessorsCompleteResponse) {
+    // The header of a block is at the beginning of its serialization. Blocks that cannot be
+    // deserialized or whose headers are invalid are reported when inserting them, and the
+    // headers that come after them aren't added.
+    for block_bytes in response.blocks.iter() {
+        let header = match BlockHeader::consensus_decode(block_bytes.as_slice()) {
+            Ok(header) => header,
+            Err(_) => return,
+        };
+
+        if state
+            .header_chain
+            .get(&BlockHash::from(header.block_hash()))
+            .is_some()
+        {
+            continue;
+        }
+
+        if performance_counter() >= state.heartbeat_limits.validation_instructions
+            || state::insert_next_headers(state, &[header]).is_err()
+        {
+            return;
+        }
+    }
+
+    let mut next_headers = vec![];
+    for header_bytes in response.next.iter() {
+        match BlockHeader::consensus_decode(header_bytes.as_slice()) {
+            Ok(header) => next_headers.push(header),
+            Err(err) => {
+                state.logs.error(
+                    "Cannot deserialize block header",
+                    &[
+                        ("error", &err),
+                        ("header_bytes", &hex::encode(header_bytes)),
+                    ],
+                );
+                state.syncing_state.num_invalid_headers += 1;
+                break;
+            }
+        }
+    }
+
+    for header in next_headers {
+        // The headers left once the validation budget is used up are dropped, as they're also
+        // part of subsequent responses.
+        if performance_counter() >= state.heartbeat_limits.validation_instructions {
+            state.logs.debug(
+                "Validation budget used up. Skipping the remaining headers",
+                &[],
+            );
+            break;
+        }
+
+        if let Err(err) = state::insert_next_headers(state, &[header]) {
+            state.logs.error(
+                "Received an invalid block header",
+                &[
+                    ("block_hash", &header.block_hash().to_string()),
+                    ("error", &err),
+                ],
+            );
+            state.syncing_state.num_invalid_headers += 1;
+            break;
+        }
+    }
+}
+
 // Retrieves a `GetSuccessorsRequest` to send to the adapter.
 fn maybe_get_successors_request() -> Option<GetSuccessorsRequest> {
     with_state(|state| match &state.syncing_state.response_to_process {
@@ -188,6 +679,11 @@ fn maybe_get_successors_request() -> Option<GetSuccessorsRequest> {
             assert!(partial_response.remaining_follow_ups >= *follow_up_index);
             Some(GetSuccessorsRequest::FollowUp(*follow_up_index))
         }
+        None if state.unstable_blocks.inserting_block().is_some() => {
+            // A block is partially inserted. Wait until it's inserted, so that it's included
+            // in the processed block hashes of the next request.
+            None
+        }
         None => {
             // No response is present. Send an initial request for new blocks.
             let mut processed_block_hashes: Vec<BlockHash> = state::get_unstable_blocks(state)
@@ -211,16 +707,18 @@ fn maybe_get_successors_request() -> Option<GetSuccessorsRequest> {
 mod test {
     use super::*;
     use crate::{
+        block_sources::BlockSourceStats,
         genesis_block, init,
         runtime::{self, GetSuccessorsReply},
         test_utils::{random_p2pkh_address, BlockBuilder, TransactionBuilder},
         types::{
-            Address, BlockBlob, Config, GetSuccessorsCompleteResponse,
-            GetSuccessorsPartialResponse, Network,
+            Address, BlockBlob, BlockHeaderBlob, Config, GetSuccessorsCompleteResponse,
+            GetSuccessorsPartialResponse, HeartbeatLimits, Network, SetConfigRequest,
         },
         utxo_set::IngestingBlock,
     };
-    use bitcoin::BlockHeader;
+    use bitcoin::consensus::Encodable;
+    use ic_cdk::api::call::RejectionCode;
 
     fn build_block(prev_header: &BlockHeader, address: Address, num_transactions: u128) -> Block {
         let mut block = BlockBuilder::with_prev_header(prev_header);
@@ -620,6 +1118,264 @@ mod test {
         );
     }
 
+    #[async_std::test]
+    async fn fast_syncs_when_far_behind_the_tip() {
+        let network = Network::Regtest;
+
+        init(Config {
+            stability_threshold: 2,
+            network,
+            fast_sync: Some(Flag::Enabled),
+            ..Default::default()
+        });
+
+        let address = random_p2pkh_address(network);
+        let block_1 = build_block(genesis_block(network).header(), address.clone(), 1);
+        let block_2 = BlockBuilder::with_prev_header(block_1.header()).build();
+        let block_3 = BlockBuilder::with_prev_header(block_2.header()).build();
+
+        let encode_block = |block: &Block| {
+            let mut block_bytes = vec![];
+            block.consensus_encode(&mut block_bytes).unwrap();
+            block_bytes
+        };
+        let encode_header = |block: &Block| {
+            let mut header_bytes = vec![];
+            block.header().consensus_encode(&mut header_bytes).unwrap();
+            BlockHeaderBlob::from(header_bytes)
+        };
+
+        // The headers of two more blocks are available, so block 1 is already stable.
+        runtime::set_successors_response(GetSuccessorsReply::Ok(GetSuccessorsResponse::Complete(
+            GetSuccessorsCompleteResponse {
+                blocks: vec![encode_block(&block_1)],
+                next: vec![encode_header(&block_2), encode_header(&block_3)],
+            },
+        )));
+
+        // Fetch blocks.
+        heartbeat().await;
+
+        // Process response.
+        heartbeat().await;
+        assert!(with_state(|s| s.unstable_blocks.is_fast_sync()));
+
+        // The genesis block is ingested right away, even though it has only one block on top.
+        heartbeat().await;
+        assert_eq!(with_state(|s| s.utxos.next_height()), 1);
+
+        // The rest of the blocks are near the tip.
+        runtime::set_successors_response(GetSuccessorsReply::Ok(GetSuccessorsResponse::Complete(
+            GetSuccessorsCompleteResponse {
+                blocks: vec![encode_block(&block_2), encode_block(&block_3)],
+                next: vec![],
+            },
+        )));
+
+        // Fetch blocks.
+        heartbeat().await;
+
+        // Process response.
+        heartbeat().await;
+        assert!(!with_state(|s| s.unstable_blocks.is_fast_sync()));
+        assert_eq!(with_state(state::main_chain_height), 3);
+
+        // Block 1 isn't stable anymore and its outpoints are cached again.
+        assert_eq!(with_state(|s| s.utxos.next_height()), 1);
+        assert_eq!(
+            crate::api::get_balance(crate::types::GetBalanceRequest {
+                address: address.to_string(),
+                min_confirmations: None
+            }),
+            1
+        );
+    }
+
+    #[async_std::test]
+    async fn does_not_fast_sync_unless_enabled() {
+        let network = Network::Regtest;
+
+        init(Config {
+            stability_threshold: 2,
+            network,
+            ..Default::default()
+        });
+
+        let block_1 = BlockBuilder::with_prev_header(genesis_block(network).header()).build();
+        let block_2 = BlockBuilder::with_prev_header(block_1.header()).build();
+        let block_3 = BlockBuilder::with_prev_header(block_2.header()).build();
+
+        let mut block_bytes = vec![];
+        block_1.consensus_encode(&mut block_bytes).unwrap();
+        let next = [&block_2, &block_3]
+            .iter()
+            .map(|block| {
+                let mut header_bytes = vec![];
+                block.header().consensus_encode(&mut header_bytes).unwrap();
+                BlockHeaderBlob::from(header_bytes)
+            })
+            .collect();
+
+        // The headers show that the canister is far behind the tip.
+        runtime::set_successors_response(GetSuccessorsReply::Ok(GetSuccessorsResponse::Complete(
+            GetSuccessorsCompleteResponse {
+                blocks: vec![block_bytes],
+                next,
+            },
+        )));
+
+        // Fetch blocks.
+        heartbeat().await;
+
+        // Process response.
+        heartbeat().await;
+        assert_eq!(with_state(state::blocks_behind), 2);
+        assert!(!with_state(|s| s.unstable_blocks.is_fast_sync()));
+
+        // The genesis block isn't stable yet.
+        heartbeat().await;
+        assert_eq!(with_state(|s| s.utxos.next_height()), 0);
+    }
+
+    #[async_std::test]
+    async fn tracks_next_headers_and_refuses_invalid_blocks() {
+        let network = Network::Regtest;
+        init(Config {
+            stability_threshold: 2,
+            network,
+            ..Default::default()
+        });
+
+        let block_1 = BlockBuilder::with_prev_header(genesis_block(network).header()).build();
+        let block_2 = BlockBuilder::with_prev_header(block_1.header()).build();
+
+        let mut block_bytes = vec![];
+        block_1.consensus_encode(&mut block_bytes).unwrap();
+        let mut header_bytes = vec![];
+        block_2
+            .header()
+            .consensus_encode(&mut header_bytes)
+            .unwrap();
+
+        runtime::set_successors_response(GetSuccessorsReply::Ok(GetSuccessorsResponse::Complete(
+            GetSuccessorsCompleteResponse {
+                blocks: vec![block_bytes.clone()],
+                next: vec![BlockHeaderBlob::from(header_bytes)],
+            },
+        )));
+
+        // Fetch blocks and process the response.
+        heartbeat().await;
+        heartbeat().await;
+
+        // The height of block 2 is known even though the block hasn't been received yet.
+        assert_eq!(with_state(state::main_chain_height), 1);
+        assert_eq!(with_state(state::best_header_height), 2);
+        assert_eq!(with_state(|s| s.syncing_state.num_invalid_headers), 0);
+
+        // Change the nonce of block 1 until its proof of work is invalid.
+        init(Config {
+            stability_threshold: 2,
+            network,
+            ..Default::default()
+        });
+        loop {
+            block_bytes[76] = block_bytes[76].wrapping_add(1);
+            let header = BlockHeader::consensus_decode(&block_bytes[..80]).unwrap();
+            if header.validate_pow(&header.target()).is_err() {
+                break;
+            }
+        }
+
+        runtime::set_successors_response(GetSuccessorsReply::Ok(GetSuccessorsResponse::Complete(
+            GetSuccessorsCompleteResponse {
+                blocks: vec![block_bytes],
+                next: vec![],
+            },
+        )));
+        heartbeat().await;
+        heartbeat().await;
+
+        // The block is refused.
+        assert_eq!(with_state(state::main_chain_height), 0);
+        assert_eq!(with_state(|s| s.syncing_state.num_invalid_headers), 1);
+    }
+
+    #[async_std::test]
+    async fn refuses_blocks_off_the_header_chain() {
+        let network = Network::Regtest;
+        init(Config {
+            stability_threshold: 2,
+            network,
+            ..Default::default()
+        });
+
+        let block_1 = BlockBuilder::with_prev_header(genesis_block(network).header()).build();
+        let block_2 = BlockBuilder::with_prev_header(block_1.header()).build();
+        let fork_2 = build_block(block_1.header(), random_p2pkh_address(network), 1);
+        let fork_3 = BlockBuilder::with_prev_header(fork_2.header()).build();
+
+        let encode_block = |block: &Block| {
+            let mut block_bytes = vec![];
+            block.consensus_encode(&mut block_bytes).unwrap();
+            block_bytes
+        };
+        let encode_header = |block: &Block| {
+            let mut header_bytes = vec![];
+            block.header().consensus_encode(&mut header_bytes).unwrap();
+            BlockHeaderBlob::from(header_bytes)
+        };
+        let respond = |blocks: Vec<&Block>, next: Vec<&Block>| {
+            runtime::set_successors_response(GetSuccessorsReply::Ok(
+                GetSuccessorsResponse::Complete(GetSuccessorsCompleteResponse {
+                    blocks: blocks.into_iter().map(encode_block).collect(),
+                    next: next.into_iter().map(encode_header).collect(),
+                }),
+            ));
+        };
+
+        // Receive block 1 along with the header of block 2.
+        respond(vec![&block_1], vec![&block_2]);
+        heartbeat().await;
+        heartbeat().await;
+        assert_eq!(with_state(state::best_header_height), 2);
+
+        // A block at the height of block 2 that isn't block 2 doesn't have more work than the
+        // header chain, and so it's refused.
+        respond(vec![&fork_2], vec![]);
+        heartbeat().await;
+        heartbeat().await;
+        assert_eq!(with_state(state::main_chain_height), 1);
+        assert_eq!(with_state(|s| s.syncing_state.num_insert_block_errors), 1);
+
+        // The network reorgs to the chain of the fork, which now has more work than block 2.
+        respond(vec![&fork_2], vec![&fork_3]);
+        heartbeat().await;
+        heartbeat().await;
+        assert_eq!(with_state(state::main_chain_height), 2);
+        assert_eq!(with_state(state::best_header_height), 3);
+        assert_eq!(
+            with_state(|s| *unstable_blocks::get_main_chain(&s.unstable_blocks)
+                .tip()
+                .header()),
+            *fork_2.header()
+        );
+        assert_eq!(with_state(|s| s.syncing_state.num_insert_block_errors), 1);
+
+        // Block 2 is now off the header chain.
+        respond(vec![&block_2], vec![]);
+        heartbeat().await;
+        heartbeat().await;
+        assert_eq!(with_state(|s| s.syncing_state.num_insert_block_errors), 2);
+
+        // The rest of the fork is accepted.
+        respond(vec![&fork_3], vec![]);
+        heartbeat().await;
+        heartbeat().await;
+        assert_eq!(with_state(state::main_chain_height), 3);
+        assert_eq!(with_state(|s| s.syncing_state.num_insert_block_errors), 2);
+    }
+
     #[async_std::test]
     async fn handles_block_deserialize_errors() {
         init(Config::default());
@@ -687,4 +1443,416 @@ mod test {
             assert_eq!(s.syncing_state.response_to_process, None);
         });
     }
+
+    fn reject() -> GetSuccessorsReply {
+        GetSuccessorsReply::Err(RejectionCode::SysTransient, String::from("Test error."))
+    }
+
+    fn encoded_block(network: Network) -> Vec<u8> {
+        let block = BlockBuilder::with_prev_header(genesis_block(network).header()).build();
+        let mut block_bytes = vec![];
+        block.consensus_encode(&mut block_bytes).unwrap();
+        block_bytes
+    }
+
+    #[async_std::test]
+    async fn backs_off_exponentially_after_rejects() {
+        let network = Network::Regtest;
+        init(Config {
+            stability_threshold: 0,
+            network,
+            ..Default::default()
+        });
+
+        runtime::set_successors_responses(vec![
+            reject(),
+            reject(),
+            GetSuccessorsReply::Ok(GetSuccessorsResponse::Complete(
+                GetSuccessorsCompleteResponse {
+                    blocks: vec![encoded_block(network)],
+                    next: vec![],
+                },
+            )),
+        ]);
+
+        // The first reject delays the next request by one second.
+        heartbeat().await;
+        with_state(|s| {
+            assert_eq!(s.syncing_state.num_get_successors_rejects, 1);
+            assert_eq!(s.syncing_state.num_consecutive_failures, 1);
+            assert_eq!(s.syncing_state.backoff_until, 1_000_000_000);
+        });
+        heartbeat().await;
+        assert_eq!(
+            with_state(|s| s.syncing_state.num_get_successors_rejects),
+            1
+        );
+
+        // The second reject doubles the delay.
+        runtime::set_time(1_000_000_000);
+        heartbeat().await;
+        with_state(|s| {
+            assert_eq!(s.syncing_state.num_get_successors_rejects, 2);
+            assert_eq!(s.syncing_state.num_consecutive_failures, 2);
+            assert_eq!(s.syncing_state.backoff_until, 3_000_000_000);
+        });
+        runtime::set_time(2_000_000_000);
+        heartbeat().await;
+        assert_eq!(
+            with_state(|s| s.syncing_state.num_get_successors_rejects),
+            2
+        );
+
+        // Once the delay is over, the blocks are fetched and the failures are reset.
+        runtime::set_time(3_000_000_000);
+        heartbeat().await;
+        assert_eq!(with_state(|s| s.syncing_state.num_consecutive_failures), 0);
+        heartbeat().await;
+        assert_eq!(with_state(state::main_chain_height), 1);
+    }
+
+    #[async_std::test]
+    async fn retries_follow_ups_and_drops_partial_responses_after_too_many_failures() {
+        let network = Network::Regtest;
+        init(Config {
+            stability_threshold: 10,
+            network,
+            ..Default::default()
+        });
+
+        let block_bytes = encoded_block(network);
+        let partial_response = GetSuccessorsReply::Ok(GetSuccessorsResponse::Partial(
+            GetSuccessorsPartialResponse {
+                partial_block: block_bytes[0..40].to_vec(),
+                next: vec![],
+                remaining_follow_ups: 1,
+            },
+        ));
+
+        // A failed follow-up is requested again and completes the partial response.
+        runtime::set_successors_responses(vec![
+            partial_response.clone(),
+            reject(),
+            GetSuccessorsReply::Ok(GetSuccessorsResponse::FollowUp(block_bytes[40..].to_vec())),
+        ]);
+        heartbeat().await;
+        heartbeat().await;
+        with_state(|s| {
+            assert!(matches!(
+                s.syncing_state.response_to_process,
+                Some(ResponseToProcess::Partial(_, 0))
+            ))
+        });
+        runtime::set_time(1_000_000_000);
+        heartbeat().await;
+        with_state(|s| {
+            assert!(matches!(
+                s.syncing_state.response_to_process,
+                Some(ResponseToProcess::Complete(_))
+            ))
+        });
+        heartbeat().await;
+        assert_eq!(with_state(state::main_chain_height), 1);
+
+        // A partial response whose follow-ups keep failing is eventually dropped.
+        runtime::set_successors_responses(vec![partial_response, reject(), reject(), reject()]);
+        heartbeat().await;
+        for i in 1..=MAX_PARTIAL_RESPONSE_FAILURES {
+            assert!(with_state(|s| s
+                .syncing_state
+                .response_to_process
+                .is_some()));
+            runtime::set_time(with_state(|s| s.syncing_state.backoff_until));
+            heartbeat().await;
+            assert_eq!(with_state(|s| s.syncing_state.num_consecutive_failures), i);
+        }
+        assert!(with_state(|s| s
+            .syncing_state
+            .response_to_process
+            .is_none()));
+    }
+
+    #[async_std::test]
+    async fn drops_unexpected_responses() {
+        let network = Network::Regtest;
+        init(Config {
+            stability_threshold: 0,
+            network,
+            ..Default::default()
+        });
+
+        let block_bytes = encoded_block(network);
+        runtime::set_successors_responses(vec![
+            // A follow-up response without a partial response.
+            GetSuccessorsReply::Ok(GetSuccessorsResponse::FollowUp(block_bytes.clone())),
+            // A complete response while a partial response is pending.
+            GetSuccessorsReply::Ok(GetSuccessorsResponse::Partial(
+                GetSuccessorsPartialResponse {
+                    partial_block: block_bytes[0..40].to_vec(),
+                    next: vec![],
+                    remaining_follow_ups: 1,
+                },
+            )),
+            GetSuccessorsReply::Ok(GetSuccessorsResponse::Complete(
+                GetSuccessorsCompleteResponse {
+                    blocks: vec![block_bytes],
+                    next: vec![],
+                },
+            )),
+        ]);
+
+        heartbeat().await;
+        with_state(|s| {
+            assert_eq!(s.syncing_state.num_unexpected_responses, 1);
+            assert_eq!(s.syncing_state.response_to_process, None);
+        });
+
+        runtime::set_time(with_state(|s| s.syncing_state.backoff_until));
+        heartbeat().await;
+        heartbeat().await;
+        with_state(|s| {
+            assert_eq!(s.syncing_state.num_unexpected_responses, 2);
+            assert_eq!(s.syncing_state.num_consecutive_failures, 1);
+            assert_eq!(s.syncing_state.response_to_process, None);
+        });
+    }
+
+    fn block_source_stats(principal: Principal) -> BlockSourceStats {
+        with_state(|s| {
+            let source = s
+                .block_sources
+                .iter()
+                .find(|source| source.principal == principal)
+                .unwrap();
+            source.stats.clone()
+        })
+    }
+
+    fn complete_response(blocks: &[&Block]) -> GetSuccessorsReply {
+        GetSuccessorsReply::Ok(GetSuccessorsResponse::Complete(
+            GetSuccessorsCompleteResponse {
+                blocks: blocks
+                    .iter()
+                    .map(|block| {
+                        let mut block_bytes = vec![];
+                        block.consensus_encode(&mut block_bytes).unwrap();
+                        block_bytes
+                    })
+                    .collect(),
+                next: vec![],
+            },
+        ))
+    }
+
+    #[async_std::test]
+    async fn rotates_block_sources_on_failure() {
+        let network = Network::Regtest;
+        let (primary, backup) = (Principal::from_slice(&[1]), Principal::from_slice(&[2]));
+        init(Config {
+            stability_threshold: 10,
+            network,
+            blocks_source: primary,
+            backup_blocks_sources: Some(vec![backup]),
+            ..Default::default()
+        });
+
+        let block = BlockBuilder::with_prev_header(genesis_block(network).header()).build();
+        runtime::set_successors_responses(vec![reject(), complete_response(&[&block])]);
+
+        // The primary source fails, and the blocks are fetched from the backup source.
+        heartbeat().await;
+        runtime::set_time(with_state(|s| s.syncing_state.backoff_until));
+        heartbeat().await;
+        heartbeat().await;
+
+        assert_eq!(runtime::get_successors_calls(), vec![primary, backup]);
+        assert_eq!(block_source_stats(primary).num_rejects, 1);
+        assert_eq!(block_source_stats(backup).num_requests, 1);
+        assert_eq!(with_state(state::main_chain_height), 1);
+
+        // A source that sends an unexpected response is demoted.
+        runtime::set_successors_responses(vec![GetSuccessorsReply::Ok(
+            GetSuccessorsResponse::FollowUp(vec![]),
+        )]);
+        heartbeat().await;
+        assert_eq!(runtime::get_successors_calls(), vec![backup]);
+        assert_eq!(block_source_stats(backup).num_invalid_responses, 1);
+        assert!(block_source_stats(backup).demoted);
+        assert_eq!(with_state(|s| s.block_sources.current()), primary);
+    }
+
+    #[async_std::test]
+    async fn cross_checks_block_sources() {
+        let network = Network::Regtest;
+        let (primary, backup) = (Principal::from_slice(&[1]), Principal::from_slice(&[2]));
+        init(Config {
+            stability_threshold: 10,
+            network,
+            blocks_source: primary,
+            backup_blocks_sources: Some(vec![backup]),
+            cross_check_blocks_sources: Some(Flag::Enabled),
+            ..Default::default()
+        });
+
+        let block_1 = BlockBuilder::with_prev_header(genesis_block(network).header()).build();
+        let block_2 = BlockBuilder::with_prev_header(block_1.header()).build();
+        let fork_1 = BlockBuilder::with_prev_header(genesis_block(network).header()).build();
+
+        // The sources conflict at height 1, and the primary source's blocks reach a greater
+        // height. The backup source is demoted and the response is kept.
+        runtime::set_successors_responses(vec![
+            complete_response(&[&block_1, &block_2]),
+            complete_response(&[&fork_1]),
+        ]);
+        heartbeat().await;
+        assert_eq!(runtime::get_successors_calls(), vec![primary, backup]);
+        assert_eq!(block_source_stats(primary).num_conflicts, 1);
+        assert_eq!(block_source_stats(backup).num_conflicts, 1);
+        assert!(!block_source_stats(primary).demoted);
+        assert!(block_source_stats(backup).demoted);
+        heartbeat().await;
+        assert_eq!(with_state(state::main_chain_height), 2);
+
+        // Responses that agree are accepted.
+        with_state_mut(|s| s.block_sources.stats_mut(backup).unwrap().demoted = false);
+        let block_3 = BlockBuilder::with_prev_header(block_2.header()).build();
+        runtime::set_successors_responses(vec![
+            complete_response(&[&block_3]),
+            complete_response(&[&block_3]),
+        ]);
+        heartbeat().await;
+        assert_eq!(runtime::get_successors_calls(), vec![primary, backup]);
+        assert_eq!(block_source_stats(primary).num_conflicts, 1);
+        heartbeat().await;
+        assert_eq!(with_state(state::main_chain_height), 3);
+    }
+
+    #[async_std::test]
+    async fn tolerates_block_sources_changing_during_a_fetch() {
+        let network = Network::Regtest;
+        let (primary, backup, other) = (
+            Principal::from_slice(&[1]),
+            Principal::from_slice(&[2]),
+            Principal::from_slice(&[3]),
+        );
+        init(Config {
+            stability_threshold: 10,
+            network,
+            blocks_source: primary,
+            backup_blocks_sources: Some(vec![backup]),
+            cross_check_blocks_sources: Some(Flag::Enabled),
+            ..Default::default()
+        });
+
+        let set_block_sources = |primary: Principal, backups: Vec<Principal>| {
+            crate::set_config(SetConfigRequest {
+                blocks_source: Some(primary),
+                backup_blocks_sources: Some(backups),
+                ..Default::default()
+            })
+            .unwrap()
+        };
+
+        let block_1 = BlockBuilder::with_prev_header(genesis_block(network).header()).build();
+        let fork_1 = BlockBuilder::with_prev_header(genesis_block(network).header()).build();
+
+        // The witness is removed while it's being called. Its conflicting response is ignored
+        // and the response of the primary source is kept.
+        runtime::set_successors_responses(vec![
+            complete_response(&[&block_1]),
+            complete_response(&[&fork_1]),
+        ]);
+        let mut num_calls = 0;
+        runtime::set_on_get_successors_call(move || {
+            num_calls += 1;
+            if num_calls == 2 {
+                set_block_sources(primary, vec![]);
+            }
+        });
+        heartbeat().await;
+        assert_eq!(runtime::get_successors_calls(), vec![primary, backup]);
+        assert!(!with_state(|s| s.syncing_state.is_fetching_blocks));
+        assert!(!block_source_stats(primary).demoted);
+        heartbeat().await;
+        assert_eq!(with_state(state::main_chain_height), 1);
+
+        // The primary source is replaced while it's being called. Its response is dropped, and
+        // the blocks are requested again from the new source.
+        let block_2 = BlockBuilder::with_prev_header(block_1.header()).build();
+        runtime::set_successors_responses(vec![complete_response(&[&block_2])]);
+        runtime::set_on_get_successors_call(move || set_block_sources(other, vec![]));
+        heartbeat().await;
+        assert_eq!(runtime::get_successors_calls(), vec![primary]);
+        assert!(!with_state(|s| s.syncing_state.is_fetching_blocks));
+        assert!(with_state(|s| s
+            .syncing_state
+            .response_to_process
+            .is_none()));
+
+        runtime::set_successors_responses(vec![complete_response(&[&block_2])]);
+        heartbeat().await;
+        heartbeat().await;
+        assert_eq!(runtime::get_successors_calls(), vec![other]);
+        assert_eq!(block_source_stats(other).num_requests, 1);
+        assert_eq!(with_state(state::main_chain_height), 2);
+    }
+
+    #[async_std::test]
+    async fn limits_the_number_of_blocks_per_heartbeat() {
+        let network = Network::Regtest;
+        init(Config {
+            stability_threshold: 10,
+            network,
+            heartbeat_limits: Some(HeartbeatLimits {
+                max_blocks_per_heartbeat: 2,
+                ..Default::default()
+            }),
+            ..Default::default()
+        });
+
+        let block_1 = BlockBuilder::with_prev_header(genesis_block(network).header()).build();
+        let block_2 = BlockBuilder::with_prev_header(block_1.header()).build();
+        let block_3 = BlockBuilder::with_prev_header(block_2.header()).build();
+        runtime::set_successors_response(complete_response(&[&block_1, &block_2, &block_3]));
+
+        // Fetch blocks.
+        heartbeat().await;
+
+        // The first two blocks are inserted, and the third one is kept for the next heartbeat.
+        heartbeat().await;
+        assert_eq!(with_state(state::main_chain_height), 2);
+        with_state(|s| match &s.syncing_state.response_to_process {
+            Some(ResponseToProcess::Complete(response)) => assert_eq!(response.blocks.len(), 1),
+            other => panic!("Unexpected response to process: {:?}", other),
+        });
+
+        heartbeat().await;
+        assert_eq!(with_state(state::main_chain_height), 3);
+        assert_eq!(runtime::get_successors_calls().len(), 1);
+    }
+
+    #[async_std::test]
+    async fn runs_several_phases_in_one_heartbeat() {
+        let network = Network::Regtest;
+        init(Config {
+            stability_threshold: 0,
+            network,
+            ..Default::default()
+        });
+
+        let block_1 = BlockBuilder::with_prev_header(genesis_block(network).header()).build();
+        let block_2 = BlockBuilder::with_prev_header(block_1.header()).build();
+        runtime::set_successors_response(complete_response(&[&block_1, &block_2]));
+
+        // Fetch blocks, and process the response.
+        heartbeat().await;
+        heartbeat().await;
+        assert_eq!(with_state(|s| s.utxos.next_height()), 0);
+
+        // The stable blocks are ingested within the budget, so new blocks are fetched in the
+        // same heartbeat.
+        heartbeat().await;
+        assert_eq!(with_state(|s| s.utxos.next_height()), 2);
+        assert_eq!(runtime::get_successors_calls().len(), 2);
+    }
 }
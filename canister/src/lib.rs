@@ -1,12 +1,20 @@
+mod access_control;
 mod address_utxoset;
 mod api;
 mod block_header_store;
+mod block_sources;
 mod blocktree;
+mod certification;
+mod config_history;
+mod header_chain;
 mod heartbeat;
+mod logs;
 mod memory;
 mod metrics;
+mod muhash;
 mod multi_iter;
 pub mod runtime;
+mod scheduler;
 pub mod state;
 #[cfg(test)]
 mod test_utils;
@@ -15,18 +23,27 @@ mod tests;
 pub mod types;
 mod unstable_blocks;
 mod utxo_set;
+mod validation;
 
 use crate::{
+    block_sources::BlockSources,
     runtime::{msg_cycles_accept, msg_cycles_available},
     state::State,
-    types::{Block, Config, HttpRequest, HttpResponse, Network, SetConfigRequest},
+    types::{
+        Block, Config, ConfigHistoryPage, Flag, GetBalanceQueryResponse, GetCertifiedTipResponse,
+        GetUtxosQueryResponse, HttpRequest, HttpResponse, LogLevel, Network, RequestError,
+        SetConfigRequest, StabilityMode,
+    },
+    utxo_set::MAX_UNDO_LOG_SIZE,
 };
+pub use api::get_sync_status;
+pub use api::get_utxo_set_hash;
 pub use api::send_transaction;
 pub use api::set_config;
 pub use heartbeat::heartbeat;
 use ic_btc_types::{
-    GetBalanceRequest, GetCurrentFeePercentilesRequest, GetUtxosRequest, GetUtxosResponse,
-    MillisatoshiPerByte, Satoshi,
+    GetBalanceError, GetBalanceRequest, GetCurrentFeePercentilesRequest, GetUtxosError,
+    GetUtxosRequest, GetUtxosResponse, MillisatoshiPerByte, Satoshi,
 };
 use ic_stable_structures::Memory;
 pub use memory::get_memory;
@@ -80,8 +97,40 @@ pub fn init(config: Config) {
         genesis_block(config.network),
     ));
 
-    with_state_mut(|s| s.blocks_source = config.blocks_source);
+    with_state_mut(|s| {
+        s.unstable_blocks
+            .set_stability_mode(config.stability_mode.unwrap_or(StabilityMode::BlockCount))
+    });
+    with_state_mut(|s| {
+        s.block_sources = BlockSources::new(
+            config.blocks_source,
+            config.backup_blocks_sources.unwrap_or_default(),
+        );
+        s.block_sources.cross_check = config.cross_check_blocks_sources.unwrap_or(Flag::Disabled);
+        s.syncing_state.fast_sync = config.fast_sync.unwrap_or(Flag::Disabled);
+    });
     with_state_mut(|s| s.fees = config.fees);
+    with_state_mut(|s| s.max_lag = config.max_lag.and_then(state::max_lag));
+    with_state_mut(|s| state::set_heartbeat_limits(s, config.heartbeat_limits.unwrap_or_default()));
+    with_state_mut(|s| {
+        s.access_control
+            .set_admins(config.admins.unwrap_or_default());
+        s.access_control
+            .set_controllers(config.controllers.unwrap_or_default());
+        s.access_control.set_controller(runtime::caller());
+    });
+    with_state_mut(|s| s.http_api = config.http_api.unwrap_or(Flag::Disabled));
+    with_state_mut(|s| s.logs.set_level(config.log_level.unwrap_or(LogLevel::Info)));
+    if let Some(max_rollback_depth) = config.max_rollback_depth {
+        assert!(
+            max_rollback_depth <= MAX_UNDO_LOG_SIZE,
+            "max rollback depth too large"
+        );
+        with_state_mut(|s| s.utxos.set_max_undo_log_size(max_rollback_depth));
+    }
+    with_state(certification::certify_tip);
+
+    scheduler::start();
 }
 
 pub fn get_current_fee_percentiles(
@@ -93,24 +142,98 @@ pub fn get_current_fee_percentiles(
 
 pub fn get_balance(request: GetBalanceRequest) -> Satoshi {
     verify_network(request.network.into());
+    verify_synced();
     api::get_balance(request.into())
 }
 
 pub fn get_utxos(request: GetUtxosRequest) -> GetUtxosResponse {
     verify_network(request.network.into());
+    verify_synced();
     api::get_utxos(request.into())
 }
 
+/// Same as `get_balance`, but returns an error instead of panicking if the request is invalid,
+/// is for another network, or if the canister isn't synced.
+///
+/// The canister rejects such requests rather than trapping, as trapping would roll back the
+/// error count in the metrics.
+pub fn try_get_balance(
+    request: GetBalanceRequest,
+) -> Result<Satoshi, RequestError<GetBalanceError>> {
+    check_chain_request(api::GET_BALANCE_ENDPOINT, request.network.into())?;
+    api::try_get_balance(request.into()).map_err(RequestError::InvalidRequest)
+}
+
+/// Same as `get_utxos`, but returns an error instead of panicking if the request is invalid,
+/// is for another network, or if the canister isn't synced. See `try_get_balance`.
+pub fn try_get_utxos(
+    request: GetUtxosRequest,
+) -> Result<GetUtxosResponse, RequestError<GetUtxosError>> {
+    check_chain_request(api::GET_UTXOS_ENDPOINT, request.network.into())?;
+    api::try_get_utxos(request.into()).map_err(RequestError::InvalidRequest)
+}
+
+/// Same as `get_balance`, but served as a query along with a certificate for the tip of the main
+/// chain. No cycles are charged, as queries cannot accept cycles.
+pub fn get_balance_query(request: GetBalanceRequest) -> GetBalanceQueryResponse {
+    verify_network(request.network.into());
+    verify_synced();
+    let balance = api::get_balance_query(request.into());
+    GetBalanceQueryResponse {
+        balance,
+        certified_tip: with_state(certification::certified_tip),
+        certificate: runtime::data_certificate().map(ByteBuf::from),
+    }
+}
+
+/// Same as `get_utxos`, but served as a query along with a certificate for the tip of the main
+/// chain. No cycles are charged, as queries cannot accept cycles.
+pub fn get_utxos_query(request: GetUtxosRequest) -> GetUtxosQueryResponse {
+    verify_network(request.network.into());
+    verify_synced();
+    let response = api::get_utxos_query(request.into());
+    GetUtxosQueryResponse {
+        response,
+        certified_tip: with_state(certification::certified_tip),
+        certificate: runtime::data_certificate().map(ByteBuf::from),
+    }
+}
+
+/// Returns the canister's view of the chain along with the certificate that commits to it.
+pub fn get_certified_tip() -> GetCertifiedTipResponse {
+    GetCertifiedTipResponse {
+        certified_tip: with_state(certification::certified_tip),
+        certificate: runtime::data_certificate().map(ByteBuf::from),
+    }
+}
+
 pub fn get_config() -> Config {
     with_state(|s| Config {
         stability_threshold: s.unstable_blocks.stability_threshold() as u128,
+        stability_mode: Some(s.unstable_blocks.stability_mode()),
         syncing: s.syncing_state.syncing,
-        blocks_source: s.blocks_source,
+        blocks_source: s.block_sources.primary(),
+        backup_blocks_sources: Some(s.block_sources.backups()),
+        cross_check_blocks_sources: Some(s.block_sources.cross_check),
+        fast_sync: Some(s.syncing_state.fast_sync),
         network: s.network(),
         fees: s.fees.clone(),
+        max_lag: s.max_lag,
+        best_header_height: Some(state::best_header_height(s)),
+        heartbeat_limits: Some(s.heartbeat_limits),
+        admins: Some(s.access_control.admins()),
+        controllers: Some(s.access_control.controllers()),
+        http_api: Some(s.http_api),
+        log_level: Some(s.logs.level()),
+        max_rollback_depth: Some(s.utxos.max_undo_log_size()),
     })
 }
 
+/// Returns the given page of the config history, the most recent changes first.
+pub fn get_config_history(page: u64) -> ConfigHistoryPage {
+    with_state(|s| s.config_history.page(page))
+}
+
 pub fn pre_upgrade() {
     // Serialize the state.
     let mut state_bytes = vec![];
@@ -138,19 +261,35 @@ pub fn post_upgrade() {
     memory.read(4, &mut state_bytes);
 
     // Deserialize and set the state.
-    let state = ciborium::de::from_reader(&*state_bytes).expect("failed to decode state");
+    let mut state: State =
+        ciborium::de::from_reader(&*state_bytes).expect("failed to decode state");
+
+    // The network of the unstable blocks isn't part of the state of previous versions.
+    let network = state.network();
+    state.unstable_blocks.set_network(network);
+
+    // Only controllers can upgrade the canister.
+    state.access_control.set_controller(runtime::caller());
+
     set_state(state);
+    with_state(certification::certify_tip);
+
+    scheduler::start();
 }
 
 pub fn http_request(req: HttpRequest) -> HttpResponse {
     let parts: Vec<&str> = req.url.split('?').collect();
     match parts[0] {
         "/metrics" => crate::api::get_metrics(),
-        _ => HttpResponse {
-            status_code: 404,
-            headers: vec![],
-            body: ByteBuf::from(String::from("Not found.")),
-        },
+        "/snapshot" => crate::api::get_snapshot(parts.get(1).copied()),
+        "/logs" => crate::api::get_logs(parts.get(1).copied()),
+        path => crate::api::serve_http_api(&req.method, path, parts.get(1).copied())
+            .or_else(|| crate::api::serve_esplora(&req.method, path))
+            .unwrap_or_else(|| HttpResponse {
+                status_code: 404,
+                headers: vec![],
+                body: ByteBuf::from(String::from("Not found.")),
+            }),
     }
 }
 
@@ -159,7 +298,24 @@ pub(crate) fn genesis_block(network: Network) -> Block {
     Block::new(bitcoin::blockdata::constants::genesis_block(network.into()))
 }
 
-pub(crate) fn charge_cycles(amount: u128) {
+pub(crate) fn charge_cycles(endpoint: &str, amount: u128) {
+    let amount = verify_cycles_available(amount);
+
+    assert_eq!(
+        msg_cycles_accept(amount),
+        amount,
+        "Accepting cycles must succeed"
+    );
+
+    with_state_mut(|s| {
+        s.metrics
+            .observe_cycles_charged(endpoint, u128::from(amount))
+    });
+}
+
+// Verifies that at least the given amount of cycles was received, without accepting any of them.
+// Cycles that aren't accepted are refunded to the caller.
+pub(crate) fn verify_cycles_available(amount: u128) -> u64 {
     let amount: u64 = amount.try_into().expect("amount must be u64");
 
     if msg_cycles_available() < amount {
@@ -170,11 +326,7 @@ pub(crate) fn charge_cycles(amount: u128) {
         );
     }
 
-    assert_eq!(
-        msg_cycles_accept(amount),
-        amount,
-        "Accepting cycles must succeed"
-    );
+    amount
 }
 
 // Verifies that the network is equal to the one maintained by this canister's state.
@@ -186,10 +338,48 @@ fn verify_network(network: Network) {
     });
 }
 
+// Checks that a request served from the chain is for the canister's network and that the
+// canister isn't behind the network by more than the maximum lag.
+//
+// Failed checks are observed in the metrics of the given endpoint, as the request isn't
+// processed any further.
+fn check_chain_request<E: std::fmt::Debug>(
+    endpoint: &str,
+    network: Network,
+) -> Result<(), RequestError<E>> {
+    with_state_mut(|state| {
+        let result = if state.network() != network {
+            Err(RequestError::NetworkMismatch {
+                expected: state.network(),
+                found: network,
+            })
+        } else {
+            state::check_synced(state, runtime::time() / 1_000_000_000)
+                .map_err(RequestError::NotSynced)
+        };
+
+        if let Err(err) = &result {
+            state.metrics.observe_call(endpoint);
+            state.metrics.observe_error(endpoint, err);
+        }
+        result
+    })
+}
+
+// Verifies that the canister isn't behind the network by more than the maximum lag.
+fn verify_synced() {
+    with_state(|state| {
+        if let Err(err) = state::check_synced(state, runtime::time() / 1_000_000_000) {
+            panic!("NotSynced: {:?}", err);
+        }
+    });
+}
+
 #[cfg(test)]
 mod test {
     use super::*;
     use crate::{test_utils::build_regtest_chain, types::Network};
+    use ciborium::value::Value;
     use ic_btc_types::NetworkInRequest;
     use proptest::prelude::*;
 
@@ -209,11 +399,11 @@ mod test {
                 ..Default::default()
             });
 
-            with_state(|state| {
-                assert!(
-                    *state == State::new(stability_threshold as u32, network, genesis_block(network))
-                );
-            });
+            // The caller of `init` is the controller that installed the canister.
+            let mut expected_state =
+                State::new(stability_threshold as u32, network, genesis_block(network));
+            expected_state.access_control.set_controller(runtime::caller());
+            with_state(|state| assert!(*state == expected_state));
         }
     }
 
@@ -258,6 +448,187 @@ mod test {
         }
     }
 
+    // Returns the fields of a serialized struct.
+    fn fields(value: &mut Value) -> &mut Vec<(Value, Value)> {
+        match value {
+            Value::Map(fields) => fields,
+            _ => panic!("value must be a struct"),
+        }
+    }
+
+    fn is_named(key: &Value, name: &str) -> bool {
+        matches!(key, Value::Text(key) if key == name)
+    }
+
+    // Returns the value of the field with the given name in a serialized struct.
+    fn field<'a>(value: &'a mut Value, name: &str) -> &'a mut Value {
+        fields(value)
+            .iter_mut()
+            .find(|(key, _)| is_named(key, name))
+            .map(|(_, value)| value)
+            .unwrap_or_else(|| panic!("field {} must exist", name))
+    }
+
+    // Removes the fields with the given names from a serialized struct.
+    fn remove_fields(value: &mut Value, names: &[&str]) {
+        fields(value).retain(|(key, _)| !names.iter().any(|name| is_named(key, name)));
+    }
+
+    fn to_value<T: serde::Serialize>(value: &T) -> Value {
+        let mut bytes = vec![];
+        ciborium::ser::into_writer(value, &mut bytes).unwrap();
+        ciborium::de::from_reader(&*bytes).unwrap()
+    }
+
+    // Converts the given state into the representation of the first deployed version of the
+    // canister, i.e. without any of the fields that were added since.
+    //
+    // The metrics are replaced with instruction histograms, as they were represented then, that
+    // have a value in their 1B instructions bucket.
+    fn to_baseline_state(state: &State) -> Value {
+        let mut value = to_value(state);
+        remove_fields(
+            &mut value,
+            &[
+                "header_chain",
+                "max_lag",
+                "heartbeat_limits",
+                "access_control",
+                "config_history",
+                "http_api",
+                "logs",
+                "forks",
+                "rollback",
+            ],
+        );
+
+        // The block sources used to be a single principal.
+        let (key, block_sources) = fields(&mut value)
+            .iter_mut()
+            .find(|(key, _)| is_named(key, "block_sources"))
+            .unwrap();
+        *key = Value::Text("blocks_source".to_string());
+        *block_sources = to_value(&state.block_sources.primary());
+
+        remove_fields(field(&mut value, "fees"), &["usage_based"]);
+        remove_fields(
+            field(&mut value, "syncing_state"),
+            &[
+                "num_invalid_headers",
+                "num_unexpected_responses",
+                "num_consecutive_failures",
+                "backoff_until",
+                "fast_sync",
+            ],
+        );
+        remove_fields(
+            field(&mut value, "utxos"),
+            &[
+                "muhash",
+                "instructions_budget",
+                "undoing_block",
+                "undo_log",
+                "max_undo_log_size",
+                "last_ingestion_stats",
+            ],
+        );
+        remove_fields(
+            field(&mut value, "unstable_blocks"),
+            &[
+                "stability_mode",
+                "network",
+                "inserting_block",
+                "fast_sync",
+                "instructions_budget",
+                "stable_difficulty_bits",
+                "recaching",
+                "observed_chain",
+            ],
+        );
+
+        let instruction_histogram = |name: &str| {
+            let mut buckets = vec![Value::Integer(0.into()); 21];
+            buckets[1] = Value::Integer(1.into());
+            Value::Map(vec![
+                (Value::Text("name".into()), Value::Text(name.into())),
+                (Value::Text("buckets".into()), Value::Array(buckets)),
+                (Value::Text("sum".into()), Value::Float(1000.0)),
+                (Value::Text("help".into()), Value::Text(String::new())),
+            ])
+        };
+        *field(&mut value, "metrics") = Value::Map(
+            [
+                "get_utxos_total",
+                "get_utxos_apply_unstable_blocks",
+                "get_utxos_build_utxos_vec",
+                "get_balance_total",
+                "get_balance_apply_unstable_blocks",
+                "get_current_fee_percentiles_total",
+            ]
+            .iter()
+            .map(|name| (Value::Text(name.to_string()), instruction_histogram(name)))
+            .collect(),
+        );
+
+        value
+    }
+
+    #[test]
+    fn upgrade_from_baseline_state() {
+        let network = Network::Regtest;
+        init(Config {
+            stability_threshold: 2,
+            network,
+            ..Default::default()
+        });
+
+        for block in build_regtest_chain(10, 5)[1..].iter() {
+            with_state_mut(|s| {
+                crate::state::insert_block(s, block.clone()).unwrap();
+                crate::state::ingest_stable_blocks_into_utxoset(s);
+            });
+        }
+
+        // Write the state as it would have been serialized by the first deployed version.
+        let baseline_state = with_state(to_baseline_state);
+        let mut state_bytes = vec![];
+        ciborium::ser::into_writer(&baseline_state, &mut state_bytes).unwrap();
+        let memory = memory::get_upgrades_memory();
+        crate::memory::write(&memory, 0, &(state_bytes.len() as u32).to_le_bytes());
+        crate::memory::write(&memory, 4, &state_bytes);
+        STATE.with(|cell| cell.take().unwrap());
+
+        post_upgrade();
+
+        // The state is preserved, and the fields that didn't exist are set to their defaults.
+        with_state(|s| {
+            assert_eq!(to_baseline_state(s), baseline_state);
+            assert_eq!(
+                s.block_sources,
+                BlockSources::new(ic_cdk::export::Principal::management_canister(), vec![])
+            );
+            assert_eq!(s.heartbeat_limits, types::HeartbeatLimits::default());
+            assert_eq!(s.http_api, Flag::Disabled);
+            assert_eq!(s.max_lag, None);
+            assert_eq!(s.syncing_state.num_invalid_headers, 0);
+
+            // The hash of the UTXO set is unknown, as the coinbase UTXOs weren't tracked.
+            assert_eq!(s.utxos.muhash_hex(), None);
+
+            // The instruction histograms are migrated to the current buckets.
+            assert_eq!(s.metrics.get_utxos_total.sum, 1000.0);
+            assert_eq!(
+                s.metrics
+                    .get_utxos_total
+                    .buckets()
+                    .filter(|(_, count)| *count > 0.0)
+                    .collect::<Vec<_>>(),
+                vec![(1024.0, 1.0)]
+            );
+            assert_eq!(s.metrics.block_ingestion_total.sum, 0.0);
+        });
+    }
+
     #[test]
     #[should_panic(expected = "Network must be mainnet. Found testnet")]
     fn get_balance_correct_network() {
@@ -300,4 +671,136 @@ mod test {
             network: NetworkInRequest::Testnet,
         });
     }
+
+    #[test]
+    #[should_panic(expected = "NotSynced: TipTooOld")]
+    fn get_balance_refuses_to_respond_when_lagging() {
+        init(Config {
+            network: Network::Regtest,
+            max_lag: Some(types::MaxLag::Seconds(3600)),
+            ..Default::default()
+        });
+
+        // A day after the genesis block.
+        let genesis_time = genesis_block(Network::Regtest).header().time as u64;
+        runtime::set_time((genesis_time + 24 * 3600) * 1_000_000_000);
+
+        get_balance(GetBalanceRequest {
+            address: String::from(""),
+            network: NetworkInRequest::Regtest,
+            min_confirmations: None,
+        });
+    }
+
+    #[test]
+    fn try_get_utxos_returns_an_error_when_lagging_or_on_another_network() {
+        init(Config {
+            network: Network::Regtest,
+            max_lag: Some(types::MaxLag::Seconds(3600)),
+            ..Default::default()
+        });
+
+        let request = |network| GetUtxosRequest {
+            address: String::from(""),
+            network,
+            filter: None,
+        };
+
+        let err = try_get_utxos(request(NetworkInRequest::Testnet)).unwrap_err();
+        assert!(matches!(
+            err,
+            RequestError::NetworkMismatch {
+                expected: Network::Regtest,
+                found: Network::Testnet,
+            }
+        ));
+        assert_eq!(
+            err.to_string(),
+            "network_mismatch: the canister serves regtest, not testnet"
+        );
+
+        // A day after the genesis block.
+        let genesis_time = genesis_block(Network::Regtest).header().time as u64;
+        runtime::set_time((genesis_time + 24 * 3600) * 1_000_000_000);
+        let err = try_get_utxos(request(NetworkInRequest::Regtest)).unwrap_err();
+        assert!(matches!(
+            err,
+            RequestError::NotSynced(state::NotSynced::TipTooOld {
+                tip_age_secs: 86_400,
+                max: 3600,
+            })
+        ));
+        assert_eq!(
+            err.to_string(),
+            "not_synced: the canister's tip is 86400 seconds old, at most 3600 are allowed"
+        );
+
+        // Both errors are kept in the metrics.
+        with_state(|s| {
+            let endpoint = api::GET_UTXOS_ENDPOINT.to_string();
+            assert_eq!(s.metrics.num_calls.get(api::GET_UTXOS_ENDPOINT), Some(&2));
+            for error in ["NetworkMismatch", "NotSynced"] {
+                assert_eq!(
+                    s.metrics
+                        .num_errors
+                        .get(&(endpoint.clone(), error.to_string())),
+                    Some(&1)
+                );
+            }
+        });
+    }
+
+    #[test]
+    fn query_variants_return_the_same_data_along_with_the_certified_tip() {
+        let network = Network::Regtest;
+        init(Config {
+            stability_threshold: 1,
+            network,
+            ..Default::default()
+        });
+
+        let address = test_utils::random_p2pkh_address(network).to_string();
+        let get_utxos_request = || GetUtxosRequest {
+            address: address.clone(),
+            network: NetworkInRequest::Regtest,
+            filter: None,
+        };
+        let get_balance_request = || GetBalanceRequest {
+            address: address.clone(),
+            network: NetworkInRequest::Regtest,
+            min_confirmations: None,
+        };
+
+        let tip = types::CertifiedTip {
+            block_hash: genesis_block(network).block_hash().to_vec(),
+            height: 0,
+            stable_height: 0,
+            utxos_length: 0,
+        };
+        let certificate = Some(ByteBuf::from(certification::commitment(&tip).to_vec()));
+
+        assert_eq!(
+            get_utxos_query(get_utxos_request()),
+            GetUtxosQueryResponse {
+                response: get_utxos(get_utxos_request()),
+                certified_tip: tip.clone(),
+                certificate: certificate.clone(),
+            }
+        );
+        assert_eq!(
+            get_balance_query(get_balance_request()),
+            GetBalanceQueryResponse {
+                balance: get_balance(get_balance_request()),
+                certified_tip: tip.clone(),
+                certificate: certificate.clone(),
+            }
+        );
+        assert_eq!(
+            get_certified_tip(),
+            GetCertifiedTipResponse {
+                certified_tip: tip,
+                certificate,
+            }
+        );
+    }
 }
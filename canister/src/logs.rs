@@ -0,0 +1,243 @@
+use crate::{runtime, types::LogLevel};
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::VecDeque,
+    fmt::{self, Debug, Write},
+};
+
+/// The maximum number of entries kept in the logs. Older entries are dropped.
+const MAX_LOG_ENTRIES: usize = 1000;
+
+/// The maximum length in bytes of a message or a field value. Longer ones are truncated, so
+/// that logging large values, e.g. blocks, neither fills the logs nor costs many instructions.
+const MAX_VALUE_LEN: usize = 512;
+
+// Appended to the values that were truncated.
+const TRUNCATED_SUFFIX: &str = "...";
+
+/// An entry in the logs.
+#[derive(Serialize, Deserialize, PartialEq, Eq, Clone, Debug)]
+pub struct LogEntry {
+    /// The time of the entry, in nanoseconds since the epoch.
+    pub timestamp: u64,
+    pub level: LogLevel,
+    pub message: String,
+
+    /// The values attached to the entry, formatted with `Debug`.
+    pub fields: Vec<(String, String)>,
+}
+
+/// A bounded buffer of the most recent log entries.
+#[derive(Serialize, Deserialize, PartialEq, Eq, Debug)]
+pub struct Logs {
+    // The most recent entries, the oldest first.
+    entries: VecDeque<LogEntry>,
+
+    // The minimum level of the entries that are logged.
+    level: LogLevel,
+}
+
+impl Default for Logs {
+    fn default() -> Self {
+        Self {
+            entries: VecDeque::new(),
+            level: LogLevel::Info,
+        }
+    }
+}
+
+impl Logs {
+    pub fn level(&self) -> LogLevel {
+        self.level
+    }
+
+    pub fn set_level(&mut self, level: LogLevel) {
+        self.level = level;
+    }
+
+    /// Logs an entry with the given fields, unless its level is below the configured level.
+    /// The entry is also printed to the canister's debug output.
+    pub fn log(&mut self, level: LogLevel, message: &str, fields: &[(&str, &dyn Debug)]) {
+        if level < self.level {
+            return;
+        }
+
+        let entry = LogEntry {
+            timestamp: runtime::time(),
+            level,
+            message: truncate(message),
+            fields: fields
+                .iter()
+                .map(|(name, value)| (name.to_string(), format_truncated(value)))
+                .collect(),
+        };
+
+        runtime::print(&format!(
+            "[{:?}] {}{}",
+            entry.level,
+            entry.message,
+            entry
+                .fields
+                .iter()
+                .map(|(name, value)| format!(" {}={}", name, value))
+                .collect::<String>()
+        ));
+
+        if self.entries.len() == MAX_LOG_ENTRIES {
+            self.entries.pop_front();
+        }
+        self.entries.push_back(entry);
+    }
+
+    pub fn debug(&mut self, message: &str, fields: &[(&str, &dyn Debug)]) {
+        self.log(LogLevel::Debug, message, fields);
+    }
+
+    pub fn info(&mut self, message: &str, fields: &[(&str, &dyn Debug)]) {
+        self.log(LogLevel::Info, message, fields);
+    }
+
+    pub fn warn(&mut self, message: &str, fields: &[(&str, &dyn Debug)]) {
+        self.log(LogLevel::Warn, message, fields);
+    }
+
+    pub fn error(&mut self, message: &str, fields: &[(&str, &dyn Debug)]) {
+        self.log(LogLevel::Error, message, fields);
+    }
+
+    /// Returns the entries of at least the given level that were logged at or after the given
+    /// time, the oldest first.
+    pub fn entries(&self, level: LogLevel, since: u64) -> impl Iterator<Item = &LogEntry> {
+        self.entries
+            .iter()
+            .filter(move |entry| entry.level >= level && entry.timestamp >= since)
+    }
+}
+
+// Truncates a string to at most `MAX_VALUE_LEN` bytes, not counting the suffix.
+fn truncate(value: &str) -> String {
+    let mut writer = TruncatingWriter::default();
+    let _ = writer.write_str(value);
+    writer.into_string()
+}
+
+// Formats a value with `Debug`, stopping as soon as `MAX_VALUE_LEN` bytes are written.
+fn format_truncated(value: &dyn Debug) -> String {
+    let mut writer = TruncatingWriter::default();
+    // Writing fails once the writer is full, which stops the formatting early.
+    let _ = write!(writer, "{:?}", value);
+    writer.into_string()
+}
+
+// A writer that keeps at most `MAX_VALUE_LEN` bytes and fails once more are written.
+#[derive(Default)]
+struct TruncatingWriter {
+    buf: String,
+    truncated: bool,
+}
+
+impl TruncatingWriter {
+    fn into_string(mut self) -> String {
+        if self.truncated {
+            self.buf.push_str(TRUNCATED_SUFFIX);
+        }
+        self.buf
+    }
+}
+
+impl Write for TruncatingWriter {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        let remaining = MAX_VALUE_LEN - self.buf.len();
+        if s.len() <= remaining {
+            self.buf.push_str(s);
+            return Ok(());
+        }
+
+        // Cut the string at the last character boundary that fits.
+        let mut end = remaining;
+        while !s.is_char_boundary(end) {
+            end -= 1;
+        }
+        self.buf.push_str(&s[..end]);
+        self.truncated = true;
+        Err(fmt::Error)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::runtime::set_time;
+
+    #[test]
+    fn entries_below_the_level_are_dropped() {
+        let mut logs = Logs::default();
+        logs.debug("debug", &[]);
+        logs.info("info", &[]);
+        logs.error("error", &[]);
+
+        let messages = |logs: &Logs, level| {
+            logs.entries(level, 0)
+                .map(|entry| entry.message.clone())
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(messages(&logs, LogLevel::Debug), vec!["info", "error"]);
+        assert_eq!(messages(&logs, LogLevel::Warn), vec!["error"]);
+
+        logs.set_level(LogLevel::Debug);
+        logs.debug("debug", &[]);
+        assert_eq!(
+            messages(&logs, LogLevel::Debug),
+            vec!["info", "error", "debug"]
+        );
+    }
+
+    #[test]
+    fn entries_are_filtered_by_time() {
+        let mut logs = Logs::default();
+        for timestamp in 1..=3 {
+            set_time(timestamp);
+            logs.info("", &[]);
+        }
+
+        assert_eq!(
+            logs.entries(LogLevel::Debug, 2)
+                .map(|entry| entry.timestamp)
+                .collect::<Vec<_>>(),
+            vec![2, 3]
+        );
+    }
+
+    #[test]
+    fn drops_the_oldest_entries() {
+        let mut logs = Logs::default();
+        for i in 0..=MAX_LOG_ENTRIES {
+            logs.info(&i.to_string(), &[]);
+        }
+
+        assert_eq!(logs.entries.len(), MAX_LOG_ENTRIES);
+        assert_eq!(logs.entries.front().unwrap().message, "1");
+    }
+
+    #[test]
+    fn large_values_are_truncated() {
+        let mut logs = Logs::default();
+        let block_bytes = vec![0u8; 10_000];
+        logs.error(
+            &"é".repeat(MAX_VALUE_LEN),
+            &[("bytes", &block_bytes), ("height", &5)],
+        );
+
+        let entry = logs.entries.back().unwrap();
+        assert_eq!(
+            entry.message,
+            format!("{}{}", "é".repeat(MAX_VALUE_LEN / 2), TRUNCATED_SUFFIX)
+        );
+        assert_eq!(
+            entry.fields[0].1.len(),
+            MAX_VALUE_LEN + TRUNCATED_SUFFIX.len()
+        );
+        assert!(entry.fields[0].1.starts_with("[0, 0, "));
+        assert_eq!(entry.fields[1], (String::from("height"), String::from("5")));
+    }
+}
@@ -1,9 +1,14 @@
-use ic_btc_canister::types::{Config, HttpRequest, HttpResponse, SetConfigRequest};
+use ic_btc_canister::types::{
+    Config, ConfigHistoryPage, GetBalanceQueryResponse, GetCertifiedTipResponse,
+    GetUtxosQueryResponse, HttpRequest, HttpResponse, SetConfigError, SetConfigRequest, SyncStatus,
+    UtxoSetHash,
+};
 use ic_btc_types::{
     GetBalanceRequest, GetCurrentFeePercentilesRequest, GetUtxosRequest, GetUtxosResponse,
     MillisatoshiPerByte, Satoshi, SendTransactionRequest,
 };
-use ic_cdk_macros::{heartbeat, init, post_upgrade, pre_upgrade, query, update};
+use ic_cdk::api::call::ManualReply;
+use ic_cdk_macros::{init, post_upgrade, pre_upgrade, query, update};
 
 #[init]
 fn init(config: Config) {
@@ -20,19 +25,32 @@ fn post_upgrade() {
     ic_btc_canister::post_upgrade();
 }
 
-#[heartbeat]
-async fn heartbeat() {
-    ic_btc_canister::heartbeat().await
+// Invalid requests are rejected rather than trapping, which would roll back the error count in
+// the metrics. The reject messages are documented in `candid.did`.
+#[update(manual_reply = true)]
+pub fn bitcoin_get_balance(request: GetBalanceRequest) -> ManualReply<Satoshi> {
+    match ic_btc_canister::try_get_balance(request) {
+        Ok(balance) => ManualReply::one(balance),
+        Err(err) => ManualReply::reject(err.to_string()),
+    }
 }
 
-#[update]
-pub fn bitcoin_get_balance(request: GetBalanceRequest) -> Satoshi {
-    ic_btc_canister::get_balance(request)
+#[update(manual_reply = true)]
+pub fn bitcoin_get_utxos(request: GetUtxosRequest) -> ManualReply<GetUtxosResponse> {
+    match ic_btc_canister::try_get_utxos(request) {
+        Ok(response) => ManualReply::one(response),
+        Err(err) => ManualReply::reject(err.to_string()),
+    }
 }
 
-#[update]
-pub fn bitcoin_get_utxos(request: GetUtxosRequest) -> GetUtxosResponse {
-    ic_btc_canister::get_utxos(request)
+#[query]
+pub fn bitcoin_get_balance_query(request: GetBalanceRequest) -> GetBalanceQueryResponse {
+    ic_btc_canister::get_balance_query(request)
+}
+
+#[query]
+pub fn bitcoin_get_utxos_query(request: GetUtxosRequest) -> GetUtxosQueryResponse {
+    ic_btc_canister::get_utxos_query(request)
 }
 
 #[update]
@@ -47,16 +65,36 @@ pub fn bitcoin_get_current_fee_percentiles(
     ic_btc_canister::get_current_fee_percentiles(request)
 }
 
+#[query]
+pub fn get_certified_tip() -> GetCertifiedTipResponse {
+    ic_btc_canister::get_certified_tip()
+}
+
 #[query]
 pub fn get_config() -> Config {
     ic_btc_canister::get_config()
 }
 
 #[update]
-pub fn set_config(request: SetConfigRequest) {
+fn set_config(request: SetConfigRequest) -> Result<(), SetConfigError> {
     ic_btc_canister::set_config(request)
 }
 
+#[query]
+pub fn get_config_history(page: u64) -> ConfigHistoryPage {
+    ic_btc_canister::get_config_history(page)
+}
+
+#[query]
+pub fn get_utxo_set_hash() -> Option<UtxoSetHash> {
+    ic_btc_canister::get_utxo_set_hash()
+}
+
+#[query]
+pub fn get_sync_status() -> SyncStatus {
+    ic_btc_canister::get_sync_status()
+}
+
 #[query]
 pub fn http_request(request: HttpRequest) -> HttpResponse {
     ic_btc_canister::http_request(request)
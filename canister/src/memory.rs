@@ -13,6 +13,7 @@ const MEDIUM_UTXOS: MemoryId = MemoryId::new(3);
 const BALANCES: MemoryId = MemoryId::new(4);
 const BLOCK_HEADERS: MemoryId = MemoryId::new(5);
 const BLOCK_HEIGHTS: MemoryId = MemoryId::new(6);
+const COINBASE_UTXOS: MemoryId = MemoryId::new(7);
 
 pub type Memory = VirtualMemory<DefaultMemoryImpl>;
 
@@ -55,6 +56,10 @@ pub fn get_block_heights_memory() -> Memory {
     MEMORY_MANAGER.with(|m| m.get(BLOCK_HEIGHTS))
 }
 
+pub fn get_coinbase_utxos_memory() -> Memory {
+    MEMORY_MANAGER.with(|m| m.get(COINBASE_UTXOS))
+}
+
 /// Writes the bytes at the specified offset, growing the memory size if needed.
 pub fn write<M: MemoryTrait>(memory: &M, offset: u64, bytes: &[u8]) {
     let last_byte = offset
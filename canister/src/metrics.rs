@@ -1,107 +1,311 @@
 use serde::{Deserialize, Serialize};
+use std::{collections::BTreeMap, fmt::Debug};
 
 const M: u64 = 1_000_000;
-const BUCKET_SIZE: u64 = 500 * M;
-const NUM_BUCKETS: u64 = 21;
 
 /// Metrics for various endpoints.
+///
+/// Fields missing from the serialized metrics of previous versions are set to their defaults.
 #[derive(Serialize, Deserialize, PartialEq)]
+#[serde(default)]
 pub struct Metrics {
-    pub get_utxos_total: InstructionHistogram,
-    pub get_utxos_apply_unstable_blocks: InstructionHistogram,
-    pub get_utxos_build_utxos_vec: InstructionHistogram,
+    pub get_utxos_total: Histogram,
+    pub get_utxos_apply_unstable_blocks: Histogram,
+    pub get_utxos_build_utxos_vec: Histogram,
+    pub get_utxos_num_utxos: Histogram,
+
+    pub get_balance_total: Histogram,
+    pub get_balance_apply_unstable_blocks: Histogram,
+
+    pub get_current_fee_percentiles_total: Histogram,
+
+    pub block_ingestion_total: Histogram,
+    pub block_ingestion_rounds: Histogram,
+
+    /// The number of calls to each update endpoint. Calls that trap aren't counted, as their
+    /// changes to the state are rolled back, and neither are queries, for the same reason.
+    pub num_calls: BTreeMap<String, u64>,
+
+    /// The cycles charged by each endpoint.
+    pub cycles_charged: BTreeMap<String, u128>,
 
-    pub get_balance_total: InstructionHistogram,
-    pub get_balance_apply_unstable_blocks: InstructionHistogram,
+    /// The number of errors returned by each endpoint, by endpoint and error variant.
+    pub num_errors: BTreeMap<(String, String), u64>,
 
-    pub get_current_fee_percentiles_total: InstructionHistogram,
+    /// The number of times the tip of the main chain was replaced by a block that doesn't
+    /// descend from it.
+    pub num_reorgs: u64,
+
+    /// The number of blocks that were removed from the main chain by the deepest reorg.
+    pub max_reorg_depth: u64,
+}
+
+impl Metrics {
+    pub fn observe_call(&mut self, endpoint: &str) {
+        *self.num_calls.entry(endpoint.to_string()).or_default() += 1;
+    }
+
+    pub fn observe_cycles_charged(&mut self, endpoint: &str, amount: u128) {
+        let charged = self.cycles_charged.entry(endpoint.to_string()).or_default();
+        *charged = charged.saturating_add(amount);
+    }
+
+    /// Observes an error, which is counted by the name of its variant.
+    pub fn observe_error(&mut self, endpoint: &str, err: &impl Debug) {
+        let variant = format!("{:?}", err)
+            .split(|c: char| !c.is_alphanumeric() && c != '_')
+            .next()
+            .unwrap_or_default()
+            .to_string();
+        *self
+            .num_errors
+            .entry((endpoint.to_string(), variant))
+            .or_default() += 1;
+    }
+
+    /// Observes a reorg that removed the given number of blocks from the main chain.
+    pub fn observe_reorg(&mut self, depth: u64) {
+        self.num_reorgs += 1;
+        self.max_reorg_depth = std::cmp::max(self.max_reorg_depth, depth);
+    }
 }
 
 impl Default for Metrics {
     fn default() -> Self {
         Self {
-            get_utxos_total: InstructionHistogram::new(
+            get_utxos_total: Histogram::instructions(
                 "ins_get_utxos_total",
                 "Instructions needed to execute a get_utxos request.",
             ),
-            get_utxos_apply_unstable_blocks: InstructionHistogram::new(
+            get_utxos_apply_unstable_blocks: Histogram::instructions(
                 "ins_get_utxos_apply_unstable_blocks",
                 "Instructions needed to apply the unstable blocks in a get_utxos request.",
             ),
-            get_utxos_build_utxos_vec: InstructionHistogram::new(
+            get_utxos_build_utxos_vec: Histogram::instructions(
                 "inst_count_get_utxos_build_utxos_vec",
                 "Instructions needed to build the UTXOs vec in a get_utxos request.",
             ),
+            get_utxos_num_utxos: Histogram::new(
+                "get_utxos_num_utxos",
+                "The number of UTXOs returned in a get_utxos response.",
+                Buckets::Exponential {
+                    start: 1.0,
+                    factor: 4.0,
+                    count: 8,
+                },
+            ),
 
-            get_balance_total: InstructionHistogram::new(
+            get_balance_total: Histogram::instructions(
                 "ins_get_balance_total",
                 "Instructions needed to execute a get_balance request.",
             ),
-            get_balance_apply_unstable_blocks: InstructionHistogram::new(
+            get_balance_apply_unstable_blocks: Histogram::instructions(
                 "ins_get_balance_apply_unstable_blocks",
                 "Instructions needed to apply the unstable blocks in a get_utxos request.",
             ),
 
-            get_current_fee_percentiles_total: InstructionHistogram::new(
+            get_current_fee_percentiles_total: Histogram::instructions(
                 "ins_get_current_fee_percentiles_total",
                 "Instructions needed to execute a get_current_fee_percentiles request.",
             ),
+
+            block_ingestion_total: Histogram::instructions(
+                "ins_block_ingestion_total",
+                "Instructions needed to ingest a block into the UTXO set.",
+            ),
+            block_ingestion_rounds: Histogram::new(
+                "block_ingestion_rounds",
+                "The number of rounds needed to ingest a block into the UTXO set.",
+                Buckets::Linear {
+                    start: 1.0,
+                    width: 1.0,
+                    count: 10,
+                },
+            ),
+
+            num_calls: BTreeMap::new(),
+            cycles_charged: BTreeMap::new(),
+            num_errors: BTreeMap::new(),
+            num_reorgs: 0,
+            max_reorg_depth: 0,
         }
     }
 }
 
-/// A histogram for observing instruction counts.
-///
-/// The histogram observes the values in buckets of:
-///
-///  (500M, 1B, 1.5B, ..., 9B, 9.5B, 10B, +Inf)
-#[derive(Serialize, Deserialize, PartialEq)]
-pub struct InstructionHistogram {
+/// The buckets of a histogram, given by their upper bounds. A final bucket for values above
+/// the largest bound, `+Inf`, is always added.
+#[derive(Clone, Debug, PartialEq)]
+pub enum Buckets {
+    /// `count` buckets of equal `width`, the first one ending at `start`.
+    Linear {
+        start: f64,
+        width: f64,
+        count: usize,
+    },
+
+    /// `count` buckets, the first one ending at `start` and each one `factor` times wider
+    /// than the one before.
+    Exponential {
+        start: f64,
+        factor: f64,
+        count: usize,
+    },
+}
+
+impl Buckets {
+    /// Returns the upper bounds of the buckets, excluding `+Inf`.
+    pub fn bounds(&self) -> Vec<f64> {
+        match *self {
+            Self::Linear {
+                start,
+                width,
+                count,
+            } => (0..count).map(|i| start + width * i as f64).collect(),
+            Self::Exponential {
+                start,
+                factor,
+                count,
+            } => (0..count).map(|i| start * factor.powi(i as i32)).collect(),
+        }
+    }
+}
+
+// The buckets of instruction histograms, in millions of instructions: (1M, 2M, 4M, ..., 16B, +Inf).
+const INSTRUCTION_BUCKETS: Buckets = Buckets::Exponential {
+    start: 1.0,
+    factor: 2.0,
+    count: 15,
+};
+
+/// A histogram with configurable buckets.
+#[derive(Serialize, Deserialize, PartialEq, Debug)]
+#[serde(from = "HistogramRepr")]
+pub struct Histogram {
     pub name: String,
-    pub buckets: Vec<u64>,
-    pub sum: f64,
     pub help: String,
+
+    // The upper bounds of the buckets, in increasing order and excluding `+Inf`.
+    bounds: Vec<f64>,
+
+    // The number of values observed in each bucket, including `+Inf`. These counts are not
+    // cumulative.
+    counts: Vec<u64>,
+
+    pub sum: f64,
 }
 
-impl InstructionHistogram {
-    pub fn new<S: Into<String>>(name: S, help: S) -> Self {
+impl Histogram {
+    pub fn new<S: Into<String>>(name: S, help: S, buckets: Buckets) -> Self {
+        let bounds = buckets.bounds();
         Self {
             name: name.into(),
             help: help.into(),
+            counts: vec![0; bounds.len() + 1],
+            bounds,
             sum: 0.0,
-            buckets: vec![0; 21],
         }
     }
 
-    /// Observes an instruction count.
-    pub fn observe(&mut self, value: u64) {
-        let bucket_idx = Self::get_bucket(value);
+    /// Creates a histogram for observing instruction counts, in millions of instructions.
+    pub fn instructions<S: Into<String>>(name: S, help: S) -> Self {
+        Self::new(name, help, INSTRUCTION_BUCKETS)
+    }
 
-        // Divide value by 1M to keep the counts sane.
-        let value: f64 = value as f64 / M as f64;
+    /// Observes a value.
+    pub fn observe(&mut self, value: f64) {
+        let bucket_idx = self.bucket_idx(value);
+        self.counts[bucket_idx] += 1;
+        self.sum += value;
+    }
 
-        self.buckets[bucket_idx] += 1;
+    // Returns the index of the bucket that contains the given value.
+    fn bucket_idx(&self, value: f64) -> usize {
+        self.bounds
+            .iter()
+            .position(|bound| value <= *bound)
+            .unwrap_or(self.bounds.len())
+    }
 
-        self.sum += value;
+    /// Observes an instruction count.
+    pub fn observe_instructions(&mut self, instructions: u64) {
+        // Divide value by 1M to keep the counts sane.
+        self.observe(instructions as f64 / M as f64);
     }
 
-    /// Returns an iterator with the various buckets.
+    /// Returns an iterator with the upper bound of each bucket and the number of values in it.
     pub fn buckets(&self) -> impl Iterator<Item = (f64, f64)> + '_ {
-        (500..10_500)
-            .step_by((BUCKET_SIZE / M) as usize)
-            .map(|e| e as f64)
+        self.bounds
+            .iter()
+            .copied()
             .chain([f64::INFINITY])
-            .zip(self.buckets.iter().map(|e| *e as f64))
+            .zip(self.counts.iter().map(|e| *e as f64))
     }
+}
 
-    // Returns the index of the bucket where the value belongs.
-    fn get_bucket(value: u64) -> usize {
-        if value == 0 {
-            return 0;
-        }
+// The serialized representations of a histogram, which allow migrating the histograms of
+// previous versions of the canister.
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum HistogramRepr {
+    Current {
+        name: String,
+        help: String,
+        bounds: Vec<f64>,
+        counts: Vec<u64>,
+        sum: f64,
+    },
 
-        let idx = (value - 1) / BUCKET_SIZE;
-        std::cmp::min(idx, NUM_BUCKETS - 1) as usize
+    // An instruction histogram with fixed buckets of 500M instructions:
+    // (500M, 1B, 1.5B, ..., 10B, +Inf).
+    Instructions {
+        name: String,
+        help: String,
+        buckets: Vec<u64>,
+        sum: f64,
+    },
+}
+
+impl From<HistogramRepr> for Histogram {
+    fn from(repr: HistogramRepr) -> Self {
+        match repr {
+            HistogramRepr::Current {
+                name,
+                help,
+                bounds,
+                counts,
+                sum,
+            } => Self {
+                name,
+                help,
+                bounds,
+                counts,
+                sum,
+            },
+            HistogramRepr::Instructions {
+                name,
+                help,
+                buckets,
+                sum,
+            } => {
+                // The values within each of the old buckets are unknown, so they're moved into
+                // the new bucket that contains the upper bound of their old bucket.
+                let old_bounds = Buckets::Linear {
+                    start: 500.0,
+                    width: 500.0,
+                    count: buckets.len().saturating_sub(1),
+                }
+                .bounds()
+                .into_iter()
+                .chain([f64::INFINITY]);
+                let mut histogram = Self::instructions(name, help);
+                for (old_bound, count) in old_bounds.zip(buckets) {
+                    let bucket_idx = histogram.bucket_idx(old_bound);
+                    histogram.counts[bucket_idx] += count;
+                }
+                histogram.sum = sum;
+                histogram
+            }
+        }
     }
 }
 
@@ -109,32 +313,55 @@ impl InstructionHistogram {
 mod test {
     use super::*;
 
+    #[test]
+    fn errors_are_counted_by_variant() {
+        #[derive(Debug)]
+        enum Error {
+            Unit,
+            Struct { _given: u32 },
+            Tuple(u32),
+        }
+
+        let mut metrics = Metrics::default();
+        metrics.observe_error("a", &Error::Unit);
+        metrics.observe_error("a", &Error::Struct { _given: 1 });
+        metrics.observe_error("a", &Error::Struct { _given: 2 });
+        metrics.observe_error("b", &Error::Tuple(3));
+
+        let key = |endpoint: &str, variant: &str| (endpoint.to_string(), variant.to_string());
+        assert_eq!(
+            metrics.num_errors,
+            vec![
+                (key("a", "Struct"), 2),
+                (key("a", "Unit"), 1),
+                (key("b", "Tuple"), 1)
+            ]
+            .into_iter()
+            .collect()
+        );
+    }
+
     #[test]
     fn empty_buckets() {
-        let h = InstructionHistogram::new("", "");
+        let h = Histogram::instructions("", "");
         assert_eq!(
             h.buckets().collect::<Vec<_>>(),
             vec![
-                (500.0, 0.0),
-                (1000.0, 0.0),
-                (1500.0, 0.0),
-                (2000.0, 0.0),
-                (2500.0, 0.0),
-                (3000.0, 0.0),
-                (3500.0, 0.0),
-                (4000.0, 0.0),
-                (4500.0, 0.0),
-                (5000.0, 0.0),
-                (5500.0, 0.0),
-                (6000.0, 0.0),
-                (6500.0, 0.0),
-                (7000.0, 0.0),
-                (7500.0, 0.0),
-                (8000.0, 0.0),
-                (8500.0, 0.0),
-                (9000.0, 0.0),
-                (9500.0, 0.0),
-                (10000.0, 0.0),
+                (1.0, 0.0),
+                (2.0, 0.0),
+                (4.0, 0.0),
+                (8.0, 0.0),
+                (16.0, 0.0),
+                (32.0, 0.0),
+                (64.0, 0.0),
+                (128.0, 0.0),
+                (256.0, 0.0),
+                (512.0, 0.0),
+                (1024.0, 0.0),
+                (2048.0, 0.0),
+                (4096.0, 0.0),
+                (8192.0, 0.0),
+                (16384.0, 0.0),
                 (f64::INFINITY, 0.0),
             ]
         );
@@ -142,51 +369,127 @@ mod test {
     }
 
     #[test]
-    fn observing_values() {
-        let mut h = InstructionHistogram::new("", "");
-        h.observe(500 * M);
+    fn linear_buckets() {
+        let mut h = Histogram::new(
+            "",
+            "",
+            Buckets::Linear {
+                start: 10.0,
+                width: 5.0,
+                count: 3,
+            },
+        );
+        h.observe(10.0);
+        h.observe(10.5);
+        h.observe(21.0);
+        assert_eq!(
+            h.buckets().collect::<Vec<_>>(),
+            vec![(10.0, 1.0), (15.0, 1.0), (20.0, 0.0), (f64::INFINITY, 1.0)]
+        );
+        assert_eq!(h.sum, 41.5);
+    }
+
+    #[test]
+    fn observing_instructions() {
+        let mut h = Histogram::instructions("", "");
+        h.observe_instructions(M);
         assert_eq!(
             h.buckets().take(3).collect::<Vec<_>>(),
-            vec![(500.0, 1.0), (1000.0, 0.0), (1500.0, 0.0)]
+            vec![(1.0, 1.0), (2.0, 0.0), (4.0, 0.0)]
         );
-        assert_eq!(h.sum, 500_f64);
+        assert_eq!(h.sum, 1_f64);
 
-        h.observe(1);
+        h.observe_instructions(0);
         assert_eq!(
             h.buckets().take(3).collect::<Vec<_>>(),
-            vec![(500.0, 2.0), (1000.0, 0.0), (1500.0, 0.0)]
+            vec![(1.0, 2.0), (2.0, 0.0), (4.0, 0.0)]
         );
-        assert_eq!(h.sum, 500.000001);
+        assert_eq!(h.sum, 1_f64);
 
-        h.observe(500 * M + 1);
+        h.observe_instructions(3 * M / 2);
         assert_eq!(
             h.buckets().take(3).collect::<Vec<_>>(),
-            vec![(500.0, 2.0), (1000.0, 1.0), (1500.0, 0.0)]
+            vec![(1.0, 2.0), (2.0, 1.0), (4.0, 0.0)]
         );
-        assert_eq!(h.sum, 1000.000002);
+        assert_eq!(h.sum, 2.5);
 
-        h.observe(0);
+        h.observe_instructions(3 * M);
         assert_eq!(
             h.buckets().take(3).collect::<Vec<_>>(),
-            vec![(500.0, 3.0), (1000.0, 1.0), (1500.0, 0.0)]
+            vec![(1.0, 2.0), (2.0, 1.0), (4.0, 1.0)]
         );
-        assert_eq!(h.sum, 1000.000002);
+        assert_eq!(h.sum, 5.5);
     }
 
     #[test]
     fn infinity_bucket() {
-        let mut h = InstructionHistogram::new("", "");
-        h.observe(10_000 * M + 1);
+        let mut h = Histogram::instructions("", "");
+        h.observe_instructions(16_384 * M + 1);
         assert_eq!(
-            h.buckets().skip(20).collect::<Vec<_>>(),
+            h.buckets().skip(15).collect::<Vec<_>>(),
             vec![(f64::INFINITY, 1.0)]
         );
-        assert_eq!(h.sum, 10_000.000001);
+        assert_eq!(h.sum, 16_384.000001);
 
-        h.observe(u64::MAX);
+        h.observe_instructions(u64::MAX);
         assert_eq!(
-            h.buckets().skip(20).collect::<Vec<_>>(),
+            h.buckets().skip(15).collect::<Vec<_>>(),
             vec![(f64::INFINITY, 2.0)]
         );
     }
+
+    #[test]
+    fn serialization_roundtrip() {
+        let mut h = Histogram::instructions("name", "help");
+        h.observe_instructions(3 * M);
+        h.observe_instructions(u64::MAX);
+
+        let mut bytes = vec![];
+        ciborium::ser::into_writer(&h, &mut bytes).unwrap();
+        let deserialized: Histogram = ciborium::de::from_reader(&*bytes).unwrap();
+        assert_eq!(deserialized, h);
+    }
+
+    #[test]
+    fn migrates_instruction_histograms() {
+        #[derive(Serialize)]
+        struct OldInstructionHistogram {
+            name: String,
+            buckets: Vec<u64>,
+            sum: f64,
+            help: String,
+        }
+
+        // One value in the 500M bucket, billions in the 1B bucket and one in `+Inf`.
+        let mut buckets = vec![0; 21];
+        buckets[0] = 1;
+        buckets[1] = 2_000_000_000;
+        buckets[20] = 1;
+        let old = OldInstructionHistogram {
+            name: "name".to_string(),
+            buckets,
+            sum: 12_345.0,
+            help: "help".to_string(),
+        };
+
+        let mut bytes = vec![];
+        ciborium::ser::into_writer(&old, &mut bytes).unwrap();
+        let h: Histogram = ciborium::de::from_reader(&*bytes).unwrap();
+
+        assert_eq!(h.name, "name");
+        assert_eq!(h.help, "help");
+        assert_eq!(h.sum, 12_345.0);
+        assert_eq!(
+            h.buckets().skip(9).collect::<Vec<_>>(),
+            vec![
+                (512.0, 1.0),
+                (1024.0, 2_000_000_000.0),
+                (2048.0, 0.0),
+                (4096.0, 0.0),
+                (8192.0, 0.0),
+                (16384.0, 0.0),
+                (f64::INFINITY, 1.0),
+            ]
+        );
+    }
 }
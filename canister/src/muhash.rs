@@ -0,0 +1,332 @@
+//! An implementation of MuHash3072, a rolling hash over a set of elements.
+//!
+//! The implementation is compatible with the one in bitcoind, which uses it to
+//! compute the `muhash` of the UTXO set in `gettxoutsetinfo`. Elements can be
+//! added and removed in any order, and the resulting hash only depends on the
+//! final set of elements.
+//!
+//! See: https://github.com/bitcoin/bitcoin/blob/master/src/crypto/muhash.cpp
+use bitcoin::hashes::{sha256, Hash};
+use serde::{de::Deserializer, ser::Serializer, Deserialize, Serialize};
+use serde_bytes::ByteBuf;
+use std::convert::TryInto;
+
+// The number of 64-bit limbs in a `Num3072`.
+const LIMBS: usize = 48;
+
+// The size of a `Num3072` in bytes.
+const BYTE_SIZE: usize = LIMBS * 8;
+
+// The prime used for the group is `2^3072 - MAX_PRIME_DIFF`.
+const MAX_PRIME_DIFF: u64 = 1103717;
+
+/// A rolling hash over a set of elements.
+///
+/// Insertions and removals are accumulated in a numerator and a denominator
+/// respectively, so that both operations only require a single multiplication.
+/// The (expensive) division is deferred until the hash is finalized.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct MuHash3072 {
+    numerator: Num3072,
+    denominator: Num3072,
+}
+
+impl Default for MuHash3072 {
+    fn default() -> Self {
+        Self {
+            numerator: Num3072::one(),
+            denominator: Num3072::one(),
+        }
+    }
+}
+
+impl MuHash3072 {
+    /// Adds an element to the set.
+    pub fn insert(&mut self, element: &[u8]) {
+        self.numerator = self.numerator.mul(&Num3072::from_element(element));
+    }
+
+    /// Removes an element from the set.
+    pub fn remove(&mut self, element: &[u8]) {
+        self.denominator = self.denominator.mul(&Num3072::from_element(element));
+    }
+
+    /// Applies all the insertions and removals of `other` to this hash.
+    pub fn combine(&mut self, other: &MuHash3072) {
+        self.numerator = self.numerator.mul(&other.numerator);
+        self.denominator = self.denominator.mul(&other.denominator);
+    }
+
+    /// Returns the 32-byte hash of the set.
+    ///
+    /// The bytes are in the order they're computed in, which is the reverse of how bitcoind
+    /// displays them. Use `to_hex` to get bitcoind's representation.
+    ///
+    /// NOTE: Finalizing requires a modular inversion, which is expensive. Callers that need the
+    /// hash repeatedly should cache it.
+    pub fn finalize(&self) -> [u8; 32] {
+        let set = self.numerator.mul(&self.denominator.inverse());
+        sha256::Hash::hash(&set.to_bytes()).into_inner()
+    }
+}
+
+/// Returns a finalized hash as displayed by bitcoind (e.g. in `gettxoutsetinfo`).
+pub fn to_hex(digest: &[u8; 32]) -> String {
+    let mut bytes = *digest;
+    bytes.reverse();
+    hex::encode(bytes)
+}
+
+/// A number modulo the prime `2^3072 - MAX_PRIME_DIFF`, stored as little-endian limbs.
+#[derive(Clone, Debug, PartialEq, Eq)]
+struct Num3072 {
+    limbs: [u64; LIMBS],
+}
+
+impl Num3072 {
+    fn one() -> Self {
+        let mut limbs = [0; LIMBS];
+        limbs[0] = 1;
+        Self { limbs }
+    }
+
+    // Maps an element of the set to a number by expanding its SHA256 hash with ChaCha20.
+    fn from_element(element: &[u8]) -> Self {
+        let key = sha256::Hash::hash(element).into_inner();
+        Self::from_bytes(&chacha20_keystream(&key))
+    }
+
+    fn from_bytes(bytes: &[u8; BYTE_SIZE]) -> Self {
+        let mut limbs = [0; LIMBS];
+        for (i, limb) in limbs.iter_mut().enumerate() {
+            *limb = u64::from_le_bytes(bytes[i * 8..(i + 1) * 8].try_into().unwrap());
+        }
+        Self { limbs }
+    }
+
+    fn to_bytes(&self) -> Vec<u8> {
+        self.limbs.iter().flat_map(|l| l.to_le_bytes()).collect()
+    }
+
+    // Returns true if the number is >= the prime (but still < 2^3072).
+    fn is_overflow(&self) -> bool {
+        self.limbs[0] > u64::MAX - MAX_PRIME_DIFF && self.limbs[1..].iter().all(|l| *l == u64::MAX)
+    }
+
+    // Adds a small value to the limbs, returning the carry out of the top limb.
+    fn add_small(limbs: &mut [u64; LIMBS], value: u128) -> u128 {
+        let mut carry = value;
+        for limb in limbs.iter_mut() {
+            if carry == 0 {
+                break;
+            }
+            let cur = *limb as u128 + carry;
+            *limb = cur as u64;
+            carry = cur >> 64;
+        }
+        carry
+    }
+
+    // Multiplies two numbers modulo the prime.
+    fn mul(&self, other: &Num3072) -> Num3072 {
+        // Schoolbook multiplication into a 6144-bit product.
+        let mut product = [0u64; 2 * LIMBS];
+        for i in 0..LIMBS {
+            let mut carry: u128 = 0;
+            for j in 0..LIMBS {
+                let cur = product[i + j] as u128
+                    + (self.limbs[i] as u128) * (other.limbs[j] as u128)
+                    + carry;
+                product[i + j] = cur as u64;
+                carry = cur >> 64;
+            }
+            product[i + LIMBS] = carry as u64;
+        }
+
+        // Reduce using the identity 2^3072 = MAX_PRIME_DIFF (mod p).
+        let mut limbs = [0u64; LIMBS];
+        let mut carry: u128 = 0;
+        for i in 0..LIMBS {
+            let cur =
+                product[i] as u128 + (product[i + LIMBS] as u128) * MAX_PRIME_DIFF as u128 + carry;
+            limbs[i] = cur as u64;
+            carry = cur >> 64;
+        }
+
+        // Fold the remaining carry back in until there's nothing left to fold.
+        while carry > 0 {
+            carry = Self::add_small(&mut limbs, carry * MAX_PRIME_DIFF as u128);
+        }
+
+        let mut res = Num3072 { limbs };
+        if res.is_overflow() {
+            // Subtracting the prime is equivalent to adding MAX_PRIME_DIFF and
+            // dropping the bit at 2^3072.
+            Self::add_small(&mut res.limbs, MAX_PRIME_DIFF as u128);
+        }
+        res
+    }
+
+    // Computes the multiplicative inverse using Fermat's little theorem, i.e. `self^(p-2)`.
+    fn inverse(&self) -> Num3072 {
+        // The exponent p - 2 = 2^3072 - MAX_PRIME_DIFF - 2.
+        let mut exponent = [u64::MAX; LIMBS];
+        exponent[0] = u64::MAX - MAX_PRIME_DIFF - 1;
+
+        let mut res = Num3072::one();
+        for limb in exponent.iter().rev() {
+            for bit in (0..64).rev() {
+                res = res.mul(&res);
+                if (limb >> bit) & 1 == 1 {
+                    res = res.mul(self);
+                }
+            }
+        }
+        res
+    }
+}
+
+impl Serialize for Num3072 {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_bytes(&self.to_bytes())
+    }
+}
+
+impl<'de> Deserialize<'de> for Num3072 {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let bytes = ByteBuf::deserialize(deserializer)?;
+        let bytes: &[u8; BYTE_SIZE] = bytes
+            .as_slice()
+            .try_into()
+            .map_err(|_| serde::de::Error::invalid_length(bytes.len(), &"384 bytes"))?;
+        Ok(Self::from_bytes(bytes))
+    }
+}
+
+// Returns the first `BYTE_SIZE` bytes of the ChaCha20 keystream with the given key,
+// a zero nonce and a zero initial block counter.
+fn chacha20_keystream(key: &[u8; 32]) -> [u8; BYTE_SIZE] {
+    fn quarter_round(s: &mut [u32; 16], a: usize, b: usize, c: usize, d: usize) {
+        s[a] = s[a].wrapping_add(s[b]);
+        s[d] = (s[d] ^ s[a]).rotate_left(16);
+        s[c] = s[c].wrapping_add(s[d]);
+        s[b] = (s[b] ^ s[c]).rotate_left(12);
+        s[a] = s[a].wrapping_add(s[b]);
+        s[d] = (s[d] ^ s[a]).rotate_left(8);
+        s[c] = s[c].wrapping_add(s[d]);
+        s[b] = (s[b] ^ s[c]).rotate_left(7);
+    }
+
+    let mut initial_state = [0u32; 16];
+    initial_state[..4].copy_from_slice(&[0x61707865, 0x3320646e, 0x79622d32, 0x6b206574]);
+    for i in 0..8 {
+        initial_state[4 + i] = u32::from_le_bytes(key[i * 4..(i + 1) * 4].try_into().unwrap());
+    }
+
+    let mut keystream = [0; BYTE_SIZE];
+    for (counter, block) in keystream.chunks_mut(64).enumerate() {
+        let mut state = initial_state;
+        state[12] = counter as u32;
+        let mut working_state = state;
+        for _ in 0..10 {
+            quarter_round(&mut working_state, 0, 4, 8, 12);
+            quarter_round(&mut working_state, 1, 5, 9, 13);
+            quarter_round(&mut working_state, 2, 6, 10, 14);
+            quarter_round(&mut working_state, 3, 7, 11, 15);
+            quarter_round(&mut working_state, 0, 5, 10, 15);
+            quarter_round(&mut working_state, 1, 6, 11, 12);
+            quarter_round(&mut working_state, 2, 7, 8, 13);
+            quarter_round(&mut working_state, 3, 4, 9, 14);
+        }
+
+        for (i, word) in working_state.iter().enumerate() {
+            block[i * 4..(i + 1) * 4].copy_from_slice(&word.wrapping_add(state[i]).to_le_bytes());
+        }
+    }
+
+    keystream
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    // Mirrors `FromInt` in bitcoind's muhash tests: a 32-byte element whose first byte is `i`.
+    fn element(i: u8) -> [u8; 32] {
+        let mut e = [0; 32];
+        e[0] = i;
+        e
+    }
+
+    #[test]
+    fn empty_set() {
+        assert_eq!(
+            to_hex(&MuHash3072::default().finalize()),
+            "dd5ad2a105c2d29495f577245c357409002329b9f4d6182c0af3dc2f462555c8"
+        );
+    }
+
+    #[test]
+    fn matches_bitcoind_test_vector() {
+        let mut muhash = MuHash3072::default();
+        muhash.insert(&element(0));
+        muhash.insert(&element(1));
+        muhash.remove(&element(2));
+
+        assert_eq!(
+            to_hex(&muhash.finalize()),
+            "10d312b100cbd32ada024a6646e40d3482fcff103668d2625f10002a607d5863"
+        );
+    }
+
+    #[test]
+    fn order_independent() {
+        let mut a = MuHash3072::default();
+        a.insert(&element(1));
+        a.insert(&element(2));
+        a.insert(&element(3));
+        a.remove(&element(2));
+
+        let mut b = MuHash3072::default();
+        b.remove(&element(2));
+        b.insert(&element(3));
+        b.insert(&element(2));
+        b.insert(&element(1));
+
+        let mut c = MuHash3072::default();
+        c.insert(&element(3));
+        c.insert(&element(1));
+
+        assert_eq!(a.finalize(), b.finalize());
+        assert_eq!(a.finalize(), c.finalize());
+    }
+
+    #[test]
+    fn combine() {
+        let mut a = MuHash3072::default();
+        a.insert(&element(1));
+
+        let mut delta = MuHash3072::default();
+        delta.insert(&element(2));
+        delta.remove(&element(1));
+        a.combine(&delta);
+
+        let mut b = MuHash3072::default();
+        b.insert(&element(2));
+
+        assert_eq!(a.finalize(), b.finalize());
+    }
+
+    #[test]
+    fn serialize_deserialize() {
+        let mut muhash = MuHash3072::default();
+        muhash.insert(&element(7));
+        muhash.remove(&element(9));
+
+        let mut bytes = vec![];
+        ciborium::ser::into_writer(&muhash, &mut bytes).unwrap();
+        let new_muhash: MuHash3072 = ciborium::de::from_reader(&bytes[..]).unwrap();
+
+        assert_eq!(muhash, new_muhash);
+    }
+}
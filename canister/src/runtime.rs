@@ -9,6 +9,7 @@ use serde::Deserialize;
 #[cfg(not(target_arch = "wasm32"))]
 use std::cell::RefCell;
 use std::future::Future;
+use std::time::Duration;
 #[cfg(not(target_arch = "wasm32"))]
 const INSTRUCTIONS_LIMIT: u64 = 5_000_000_000;
 
@@ -41,11 +42,39 @@ thread_local! {
 
     static GET_SUCCESSORS_RESPONSES_INDEX: RefCell<usize> = RefCell::new(0);
 
+    static GET_SUCCESSORS_CALLS: RefCell<Vec<Principal>> = RefCell::new(Vec::default());
+
+    // The certified data at the time of every call to `call_get_successors`.
+    static GET_SUCCESSORS_CALLS_CERTIFIED_DATA: RefCell<Vec<Vec<u8>>> = RefCell::new(Vec::default());
+
     static PERFORMANCE_COUNTER: RefCell<u64> = RefCell::new(0);
 
     static PERFORMANCE_COUNTER_STEP: RefCell<u64> = RefCell::new(0);
 
     static CYCLES_BALANCE: RefCell<u64> = RefCell::new(0);
+
+    static CYCLES_AVAILABLE: RefCell<u64> = RefCell::new(u64::MAX);
+
+    static TIME: RefCell<u64> = RefCell::new(0);
+
+    // The mock caller. By default, the caller is the principal that installs the canister, and
+    // therefore its controller.
+    static CALLER: RefCell<Principal> = RefCell::new(Principal::anonymous());
+
+    static CERTIFIED_DATA: RefCell<Vec<u8>> = RefCell::new(Vec::default());
+
+    // Mock timers along with the (mock) time at which they're due.
+    static TIMERS: RefCell<Vec<(u64, Timer)>> = RefCell::new(Vec::default());
+
+    // A function run on every call to `call_get_successors`, simulating what happens while the
+    // call is in flight.
+    static ON_GET_SUCCESSORS_CALL: RefCell<Option<Box<dyn FnMut()>>> = RefCell::new(None);
+}
+
+#[cfg(not(target_arch = "wasm32"))]
+enum Timer {
+    Once(Box<dyn FnOnce()>),
+    Interval(Duration, Box<dyn FnMut()>),
 }
 
 #[cfg(target_arch = "wasm32")]
@@ -58,11 +87,18 @@ pub fn call_get_successors(
 
 #[cfg(not(target_arch = "wasm32"))]
 pub fn call_get_successors(
-    _id: Principal,
+    id: Principal,
     _request: GetSuccessorsRequest,
 ) -> impl Future<Output = CallResult<(GetSuccessorsResponse,)>> {
     use crate::types::GetSuccessorsCompleteResponse;
 
+    GET_SUCCESSORS_CALLS.with(|calls| calls.borrow_mut().push(id));
+    GET_SUCCESSORS_CALLS_CERTIFIED_DATA.with(|calls| {
+        calls
+            .borrow_mut()
+            .push(CERTIFIED_DATA.with(|d| d.borrow().clone()))
+    });
+
     let reply = GET_SUCCESSORS_RESPONSES.with(|responses| {
         // Get the response at the current index.
         GET_SUCCESSORS_RESPONSES_INDEX.with(|i| {
@@ -84,6 +120,12 @@ pub fn call_get_successors(
         })
     });
 
+    // The function is taken out while it runs, so that it can use the mocks itself.
+    if let Some(mut on_call) = ON_GET_SUCCESSORS_CALL.with(|f| f.borrow_mut().take()) {
+        on_call();
+        ON_GET_SUCCESSORS_CALL.with(|f| *f.borrow_mut() = Some(on_call));
+    }
+
     match reply {
         GetSuccessorsReply::Ok(response) => std::future::ready(Ok((response,))),
         GetSuccessorsReply::Err(code, msg) => std::future::ready(Err((code, msg))),
@@ -119,6 +161,30 @@ pub fn set_successors_response(response: GetSuccessorsReply) {
 pub fn set_successors_responses(responses: Vec<GetSuccessorsReply>) {
     GET_SUCCESSORS_RESPONSES.with(|e| e.replace(responses));
     GET_SUCCESSORS_RESPONSES_INDEX.with(|e| e.replace(0));
+    GET_SUCCESSORS_CALLS.with(|e| e.replace(vec![]));
+    GET_SUCCESSORS_CALLS_CERTIFIED_DATA.with(|e| e.replace(vec![]));
+    ON_GET_SUCCESSORS_CALL.with(|e| e.replace(None));
+}
+
+/// Sets a function to run on every call to `call_get_successors`, e.g. to change the state
+/// while the call is in flight. The function is reset when the (mock) responses are set.
+#[cfg(not(target_arch = "wasm32"))]
+pub fn set_on_get_successors_call(on_call: impl FnMut() + 'static) {
+    ON_GET_SUCCESSORS_CALL.with(|e| e.replace(Some(Box::new(on_call))));
+}
+
+/// Returns the principals that `call_get_successors` has been invoked with since the (mock)
+/// responses were last set.
+#[cfg(not(target_arch = "wasm32"))]
+pub fn get_successors_calls() -> Vec<Principal> {
+    GET_SUCCESSORS_CALLS.with(|e| e.borrow().clone())
+}
+
+/// Returns the certified data at the time of every call to `call_get_successors` since the
+/// (mock) responses were last set.
+#[cfg(not(target_arch = "wasm32"))]
+pub fn get_successors_calls_certified_data() -> Vec<Vec<u8>> {
+    GET_SUCCESSORS_CALLS_CERTIFIED_DATA.with(|e| e.borrow().clone())
 }
 
 /// In production this is equivalent to `performance_counter`.
@@ -165,6 +231,157 @@ pub fn set_performance_counter_step(step_size: u64) {
     PERFORMANCE_COUNTER_STEP.with(|pc| *pc.borrow_mut() = step_size)
 }
 
+/// Returns the current time in nanoseconds since the epoch.
+#[cfg(target_arch = "wasm32")]
+pub fn time() -> u64 {
+    ic_cdk::api::time()
+}
+
+/// Returns the current time in nanoseconds since the epoch.
+#[cfg(not(target_arch = "wasm32"))]
+pub fn time() -> u64 {
+    TIME.with(|t| *t.borrow())
+}
+
+#[cfg(test)]
+#[cfg(not(target_arch = "wasm32"))]
+pub fn set_time(time: u64) {
+    TIME.with(|t| *t.borrow_mut() = time)
+}
+
+/// Runs the given function once after the given delay.
+#[cfg(target_arch = "wasm32")]
+pub fn set_timer(delay: Duration, func: impl FnOnce() + 'static) {
+    ic_cdk::timer::set_timer(delay, func);
+}
+
+/// Registers a mock timer. See `run_timers`.
+#[cfg(not(target_arch = "wasm32"))]
+pub fn set_timer(delay: Duration, func: impl FnOnce() + 'static) {
+    let deadline = time() + delay.as_nanos() as u64;
+    TIMERS.with(|timers| {
+        timers
+            .borrow_mut()
+            .push((deadline, Timer::Once(Box::new(func))))
+    });
+}
+
+/// Runs the given function repeatedly, with the given interval between runs.
+#[cfg(target_arch = "wasm32")]
+pub fn set_timer_interval(interval: Duration, func: impl FnMut() + 'static) {
+    ic_cdk::timer::set_timer_interval(interval, func);
+}
+
+/// Registers a mock interval timer. See `run_timers`.
+#[cfg(not(target_arch = "wasm32"))]
+pub fn set_timer_interval(interval: Duration, func: impl FnMut() + 'static) {
+    let deadline = time() + interval.as_nanos() as u64;
+    TIMERS.with(|timers| {
+        timers
+            .borrow_mut()
+            .push((deadline, Timer::Interval(interval, Box::new(func))))
+    });
+}
+
+/// Runs the mock timers that are due at the current (mock) time, in the order of their
+/// deadlines. Timers that are registered in the meantime only run in a subsequent call, even if
+/// they're due already, so that every call is akin to one round of execution.
+#[cfg(not(target_arch = "wasm32"))]
+pub fn run_timers() {
+    let now = time();
+    let mut due_timers: Vec<(u64, Timer)> = TIMERS.with(|timers| {
+        let (due, pending) = std::mem::take(&mut *timers.borrow_mut())
+            .into_iter()
+            .partition(|(deadline, _)| *deadline <= now);
+        *timers.borrow_mut() = pending;
+        due
+    });
+    due_timers.sort_by_key(|(deadline, _)| *deadline);
+
+    for (deadline, timer) in due_timers {
+        match timer {
+            Timer::Once(func) => func(),
+            Timer::Interval(interval, mut func) => {
+                func();
+                let next_deadline = deadline + interval.as_nanos() as u64;
+                TIMERS.with(|timers| {
+                    timers
+                        .borrow_mut()
+                        .push((next_deadline, Timer::Interval(interval, func)))
+                });
+            }
+        }
+    }
+}
+
+/// Spawns a future that runs in the background.
+#[cfg(target_arch = "wasm32")]
+pub fn spawn(future: impl Future<Output = ()> + 'static) {
+    ic_cdk::spawn(future);
+}
+
+/// Runs the given future to completion. The mock calls complete right away, so the future is
+/// expected to complete the first time it's polled.
+#[cfg(not(target_arch = "wasm32"))]
+pub fn spawn(future: impl Future<Output = ()> + 'static) {
+    use std::task::{Context, Poll, RawWaker, RawWakerVTable, Waker};
+
+    fn noop_raw_waker() -> RawWaker {
+        fn clone(_: *const ()) -> RawWaker {
+            noop_raw_waker()
+        }
+        fn noop(_: *const ()) {}
+        static VTABLE: RawWakerVTable = RawWakerVTable::new(clone, noop, noop, noop);
+        RawWaker::new(std::ptr::null(), &VTABLE)
+    }
+
+    // SAFETY: The waker's functions do nothing, and so don't rely on the (null) data pointer.
+    let waker = unsafe { Waker::from_raw(noop_raw_waker()) };
+    let mut future = Box::pin(future);
+    match future.as_mut().poll(&mut Context::from_waker(&waker)) {
+        Poll::Ready(()) => {}
+        Poll::Pending => panic!("mock futures must complete right away"),
+    }
+}
+
+#[cfg(target_arch = "wasm32")]
+pub fn set_certified_data(data: &[u8]) {
+    ic_cdk::api::set_certified_data(data);
+}
+
+#[cfg(not(target_arch = "wasm32"))]
+pub fn set_certified_data(data: &[u8]) {
+    CERTIFIED_DATA.with(|d| *d.borrow_mut() = data.to_vec());
+}
+
+/// Returns the certificate of the canister's certified data. Only available in queries.
+#[cfg(target_arch = "wasm32")]
+pub fn data_certificate() -> Option<Vec<u8>> {
+    ic_cdk::api::data_certificate()
+}
+
+/// Returns a mock certificate, which is the certified data itself.
+#[cfg(not(target_arch = "wasm32"))]
+pub fn data_certificate() -> Option<Vec<u8>> {
+    Some(CERTIFIED_DATA.with(|d| d.borrow().clone()))
+}
+
+#[cfg(target_arch = "wasm32")]
+pub fn caller() -> Principal {
+    ic_cdk::api::caller()
+}
+
+#[cfg(not(target_arch = "wasm32"))]
+pub fn caller() -> Principal {
+    CALLER.with(|c| *c.borrow())
+}
+
+#[cfg(test)]
+#[cfg(not(target_arch = "wasm32"))]
+pub fn set_caller(caller: Principal) {
+    CALLER.with(|c| *c.borrow_mut() = caller)
+}
+
 #[cfg(target_arch = "wasm32")]
 pub fn msg_cycles_available() -> u64 {
     ic_cdk::api::call::msg_cycles_available()
@@ -172,7 +389,14 @@ pub fn msg_cycles_available() -> u64 {
 
 #[cfg(not(target_arch = "wasm32"))]
 pub fn msg_cycles_available() -> u64 {
-    u64::MAX
+    CYCLES_AVAILABLE.with(|c| *c.borrow())
+}
+
+/// Sets the (mock) number of cycles received with the current message.
+#[cfg(test)]
+#[cfg(not(target_arch = "wasm32"))]
+pub fn set_msg_cycles_available(cycles: u64) {
+    CYCLES_AVAILABLE.with(|c| *c.borrow_mut() = cycles)
 }
 
 #[cfg(target_arch = "wasm32")]
@@ -0,0 +1,266 @@
+use crate::{
+    heartbeat::heartbeat,
+    runtime::{self, time},
+    state::{self, State},
+    types::Flag,
+    unstable_blocks, with_state,
+};
+use std::{cell::Cell, time::Duration};
+
+/// The interval at which blocks are synced once there's no more work to do, i.e. once the
+/// canister has caught up with the tip of the network.
+const SYNC_INTERVAL_AT_TIP: Duration = Duration::from_secs(30);
+
+thread_local! {
+    // The time (in nanoseconds since the epoch) at which the next round of syncing is scheduled,
+    // if one is scheduled.
+    static NEXT_ROUND_AT: Cell<Option<u64>> = Cell::new(None);
+}
+
+/// Starts syncing blocks with timers.
+///
+/// Rounds of syncing run back to back while there's work to do, e.g. a response to process or
+/// blocks that the canister is known to be missing. Otherwise, a round runs every
+/// `SYNC_INTERVAL_AT_TIP`.
+///
+/// Timers aren't preserved across upgrades, so this needs to be called on every (re)install.
+pub fn start() {
+    NEXT_ROUND_AT.with(|n| n.set(None));
+    schedule_round(Duration::ZERO);
+    runtime::set_timer_interval(SYNC_INTERVAL_AT_TIP, watchdog);
+}
+
+// Runs a round of syncing, unless one is already scheduled to run soon.
+//
+// Besides syncing at the tip, the watchdog ensures syncing resumes if a round was interrupted
+// before scheduling the next one, e.g. because it trapped.
+fn watchdog() {
+    let overdue = NEXT_ROUND_AT.with(|n| match n.get() {
+        Some(next_round_at) => {
+            time() > next_round_at.saturating_add(SYNC_INTERVAL_AT_TIP.as_nanos() as u64)
+        }
+        None => true,
+    });
+
+    if overdue {
+        run_round();
+    }
+}
+
+fn run_round() {
+    NEXT_ROUND_AT.with(|n| n.set(None));
+    runtime::spawn(async {
+        heartbeat().await;
+        if let Some(delay) = with_state(|s| next_round_delay(s, time())) {
+            schedule_round(delay);
+        }
+    });
+}
+
+// Schedules a round after the given delay, unless one is already scheduled to run before then.
+fn schedule_round(delay: Duration) {
+    let deadline = time().saturating_add(delay.as_nanos() as u64);
+    let already_scheduled = NEXT_ROUND_AT.with(|n| match n.get() {
+        Some(next_round_at) if next_round_at <= deadline => true,
+        _ => {
+            n.set(Some(deadline));
+            false
+        }
+    });
+
+    if !already_scheduled {
+        runtime::set_timer(delay, run_round);
+    }
+}
+
+// Returns the delay before the next round of syncing, given the current time in nanoseconds, or
+// `None` if there's no work to do and the next round can wait for the watchdog.
+fn next_round_delay(state: &State, now: u64) -> Option<Duration> {
+    let syncing_state = &state.syncing_state;
+
+    if syncing_state.response_to_process.is_some()
+        || state.utxos.ingesting_block.is_some()
+        || state.rollback.is_some()
+        || state.unstable_blocks.inserting_block().is_some()
+        || state.unstable_blocks.is_caching_outpoints()
+        || unstable_blocks::peek(&state.unstable_blocks).is_some()
+    {
+        // There are blocks to process.
+        return Some(Duration::ZERO);
+    }
+
+    if syncing_state.syncing == Flag::Disabled || state::blocks_behind(state) == 0 {
+        // There are no blocks to fetch, as far as the canister knows.
+        return None;
+    }
+
+    // Catching up with the tip, but possibly backing off after a failure.
+    Some(Duration::from_nanos(
+        syncing_state.backoff_until.saturating_sub(now),
+    ))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::{
+        genesis_block, init,
+        runtime::GetSuccessorsReply,
+        test_utils::BlockBuilder,
+        types::{
+            Block, BlockHeaderBlob, Config, GetSuccessorsCompleteResponse, GetSuccessorsResponse,
+            Network,
+        },
+    };
+    use bitcoin::consensus::Encodable;
+    use ic_cdk::api::call::RejectionCode;
+
+    const SECOND: u64 = 1_000_000_000;
+
+    fn complete_response(blocks: &[&Block], next: &[&Block]) -> GetSuccessorsReply {
+        GetSuccessorsReply::Ok(GetSuccessorsResponse::Complete(
+            GetSuccessorsCompleteResponse {
+                blocks: blocks
+                    .iter()
+                    .map(|block| {
+                        let mut block_bytes = vec![];
+                        block.consensus_encode(&mut block_bytes).unwrap();
+                        block_bytes
+                    })
+                    .collect(),
+                next: next
+                    .iter()
+                    .map(|block| {
+                        let mut header_bytes = vec![];
+                        block.header().consensus_encode(&mut header_bytes).unwrap();
+                        BlockHeaderBlob::from(header_bytes)
+                    })
+                    .collect(),
+            },
+        ))
+    }
+
+    fn num_get_successors_calls() -> usize {
+        runtime::get_successors_calls().len()
+    }
+
+    #[test]
+    fn syncs_back_to_back_while_catching_up_and_slowly_at_the_tip() {
+        let network = Network::Regtest;
+        init(Config {
+            stability_threshold: 10,
+            network,
+            ..Default::default()
+        });
+
+        let block_1 = BlockBuilder::with_prev_header(genesis_block(network).header()).build();
+        let block_2 = BlockBuilder::with_prev_header(block_1.header()).build();
+
+        // The first response announces block 2, so the canister knows it's behind.
+        runtime::set_successors_responses(vec![
+            complete_response(&[&block_1], &[&block_2]),
+            complete_response(&[&block_2], &[]),
+        ]);
+        start();
+
+        // Fetch block 1.
+        runtime::run_timers();
+        assert_eq!(num_get_successors_calls(), 1);
+
+        // The response is processed right away.
+        runtime::run_timers();
+        assert_eq!(with_state(state::main_chain_height), 1);
+
+        // Block 2 is fetched right away, since the canister knows it's behind.
+        runtime::run_timers();
+        assert_eq!(num_get_successors_calls(), 2);
+        runtime::run_timers();
+        assert_eq!(with_state(state::main_chain_height), 2);
+
+        // At the tip, nothing runs until the watchdog fires.
+        runtime::run_timers();
+        runtime::set_time(SYNC_INTERVAL_AT_TIP.as_nanos() as u64 - 1);
+        runtime::run_timers();
+        assert_eq!(num_get_successors_calls(), 2);
+
+        runtime::set_time(SYNC_INTERVAL_AT_TIP.as_nanos() as u64);
+        runtime::run_timers();
+        assert_eq!(num_get_successors_calls(), 3);
+
+        // The (empty) response is processed right away, and then syncing is idle again.
+        runtime::run_timers();
+        runtime::run_timers();
+        assert_eq!(num_get_successors_calls(), 3);
+        assert_eq!(NEXT_ROUND_AT.with(|n| n.get()), None);
+    }
+
+    #[test]
+    fn waits_for_the_backoff_while_catching_up() {
+        let network = Network::Regtest;
+        init(Config {
+            stability_threshold: 10,
+            network,
+            ..Default::default()
+        });
+
+        let block_1 = BlockBuilder::with_prev_header(genesis_block(network).header()).build();
+        let block_2 = BlockBuilder::with_prev_header(block_1.header()).build();
+
+        runtime::set_successors_responses(vec![
+            complete_response(&[&block_1], &[&block_2]),
+            GetSuccessorsReply::Err(RejectionCode::SysTransient, String::from("Test error.")),
+            complete_response(&[&block_2], &[]),
+        ]);
+        start();
+
+        // Fetch and process block 1, then fetch block 2, which is rejected.
+        runtime::run_timers();
+        runtime::run_timers();
+        runtime::run_timers();
+        assert_eq!(num_get_successors_calls(), 2);
+
+        // The next round is scheduled once the backoff is over.
+        let backoff_until = with_state(|s| s.syncing_state.backoff_until);
+        assert_eq!(backoff_until, SECOND);
+        assert_eq!(NEXT_ROUND_AT.with(|n| n.get()), Some(backoff_until));
+
+        runtime::set_time(backoff_until - 1);
+        runtime::run_timers();
+        assert_eq!(num_get_successors_calls(), 2);
+
+        runtime::set_time(backoff_until);
+        runtime::run_timers();
+        assert_eq!(num_get_successors_calls(), 3);
+        runtime::run_timers();
+        assert_eq!(with_state(state::main_chain_height), 2);
+    }
+
+    #[test]
+    fn watchdog_resumes_interrupted_syncing() {
+        init(Config {
+            stability_threshold: 10,
+            network: Network::Regtest,
+            ..Default::default()
+        });
+        start();
+
+        // Fetch and process an empty response. There's nothing left to do afterwards.
+        runtime::run_timers();
+        runtime::run_timers();
+        assert_eq!(num_get_successors_calls(), 1);
+        assert_eq!(NEXT_ROUND_AT.with(|n| n.get()), None);
+
+        // Simulate a round that was scheduled, but was interrupted before running.
+        NEXT_ROUND_AT.with(|n| n.set(Some(SECOND)));
+
+        // The watchdog leaves the round some slack.
+        runtime::set_time(SYNC_INTERVAL_AT_TIP.as_nanos() as u64);
+        runtime::run_timers();
+        assert_eq!(num_get_successors_calls(), 1);
+
+        // The watchdog runs a round once the scheduled round is overdue.
+        runtime::set_time(2 * SYNC_INTERVAL_AT_TIP.as_nanos() as u64);
+        runtime::run_timers();
+        assert_eq!(num_get_successors_calls(), 2);
+    }
+}
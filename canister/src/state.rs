@@ -1,20 +1,33 @@
 use crate::{
+    access_control::AccessControl,
     address_utxoset::AddressUtxoSet,
     block_header_store::BlockHeaderStore,
-    blocktree::BlockDoesNotExtendTree,
+    block_sources::BlockSources,
+    blocktree::{self, BlockDoesNotExtendTree, BlockTree},
+    config_history::ConfigHistory,
+    header_chain::HeaderChain,
+    logs::Logs,
     metrics::Metrics,
+    runtime::performance_counter,
     types::{
         Address, Block, BlockHash, Fees, Flag, GetSuccessorsCompleteResponse,
-        GetSuccessorsPartialResponse, Network, Slicing,
+        GetSuccessorsPartialResponse, HeartbeatLimits, MaxLag, Network, Slicing,
     },
-    unstable_blocks::{self, UnstableBlocks},
+    unstable_blocks::{self, InsertBlockError, UnstableBlocks},
+    validation::{self, ValidationContext},
     UtxoSet,
 };
+use bitcoin::BlockHeader;
 use ic_btc_types::{Height, MillisatoshiPerByte};
+use ic_btc_validation::{HeaderStore, ValidateHeaderError};
 use ic_cdk::export::Principal;
 use serde::{Deserialize, Serialize};
+use std::collections::{BTreeSet, VecDeque};
 
 /// A structure used to maintain the entire state.
+///
+/// Fields that were added after the state was first deployed are set to their defaults when
+/// missing, so that the state serialized by previous versions can be decoded on upgrade.
 // NOTE: `PartialEq` is only available in tests as it would be impractically
 // expensive in production.
 #[derive(Serialize, Deserialize)]
@@ -29,9 +42,10 @@ pub struct State {
     /// State used for syncing new blocks.
     pub syncing_state: SyncingState,
 
-    /// The canister from which blocks are retrieved.
+    /// The canisters from which blocks are retrieved.
     /// Defaults to the management canister in production.
-    pub blocks_source: Principal,
+    #[serde(alias = "blocks_source")]
+    pub block_sources: BlockSources,
 
     /// Cache for the current fee percentiles.
     pub fee_percentiles_cache: Option<FeePercentilesCache>,
@@ -39,11 +53,48 @@ pub struct State {
     /// A store containing all the stable blocks' headers.
     pub stable_block_headers: BlockHeaderStore,
 
+    /// Validated headers of blocks that come after the blocks in the state.
+    #[serde(default)]
+    pub header_chain: HeaderChain,
+
     /// The fees to charge for each endpoint.
     pub fees: Fees,
 
+    /// The maximum lag behind the network. See `check_synced`.
+    #[serde(default)]
+    pub max_lag: Option<MaxLag>,
+
+    /// The limits on the work done in a single heartbeat. See `set_heartbeat_limits`.
+    #[serde(default)]
+    pub heartbeat_limits: HeartbeatLimits,
+
+    /// The admins allowed to change the config.
+    #[serde(default)]
+    pub access_control: AccessControl,
+
+    /// The recent changes made to the config.
+    #[serde(default)]
+    pub config_history: ConfigHistory,
+
+    /// Whether the read-only HTTP APIs are served by `http_request`.
+    #[serde(default = "http_api_disabled")]
+    pub http_api: Flag,
+
     /// Metrics for the various endpoints.
     pub metrics: Metrics,
+
+    /// The most recent log entries.
+    #[serde(default)]
+    pub logs: Logs,
+
+    /// Forks from stable blocks that can still be undone, which don't lead the main chain
+    /// enough for the stable blocks to be rolled back yet. See `insert_block`.
+    #[serde(default)]
+    pub forks: Vec<BlockTree>,
+
+    /// The rollback of the stable blocks that is in progress, if any.
+    #[serde(default)]
+    pub rollback: Option<Rollback>,
 }
 
 impl State {
@@ -51,7 +102,8 @@ impl State {
     ///
     /// The `stability_threshold` parameter specifies how many confirmations a
     /// block needs before it is considered stable. Stable blocks are assumed
-    /// to be final and are never removed.
+    /// to be final, although the latest few are rolled back if a fork that
+    /// forks from them leads the main chain by the stability threshold.
     pub fn new(stability_threshold: u32, network: Network, genesis_block: Block) -> Self {
         let utxos = UtxoSet::new(network);
         let unstable_blocks = UnstableBlocks::new(&utxos, stability_threshold, genesis_block);
@@ -60,11 +112,20 @@ impl State {
             utxos,
             unstable_blocks,
             syncing_state: SyncingState::default(),
-            blocks_source: Principal::management_canister(),
+            block_sources: BlockSources::new(Principal::management_canister(), vec![]),
             fee_percentiles_cache: None,
             stable_block_headers: BlockHeaderStore::init(),
+            header_chain: HeaderChain::default(),
             fees: Fees::default(),
+            max_lag: None,
+            heartbeat_limits: HeartbeatLimits::default(),
+            access_control: AccessControl::default(),
+            config_history: ConfigHistory::default(),
+            http_api: Flag::Disabled,
             metrics: Metrics::default(),
+            logs: Logs::default(),
+            forks: vec![],
+            rollback: None,
         }
     }
 
@@ -83,25 +144,332 @@ impl State {
     }
 }
 
+// The HTTP APIs are disabled in states serialized before they were introduced.
+fn http_api_disabled() -> Flag {
+    Flag::Disabled
+}
+
+// The fast sync mode is disabled in states serialized before it could be configured.
+fn fast_sync_disabled() -> Flag {
+    Flag::Disabled
+}
+
 /// Inserts a block into the state.
-/// Returns an error if the block doesn't extend any known block in the state.
-pub fn insert_block(state: &mut State, block: Block) -> Result<(), BlockDoesNotExtendTree> {
-    unstable_blocks::push(&mut state.unstable_blocks, &state.utxos, block)
+/// Returns an error if the block doesn't extend any known block in the state, or if the
+/// transaction outputs it spends cannot be found.
+///
+/// A block that extends a stable block that can still be undone is kept in a pending fork.
+/// Once the fork leads the main chain by the stability threshold, the stable blocks are rolled
+/// back so that the fork's parent becomes the anchor of the unstable blocks. Blocks that are
+/// inserted while a rollback is in progress are inserted once it's complete.
+///
+/// NOTE: The insertion is time-sliced. If the block is only partially inserted when this
+/// function returns, one or more calls to `insert_block_continue` are necessary to finish it.
+pub fn insert_block(state: &mut State, block: Block) -> Result<(), InsertBlockError> {
+    let res = push_block(state, block);
+    if state.rollback.is_none() {
+        observe_reorg(state);
+    }
+    res
+}
+
+fn push_block(state: &mut State, block: Block) -> Result<(), InsertBlockError> {
+    if let Some(rollback) = state.rollback.as_mut() {
+        rollback.blocks_to_insert.push_back(block);
+        return Ok(());
+    }
+
+    let block = match unstable_blocks::push(&mut state.unstable_blocks, &state.utxos, block) {
+        Err(InsertBlockError::BlockDoesNotExtendTree(block)) => block,
+        res => return res,
+    };
+
+    let fork_idx = add_to_fork(state, block).map_err(InsertBlockError::BlockDoesNotExtendTree)?;
+
+    // The fork competes with the stable blocks after its parent and with the unstable blocks.
+    let fork = &state.forks[fork_idx];
+    let parent_hash: BlockHash = fork.root.header().prev_blockhash.into();
+    let stable_blocks: Vec<&Block> = state
+        .utxos
+        .undoable_blocks()
+        .take_while(|block| block.block_hash() != parent_hash)
+        .collect();
+
+    if unstable_blocks::fork_leads(&state.unstable_blocks, fork, &stable_blocks) {
+        let num_stable_blocks = stable_blocks.len() + 1;
+        let fork = state.forks.swap_remove(fork_idx);
+        state.logs.warn(
+            "Rolling back the stable blocks to switch to a fork",
+            &[
+                ("anchor", &parent_hash.to_string()),
+                ("num_stable_blocks", &num_stable_blocks),
+            ],
+        );
+        start_rollback(
+            state,
+            parent_hash,
+            blocktree::blocks(&fork).into_iter().cloned().collect(),
+        );
+        rollback_continue(state);
+    }
+
+    Ok(())
+}
+
+// Adds the block to the pending fork that it extends, or starts a new fork if it extends a
+// stable block that can still be undone. Returns the index of the fork, or the block if it
+// extends neither.
+fn add_to_fork(state: &mut State, mut block: Block) -> Result<usize, Block> {
+    // Forks from blocks that can no longer be undone are dropped.
+    let undoable: BTreeSet<BlockHash> = state.utxos.undoable_block_hashes().collect();
+    state
+        .forks
+        .retain(|fork| undoable.contains(&BlockHash::from(fork.root.header().prev_blockhash)));
+
+    // Blocks that have already been ingested don't start a fork.
+    if undoable.contains(&block.block_hash()) {
+        return Err(block);
+    }
+
+    for (fork_idx, fork) in state.forks.iter_mut().enumerate() {
+        match blocktree::extend(fork, block) {
+            Ok(()) => return Ok(fork_idx),
+            Err(BlockDoesNotExtendTree(b)) => block = b,
+        }
+    }
+
+    if !undoable.contains(&BlockHash::from(block.header().prev_blockhash)) {
+        return Err(block);
+    }
+
+    state.forks.push(BlockTree::new(block));
+    Ok(state.forks.len() - 1)
+}
+
+/// Continues inserting a block that is partially inserted.
+/// Returns `None` if there's no such block, or if a rollback is in progress, in which case the
+/// block is inserted as part of the rollback. See `unstable_blocks::push_continue`.
+pub fn insert_block_continue(
+    state: &mut State,
+) -> Option<Result<Slicing<(), BlockHash>, InsertBlockError>> {
+    if state.rollback.is_some() {
+        return None;
+    }
+
+    let res = unstable_blocks::push_continue(&mut state.unstable_blocks, &state.utxos);
+    observe_reorg(state);
+    res
+}
+
+// Observes a reorg if the tip of the main chain no longer descends from the tip observed before.
+// See `unstable_blocks::observe_main_chain`.
+fn observe_reorg(state: &mut State) {
+    if let Some(depth) = unstable_blocks::observe_main_chain(&mut state.unstable_blocks) {
+        state.metrics.observe_reorg(depth as u64);
+    }
+}
+
+/// An error returned when the stable blocks cannot be rolled back.
+#[derive(Debug, PartialEq, Eq)]
+pub enum RollbackError {
+    /// Another rollback is in progress.
+    RollbackInProgress,
+
+    /// The block isn't among the stable blocks that can be undone.
+    BlockNotFound(BlockHash),
+}
+
+/// The state of a rollback of the stable blocks. Used for time slicing.
+#[derive(Serialize, Deserialize, PartialEq, Eq, Debug)]
+pub struct Rollback {
+    // The hash of the block that becomes the anchor of the unstable blocks.
+    anchor: BlockHash,
+
+    // Whether all the blocks up to the anchor are undone and the unstable blocks are rewound.
+    rewound: bool,
+
+    // The stable blocks undone so far, the latest first.
+    undone_blocks: Vec<Block>,
+
+    // The blocks to insert once the unstable blocks are rewound, where every block comes
+    // after its parent.
+    blocks_to_insert: VecDeque<Block>,
+}
+
+/// Rolls back the stable blocks such that the block with the given hash becomes the anchor of
+/// the unstable blocks, i.e. the UTXO set, the balances and the block headers are rewound
+/// to the state they had before that block was ingested. The rolled back blocks and the
+/// unstable blocks are then inserted again on top of it.
+///
+/// Only the latest few stable blocks can be rolled back. See `Config::max_rollback_depth`.
+///
+/// NOTE: The rollback is time-sliced. If it isn't complete when this function returns, it's
+/// continued by `ingest_stable_blocks_into_utxoset`, which doesn't ingest blocks until then.
+pub(crate) fn rollback_to(state: &mut State, block_hash: &BlockHash) -> Result<(), RollbackError> {
+    if state.rollback.is_some() {
+        return Err(RollbackError::RollbackInProgress);
+    }
+
+    if !state
+        .utxos
+        .undoable_block_hashes()
+        .any(|h| h == *block_hash)
+    {
+        return Err(RollbackError::BlockNotFound(block_hash.clone()));
+    }
+
+    start_rollback(state, block_hash.clone(), vec![]);
+    rollback_continue(state);
+    Ok(())
+}
+
+fn start_rollback(state: &mut State, anchor: BlockHash, blocks_to_insert: Vec<Block>) {
+    state.rollback = Some(Rollback {
+        anchor,
+        rewound: false,
+        undone_blocks: vec![],
+        blocks_to_insert: blocks_to_insert.into(),
+    });
+}
+
+/// Continues the rollback of the stable blocks that is in progress.
+/// Returns `None` if there's no such rollback. See `rollback_to`.
+pub fn rollback_continue(state: &mut State) -> Option<Slicing<(), ()>> {
+    let mut rollback = state.rollback.take()?;
+
+    if !rollback.rewound {
+        // Finish ingesting the block that's partially ingested, as it's rolled back too.
+        match state.utxos.ingest_block_continue() {
+            None => {}
+            Some(Slicing::Paused(())) => {
+                state.rollback = Some(rollback);
+                return Some(Slicing::Paused(()));
+            }
+            Some(Slicing::Done(ingested_block_hash)) => pop_block(state, ingested_block_hash),
+        }
+
+        // Undo the stable blocks, latest first, until the anchor is undone.
+        loop {
+            let res = match state
+                .utxos
+                .undo_block_continue()
+                .or_else(|| state.utxos.undo_block())
+            {
+                Some(res) => res,
+                // The anchor is no longer undoable, which is only the case if the block that
+                // was ingested above evicted it from the undo log. Roll back as far as possible.
+                None => break,
+            };
+
+            match res {
+                Slicing::Paused(()) => {
+                    state.rollback = Some(rollback);
+                    return Some(Slicing::Paused(()));
+                }
+                Slicing::Done(block) => {
+                    state.stable_block_headers.remove(state.utxos.next_height());
+                    let is_anchor = block.block_hash() == rollback.anchor;
+                    rollback.undone_blocks.push(block);
+                    if is_anchor {
+                        break;
+                    }
+                }
+            }
+        }
+
+        // The earliest undone block becomes the new anchor, followed by the other undone
+        // blocks and the blocks that were unstable.
+        let mut blocks_to_insert = VecDeque::new();
+        if let Some(anchor) = rollback.undone_blocks.pop() {
+            let undone_blocks: Vec<Block> = rollback.undone_blocks.drain(..).rev().collect();
+            let unstable_blocks = unstable_blocks::rewind(
+                &mut state.unstable_blocks,
+                &state.utxos,
+                anchor,
+                &undone_blocks,
+            );
+            blocks_to_insert.extend(undone_blocks);
+            blocks_to_insert.extend(unstable_blocks);
+        }
+        blocks_to_insert.append(&mut rollback.blocks_to_insert);
+
+        // Forks from blocks that are no longer stable can now be inserted too.
+        let undoable: BTreeSet<BlockHash> = state.utxos.undoable_block_hashes().collect();
+        let (forks, pending_forks): (Vec<_>, Vec<_>) = std::mem::take(&mut state.forks)
+            .into_iter()
+            .partition(|fork| {
+                !undoable.contains(&BlockHash::from(fork.root.header().prev_blockhash))
+            });
+        state.forks = pending_forks;
+        for fork in forks.iter() {
+            blocks_to_insert.extend(blocktree::blocks(fork).into_iter().cloned());
+        }
+
+        rollback.blocks_to_insert = blocks_to_insert;
+        rollback.rewound = true;
+    }
+
+    // Insert the blocks on top of the new anchor, stopping once the insertion budget is used up.
+    loop {
+        match unstable_blocks::push_continue(&mut state.unstable_blocks, &state.utxos) {
+            None | Some(Ok(Slicing::Done(_))) => {}
+            Some(Ok(Slicing::Paused(()))) => {
+                state.rollback = Some(rollback);
+                return Some(Slicing::Paused(()));
+            }
+            Some(Err(err)) => log_dropped_block(state, &err),
+        }
+
+        if rollback.blocks_to_insert.is_empty() {
+            break;
+        }
+
+        if performance_counter() >= state.heartbeat_limits.insertion_instructions {
+            state.rollback = Some(rollback);
+            return Some(Slicing::Paused(()));
+        }
+
+        let block = rollback
+            .blocks_to_insert
+            .pop_front()
+            .expect("a block to insert must exist");
+        if let Err(err) = unstable_blocks::push(&mut state.unstable_blocks, &state.utxos, block) {
+            log_dropped_block(state, &err);
+        }
+    }
+
+    // The cached fee percentiles may have been computed using blocks that are no longer
+    // in the main chain.
+    state.fee_percentiles_cache = None;
+    state.logs.warn(
+        "Rolled back the stable blocks",
+        &[
+            ("anchor", &rollback.anchor.to_string()),
+            ("stable_height", &state.utxos.next_height()),
+        ],
+    );
+    observe_reorg(state);
+
+    Some(Slicing::Done(()))
+}
+
+// Logs a block that cannot be inserted again after a rollback, which is then dropped.
+fn log_dropped_block(state: &mut State, err: &InsertBlockError) {
+    state
+        .logs
+        .warn("Dropped a block during the rollback", &[("error", err)]);
 }
 
 /// Pops any blocks in `UnstableBlocks` that are considered stable and ingests them to the UTXO set.
 ///
 /// NOTE: This method does a form of time-slicing to stay within the instruction limit, and
-/// multiple calls may be required for all the stable blocks to be ingested.
+/// multiple calls may be required for all the stable blocks to be ingested. No blocks are
+/// ingested while a rollback is in progress, which is continued first.
 ///
 /// Returns a bool indicating whether or not the state has changed.
 pub fn ingest_stable_blocks_into_utxoset(state: &mut State) -> bool {
-    fn pop_block(state: &mut State, ingested_block_hash: BlockHash) {
-        // Pop the stable block.
-        let popped_block = unstable_blocks::pop(&mut state.unstable_blocks);
-
-        // Sanity check that we just popped the same block that was ingested.
-        assert_eq!(popped_block.unwrap().block_hash(), ingested_block_hash);
+    if let Some(Slicing::Paused(())) = rollback_continue(state) {
+        return true;
     }
 
     let prev_state = (
@@ -135,11 +503,145 @@ pub fn ingest_stable_blocks_into_utxoset(state: &mut State) -> bool {
     has_state_changed(state)
 }
 
+// Pops the stable block that was just ingested from the unstable blocks.
+fn pop_block(state: &mut State, ingested_block_hash: BlockHash) {
+    // Pop the stable block.
+    let popped_block = unstable_blocks::pop(&mut state.unstable_blocks);
+
+    // Sanity check that we just popped the same block that was ingested.
+    assert_eq!(popped_block.unwrap().block_hash(), ingested_block_hash);
+
+    if let Some(stats) = state.utxos.last_ingestion_stats() {
+        state
+            .metrics
+            .block_ingestion_total
+            .observe_instructions(stats.ins_total);
+        state
+            .metrics
+            .block_ingestion_rounds
+            .observe(stats.num_rounds as f64);
+        state.logs.debug(
+            "Ingested block",
+            &[
+                ("height", &(state.utxos.next_height() - 1)),
+                ("stats", stats),
+            ],
+        );
+    }
+
+    // The headers of stable blocks are no longer needed in the header chain.
+    state.header_chain.remove_below(state.utxos.next_height());
+}
+
+/// Sets the limits on the work done in a single heartbeat, including the instruction budgets
+/// used to time-slice the ingestion and the insertion of blocks.
+pub fn set_heartbeat_limits(state: &mut State, heartbeat_limits: HeartbeatLimits) {
+    state
+        .utxos
+        .set_instructions_budget(heartbeat_limits.ingestion_instructions);
+    state
+        .unstable_blocks
+        .set_instructions_budget(heartbeat_limits.insertion_instructions);
+    state.heartbeat_limits = heartbeat_limits;
+}
+
 pub fn main_chain_height(state: &State) -> Height {
     unstable_blocks::get_main_chain(&state.unstable_blocks).len() as u32 + state.utxos.next_height()
         - 1
 }
 
+/// Returns the header of the block at the given height in the main chain, if any.
+pub fn header_at_height(state: &State, height: Height) -> Option<BlockHeader> {
+    let stable_height = state.utxos.next_height();
+    if height < stable_height {
+        return state.stable_block_headers.get_with_height(height);
+    }
+
+    unstable_blocks::get_main_chain(&state.unstable_blocks)
+        .into_chain()
+        .get((height - stable_height) as usize)
+        .map(|block| *block.header())
+}
+
+/// The height of the best known chain, which includes the validated headers of the blocks that
+/// haven't been received yet.
+pub fn best_header_height(state: &State) -> Height {
+    std::cmp::max(
+        main_chain_height(state),
+        state.header_chain.tip_height().unwrap_or(0),
+    )
+}
+
+/// The number of blocks that the main chain is behind the best known header chain.
+pub fn blocks_behind(state: &State) -> u32 {
+    best_header_height(state) - main_chain_height(state)
+}
+
+/// An error returned when the canister is behind the network by more than the maximum lag.
+#[derive(Debug, PartialEq, Eq)]
+pub enum NotSynced {
+    /// The main chain is too many blocks behind the best known header chain.
+    TooManyBlocksBehind { blocks_behind: u32, max: u32 },
+
+    /// The main chain's tip is too old.
+    TipTooOld { tip_age_secs: u64, max: u64 },
+}
+
+/// Returns the maximum lag to keep in the state for the given configured one, which is `None`
+/// if the maximum lag is disabled.
+pub fn max_lag(max_lag: MaxLag) -> Option<MaxLag> {
+    match max_lag {
+        MaxLag::Disabled => None,
+        max_lag => Some(max_lag),
+    }
+}
+
+/// Returns an error if the canister is behind the network by more than the maximum lag, given
+/// the current time in seconds.
+pub fn check_synced(state: &State, now_secs: u64) -> Result<(), NotSynced> {
+    match state.max_lag {
+        None | Some(MaxLag::Disabled) => Ok(()),
+        Some(MaxLag::Blocks(max)) => {
+            let blocks_behind = blocks_behind(state);
+            if blocks_behind > max {
+                return Err(NotSynced::TooManyBlocksBehind { blocks_behind, max });
+            }
+            Ok(())
+        }
+        Some(MaxLag::Seconds(max)) => {
+            let tip_time = unstable_blocks::get_main_chain(&state.unstable_blocks)
+                .tip()
+                .header()
+                .time as u64;
+            let tip_age_secs = now_secs.saturating_sub(tip_time);
+            if tip_age_secs > max {
+                return Err(NotSynced::TipTooOld { tip_age_secs, max });
+            }
+            Ok(())
+        }
+    }
+}
+
+/// Validates the given headers and adds them to the header chain.
+///
+/// Each header must extend either a block in the state or a header before it. Returns an error
+/// on the first invalid header, in which case the remaining headers are dropped.
+pub fn insert_next_headers(
+    state: &mut State,
+    headers: &[BlockHeader],
+) -> Result<(), ValidateHeaderError> {
+    for header in headers {
+        validation::validate_header(state, header)?;
+
+        let (_, prev_height) = ValidationContext::new(state)
+            .get_header(&header.prev_blockhash)
+            .expect("the parent of a valid header must exist");
+        state.header_chain.insert(*header, prev_height + 1);
+    }
+
+    Ok(())
+}
+
 pub fn get_unstable_blocks(state: &State) -> Vec<&Block> {
     unstable_blocks::get_blocks(&state.unstable_blocks)
 }
@@ -206,6 +708,29 @@ pub struct SyncingState {
 
     /// The number of errors occurred when inserting a block.
     pub num_insert_block_errors: u64,
+
+    /// The number of block headers that failed to be deserialized or validated.
+    #[serde(default)]
+    pub num_invalid_headers: u64,
+
+    /// The number of GetSuccessors responses that didn't match the request that was sent, e.g.
+    /// a follow-up response without a partial response to complete.
+    #[serde(default)]
+    pub num_unexpected_responses: u64,
+
+    /// The number of consecutive failures to fetch blocks. Reset on every expected response.
+    #[serde(default)]
+    pub num_consecutive_failures: u32,
+
+    /// The time (in nanoseconds since the epoch) before which no blocks are fetched, to back
+    /// off after failures.
+    #[serde(default)]
+    pub backoff_until: u64,
+
+    /// Whether blocks are inserted in fast sync mode when far behind the tip.
+    /// See `Config::fast_sync`.
+    #[serde(default = "fast_sync_disabled")]
+    pub fast_sync: Flag,
 }
 
 impl Default for SyncingState {
@@ -217,6 +742,11 @@ impl Default for SyncingState {
             num_get_successors_rejects: 0,
             num_block_deserialize_errors: 0,
             num_insert_block_errors: 0,
+            num_invalid_headers: 0,
+            num_unexpected_responses: 0,
+            num_consecutive_failures: 0,
+            backoff_until: 0,
+            fast_sync: Flag::Disabled,
         }
     }
 }
@@ -233,9 +763,249 @@ pub struct FeePercentilesCache {
 #[cfg(test)]
 mod test {
     use super::*;
-    use crate::test_utils::build_chain;
+    use crate::test_utils::{build_chain, random_p2pkh_address, BlockBuilder, TransactionBuilder};
     use proptest::prelude::*;
 
+    // Builds a block on top of `prev_block` that pays `value` to `address`.
+    fn build_block(prev_block: &Block, address: &Address, value: u64) -> Block {
+        BlockBuilder::with_prev_header(prev_block.header())
+            .with_transaction(
+                TransactionBuilder::coinbase()
+                    .with_output(address, value)
+                    .build(),
+            )
+            .build()
+    }
+
+    #[test]
+    fn rolls_back_stable_blocks_on_deep_fork() {
+        let network = Network::Regtest;
+        let main_address = random_p2pkh_address(network);
+        let fork_address = random_p2pkh_address(network);
+
+        let genesis = BlockBuilder::genesis().build();
+        let mut state = State::new(2, network, genesis.clone());
+
+        // Main chain: genesis -> b1 -> b2 -> b3 -> b4
+        let mut main_chain = vec![genesis];
+        for i in 1..=4 {
+            let block = build_block(main_chain.last().unwrap(), &main_address, i);
+            insert_block(&mut state, block.clone()).unwrap();
+            ingest_stable_blocks_into_utxoset(&mut state);
+            main_chain.push(block);
+        }
+
+        // The genesis block and b1 are stable.
+        assert_eq!(state.utxos.next_height(), 2);
+        assert_eq!(state.utxos.get_balance(&main_address), 1);
+        let utxos_len_after_genesis = state.utxos.utxos_len() - 1;
+
+        // A fork that starts from b1 is kept pending, as it doesn't lead the main chain.
+        let f2 = build_block(&main_chain[1], &fork_address, 100);
+        insert_block(&mut state, f2.clone()).unwrap();
+        assert_eq!(state.forks.len(), 1);
+        assert_eq!(state.rollback, None);
+        assert_eq!(state.utxos.next_height(), 2);
+        assert_eq!(state.utxos.get_balance(&main_address), 1);
+
+        // Extend the fork until it leads the main chain (b2 -> b3 -> b4) by two blocks.
+        let mut fork = vec![f2];
+        for i in 3..=5 {
+            let block = build_block(fork.last().unwrap(), &fork_address, 100 * i);
+            insert_block(&mut state, block.clone()).unwrap();
+            fork.push(block);
+        }
+        assert_eq!(state.utxos.next_height(), 2);
+        assert_eq!(state.forks.len(), 1);
+
+        // Now b1 is rolled back.
+        let f6 = build_block(fork.last().unwrap(), &fork_address, 600);
+        insert_block(&mut state, f6.clone()).unwrap();
+        fork.push(f6);
+        assert!(state.forks.is_empty());
+        assert_eq!(state.rollback, None);
+        assert_eq!(state.utxos.next_height(), 1);
+        assert_eq!(state.utxos.utxos_len(), utxos_len_after_genesis);
+        assert_eq!(state.utxos.get_balance(&main_address), 0);
+        assert_eq!(state.stable_block_headers.get_with_height(1), None);
+        assert_eq!(
+            unstable_blocks::peek(&state.unstable_blocks),
+            Some(&main_chain[1])
+        );
+
+        // The blocks of both chains are kept, and the fork is the main chain.
+        assert_eq!(unstable_blocks::num_blocks(&state.unstable_blocks), 9);
+        assert_eq!(
+            unstable_blocks::get_main_chain(&state.unstable_blocks).tip(),
+            fork.last().unwrap()
+        );
+
+        // The switch to the fork removed b2, b3 and b4 from the main chain.
+        assert_eq!(state.metrics.num_reorgs, 1);
+        assert_eq!(state.metrics.max_reorg_depth, 3);
+
+        ingest_stable_blocks_into_utxoset(&mut state);
+
+        // b1, f2 and f3 are now stable.
+        assert_eq!(state.utxos.next_height(), 4);
+        assert_eq!(state.utxos.get_balance(&main_address), 1);
+        assert_eq!(state.utxos.get_balance(&fork_address), 100 + 300);
+        assert_eq!(
+            state.stable_block_headers.get_with_height(2),
+            Some(*fork[0].header())
+        );
+    }
+
+    #[test]
+    fn observes_reorgs() {
+        let network = Network::Regtest;
+        let address = random_p2pkh_address(network);
+        let genesis = BlockBuilder::genesis().build();
+        let mut state = State::new(10, network, genesis.clone());
+
+        // Main chain: genesis -> b1 -> b2
+        let b1 = build_block(&genesis, &address, 1);
+        let b2 = build_block(&b1, &address, 2);
+        insert_block(&mut state, b1.clone()).unwrap();
+        insert_block(&mut state, b2).unwrap();
+
+        // A fork of the same length isn't a reorg yet, as the main chain then stops at b1.
+        let f2 = build_block(&b1, &address, 20);
+        insert_block(&mut state, f2.clone()).unwrap();
+        assert_eq!(state.metrics.num_reorgs, 0);
+
+        // Extending the fork makes it the main chain, removing b2 from it.
+        let f3 = build_block(&f2, &address, 30);
+        insert_block(&mut state, f3).unwrap();
+        assert_eq!(state.metrics.num_reorgs, 1);
+        assert_eq!(state.metrics.max_reorg_depth, 1);
+        assert_eq!(unstable_blocks::num_forks(&state.unstable_blocks), 1);
+        assert_eq!(unstable_blocks::num_blocks(&state.unstable_blocks), 5);
+    }
+
+    #[test]
+    fn observes_reorgs_to_forks_after_the_abandoned_fork_is_pruned() {
+        let network = Network::Regtest;
+        let address = random_p2pkh_address(network);
+        let genesis = BlockBuilder::genesis().build();
+        let mut state = State::new(0, network, genesis.clone());
+
+        // Main chain: genesis -> b1, contested by f1.
+        let b1 = build_block(&genesis, &address, 1);
+        let f1 = build_block(&genesis, &address, 10);
+        insert_block(&mut state, b1).unwrap();
+        insert_block(&mut state, f1.clone()).unwrap();
+
+        // With a stability threshold of 0, f1 becomes the anchor, and b1 is discarded.
+        ingest_stable_blocks_into_utxoset(&mut state);
+        assert_eq!(
+            unstable_blocks::get_main_chain(&state.unstable_blocks)
+                .tip()
+                .block_hash(),
+            f1.block_hash()
+        );
+        assert_eq!(unstable_blocks::num_blocks(&state.unstable_blocks), 1);
+        assert_eq!(state.metrics.num_reorgs, 0);
+
+        // Extending f1 removes b1 from the main chain, even though it's no longer in the store.
+        let f2 = build_block(&f1, &address, 20);
+        insert_block(&mut state, f2).unwrap();
+        assert_eq!(state.metrics.num_reorgs, 1);
+        assert_eq!(state.metrics.max_reorg_depth, 1);
+    }
+
+    #[test]
+    fn rollback_fails_for_unknown_blocks() {
+        let network = Network::Regtest;
+        let blocks = build_chain(network, 5, 1);
+        let mut state = State::new(1, network, blocks[0].clone());
+        for block in blocks[1..].iter() {
+            insert_block(&mut state, block.clone()).unwrap();
+        }
+        ingest_stable_blocks_into_utxoset(&mut state);
+
+        let block_hash = BlockHash::from(vec![1; 32]);
+        assert_eq!(
+            rollback_to(&mut state, &block_hash),
+            Err(RollbackError::BlockNotFound(block_hash))
+        );
+
+        // Blocks that are unstable cannot be rolled back to.
+        assert_eq!(
+            rollback_to(&mut state, &blocks[4].block_hash()),
+            Err(RollbackError::BlockNotFound(blocks[4].block_hash()))
+        );
+    }
+
+    #[test]
+    fn tracks_the_headers_of_blocks_not_yet_received() {
+        let network = Network::Regtest;
+        let blocks = build_chain(network, 5, 1);
+        let mut state = State::new(1, network, blocks[0].clone());
+        insert_block(&mut state, blocks[1].clone()).unwrap();
+
+        let headers: Vec<_> = blocks[2..].iter().map(|b| *b.header()).collect();
+        insert_next_headers(&mut state, &headers).unwrap();
+        assert_eq!(main_chain_height(&state), 1);
+        assert_eq!(best_header_height(&state), 4);
+
+        // A header that doesn't extend a known block or header is rejected.
+        let orphan = BlockBuilder::with_prev_header(&headers[0]).build();
+        let unknown = BlockBuilder::with_prev_header(orphan.header()).build();
+        assert!(matches!(
+            insert_next_headers(&mut state, &[*unknown.header()]),
+            Err(ValidateHeaderError::PrevHeaderNotFound)
+        ));
+
+        // The headers of stable blocks are pruned.
+        for block in blocks[2..].iter() {
+            insert_block(&mut state, block.clone()).unwrap();
+        }
+        ingest_stable_blocks_into_utxoset(&mut state);
+        assert_eq!(state.utxos.next_height(), 3);
+        assert_eq!(state.header_chain.get(&blocks[2].block_hash()), None);
+        assert_eq!(
+            state.header_chain.get(&blocks[3].block_hash()),
+            Some((blocks[3].header(), 3))
+        );
+        assert_eq!(best_header_height(&state), 4);
+    }
+
+    #[test]
+    fn checks_the_lag_behind_the_network() {
+        let network = Network::Regtest;
+        let blocks = build_chain(network, 4, 1);
+        let mut state = State::new(1, network, blocks[0].clone());
+        insert_block(&mut state, blocks[1].clone()).unwrap();
+        let headers: Vec<_> = blocks[2..].iter().map(|b| *b.header()).collect();
+        insert_next_headers(&mut state, &headers).unwrap();
+        let tip_time = blocks[1].header().time as u64;
+
+        // The lag isn't checked by default.
+        assert_eq!(check_synced(&state, u64::MAX), Ok(()));
+
+        state.max_lag = Some(MaxLag::Blocks(2));
+        assert_eq!(check_synced(&state, tip_time), Ok(()));
+        state.max_lag = Some(MaxLag::Blocks(1));
+        assert_eq!(
+            check_synced(&state, tip_time),
+            Err(NotSynced::TooManyBlocksBehind {
+                blocks_behind: 2,
+                max: 1
+            })
+        );
+
+        state.max_lag = Some(MaxLag::Seconds(600));
+        assert_eq!(check_synced(&state, tip_time + 600), Ok(()));
+        assert_eq!(
+            check_synced(&state, tip_time + 601),
+            Err(NotSynced::TipTooOld {
+                tip_age_secs: 601,
+                max: 600
+            })
+        );
+    }
+
     proptest! {
         #![proptest_config(ProptestConfig::with_cases(10))]
         #[test]
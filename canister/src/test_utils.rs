@@ -115,29 +115,50 @@ pub fn is_stable_btreemap_equal<M: Memory + Clone, K: Storable + Eq, V: Storable
 /// as opposed to `bitcoin::Block`.
 pub struct BlockBuilder {
     builder: ExternalBlockBuilder,
+    bits: Option<u32>,
 }
 
 impl BlockBuilder {
     pub fn genesis() -> Self {
         Self {
             builder: ExternalBlockBuilder::genesis(),
+            bits: None,
         }
     }
 
     pub fn with_prev_header(prev_header: &BlockHeader) -> Self {
         Self {
             builder: ExternalBlockBuilder::with_prev_header(*prev_header),
+            bits: None,
         }
     }
 
     pub fn with_transaction(self, transaction: Transaction) -> Self {
         Self {
             builder: self.builder.with_transaction(transaction.into()),
+            bits: self.bits,
+        }
+    }
+
+    /// Sets the difficulty target of the block in its compact form.
+    pub fn with_bits(self, bits: u32) -> Self {
+        Self {
+            builder: self.builder,
+            bits: Some(bits),
         }
     }
 
     pub fn build(self) -> Block {
-        Block::new(self.builder.build())
+        let mut block = self.builder.build();
+        if let Some(bits) = self.bits {
+            // Solve the block again for the new target.
+            block.header.bits = bits;
+            block.header.nonce = 0;
+            while block.header.validate_pow(&block.header.target()).is_err() {
+                block.header.nonce += 1;
+            }
+        }
+        Block::new(block)
     }
 }
 
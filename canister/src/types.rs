@@ -1,12 +1,12 @@
-use crate::state::OUTPOINT_SIZE;
+use crate::state::{NotSynced, OUTPOINT_SIZE};
 use bitcoin::{
     Address as BitcoinAddress, Block as BitcoinBlock, Network as BitcoinNetwork,
     OutPoint as BitcoinOutPoint, Script, TxOut as BitcoinTxOut,
 };
 use ic_btc_types::{
-    Address as AddressStr, GetBalanceRequest as PublicGetBalanceRequest,
-    GetUtxosRequest as PublicGetUtxosRequest, Height, NetworkInRequest, Satoshi, UtxosFilter,
-    UtxosFilterInRequest,
+    Address as AddressStr, GetBalanceError, GetBalanceRequest as PublicGetBalanceRequest,
+    GetUtxosError, GetUtxosRequest as PublicGetUtxosRequest, GetUtxosResponse, Height,
+    NetworkInRequest, Satoshi, UtxosFilter, UtxosFilterInRequest,
 };
 use ic_cdk::export::{candid::CandidType, Principal};
 use ic_stable_structures::{BoundedStorable, Storable as StableStructuresStorable};
@@ -29,15 +29,62 @@ pub struct Config {
     pub stability_threshold: u128,
     pub network: Network,
 
+    /// How the `stability_threshold` is measured. Defaults to `StabilityMode::BlockCount`.
+    pub stability_mode: Option<StabilityMode>,
+
     /// The principal from which blocks are retrieved.
     ///
     /// Setting this source to the management canister means that the blocks will be
     /// fetched directly from the replica, and that's what is used in production.
     pub blocks_source: Principal,
 
+    /// The principals from which blocks are retrieved when the `blocks_source` fails.
+    pub backup_blocks_sources: Option<Vec<Principal>>,
+
+    /// Whether blocks are cross-checked against a second source before being processed.
+    /// Disabled if not set.
+    pub cross_check_blocks_sources: Option<Flag>,
+
+    /// Whether blocks are inserted in fast sync mode while the validated headers show that the
+    /// canister is far behind the tip. As the headers come from the blocks source, this should
+    /// only be enabled if the source is trusted. Disabled if not set.
+    pub fast_sync: Option<Flag>,
+
     pub syncing: Flag,
 
     pub fees: Fees,
+
+    /// The maximum lag behind the network after which `get_utxos` and `get_balance` refuse
+    /// to respond. The lag isn't verified if not set.
+    pub max_lag: Option<MaxLag>,
+
+    /// The height of the best known header chain. Only returned by `get_config`, and ignored
+    /// when initializing the canister.
+    pub best_header_height: Option<Height>,
+
+    /// The limits on the work done in a single heartbeat. Defaults are used if not set.
+    pub heartbeat_limits: Option<HeartbeatLimits>,
+
+    /// The principals allowed to change parts of the config, besides the controllers.
+    pub admins: Option<Vec<Admin>>,
+
+    /// The principals allowed to change the entire config, besides the principal that installed
+    /// or last upgraded the canister. As the canister cannot look up its controllers, these
+    /// should be the canister's controllers.
+    pub controllers: Option<Vec<Principal>>,
+
+    /// Whether the read-only HTTP APIs, i.e. the JSON API, the Esplora-compatible API, the UTXO
+    /// set snapshots and the logs, are served by `http_request`. Disabled if not set. The metrics
+    /// are always served.
+    pub http_api: Option<Flag>,
+
+    /// The minimum level of the entries kept in the logs. Defaults to `LogLevel::Info`.
+    pub log_level: Option<LogLevel>,
+
+    /// The number of stable blocks that can be rolled back to switch to a fork that leads the
+    /// main chain. Defaults to `DEFAULT_MAX_UNDO_LOG_SIZE`, and can be at most
+    /// `MAX_UNDO_LOG_SIZE`, as the blocks are kept on the heap.
+    pub max_rollback_depth: Option<u32>,
 }
 
 impl Default for Config {
@@ -45,13 +92,78 @@ impl Default for Config {
         Self {
             stability_threshold: 0,
             network: Network::Regtest,
+            stability_mode: None,
             blocks_source: Principal::management_canister(),
+            backup_blocks_sources: None,
+            cross_check_blocks_sources: None,
+            fast_sync: None,
             syncing: Flag::Enabled,
             fees: Fees::default(),
+            max_lag: None,
+            best_header_height: None,
+            heartbeat_limits: None,
+            admins: None,
+            controllers: None,
+            http_api: None,
+            log_level: None,
+            max_rollback_depth: None,
+        }
+    }
+}
+
+/// The limits on the work done in a single heartbeat.
+///
+/// The heartbeat runs its phases one after the other. Each phase runs until the number of
+/// instructions executed in the heartbeat reaches the phase's instruction budget, at which point
+/// the phase is paused until the next heartbeat.
+#[derive(CandidType, Serialize, Deserialize, PartialEq, Eq, Copy, Clone, Debug)]
+pub struct HeartbeatLimits {
+    /// The instruction budget for ingesting stable blocks into the UTXO set.
+    pub ingestion_instructions: u64,
+
+    /// The instruction budget for inserting the blocks of a response into the unstable blocks.
+    pub insertion_instructions: u64,
+
+    /// The instruction budget for validating the headers of the blocks that are yet to be
+    /// received.
+    pub validation_instructions: u64,
+
+    /// The maximum number of blocks of a response that are inserted in a single heartbeat.
+    pub max_blocks_per_heartbeat: u32,
+}
+
+/// The maximum instruction budget of a heartbeat phase. At the time of this writing, equivalent
+/// to 80% of the instructions limit of a heartbeat or timer execution, leaving room for the work
+/// done after a budget is checked and for the other phases.
+pub const MAX_HEARTBEAT_INSTRUCTIONS: u64 = 4_000_000_000;
+
+impl Default for HeartbeatLimits {
+    fn default() -> Self {
+        Self {
+            ingestion_instructions: MAX_HEARTBEAT_INSTRUCTIONS,
+            insertion_instructions: MAX_HEARTBEAT_INSTRUCTIONS,
+            validation_instructions: MAX_HEARTBEAT_INSTRUCTIONS,
+            max_blocks_per_heartbeat: u32::MAX,
         }
     }
 }
 
+/// How far the canister can be behind the network.
+#[derive(CandidType, Serialize, Deserialize, PartialEq, Eq, Copy, Clone, Debug)]
+pub enum MaxLag {
+    /// The maximum number of blocks between the main chain and the best known header chain.
+    #[serde(rename = "blocks")]
+    Blocks(u32),
+
+    /// The maximum number of seconds since the timestamp of the main chain's tip.
+    #[serde(rename = "seconds")]
+    Seconds(u64),
+
+    /// No maximum lag. Requests are served however far the canister is behind the network.
+    #[serde(rename = "disabled")]
+    Disabled,
+}
+
 #[derive(CandidType, Serialize, Deserialize, PartialEq, Eq, Debug, Clone, Default)]
 pub struct Fees {
     pub get_utxos: u128,
@@ -59,6 +171,57 @@ pub struct Fees {
     pub get_current_fee_percentiles: u128,
     pub send_transaction_base: u128,
     pub send_transaction_per_byte: u128,
+
+    /// Fees charged on top of the `get_utxos` and `get_balance` fees, based on the work done to
+    /// process a request. If not set, only the flat fees are charged. Requests must come with
+    /// the cycles of the worst case, and the cycles that aren't charged are refunded.
+    #[serde(default)]
+    pub usage_based: Option<UsageBasedFees>,
+}
+
+impl Fees {
+    /// The fee of a `get_utxos` request that returned the given number of UTXOs after applying
+    /// the given number of unstable blocks.
+    pub fn get_utxos_fee(&self, num_utxos: u64, num_unstable_blocks: u64) -> u128 {
+        match &self.usage_based {
+            Some(usage_based) => self
+                .get_utxos
+                .saturating_add(
+                    usage_based
+                        .get_utxos_per_utxo
+                        .saturating_mul(num_utxos as u128),
+                )
+                .saturating_add(
+                    usage_based
+                        .per_unstable_block
+                        .saturating_mul(num_unstable_blocks as u128),
+                ),
+            None => self.get_utxos,
+        }
+    }
+
+    /// The fee of a `get_balance` request that applied the given number of unstable blocks.
+    pub fn get_balance_fee(&self, num_unstable_blocks: u64) -> u128 {
+        match &self.usage_based {
+            Some(usage_based) => self.get_balance.saturating_add(
+                usage_based
+                    .per_unstable_block
+                    .saturating_mul(num_unstable_blocks as u128),
+            ),
+            None => self.get_balance,
+        }
+    }
+}
+
+/// Fees that depend on the work done to process a request.
+#[derive(CandidType, Serialize, Deserialize, PartialEq, Eq, Debug, Clone, Default)]
+pub struct UsageBasedFees {
+    /// The fee per UTXO returned by `get_utxos`.
+    pub get_utxos_per_utxo: u128,
+
+    /// The fee per unstable block applied to compute the response of `get_utxos` or
+    /// `get_balance`.
+    pub per_unstable_block: u128,
 }
 
 #[derive(Clone, Debug, PartialEq, Serialize, Deserialize, Eq)]
@@ -840,16 +1003,336 @@ pub enum Flag {
     Disabled,
 }
 
+/// The severity of a log entry. Entries below the configured level aren't logged.
+#[derive(
+    CandidType, Serialize, Deserialize, PartialEq, Eq, PartialOrd, Ord, Copy, Clone, Debug,
+)]
+pub enum LogLevel {
+    #[serde(rename = "debug")]
+    Debug,
+    #[serde(rename = "info")]
+    Info,
+    #[serde(rename = "warn")]
+    Warn,
+    #[serde(rename = "error")]
+    Error,
+}
+
+/// A role granting permission to change a part of the config with `set_config`.
+///
+/// The controllers, i.e. the principal that installed or last upgraded the canister and the
+/// configured controllers, are allowed to change the entire config, including the admins.
+#[derive(
+    CandidType, Serialize, Deserialize, PartialEq, Eq, PartialOrd, Ord, Copy, Clone, Debug,
+)]
+pub enum Role {
+    /// Allowed to change the fees.
+    #[serde(rename = "fee_admin")]
+    FeeAdmin,
+
+    /// Allowed to change how blocks are synced, including enabling and disabling syncing.
+    #[serde(rename = "sync_admin")]
+    SyncAdmin,
+
+    /// Only allowed to disable syncing, e.g. to stop the canister from following a faulty
+    /// block source.
+    #[serde(rename = "emergency_stop")]
+    EmergencyStop,
+}
+
+/// A principal allowed to change parts of the config.
+#[derive(CandidType, Serialize, Deserialize, PartialEq, Eq, Clone, Debug)]
+pub struct Admin {
+    pub principal: Principal,
+    pub roles: Vec<Role>,
+}
+
+/// A change of the config made with `set_config`.
+#[derive(CandidType, Serialize, Deserialize, PartialEq, Eq, Clone, Debug)]
+pub struct ConfigChange {
+    /// The time of the change, in nanoseconds since the epoch.
+    pub timestamp: u64,
+
+    /// The principal that made the change.
+    pub caller: Principal,
+
+    /// The height of the main chain at the time of the change.
+    pub height: Height,
+
+    /// The fields that were changed.
+    pub changes: Vec<FieldChange>,
+}
+
+/// The change of a single config field, with its values formatted for display.
+#[derive(CandidType, Serialize, Deserialize, PartialEq, Eq, Clone, Debug)]
+pub struct FieldChange {
+    pub field: String,
+    pub old_value: String,
+    pub new_value: String,
+}
+
+/// A page of the config history, the most recent changes first.
+#[derive(CandidType, Deserialize, PartialEq, Eq, Debug)]
+pub struct ConfigHistoryPage {
+    pub changes: Vec<ConfigChange>,
+
+    /// The next page of the history, if there's one.
+    pub next_page: Option<u64>,
+}
+
+/// How the stability threshold is measured when deciding whether a block is stable.
+#[derive(CandidType, Serialize, Deserialize, PartialEq, Eq, Copy, Clone, Debug)]
+pub enum StabilityMode {
+    /// A block is stable once its chain leads all competing chains by `stability_threshold`
+    /// blocks.
+    #[serde(rename = "block_count")]
+    BlockCount,
+
+    /// A block is stable once its chain leads all competing chains by `stability_threshold`
+    /// times the work of the current block.
+    ///
+    /// Unlike a block count, this can't be gamed by blocks mined at the minimum difficulty,
+    /// as allowed on testnet. On regtest, the block count is always used instead.
+    #[serde(rename = "chainwork")]
+    Chainwork,
+}
+
 /// A request to update the canister's config.
 #[derive(CandidType, Deserialize, Default)]
 pub struct SetConfigRequest {
     pub stability_threshold: Option<u128>,
 
+    /// How the stability threshold is measured.
+    pub stability_mode: Option<StabilityMode>,
+
     /// Whether or not to enable/disable syncing of blocks from the network.
     pub syncing: Option<Flag>,
 
     /// The fees to charge for the various endpoints.
     pub fees: Option<Fees>,
+
+    /// The maximum lag behind the network. See `Config::max_lag`. `MaxLag::Disabled` clears it.
+    pub max_lag: Option<MaxLag>,
+
+    /// The principal from which blocks are retrieved. See `Config::blocks_source`.
+    pub blocks_source: Option<Principal>,
+
+    /// The principals from which blocks are retrieved when the `blocks_source` fails.
+    pub backup_blocks_sources: Option<Vec<Principal>>,
+
+    /// Whether blocks are cross-checked against a second source before being processed.
+    pub cross_check_blocks_sources: Option<Flag>,
+
+    /// Whether blocks are inserted in fast sync mode when far behind the tip.
+    /// See `Config::fast_sync`.
+    pub fast_sync: Option<Flag>,
+
+    /// The limits on the work done in a single heartbeat.
+    pub heartbeat_limits: Option<HeartbeatLimits>,
+
+    /// The principals allowed to change parts of the config. Only controllers can change them.
+    pub admins: Option<Vec<Admin>>,
+
+    /// The principals allowed to change the entire config. See `Config::controllers`. Only
+    /// controllers can change them.
+    pub controllers: Option<Vec<Principal>>,
+
+    /// Whether the read-only HTTP APIs are served. Only controllers can change it.
+    pub http_api: Option<Flag>,
+
+    /// The minimum level of the entries kept in the logs. Only controllers can change it.
+    pub log_level: Option<LogLevel>,
+
+    /// The number of stable blocks that can be rolled back. See `Config::max_rollback_depth`.
+    pub max_rollback_depth: Option<u32>,
+}
+
+/// An error returned by `try_get_balance` and `try_get_utxos`, which serve requests from the
+/// canister's chain.
+#[derive(Debug, PartialEq, Eq)]
+pub enum RequestError<E> {
+    /// The request is for a network other than the canister's.
+    NetworkMismatch { expected: Network, found: Network },
+
+    /// The canister is behind the network by more than the maximum lag, so its chain is stale.
+    NotSynced(NotSynced),
+
+    /// The request itself is invalid.
+    InvalidRequest(E),
+}
+
+impl<E> RequestError<E> {
+    // Writes the error as a reject message of the form `<code>: <details>`, where the code is
+    // stable and documented in `candid.did`, given the code and details of an invalid request.
+    fn fmt_reject(
+        &self,
+        f: &mut std::fmt::Formatter<'_>,
+        invalid_request: impl FnOnce(&E) -> (&'static str, String),
+    ) -> std::fmt::Result {
+        let (code, details) = match self {
+            Self::NetworkMismatch { expected, found } => (
+                "network_mismatch",
+                format!("the canister serves {}, not {}", expected, found),
+            ),
+            Self::NotSynced(NotSynced::TooManyBlocksBehind { blocks_behind, max }) => (
+                "not_synced",
+                format!(
+                    "the canister is {} blocks behind the network, at most {} are allowed",
+                    blocks_behind, max
+                ),
+            ),
+            Self::NotSynced(NotSynced::TipTooOld { tip_age_secs, max }) => (
+                "not_synced",
+                format!(
+                    "the canister's tip is {} seconds old, at most {} are allowed",
+                    tip_age_secs, max
+                ),
+            ),
+            Self::InvalidRequest(err) => invalid_request(err),
+        };
+
+        write!(f, "{}: {}", code, details)
+    }
+}
+
+impl std::fmt::Display for RequestError<GetBalanceError> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        self.fmt_reject(f, |err| match err {
+            GetBalanceError::MalformedAddress => (
+                "malformed_address",
+                String::from("the address is malformed"),
+            ),
+            GetBalanceError::MinConfirmationsTooLarge { given, max } => (
+                "min_confirmations_too_large",
+                format!(
+                    "{} confirmations were requested, at most {} are possible",
+                    given, max
+                ),
+            ),
+        })
+    }
+}
+
+impl std::fmt::Display for RequestError<GetUtxosError> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        self.fmt_reject(f, |err| match err {
+            GetUtxosError::MalformedAddress => (
+                "malformed_address",
+                String::from("the address is malformed"),
+            ),
+            GetUtxosError::MinConfirmationsTooLarge { given, max } => (
+                "min_confirmations_too_large",
+                format!(
+                    "{} confirmations were requested, at most {} are possible",
+                    given, max
+                ),
+            ),
+            GetUtxosError::UnknownTipBlockHash { tip_block_hash } => (
+                "unknown_tip_block_hash",
+                format!("the page's tip {} is unknown", hex::encode(tip_block_hash)),
+            ),
+            GetUtxosError::MalformedPage { err } => {
+                ("malformed_page", format!("the page is malformed: {}", err))
+            }
+        })
+    }
+}
+
+/// An error returned by `set_config` when the request contains an invalid value.
+#[derive(CandidType, Deserialize, PartialEq, Eq, Debug)]
+pub enum SetConfigError {
+    /// The stability threshold is too small for the network, e.g. zero on mainnet, which would
+    /// make every block stable right away, even if it's later orphaned.
+    #[serde(rename = "stability_threshold_too_small")]
+    StabilityThresholdTooSmall { given: u128, min: u128 },
+
+    /// The stability threshold is so large that blocks would hardly ever become stable.
+    #[serde(rename = "stability_threshold_too_large")]
+    StabilityThresholdTooLarge { given: u128, max: u128 },
+
+    /// A block source cannot be the anonymous principal.
+    #[serde(rename = "invalid_blocks_source")]
+    InvalidBlocksSource { source: Principal },
+
+    /// The maximum lag is so small that requests would be refused even when synced.
+    #[serde(rename = "max_lag_too_small")]
+    MaxLagTooSmall { given: MaxLag, min: MaxLag },
+
+    /// A heartbeat limit is outside the range of values with which syncing makes progress.
+    #[serde(rename = "heartbeat_limit_out_of_range")]
+    HeartbeatLimitOutOfRange {
+        limit: String,
+        given: u64,
+        min: u64,
+        max: u64,
+    },
+
+    /// The maximum rollback depth is larger than the number of blocks that can be kept.
+    #[serde(rename = "max_rollback_depth_too_large")]
+    MaxRollbackDepthTooLarge { given: u32, max: u32 },
+}
+
+/// The canister's view of the chain that its certified data commits to.
+/// See the `certification` module for how to verify it.
+#[derive(CandidType, Deserialize, PartialEq, Eq, Debug, Clone)]
+pub struct CertifiedTip {
+    /// The hash and height of the tip of the main chain.
+    pub block_hash: Vec<u8>,
+    pub height: Height,
+
+    /// The height up to which blocks are stable and ingested into the UTXO set.
+    pub stable_height: Height,
+
+    /// The number of UTXOs in the UTXO set.
+    pub utxos_length: u64,
+}
+
+/// The response of `get_certified_tip`.
+#[derive(CandidType, Deserialize, PartialEq, Eq, Debug)]
+pub struct GetCertifiedTipResponse {
+    pub certified_tip: CertifiedTip,
+
+    /// The certificate of the canister's certified data, which commits to `certified_tip`.
+    pub certificate: Option<ByteBuf>,
+}
+
+/// The response of `bitcoin_get_utxos_query`.
+#[derive(CandidType, Deserialize, PartialEq, Debug)]
+pub struct GetUtxosQueryResponse {
+    pub response: GetUtxosResponse,
+    pub certified_tip: CertifiedTip,
+
+    /// The certificate of the canister's certified data, which commits to `certified_tip`.
+    pub certificate: Option<ByteBuf>,
+}
+
+/// The response of `bitcoin_get_balance_query`.
+#[derive(CandidType, Deserialize, PartialEq, Eq, Debug)]
+pub struct GetBalanceQueryResponse {
+    pub balance: Satoshi,
+    pub certified_tip: CertifiedTip,
+
+    /// The certificate of the canister's certified data, which commits to `certified_tip`.
+    pub certificate: Option<ByteBuf>,
+}
+
+/// A commitment to the UTXO set at a given height.
+///
+/// The fields mirror those returned by bitcoind's `gettxoutsetinfo muhash`, so that the
+/// canister's UTXO set can be compared with that of a full node.
+#[derive(CandidType, Deserialize, Debug, PartialEq, Eq)]
+pub struct UtxoSetHash {
+    /// The height of the latest block ingested into the UTXO set.
+    pub height: Height,
+
+    /// The hash of the latest block ingested into the UTXO set, as displayed by bitcoind.
+    pub block_hash: String,
+
+    /// The number of UTXOs in the set.
+    pub txouts: u64,
+
+    /// The MuHash3072 of the UTXO set, as displayed by bitcoind.
+    pub muhash: String,
 }
 
 #[test]
@@ -939,3 +1422,44 @@ fn address_handles_script_edge_case() {
         Err(InvalidAddress)
     );
 }
+
+/// The progress of the canister in syncing with the bitcoin network.
+#[derive(CandidType, Deserialize, Debug, PartialEq, Eq)]
+pub struct SyncStatus {
+    /// The height of the latest stable block.
+    pub stable_height: Height,
+
+    /// The height of the tip of the main chain.
+    pub main_chain_height: Height,
+
+    /// The hash of the tip of the main chain.
+    pub main_chain_tip_hash: Vec<u8>,
+
+    /// The height of the best known header chain. See `state::best_header_height`.
+    pub best_header_height: Height,
+
+    /// Whether a request for new blocks is in progress.
+    pub is_fetching_blocks: bool,
+
+    /// If a partial response is being received, the index of its next follow-up request.
+    pub partial_response_follow_up_index: Option<u8>,
+
+    /// The number of rejects received when calling GetSuccessors.
+    pub num_get_successors_rejects: u64,
+
+    /// The number of errors occurred when deserializing blocks.
+    pub num_block_deserialize_errors: u64,
+
+    /// The number of errors occurred when inserting a block.
+    pub num_insert_block_errors: u64,
+
+    /// The number of block headers that failed to be deserialized or validated.
+    pub num_invalid_headers: u64,
+
+    /// The number of GetSuccessors responses that didn't match the request that was sent.
+    pub num_unexpected_responses: u64,
+
+    /// The estimated number of blocks that the main chain is behind the network's tip.
+    /// Clients can refuse to act on the canister's responses while this is too high.
+    pub blocks_behind: u32,
+}
@@ -1,23 +1,75 @@
 mod outpoints_cache;
 use crate::{
     blocktree::{self, BlockChain, BlockDoesNotExtendTree, BlockTree},
-    types::{Address, Block, BlockHash, OutPoint, TxOut},
+    runtime::performance_counter,
+    types::{
+        Address, Block, BlockHash, HeartbeatLimits, Network, OutPoint, Slicing, StabilityMode,
+        TxOut,
+    },
     UtxoSet,
 };
+use bitcoin::{util::uint::Uint256, BlockHeader};
 use ic_btc_types::Height;
-use outpoints_cache::OutPointsCache;
+use outpoints_cache::{BlockOutPoints, OutPointsCache, TxOutNotFound};
 use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, VecDeque};
+
+// The minimum difficulty target of testnet, in its compact form.
+const TESTNET_POW_LIMIT_BITS: u32 = 0x1d00ffff;
 
 /// A data structure for maintaining all unstable blocks.
 ///
-/// A block `b` is considered stable if:
-///   depth(block) ≥ stability_threshold
+/// When the stability threshold is a block count, a block `b` is considered stable if:
+///   depth(b) ≥ stability_threshold
 ///   ∀ b', height(b') = height(b): depth(b) - depth(b’) ≥ stability_threshold
+///
+/// When the stability threshold is expressed in chainwork, a block `b` is considered stable if:
+///   work(b) - work(header(b)) ≥ stability_threshold * current_work
+///   ∀ b', height(b') = height(b): work(b) - work(b’) ≥ stability_threshold * current_work
+///
+/// where `work(b)` is the cumulative work of the heaviest chain starting at `b`, and
+/// `current_work` is the work of the latest block in the main chain that wasn't mined at
+/// testnet's minimum difficulty.
+/// In both cases, `b` must also be the block with the most work at its height.
 #[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
 pub struct UnstableBlocks {
     stability_threshold: u32,
+    #[serde(default = "default_stability_mode")]
+    stability_mode: StabilityMode,
+
+    // The network of the blocks. Missing from the state of previous versions, in which case it's
+    // set on upgrade. See `set_network`.
+    #[serde(default)]
+    network: Option<Network>,
+
     tree: BlockTree,
     outpoints_cache: OutPointsCache,
+    #[serde(default)]
+    inserting_block: Option<InsertingBlock>,
+
+    // Whether the canister is far behind the tip. See `set_fast_sync`.
+    #[serde(default)]
+    fast_sync: bool,
+
+    // The number of instructions after which the insertion of a block is paused.
+    #[serde(default = "default_instructions_budget")]
+    instructions_budget: u64,
+
+    // The difficulty target, in its compact form, of the latest stable block that wasn't mined
+    // at the minimum difficulty. See `required_work`.
+    #[serde(default)]
+    stable_difficulty_bits: Option<u32>,
+
+    // The outpoints that are being cached after the fast sync mode is disabled, if any.
+    // See `set_fast_sync`.
+    #[serde(default)]
+    recaching: Option<Recaching>,
+
+    // The main chain when it was last observed, starting with the anchor. Missing from the state
+    // of previous versions, in which case it's set when the main chain is first observed.
+    // See `observe_main_chain`.
+    #[serde(default)]
+    observed_chain: VecDeque<BlockHash>,
 }
 
 impl UnstableBlocks {
@@ -30,8 +82,16 @@ impl UnstableBlocks {
 
         Self {
             stability_threshold,
+            stability_mode: default_stability_mode(),
+            network: Some(utxos.network()),
             tree: BlockTree::new(anchor.clone()),
             outpoints_cache,
+            inserting_block: None,
+            fast_sync: false,
+            instructions_budget: default_instructions_budget(),
+            stable_difficulty_bits: None,
+            recaching: None,
+            observed_chain: std::iter::once(anchor.block_hash()).collect(),
         }
     }
 
@@ -52,6 +112,27 @@ impl UnstableBlocks {
             .get_removed_outpoints(block_hash, address)
     }
 
+    /// Returns the number of transaction outputs in the outpoints cache.
+    pub fn outpoints_cache_len(&self) -> usize {
+        self.outpoints_cache.num_tx_outs()
+    }
+
+    /// Returns true if blocks are inserted in fast sync mode. See `set_fast_sync`.
+    pub fn is_fast_sync(&self) -> bool {
+        self.fast_sync
+    }
+
+    /// Returns true if the outpoints of the blocks are being cached after the fast sync mode is
+    /// disabled. See `set_fast_sync`.
+    pub fn is_caching_outpoints(&self) -> bool {
+        self.recaching.is_some()
+    }
+
+    /// Returns the block that is partially inserted, if any.
+    pub fn inserting_block(&self) -> Option<&InsertingBlock> {
+        self.inserting_block.as_ref()
+    }
+
     pub fn stability_threshold(&self) -> u32 {
         self.stability_threshold
     }
@@ -59,6 +140,46 @@ impl UnstableBlocks {
     pub fn set_stability_threshold(&mut self, stability_threshold: u32) {
         self.stability_threshold = stability_threshold;
     }
+
+    pub fn stability_mode(&self) -> StabilityMode {
+        self.stability_mode
+    }
+
+    pub fn set_stability_mode(&mut self, stability_mode: StabilityMode) {
+        self.stability_mode = stability_mode;
+    }
+
+    pub fn set_instructions_budget(&mut self, instructions_budget: u64) {
+        self.instructions_budget = instructions_budget;
+    }
+
+    pub fn set_network(&mut self, network: Network) {
+        self.network = Some(network);
+    }
+
+    // Returns the stability mode that is in effect. Regtest always uses the block count,
+    // as its blocks are all mined at the minimum difficulty.
+    fn effective_stability_mode(&self) -> StabilityMode {
+        match self.network {
+            Some(Network::Regtest) => StabilityMode::BlockCount,
+            Some(Network::Mainnet) | Some(Network::Testnet) | None => self.stability_mode,
+        }
+    }
+
+    // Returns true if the block of the given header was mined at the minimum difficulty, which
+    // testnet allows when no block is found for 20 minutes. The work of such blocks doesn't
+    // reflect the current difficulty of the network.
+    fn is_min_difficulty(&self, header: &BlockHeader) -> bool {
+        self.network == Some(Network::Testnet) && header.bits == TESTNET_POW_LIMIT_BITS
+    }
+}
+
+fn default_stability_mode() -> StabilityMode {
+    StabilityMode::BlockCount
+}
+
+fn default_instructions_budget() -> u64 {
+    HeartbeatLimits::default().insertion_instructions
 }
 
 /// Returns a reference to the `anchor` block iff ∃ a child `C` of `anchor` that is stable.
@@ -73,6 +194,9 @@ pub fn pop(blocks: &mut UnstableBlocks) -> Option<Block> {
     match get_stable_child(blocks) {
         Some(stable_child_idx) => {
             let old_anchor = blocks.tree.root.clone();
+            if !blocks.is_min_difficulty(old_anchor.header()) {
+                blocks.stable_difficulty_bits = Some(old_anchor.header().bits);
+            }
 
             // Replace the unstable block tree with that of the stable child.
             blocks.tree = blocks.tree.children.swap_remove(stable_child_idx);
@@ -80,6 +204,12 @@ pub fn pop(blocks: &mut UnstableBlocks) -> Option<Block> {
             // Remove the outpoints of the old anchor from the cache.
             blocks.outpoints_cache.remove(&old_anchor);
 
+            // The observed chain no longer starts with the anchor if it continued with one of
+            // the discarded siblings, which is then observed as a reorg.
+            if blocks.observed_chain.front() == Some(&old_anchor.block_hash()) {
+                blocks.observed_chain.pop_front();
+            }
+
             Some(old_anchor)
         }
         None => None,
@@ -87,68 +217,424 @@ pub fn pop(blocks: &mut UnstableBlocks) -> Option<Block> {
 }
 
 /// Pushes a new block into the store.
+///
+/// NOTE: This method does a form of time-slicing to stay within the instruction limit. The block
+/// is only added to the store once all its transactions have been processed. If that isn't the
+/// case when this method returns, then `inserting_block` is set and one or more calls to
+/// `push_continue` are necessary to finish the insertion. No other block can be pushed in the
+/// meantime.
 pub fn push(
     blocks: &mut UnstableBlocks,
     utxos: &UtxoSet,
     block: Block,
-) -> Result<(), BlockDoesNotExtendTree> {
-    let (parent_block_tree, depth) =
-        blocktree::find_mut(&mut blocks.tree, &block.header().prev_blockhash.into())
-            .ok_or_else(|| BlockDoesNotExtendTree(block.clone()))?;
+) -> Result<(), InsertBlockError> {
+    let instructions_budget = blocks.instructions_budget;
+    push_with_slicing(blocks, utxos, block, move || {
+        should_time_slice(instructions_budget)
+    })
+}
+
+/// Continues inserting a block that is partially inserted.
+/// Returns:
+///   * `None` if there was no block to continue inserting.
+///   * `Slicing::Done(block_hash)` if the block is now added to the store.
+///   * `Slicing::Paused(())` if the block continued to be inserted, but is time-sliced.
+///
+/// If an error is returned, the block is discarded and the store is left unchanged.
+pub fn push_continue(
+    blocks: &mut UnstableBlocks,
+    utxos: &UtxoSet,
+) -> Option<Result<Slicing<(), BlockHash>, InsertBlockError>> {
+    let instructions_budget = blocks.instructions_budget;
+    push_continue_with_slicing(blocks, utxos, move || {
+        should_time_slice(instructions_budget)
+    })
+}
+
+fn push_with_slicing(
+    blocks: &mut UnstableBlocks,
+    utxos: &UtxoSet,
+    block: Block,
+    should_time_slice: impl Fn() -> bool,
+) -> Result<(), InsertBlockError> {
+    if blocks.inserting_block.is_some() {
+        return Err(InsertBlockError::BlockInsertionInProgress);
+    }
+
+    if blocktree::contains(&blocks.tree, &block) {
+        // The block is already present in the store. Nothing to do.
+        return Ok(());
+    }
+
+    let (_, depth) = blocktree::find_mut(&mut blocks.tree, &block.header().prev_blockhash.into())
+        .ok_or_else(|| InsertBlockError::BlockDoesNotExtendTree(block.clone()))?;
 
     let height = utxos.next_height() + depth + 1;
 
-    // TODO(EXC-1253): Make this whole function atomic.
-    // TODO(EXC-1254): Add time-slicing as inserting a block into the outpoints cache can be expensive.
-    // TODO(EXC-1256): Do not maintain the OutPointsCache until we're close to the tip.
-    // TODO(EXC-1255): Propagate the error here.
-    blocks
-        .outpoints_cache
-        .insert(utxos, &block, height)
-        .unwrap();
-    blocktree::extend(parent_block_tree, block)
+    blocks.inserting_block = Some(InsertingBlock::new(block, height));
+    push_continue_with_slicing(blocks, utxos, should_time_slice)
+        .expect("a block to insert must exist.")
+        .map(|_| ())
 }
 
-/// Returns the best guess on what the main blockchain is.
+fn push_continue_with_slicing(
+    blocks: &mut UnstableBlocks,
+    utxos: &UtxoSet,
+    should_time_slice: impl Fn() -> bool,
+) -> Option<Result<Slicing<(), BlockHash>, InsertBlockError>> {
+    let InsertingBlock {
+        block,
+        height,
+        next_tx_idx,
+        mut outpoints,
+    } = blocks.inserting_block.take()?;
+
+    let num_txs = block.txdata().len();
+    for tx_idx in next_tx_idx..num_txs {
+        let tx = &block.txdata()[tx_idx];
+        // In fast sync mode, the inputs are still validated, but the addresses aren't indexed.
+        if let Err(err) =
+            blocks
+                .outpoints_cache
+                .collect_tx(utxos, tx, height, !blocks.fast_sync, &mut outpoints)
+        {
+            // Nothing has been added to the store yet, so dropping the block is sufficient.
+            return Some(Err(err.into()));
+        }
+
+        // Getting close to the instructions limit. Pause execution.
+        // At least one transaction is processed in every round to guarantee progress.
+        if tx_idx + 1 < num_txs && should_time_slice() {
+            blocks.inserting_block = Some(InsertingBlock {
+                block,
+                height,
+                next_tx_idx: tx_idx + 1,
+                outpoints,
+            });
+            return Some(Ok(Slicing::Paused(())));
+        }
+    }
+
+    // All the transactions have been processed. Add the block to the tree first, as that can
+    // fail if its parent has been discarded in the meantime, and then its outpoints to the cache.
+    // The block is added from the root so that the chainwork of all its ancestors is updated.
+    let block_hash = block.block_hash();
+    if let Err(err) = blocktree::extend(&mut blocks.tree, block) {
+        return Some(Err(err.into()));
+    }
+    blocks.outpoints_cache.commit(&block_hash, outpoints);
+
+    Some(Ok(Slicing::Done(block_hash)))
+}
+
+// Returns true if the insertion of a block should be paused to stay within its instructions budget.
+fn should_time_slice(instructions_budget: u64) -> bool {
+    performance_counter() >= instructions_budget
+}
+
+/// Enables or disables the fast sync mode, which is meant for when the canister is far behind
+/// the tip, e.g. during its initial sync.
 ///
-/// The most likely chain to be "main", we hypothesize, is the longest
-/// chain of blocks with an "uncontested" tip. As in, there exists no other
-/// block at the same height as the tip.
-pub fn get_main_chain(blocks: &UnstableBlocks) -> BlockChain {
-    // Get all the blockchains that extend the anchor.
-    let blockchains: Vec<BlockChain> = blocktree::blockchains(&blocks.tree);
+/// In fast sync mode, only the transaction outputs of blocks are cached, which is enough to
+/// validate the inputs of the blocks that are pushed, but the outpoints of addresses aren't, and
+/// so the UTXOs of addresses don't reflect the changes made by unstable blocks. As every block is
+/// assumed to be deep enough in the chain, the anchor's heaviest child is always stable, and
+/// blocks are ingested into the UTXO set as soon as they're pushed.
+///
+/// When the mode is disabled, the outpoints of all the blocks in the tree are cached again.
+/// Normally, the only such block is the anchor.
+///
+/// NOTE: Caching the outpoints is time-sliced. The store remains in fast sync mode until all the
+/// outpoints are cached, without any stable blocks in the meantime, and one or more calls to
+/// `set_fast_sync_continue` may be necessary to finish it.
+pub fn set_fast_sync(blocks: &mut UnstableBlocks, utxos: &UtxoSet, fast_sync: bool) {
+    let instructions_budget = blocks.instructions_budget;
+    set_fast_sync_with_slicing(blocks, utxos, fast_sync, move || {
+        should_time_slice(instructions_budget)
+    })
+}
+
+/// Continues caching the outpoints of the blocks in the tree after the fast sync mode is disabled.
+/// Returns:
+///   * `None` if there are no outpoints being cached.
+///   * `Slicing::Done(())` if all the outpoints are now cached and the fast sync mode is disabled.
+///   * `Slicing::Paused(())` if the outpoints continued to be cached, but are time-sliced.
+pub fn set_fast_sync_continue(
+    blocks: &mut UnstableBlocks,
+    utxos: &UtxoSet,
+) -> Option<Slicing<(), ()>> {
+    let instructions_budget = blocks.instructions_budget;
+    set_fast_sync_continue_with_slicing(blocks, utxos, move || {
+        should_time_slice(instructions_budget)
+    })
+}
 
-    // Find the length of the longest blockchain.
-    let mut longest_blockchain_len = 0;
-    for blockchain in blockchains.iter() {
-        longest_blockchain_len = longest_blockchain_len.max(blockchain.len());
+fn set_fast_sync_with_slicing(
+    blocks: &mut UnstableBlocks,
+    utxos: &UtxoSet,
+    fast_sync: bool,
+    should_time_slice: impl Fn() -> bool,
+) {
+    if fast_sync {
+        // The outpoints that are being cached, if any, are no longer needed.
+        blocks.recaching = None;
+        blocks.fast_sync = true;
+        return;
     }
 
-    // Get all the longest blockchains.
-    let longest_blockchains: Vec<Vec<&'_ Block>> = blockchains
-        .into_iter()
-        .filter(|bc| bc.len() == longest_blockchain_len)
-        .map(|bc| bc.into_chain())
-        .collect();
+    if !blocks.fast_sync {
+        return;
+    }
+
+    if blocks.recaching.is_none() {
+        blocks.recaching = Some(Recaching::default());
+    }
+    set_fast_sync_continue_with_slicing(blocks, utxos, should_time_slice);
+}
+
+fn set_fast_sync_continue_with_slicing(
+    blocks: &mut UnstableBlocks,
+    utxos: &UtxoSet,
+    should_time_slice: impl Fn() -> bool,
+) -> Option<Slicing<(), ()>> {
+    let Recaching {
+        mut outpoints_cache,
+        mut caching_block,
+    } = blocks.recaching.take()?;
+
+    loop {
+        // Finish caching the block that's partially cached, if that exists.
+        // At least one transaction is processed in every round to guarantee progress.
+        if let Some(InsertingBlock {
+            block,
+            height,
+            next_tx_idx,
+            mut outpoints,
+        }) = caching_block.take()
+        {
+            let num_txs = block.txdata().len();
+            for tx_idx in next_tx_idx..num_txs {
+                outpoints_cache
+                    .collect_tx(utxos, &block.txdata()[tx_idx], height, true, &mut outpoints)
+                    .expect("blocks in the tree must be valid.");
+
+                if tx_idx + 1 < num_txs && should_time_slice() {
+                    blocks.recaching = Some(Recaching {
+                        outpoints_cache,
+                        caching_block: Some(InsertingBlock {
+                            block,
+                            height,
+                            next_tx_idx: tx_idx + 1,
+                            outpoints,
+                        }),
+                    });
+                    return Some(Slicing::Paused(()));
+                }
+            }
+            outpoints_cache.commit(&block.block_hash(), outpoints);
+
+            if should_time_slice() {
+                blocks.recaching = Some(Recaching {
+                    outpoints_cache,
+                    caching_block: None,
+                });
+                return Some(Slicing::Paused(()));
+            }
+        }
+
+        match next_uncached_block(blocks, utxos, &outpoints_cache) {
+            Some((block, height)) => caching_block = Some(InsertingBlock::new(block, height)),
+            None => break,
+        }
+    }
+
+    blocks.outpoints_cache = outpoints_cache;
+    blocks.fast_sync = false;
+
+    // The block that is partially inserted, if any, is collected again, as the addresses of the
+    // transactions collected so far weren't indexed.
+    if let Some(InsertingBlock { block, height, .. }) = blocks.inserting_block.take() {
+        blocks.inserting_block = Some(InsertingBlock::new(block, height));
+    }
+
+    Some(Slicing::Done(()))
+}
+
+// Returns the first block in the tree whose outpoints aren't in the given cache, along with its
+// height, where every block comes after its parent.
+fn next_uncached_block(
+    blocks: &UnstableBlocks,
+    utxos: &UtxoSet,
+    outpoints_cache: &OutPointsCache,
+) -> Option<(Block, Height)> {
+    let mut heights = BTreeMap::new();
+    for block in blocktree::blocks(&blocks.tree) {
+        let parent_hash: BlockHash = block.header().prev_blockhash.into();
+        let height = match heights.get(&parent_hash) {
+            Some(parent_height) => parent_height + 1,
+            // The block is the anchor.
+            None => utxos.next_height(),
+        };
 
-    // A `BlockChain` contains at least one block which means we can safely index at
-    // height 0 of the chain.
-    let mut main_chain = BlockChain::new(longest_blockchains[0][0]);
-    for height_idx in 1..longest_blockchain_len {
-        // If all the blocks on the same height are identical, then this block is part of the
-        // "main" chain.
-        let block = longest_blockchains[0][height_idx];
         let block_hash = block.block_hash();
-        for chain in longest_blockchains.iter().skip(1) {
-            if chain[height_idx].block_hash() != block_hash {
-                return main_chain;
+        if !outpoints_cache.contains_block(&block_hash) {
+            return Some((block.clone(), height));
+        }
+        heights.insert(block_hash, height);
+    }
+
+    None
+}
+
+/// Resets the store such that it only contains the given `anchor`, keeping its configuration.
+/// The `stable_blocks` are the blocks between the `anchor` and the current anchor, which remain
+/// part of the observed main chain.
+///
+/// Returns the blocks that were in the store, where every block comes after its parent,
+/// followed by the block that was partially inserted, if any. It's up to the caller to push
+/// them again, which is time-sliced as usual.
+pub fn rewind(
+    blocks: &mut UnstableBlocks,
+    utxos: &UtxoSet,
+    anchor: Block,
+    stable_blocks: &[Block],
+) -> Vec<Block> {
+    let mut outpoints_cache = OutPointsCache::new();
+    outpoints_cache
+        .insert(utxos, &anchor, utxos.next_height())
+        .expect("anchor block must be valid.");
+
+    let mut observed_chain: VecDeque<BlockHash> = std::iter::once(&anchor)
+        .chain(stable_blocks)
+        .map(|block| block.block_hash())
+        .collect();
+    observed_chain.append(&mut blocks.observed_chain);
+    blocks.observed_chain = observed_chain;
+
+    let old_tree = std::mem::replace(&mut blocks.tree, BlockTree::new(anchor));
+    blocks.outpoints_cache = outpoints_cache;
+    if blocks.recaching.is_some() {
+        // Start caching the outpoints again from the new anchor.
+        blocks.recaching = Some(Recaching::default());
+    }
+
+    let mut old_blocks: Vec<Block> = blocktree::blocks(&old_tree).into_iter().cloned().collect();
+    old_blocks.extend(blocks.inserting_block.take().map(|b| b.block));
+    old_blocks
+}
+
+/// Returns true if the given fork leads the main chain by at least the stability threshold,
+/// and so the blocks it forks from should be rolled back.
+///
+/// The fork starts from a stable block, and `stable_blocks` are the stable blocks that come
+/// after that block, which the fork competes with along with the blocks in the store.
+pub fn fork_leads(blocks: &UnstableBlocks, fork: &BlockTree, stable_blocks: &[&Block]) -> bool {
+    let stability_threshold = blocks.stability_threshold;
+    match blocks.effective_stability_mode() {
+        StabilityMode::BlockCount => {
+            let len = blocktree::depth(fork) + 1;
+            let main_len = stable_blocks.len() as u32 + blocktree::depth(&blocks.tree) + 1;
+            len > main_len && len - main_len >= stability_threshold
+        }
+        StabilityMode::Chainwork => {
+            let work = fork.chainwork();
+            let main_work = stable_blocks
+                .iter()
+                .fold(blocks.tree.chainwork(), |work, block| {
+                    work + block.header().work()
+                });
+            work > main_work && work - main_work >= required_work(blocks, stability_threshold)
+        }
+    }
+}
+
+/// Returns the best guess on what the main blockchain is.
+///
+/// The most likely chain to be "main", we hypothesize, is the chain with the
+/// most cumulative work that has an "uncontested" tip. As in, there exists no
+/// other block at the same height as the tip with as much work built on top of it.
+pub fn get_main_chain(blocks: &UnstableBlocks) -> BlockChain {
+    let mut main_chain = BlockChain::new(&blocks.tree.root);
+    let mut tree = &blocks.tree;
+
+    loop {
+        let max_chainwork = match tree.children.iter().map(|child| child.chainwork()).max() {
+            Some(max_chainwork) => max_chainwork,
+            None => return main_chain,
+        };
+
+        // Follow the heaviest child, unless another child is just as heavy.
+        let mut heaviest_children = tree
+            .children
+            .iter()
+            .filter(|child| child.chainwork() == max_chainwork);
+        match (heaviest_children.next(), heaviest_children.next()) {
+            (Some(child), None) => {
+                main_chain.push(&child.root);
+                tree = child;
             }
+            _ => return main_chain,
         }
+    }
+}
 
-        main_chain.push(block);
+/// Observes the main chain after blocks have been added to the store. Returns the number of
+/// blocks that were removed from the main chain if its tip doesn't descend from the tip that
+/// was observed before, i.e. the number of observed blocks after the fork point.
+///
+/// The observed chain is kept while the main chain is a prefix of it, e.g. when a competing block
+/// makes the main chain end at the fork point, so that a contested tip that's later abandoned is
+/// still counted as removed. Observed blocks that were discarded when the anchor changed are
+/// counted as removed too.
+pub fn observe_main_chain(blocks: &mut UnstableBlocks) -> Option<u32> {
+    let main_chain = get_main_chain(blocks).into_chain();
+    let tip = main_chain
+        .last()
+        .expect("the main chain must contain the anchor");
+
+    // Usually, the tip is either unchanged or a child of the observed tip.
+    let observed_tip = blocks.observed_chain.back();
+    if observed_tip == Some(&tip.block_hash()) {
+        return None;
+    }
+    if main_chain.len() > 1 && observed_tip == Some(&BlockHash::from(tip.header().prev_blockhash)) {
+        blocks.observed_chain.push_back(tip.block_hash());
+        return None;
+    }
+
+    let num_common_blocks = main_chain
+        .iter()
+        .zip(blocks.observed_chain.iter())
+        .take_while(|(block, block_hash)| block.block_hash() == **block_hash)
+        .count();
+    if num_common_blocks == main_chain.len() {
+        // The main chain is a prefix of the observed chain.
+        return None;
     }
 
-    main_chain
+    let depth = blocks.observed_chain.len() - num_common_blocks;
+    blocks.observed_chain = main_chain.iter().map(|block| block.block_hash()).collect();
+    if depth > 0 {
+        Some(depth as u32)
+    } else {
+        None
+    }
+}
+
+/// Returns the number of blocks in the tree, including the anchor.
+pub fn num_blocks(blocks: &UnstableBlocks) -> usize {
+    blocktree::blocks(&blocks.tree).len()
+}
+
+/// Returns the depth of the tree, i.e. the number of blocks of its longest chain after the
+/// anchor.
+pub fn depth(blocks: &UnstableBlocks) -> u32 {
+    blocktree::depth(&blocks.tree)
+}
+
+/// Returns the number of forks, i.e. the number of chains in the tree besides the first.
+pub fn num_forks(blocks: &UnstableBlocks) -> usize {
+    blocktree::blockchains(&blocks.tree).len() - 1
 }
 
 pub fn get_blocks(blocks: &UnstableBlocks) -> Vec<&Block> {
@@ -170,38 +656,57 @@ pub fn get_chain_with_tip<'a, 'b>(
 
 // Returns the index of the `anchor`'s stable child if it exists.
 fn get_stable_child(blocks: &UnstableBlocks) -> Option<usize> {
-    // Compute the depth of all the children.
-    let mut depths: Vec<_> = blocks
-        .tree
-        .children
-        .iter()
-        .enumerate()
-        .map(|(idx, child)| (blocktree::depth(child), idx))
-        .collect();
-
-    // Sort by depth.
-    depths.sort_by_key(|(depth, _child_idx)| *depth);
-
-    match depths.last() {
-        Some((deepest_depth, child_idx)) => {
-            // The deepest child tree must have a depth >= stability_threshold.
-            if *deepest_depth < blocks.stability_threshold {
-                // Need a depth of at least >= stability_threshold
-                return None;
-            }
+    // No blocks are stable while the outpoints are being cached, as the anchor must not change.
+    if blocks.recaching.is_some() {
+        return None;
+    }
 
-            // If there is more than one child, the difference in depth
-            // between the deepest child and all the others must be >= stability_threshold.
-            if depths.len() >= 2 {
-                if let Some((second_deepest_depth, _)) = depths.get(depths.len() - 2) {
-                    if deepest_depth - second_deepest_depth < blocks.stability_threshold {
-                        // Difference must be >= stability_threshold
-                        return None;
-                    }
+    // In fast sync mode, blocks are assumed to be deep enough to be stable.
+    let stability_threshold = if blocks.fast_sync {
+        0
+    } else {
+        blocks.stability_threshold
+    };
+
+    // Sort the children by the work of their heaviest chain.
+    let mut children: Vec<_> = blocks.tree.children.iter().enumerate().collect();
+    children.sort_by_key(|(_child_idx, child)| child.chainwork());
+
+    match children.pop() {
+        Some((child_idx, heaviest_child)) => {
+            let is_stable = match blocks.effective_stability_mode() {
+                StabilityMode::BlockCount => {
+                    // The heaviest child tree must have a depth >= stability_threshold, and
+                    // the difference in depth between it and all the others must also be
+                    // >= stability_threshold.
+                    let depth = blocktree::depth(heaviest_child);
+                    depth >= stability_threshold
+                        && children.iter().all(|(_, child)| {
+                            depth.saturating_sub(blocktree::depth(child)) >= stability_threshold
+                        })
                 }
-            }
+                StabilityMode::Chainwork => {
+                    // The work on top of the heaviest child, and the difference in work between
+                    // it and all the others, must be worth at least stability_threshold blocks
+                    // at the current difficulty.
+                    let required_work = required_work(blocks, stability_threshold);
+                    let work_on_top =
+                        heaviest_child.chainwork() - heaviest_child.root.header().work();
+                    let lead = match children.last() {
+                        Some((_, second_heaviest_child)) => {
+                            heaviest_child.chainwork() - second_heaviest_child.chainwork()
+                        }
+                        None => heaviest_child.chainwork(),
+                    };
+                    work_on_top >= required_work && lead >= required_work
+                }
+            };
 
-            Some(*child_idx)
+            if is_stable {
+                Some(child_idx)
+            } else {
+                None
+            }
         }
         None => {
             // The anchor has no children. Nothing to return.
@@ -210,10 +715,95 @@ fn get_stable_child(blocks: &UnstableBlocks) -> Option<usize> {
     }
 }
 
+// Returns the work of `stability_threshold` blocks at the current difficulty, i.e. that of the
+// latest block in the main chain that wasn't mined at the minimum difficulty.
+fn required_work(blocks: &UnstableBlocks, stability_threshold: u32) -> Uint256 {
+    let anchor_header = blocks.tree.root.header();
+    let current_header = get_main_chain(blocks)
+        .into_chain()
+        .into_iter()
+        .rev()
+        .map(|block| *block.header())
+        .find(|header| !blocks.is_min_difficulty(header));
+
+    let work = match (current_header, blocks.stable_difficulty_bits) {
+        (Some(header), _) => header.work(),
+        // All the blocks in the main chain were mined at the minimum difficulty.
+        (None, Some(bits)) => BlockHeader {
+            bits,
+            ..*anchor_header
+        }
+        .work(),
+        (None, None) => anchor_header.work(),
+    };
+    work.mul_u32(stability_threshold)
+}
+
+/// A block that is partially inserted into `UnstableBlocks`. Used for time slicing.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct InsertingBlock {
+    pub block: Block,
+    pub next_tx_idx: usize,
+
+    // The height of the block.
+    height: Height,
+
+    // The outpoints of the transactions processed so far.
+    outpoints: BlockOutPoints,
+}
+
+impl InsertingBlock {
+    fn new(block: Block, height: Height) -> Self {
+        Self {
+            block,
+            next_tx_idx: 0,
+            height,
+            outpoints: BlockOutPoints::default(),
+        }
+    }
+}
+
+/// The outpoints cache that is rebuilt after the fast sync mode is disabled. Used for time slicing.
+#[derive(Clone, Debug, Default, PartialEq, Serialize, Deserialize)]
+struct Recaching {
+    outpoints_cache: OutPointsCache,
+
+    // The block whose outpoints are partially collected, if any.
+    caching_block: Option<InsertingBlock>,
+}
+
+/// An error returned when a block cannot be inserted into `UnstableBlocks`.
+#[derive(Debug, PartialEq)]
+pub enum InsertBlockError {
+    /// The block doesn't extend any of the blocks in the store.
+    BlockDoesNotExtendTree(Block),
+
+    /// The transaction output spent by one of the block's inputs cannot be found.
+    TxOutNotFound(OutPoint),
+
+    /// Another block is partially inserted. See `push_continue`.
+    BlockInsertionInProgress,
+}
+
+impl From<BlockDoesNotExtendTree> for InsertBlockError {
+    fn from(err: BlockDoesNotExtendTree) -> Self {
+        Self::BlockDoesNotExtendTree(err.0)
+    }
+}
+
+impl From<TxOutNotFound> for InsertBlockError {
+    fn from(err: TxOutNotFound) -> Self {
+        Self::TxOutNotFound(err.0)
+    }
+}
+
 #[cfg(test)]
 mod test {
     use super::*;
-    use crate::{test_utils::BlockBuilder, types::Network};
+    use crate::{
+        test_utils::{random_p2pkh_address, BlockBuilder, TransactionBuilder},
+        types::{Network, Txid},
+    };
 
     #[test]
     fn empty() {
@@ -285,6 +875,350 @@ mod test {
         assert_eq!(pop(&mut forest), None);
     }
 
+    // Creating the following forest, where `x` has 4 times the work of the other blocks:
+    //
+    // * -> 1 -> 2 -> 3
+    //   -> x
+    //
+    // The shorter fork has more work, and becomes stable once it's extended if the
+    // stability threshold is expressed in chainwork.
+    #[test]
+    fn forks_with_different_work() {
+        let genesis_block = BlockBuilder::genesis().build();
+        let block_1 = BlockBuilder::with_prev_header(genesis_block.header()).build();
+        let block_2 = BlockBuilder::with_prev_header(block_1.header()).build();
+        let block_3 = BlockBuilder::with_prev_header(block_2.header()).build();
+        let block_x = BlockBuilder::with_prev_header(genesis_block.header())
+            .with_bits(0x201fffff)
+            .build();
+
+        let utxos = UtxoSet::new(Network::Mainnet);
+        let mut forest = UnstableBlocks::new(&utxos, 1, genesis_block.clone());
+        forest.set_stability_mode(StabilityMode::Chainwork);
+
+        push(&mut forest, &utxos, block_1).unwrap();
+        push(&mut forest, &utxos, block_2).unwrap();
+        push(&mut forest, &utxos, block_3).unwrap();
+        push(&mut forest, &utxos, block_x.clone()).unwrap();
+
+        // The fork of `x` is heavier, but it isn't deep enough to be 1-stable.
+        assert_eq!(peek(&forest), None);
+        assert_eq!(pop(&mut forest), None);
+
+        // Extend the fork of `x` by another block.
+        push(
+            &mut forest,
+            &utxos,
+            BlockBuilder::with_prev_header(block_x.header()).build(),
+        )
+        .unwrap();
+
+        // Now the fork of `x` is 1-stable, even though it's shorter.
+        assert_eq!(peek(&forest), Some(&genesis_block));
+        assert_eq!(pop(&mut forest), Some(genesis_block));
+        assert_eq!(forest.tree.root, block_x);
+    }
+
+    // The difficulty target of testnet blocks that aren't mined at the minimum difficulty.
+    // Their work is 256 times that of blocks mined at the minimum difficulty.
+    const TESTNET_BITS: u32 = 0x1c00ffff;
+
+    // Builds a chain with the given difficulty targets. The blocks aren't solved, as that takes
+    // too long at testnet's difficulty, and their proof of work isn't checked by the store.
+    fn build_chain_with_bits(bits: &[u32]) -> Vec<Block> {
+        let mut blocks: Vec<Block> = vec![];
+        for bits in bits.iter() {
+            let mut block = match blocks.last() {
+                Some(prev_block) => {
+                    ic_btc_test_utils::BlockBuilder::with_prev_header(*prev_block.header())
+                }
+                None => ic_btc_test_utils::BlockBuilder::genesis(),
+            }
+            .build();
+            block.header.bits = *bits;
+            blocks.push(Block::new(block));
+        }
+        blocks
+    }
+
+    // Creating a chain `* -> 1 -> 2 -> 3`, where blocks 1 to 3 are mined at the minimum
+    // difficulty, as is the case on testnet when no block is found for 20 minutes.
+    fn build_chain_after_difficulty_drop() -> Vec<Block> {
+        build_chain_with_bits(&[
+            TESTNET_BITS,
+            TESTNET_POW_LIMIT_BITS,
+            TESTNET_POW_LIMIT_BITS,
+            TESTNET_POW_LIMIT_BITS,
+        ])
+    }
+
+    #[test]
+    fn chainwork_threshold_requires_more_blocks_after_difficulty_drop() {
+        let blocks = build_chain_after_difficulty_drop();
+
+        let utxos = UtxoSet::new(Network::Testnet);
+        let mut block_count_forest = UnstableBlocks::new(&utxos, 2, blocks[0].clone());
+        let mut chainwork_forest = UnstableBlocks::new(&utxos, 2, blocks[0].clone());
+        chainwork_forest.set_stability_mode(StabilityMode::Chainwork);
+
+        for block in blocks[1..].iter() {
+            push(&mut block_count_forest, &utxos, block.clone()).unwrap();
+            push(&mut chainwork_forest, &utxos, block.clone()).unwrap();
+        }
+
+        // Block 1 has two blocks on top of it, which is enough for the block count,
+        // but these aren't worth two blocks at the current difficulty.
+        assert_eq!(peek(&block_count_forest), Some(&blocks[0]));
+        assert_eq!(peek(&chainwork_forest), None);
+    }
+
+    #[test]
+    fn chainwork_threshold_ignores_the_difficulty_of_a_min_difficulty_anchor() {
+        // `* -> 1 -> 2 -> 3 -> 4 -> 5`, where the anchor and blocks 2 and 3 are mined at the
+        // minimum difficulty.
+        let blocks = build_chain_with_bits(&[
+            TESTNET_POW_LIMIT_BITS,
+            TESTNET_BITS,
+            TESTNET_POW_LIMIT_BITS,
+            TESTNET_POW_LIMIT_BITS,
+            TESTNET_BITS,
+            TESTNET_BITS,
+        ]);
+
+        let utxos = UtxoSet::new(Network::Testnet);
+        let mut forest = UnstableBlocks::new(&utxos, 2, blocks[0].clone());
+        forest.set_stability_mode(StabilityMode::Chainwork);
+
+        for block in blocks[1..4].iter() {
+            push(&mut forest, &utxos, block.clone()).unwrap();
+        }
+
+        // Block 1 has two blocks on top of it, which are worth two blocks at the difficulty of
+        // the anchor, but not at the current difficulty, which is that of block 1.
+        assert_eq!(peek(&forest), None);
+
+        for block in blocks[4..].iter() {
+            push(&mut forest, &utxos, block.clone()).unwrap();
+        }
+        assert_eq!(peek(&forest), Some(&blocks[0]));
+    }
+
+    #[test]
+    fn chainwork_threshold_falls_back_to_block_count_on_regtest() {
+        let blocks = build_chain_after_difficulty_drop();
+
+        let utxos = UtxoSet::new(Network::Regtest);
+        let mut forest = UnstableBlocks::new(&utxos, 2, blocks[0].clone());
+        forest.set_stability_mode(StabilityMode::Chainwork);
+
+        for block in blocks[1..].iter() {
+            push(&mut forest, &utxos, block.clone()).unwrap();
+        }
+
+        assert_eq!(peek(&forest), Some(&blocks[0]));
+    }
+
+    #[test]
+    fn push_is_time_sliced() {
+        let network = Network::Mainnet;
+        let address = random_p2pkh_address(network);
+        let block_0 = BlockBuilder::genesis().build();
+        let mut block_1 = BlockBuilder::with_prev_header(block_0.header());
+        for value in 1..4 {
+            block_1 = block_1.with_transaction(
+                TransactionBuilder::coinbase()
+                    .with_output(&address, value)
+                    .build(),
+            );
+        }
+        let block_1 = block_1.build();
+        let block_2 = BlockBuilder::with_prev_header(block_1.header()).build();
+        let outpoint = OutPoint::new(block_1.txdata()[0].txid(), 0);
+
+        let utxos = UtxoSet::new(network);
+        let mut forest = UnstableBlocks::new(&utxos, 1, block_0.clone());
+
+        // Pause after every transaction.
+        push_with_slicing(&mut forest, &utxos, block_1.clone(), || true).unwrap();
+        assert_eq!(forest.inserting_block().unwrap().next_tx_idx, 1);
+
+        // Another block cannot be pushed while the block is being inserted.
+        assert_eq!(
+            push(&mut forest, &utxos, block_2),
+            Err(InsertBlockError::BlockInsertionInProgress)
+        );
+
+        for next_tx_idx in 2..block_1.txdata().len() {
+            assert_eq!(
+                push_continue_with_slicing(&mut forest, &utxos, || true),
+                Some(Ok(Slicing::Paused(())))
+            );
+            assert_eq!(forest.inserting_block().unwrap().next_tx_idx, next_tx_idx);
+
+            // The block isn't visible until it's fully inserted.
+            assert_eq!(get_blocks(&forest), vec![&block_0]);
+            assert_eq!(forest.get_tx_out(&outpoint), None);
+        }
+
+        assert_eq!(
+            push_continue_with_slicing(&mut forest, &utxos, || true),
+            Some(Ok(Slicing::Done(block_1.block_hash())))
+        );
+        assert_eq!(forest.inserting_block(), None);
+        assert_eq!(get_blocks(&forest), vec![&block_0, &block_1]);
+        assert!(forest.get_tx_out(&outpoint).is_some());
+        assert_eq!(
+            push_continue_with_slicing(&mut forest, &utxos, || true),
+            None
+        );
+    }
+
+    #[test]
+    fn fast_sync_skips_address_indices() {
+        let network = Network::Mainnet;
+        let address = random_p2pkh_address(network);
+        let block_0 = BlockBuilder::genesis().build();
+        let block_1 = BlockBuilder::with_prev_header(block_0.header())
+            .with_transaction(
+                TransactionBuilder::coinbase()
+                    .with_output(&address, 1)
+                    .build(),
+            )
+            .build();
+        let block_2 = BlockBuilder::with_prev_header(block_1.header()).build();
+        let outpoint = OutPoint::new(block_1.txdata()[0].txid(), 0);
+
+        let utxos = UtxoSet::new(network);
+        let mut forest = UnstableBlocks::new(&utxos, 2, block_0.clone());
+        set_fast_sync(&mut forest, &utxos, true);
+        assert!(forest.is_fast_sync());
+
+        // The transaction outputs of the block are cached, but not the outpoints of its addresses.
+        push(&mut forest, &utxos, block_1.clone()).unwrap();
+        assert_eq!(get_blocks(&forest), vec![&block_0, &block_1]);
+        assert!(forest.get_tx_out(&outpoint).is_some());
+        assert_eq!(
+            forest.get_added_outpoints(&block_1.block_hash(), &address),
+            &[] as &[OutPoint]
+        );
+
+        // Leaving fast sync caches the outpoints of the blocks in the tree.
+        set_fast_sync(&mut forest, &utxos, false);
+        assert!(!forest.is_fast_sync());
+        assert_eq!(
+            forest.get_added_outpoints(&block_1.block_hash(), &address),
+            &[outpoint]
+        );
+
+        // Blocks are stable right away in fast sync mode.
+        push(&mut forest, &utxos, block_2.clone()).unwrap();
+        assert_eq!(peek(&forest), None);
+        set_fast_sync(&mut forest, &utxos, true);
+        assert_eq!(pop(&mut forest), Some(block_0));
+        assert_eq!(pop(&mut forest), Some(block_1));
+        assert_eq!(pop(&mut forest), None);
+        assert_eq!(get_blocks(&forest), vec![&block_2]);
+    }
+
+    #[test]
+    fn fast_sync_validates_inputs() {
+        let network = Network::Mainnet;
+        let block_0 = BlockBuilder::genesis().build();
+        let missing_outpoint = OutPoint::new(Txid::from(vec![1; 32]), 0);
+        let block_1 = BlockBuilder::with_prev_header(block_0.header())
+            .with_transaction(
+                TransactionBuilder::new()
+                    .with_input(missing_outpoint.clone())
+                    .with_output(&random_p2pkh_address(network), 1000)
+                    .build(),
+            )
+            .build();
+
+        let utxos = UtxoSet::new(network);
+        let mut forest = UnstableBlocks::new(&utxos, 1, block_0.clone());
+        set_fast_sync(&mut forest, &utxos, true);
+
+        // The block is refused, rather than being ingested into the UTXO set.
+        assert_eq!(
+            push(&mut forest, &utxos, block_1),
+            Err(InsertBlockError::TxOutNotFound(missing_outpoint))
+        );
+        assert_eq!(get_blocks(&forest), vec![&block_0]);
+    }
+
+    #[test]
+    fn leaving_fast_sync_is_time_sliced() {
+        let network = Network::Mainnet;
+        let address = random_p2pkh_address(network);
+        let block_0 = BlockBuilder::genesis().build();
+        let mut block_1 = BlockBuilder::with_prev_header(block_0.header());
+        for value in 1..4 {
+            block_1 = block_1.with_transaction(
+                TransactionBuilder::coinbase()
+                    .with_output(&address, value)
+                    .build(),
+            );
+        }
+        let block_1 = block_1.build();
+        let block_2 = BlockBuilder::with_prev_header(block_1.header()).build();
+
+        let utxos = UtxoSet::new(network);
+        let mut forest = UnstableBlocks::new(&utxos, 2, block_0.clone());
+        set_fast_sync(&mut forest, &utxos, true);
+        push(&mut forest, &utxos, block_1.clone()).unwrap();
+
+        // Time-slice after every transaction.
+        set_fast_sync_with_slicing(&mut forest, &utxos, false, || true);
+        let mut num_rounds = 1;
+        while forest.is_caching_outpoints() {
+            // The store remains in fast sync mode, without stable blocks, until it's done.
+            assert!(forest.is_fast_sync());
+            assert_eq!(peek(&forest), None);
+
+            // Blocks can still be pushed in the meantime, and their outpoints are cached too.
+            push(&mut forest, &utxos, block_2.clone()).unwrap();
+
+            set_fast_sync_continue_with_slicing(&mut forest, &utxos, || true);
+            num_rounds += 1;
+        }
+
+        // Each transaction of block 1 is cached in its own round.
+        assert!(num_rounds > block_1.txdata().len());
+        assert!(!forest.is_fast_sync());
+        assert_eq!(get_blocks(&forest), vec![&block_0, &block_1, &block_2]);
+        for tx in block_1.txdata() {
+            assert!(forest.get_tx_out(&OutPoint::new(tx.txid(), 0)).is_some());
+        }
+        assert!(forest.outpoints_cache.contains_block(&block_2.block_hash()));
+    }
+
+    #[test]
+    fn push_returns_error_if_tx_out_not_found() {
+        let network = Network::Mainnet;
+        let block_0 = BlockBuilder::genesis().build();
+        let missing_outpoint = OutPoint::new(Txid::from(vec![1; 32]), 0);
+        let block_1 = BlockBuilder::with_prev_header(block_0.header())
+            .with_transaction(
+                TransactionBuilder::new()
+                    .with_input(missing_outpoint.clone())
+                    .with_output(&random_p2pkh_address(network), 1000)
+                    .build(),
+            )
+            .build();
+
+        let utxos = UtxoSet::new(network);
+        let mut forest = UnstableBlocks::new(&utxos, 1, block_0);
+        let forest_before = forest.clone();
+
+        assert_eq!(
+            push(&mut forest, &utxos, block_1),
+            Err(InsertBlockError::TxOutNotFound(missing_outpoint))
+        );
+
+        // The store is left unchanged.
+        assert_eq!(forest, forest_before);
+    }
+
     #[test]
     fn insert_in_order() {
         let block_0 = BlockBuilder::genesis().build();
@@ -371,6 +1305,37 @@ mod test {
         );
     }
 
+    // Creating the following forest, where `x` has 4 times the work of the other blocks:
+    //
+    // * -> 1 -> 2 -> 3
+    //   -> x
+    //
+    // "x" has the most work and should be considered "main", even though it's shorter.
+    #[test]
+    fn get_main_chain_shorter_fork_with_more_work() {
+        let block_0 = BlockBuilder::genesis().build();
+        let block_1 = BlockBuilder::with_prev_header(block_0.header()).build();
+        let block_2 = BlockBuilder::with_prev_header(block_1.header()).build();
+        let block_3 = BlockBuilder::with_prev_header(block_2.header()).build();
+        let block_x = BlockBuilder::with_prev_header(block_0.header())
+            .with_bits(0x201fffff)
+            .build();
+
+        let utxos = UtxoSet::new(Network::Mainnet);
+        let mut forest = UnstableBlocks::new(&utxos, 1, block_0.clone());
+
+        push(&mut forest, &utxos, block_1).unwrap();
+        push(&mut forest, &utxos, block_2).unwrap();
+        push(&mut forest, &utxos, block_3.clone()).unwrap();
+        assert_eq!(get_main_chain(&forest).tip(), &block_3);
+
+        push(&mut forest, &utxos, block_x.clone()).unwrap();
+        assert_eq!(
+            get_main_chain(&forest),
+            BlockChain::new_with_successors(&block_0, vec![&block_x])
+        );
+    }
+
     // Creating the following forest:
     //
     // * -> 1 -> 2 -> 3
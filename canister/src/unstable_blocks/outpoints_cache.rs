@@ -1,5 +1,5 @@
 use crate::{
-    types::{Address, Block, BlockHash, OutPoint, TxOut},
+    types::{Address, Block, BlockHash, OutPoint, Transaction, TxOut},
     UtxoSet,
 };
 use ic_btc_types::Height;
@@ -19,6 +19,12 @@ pub struct OutPointsCache {
     removed_outpoints: BTreeMap<BlockHash, BTreeMap<Address, Vec<OutPoint>>>,
 }
 
+impl Default for OutPointsCache {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
 impl OutPointsCache {
     pub fn new() -> Self {
         Self {
@@ -54,6 +60,16 @@ impl OutPointsCache {
             .unwrap_or(&[])
     }
 
+    /// Returns true if the outpoints of the block with the given hash are in the cache.
+    pub fn contains_block(&self, block_hash: &BlockHash) -> bool {
+        self.added_outpoints.contains_key(block_hash)
+    }
+
+    /// Returns the number of transaction outputs in the cache.
+    pub fn num_tx_outs(&self) -> usize {
+        self.tx_outs.len()
+    }
+
     /// Retrieves the `TxOut` associated with the given `outpoint`, along with its height.
     pub fn get_tx_out(&self, outpoint: &OutPoint) -> Option<(&TxOut, Height)> {
         self.tx_outs
@@ -68,10 +84,36 @@ impl OutPointsCache {
         block: &Block,
         height: Height,
     ) -> Result<(), TxOutNotFound> {
-        // A map to store all the transaction outputs referenced by the given block.
-        let mut tx_outs: BTreeMap<OutPoint, TxOutInfo> = BTreeMap::new();
-        let mut removed_outpoints = BTreeMap::new();
-        let mut added_outpoints = BTreeMap::new();
+        let mut block_outpoints = BlockOutPoints::default();
+        for tx in block.txdata() {
+            self.collect_tx(utxos, tx, height, true, &mut block_outpoints)?;
+        }
+
+        self.commit(&block.block_hash(), block_outpoints);
+        Ok(())
+    }
+
+    /// Collects the outpoints of a transaction in a block at the given height, along with their
+    /// transaction outputs, into `block_outpoints`.
+    ///
+    /// The cache itself isn't modified. The outpoints of the block are only added to the cache
+    /// once all its transactions are collected and `commit` is called.
+    ///
+    /// If `index_addresses` is false, only the transaction outputs are collected, which is enough
+    /// to validate the inputs of subsequent transactions, but not the outpoints of each address.
+    pub fn collect_tx(
+        &self,
+        utxos: &UtxoSet,
+        tx: &Transaction,
+        height: Height,
+        index_addresses: bool,
+        block_outpoints: &mut BlockOutPoints,
+    ) -> Result<(), TxOutNotFound> {
+        let BlockOutPoints {
+            tx_outs,
+            added_outpoints,
+            removed_outpoints,
+        } = block_outpoints;
 
         // The inputs of a transaction contain outpoints that reference the previous
         // outputs that it is consuming. These outputs can be retrieved from a number
@@ -84,29 +126,29 @@ impl OutPointsCache {
         // 3. From the cache itself, if the outpoint references a tx in an unstable block.
         //    The assumption here is that this cache already contains all the outpoints
         //    referenced by the unstable blocks.
-        for tx in block.txdata() {
-            for input in tx.input() {
-                if input.previous_output.is_null() {
-                    continue;
-                }
+        for input in tx.input() {
+            if input.previous_output.is_null() {
+                continue;
+            }
 
-                let outpoint = (&input.previous_output).into();
+            let outpoint = (&input.previous_output).into();
 
-                // Lookup the `TxOut` in the current cache.
-                let (txout, height) = match self.get_tx_out(&outpoint) {
-                    Some((txout, height)) => (txout.clone(), height),
+            // Lookup the `TxOut` in the current cache.
+            let (txout, height) = match self.get_tx_out(&outpoint) {
+                Some((txout, height)) => (txout.clone(), height),
 
-                    // Lookup the `TxOut` in the current block.
-                    None => match tx_outs.get(&outpoint) {
-                        Some(e) => (e.txout.clone(), e.height),
+                // Lookup the `TxOut` in the current block.
+                None => match tx_outs.get(&outpoint) {
+                    Some(e) => (e.txout.clone(), e.height),
 
-                        // Lookup the `TxOut` in the UTXO set.
-                        None => utxos
-                            .get_utxo(&outpoint)
-                            .ok_or_else(|| TxOutNotFound(outpoint.clone()))?,
-                    },
-                };
+                    // Lookup the `TxOut` in the UTXO set.
+                    None => utxos
+                        .get_utxo(&outpoint)
+                        .ok_or_else(|| TxOutNotFound(outpoint.clone()))?,
+                },
+            };
 
+            if index_addresses {
                 if let Ok(address) = Address::from_script(
                     &bitcoin::Script::from(txout.script_pubkey.clone()),
                     utxos.network(),
@@ -114,40 +156,47 @@ impl OutPointsCache {
                     let entry = removed_outpoints.entry(address).or_insert(vec![]);
                     entry.push(outpoint.clone());
                 }
-
-                let entry = tx_outs.entry(outpoint).or_insert(TxOutInfo {
-                    txout,
-                    height,
-                    count: 0,
-                });
-                entry.count += 1;
             }
 
-            // Outputs can be inserted as-is into the cache, maintaining a count of how
-            // many we inserted into the cache that reference them.
-            for (i, txout) in tx.output().iter().enumerate() {
-                let outpoint = OutPoint {
-                    txid: tx.txid(),
-                    vout: i as u32,
-                };
+            let entry = tx_outs.entry(outpoint).or_insert(TxOutInfo {
+                txout,
+                height,
+                count: 0,
+            });
+            entry.count += 1;
+        }
+
+        // Outputs can be inserted as-is into the cache, maintaining a count of how
+        // many we inserted into the cache that reference them.
+        for (i, txout) in tx.output().iter().enumerate() {
+            let outpoint = OutPoint {
+                txid: tx.txid(),
+                vout: i as u32,
+            };
 
+            if index_addresses {
                 if let Ok(address) = Address::from_script(&txout.script_pubkey, utxos.network()) {
                     let entry = added_outpoints.entry(address).or_insert(vec![]);
                     entry.push(outpoint.clone());
                 }
-
-                // Retrieve the associated entry in the cache and increment its count.
-                let entry = tx_outs.entry(outpoint.clone()).or_insert(TxOutInfo {
-                    txout: txout.into(),
-                    height,
-                    count: 0,
-                });
-                entry.count += 1;
             }
+
+            // Retrieve the associated entry in the cache and increment its count.
+            let entry = tx_outs.entry(outpoint.clone()).or_insert(TxOutInfo {
+                txout: txout.into(),
+                height,
+                count: 0,
+            });
+            entry.count += 1;
         }
 
+        Ok(())
+    }
+
+    /// Merges the outpoints collected for the block with the given hash into the cache.
+    pub fn commit(&mut self, block_hash: &BlockHash, block_outpoints: BlockOutPoints) {
         // Merge all the transaction outputs of this block into the cache.
-        for (outpoint, tx_out_info) in tx_outs {
+        for (outpoint, tx_out_info) in block_outpoints.tx_outs {
             self.tx_outs
                 .entry(outpoint)
                 .and_modify(|t| t.count += tx_out_info.count)
@@ -155,11 +204,9 @@ impl OutPointsCache {
         }
 
         self.added_outpoints
-            .insert(block.block_hash(), added_outpoints);
+            .insert(block_hash.clone(), block_outpoints.added_outpoints);
         self.removed_outpoints
-            .insert(block.block_hash(), removed_outpoints);
-
-        Ok(())
+            .insert(block_hash.clone(), block_outpoints.removed_outpoints);
     }
 
     /// Removes the outpoints of a block from the cache.
@@ -211,8 +258,22 @@ impl OutPointsCache {
     }
 }
 
+/// The outpoints of a block that are collected before being added to the cache.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize, Default)]
+pub struct BlockOutPoints {
+    // The transaction outputs referenced by the block.
+    tx_outs: BTreeMap<OutPoint, TxOutInfo>,
+
+    // The outpoints added for each address in the block.
+    added_outpoints: BTreeMap<Address, Vec<OutPoint>>,
+
+    // The outpoints removed for each address in the block.
+    removed_outpoints: BTreeMap<Address, Vec<OutPoint>>,
+}
+
+/// An error returned when the transaction output referenced by an input cannot be found.
 #[derive(Debug, PartialEq)]
-pub struct TxOutNotFound(OutPoint);
+pub struct TxOutNotFound(pub OutPoint);
 
 // A wrapper that stores a `TxOut` along with metadata.
 #[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
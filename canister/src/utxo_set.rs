@@ -1,21 +1,33 @@
 use crate::{
     memory::Memory,
+    muhash::{self, MuHash3072},
     multi_iter::MultiIter,
-    runtime::{inc_performance_counter, performance_counter, print},
+    runtime::{inc_performance_counter, performance_counter},
     types::{
-        Address, AddressUtxo, Block, BlockHash, Network, OutPoint, Slicing, Storable, Transaction,
-        TxOut, Txid, Utxo,
+        Address, AddressUtxo, Block, BlockHash, HeartbeatLimits, Network, OutPoint, Slicing,
+        Storable, Transaction, TxOut, Txid, Utxo,
     },
 };
 use bitcoin::{Script, TxOut as BitcoinTxOut};
 use ic_btc_types::{Height, Satoshi};
 use ic_stable_structures::{StableBTreeMap, Storable as _};
 use serde::{Deserialize, Serialize};
-use std::{collections::BTreeSet, iter::Iterator, str::FromStr};
+use std::{
+    collections::{BTreeSet, VecDeque},
+    iter::Iterator,
+    str::FromStr,
+};
 mod utxos;
 mod utxos_delta;
 use utxos::Utxos;
-use utxos_delta::UtxosDelta;
+use utxos_delta::{SpentUtxo, UtxosDelta};
+
+/// The number of stable blocks that can be undone, unless configured otherwise.
+/// The undo information is kept on the heap and serialized on upgrades, so it's kept small.
+pub const DEFAULT_MAX_UNDO_LOG_SIZE: u32 = 3;
+
+/// The largest number of stable blocks that can be configured to be undone.
+pub const MAX_UNDO_LOG_SIZE: u32 = 12;
 
 lazy_static::lazy_static! {
     pub static ref DUPLICATE_TX_IDS: [Txid; 2] = [
@@ -40,6 +52,14 @@ pub struct UtxoSet {
     #[serde(skip, default = "init_balances")]
     balances: StableBTreeMap<Memory, Address, u64>,
 
+    // The outpoints of the UTXOs that were created by coinbase transactions.
+    // This is needed for computing the UTXO set's hash in a way that's compatible with bitcoind.
+    // UTXO sets created by previous versions of the canister don't have the coinbase UTXOs that
+    // were created before the upgrade, and so their hash is unknown. See `muhash`.
+    // NOTE: Stable structures don't need to be serialized.
+    #[serde(skip, default = "init_coinbase_utxos")]
+    coinbase_utxos: StableBTreeMap<Memory, OutPoint, ()>,
+
     // The height of the block that will be ingested next.
     // NOTE: The `next_height` is stored, rather than the current height, because:
     //   * The `UtxoSet` is initialized as empty with no blocks.
@@ -49,13 +69,47 @@ pub struct UtxoSet {
     // instead store the `next_height` to avoid having this special case.
     next_height: Height,
 
-    // The predicate used to determine whether or not we should time-slice.
-    // The default predicate is to check the performance counter, but can be overridden for tests.
+    // A rolling hash (MuHash3072) of all the UTXOs in the set, as of `next_height`.
+    // Changes made by the ingesting block are only applied once the block is fully ingested.
+    //
+    // `None` if the UTXO set was created by a version of the canister that didn't maintain the
+    // hash, as it can't be rebuilt without knowing which of the existing UTXOs are coinbase UTXOs.
+    //
+    // NOTE: Only the numerator and denominator of the hash are kept up to date. The expensive
+    // division is only done when the hash is queried, as queries don't count against the
+    // heartbeat's instructions budget.
+    #[serde(default)]
+    muhash: Option<MuHash3072>,
+
+    // The predicate used to determine whether or not we should time-slice, given the
+    // instructions budget. The default predicate is to check the performance counter, but can be
+    // overridden for tests.
     #[serde(skip, default = "default_should_time_slice")]
-    should_time_slice: Box<dyn FnMut() -> bool>,
+    should_time_slice: Box<dyn FnMut(u64) -> bool>,
+
+    // The number of instructions after which the ingestion of a block is paused.
+    #[serde(default = "default_instructions_budget")]
+    instructions_budget: u64,
 
     /// A block that is currently being ingested into the UtxoSet. Used for time slicing.
     pub ingesting_block: Option<IngestingBlock>,
+
+    // A stable block that is currently being undone. Used for time slicing.
+    #[serde(default)]
+    undoing_block: Option<UndoingBlock>,
+
+    // The information needed to undo the latest stable blocks, ordered from oldest to latest.
+    // Allows recovering from forks that are deeper than the stable blocks.
+    #[serde(default)]
+    undo_log: VecDeque<BlockUndo>,
+
+    // The maximum number of blocks in the undo log.
+    #[serde(default = "default_max_undo_log_size")]
+    max_undo_log_size: u32,
+
+    // The stats of the latest block that was fully ingested.
+    #[serde(default)]
+    last_ingestion_stats: Option<BlockIngestionStats>,
 }
 
 impl UtxoSet {
@@ -64,10 +118,17 @@ impl UtxoSet {
             utxos: Utxos::default(),
             balances: init_balances(),
             address_utxos: init_address_utxos(),
+            coinbase_utxos: init_coinbase_utxos(),
             network,
             next_height: 0,
+            muhash: Some(MuHash3072::default()),
             ingesting_block: None,
+            undoing_block: None,
             should_time_slice: default_should_time_slice(),
+            instructions_budget: default_instructions_budget(),
+            undo_log: VecDeque::new(),
+            max_undo_log_size: default_max_undo_log_size(),
+            last_ingestion_stats: None,
         }
     }
 
@@ -85,6 +146,11 @@ impl UtxoSet {
             "Cannot ingest new block while previous block (height {}) isn't fully ingested",
             self.next_height
         );
+        assert!(
+            self.undoing_block.is_none(),
+            "Cannot ingest new block while a block (height {}) isn't fully undone",
+            self.next_height - 1
+        );
 
         // Store in the state the new block to be ingested.
         self.ingesting_block = Some(IngestingBlock::new(block));
@@ -145,24 +211,33 @@ impl UtxoSet {
         }
 
         stats.ins_total += performance_counter() - ins_start;
-        print(&format!(
-            "[INSTRUCTION COUNT] Ingest Block {}: {:?}",
-            self.next_height, stats
-        ));
+        self.last_ingestion_stats = Some(stats);
 
         // Block ingestion complete.
+        if let Some(muhash) = self.muhash.as_mut() {
+            muhash.combine(utxos_delta.muhash());
+        }
         self.next_height += 1;
-        Some(Slicing::Done(block.block_hash()))
+
+        // Keep the information needed to undo the block.
+        let block_hash = block.block_hash();
+        let (spent_utxos, created_outpoints) = utxos_delta.into_undo_data();
+        self.undo_log.push_back(BlockUndo {
+            block,
+            spent_utxos,
+            created_outpoints,
+        });
+        self.truncate_undo_log();
+
+        Some(Slicing::Done(block_hash))
     }
 
     /// Returns the balance of the given address.
     pub fn get_balance(&self, address: &Address) -> Satoshi {
         let mut balance = self.balances.get(address).unwrap_or(0);
 
-        // Revert any changes to the balance that were done by the ingesting block.
-        if let Some(ingesting_block) = &self.ingesting_block {
-            let utxos_delta = &ingesting_block.utxos_delta;
-
+        // Revert any changes to the balance that were done by the ingesting or undoing block.
+        if let Some(utxos_delta) = self.partial_utxos_delta() {
             // Add any removed outpoints back to the balance.
             for outpoint in utxos_delta.get_removed_outpoints(address) {
                 let (tx_out, _) = utxos_delta.get_utxo(outpoint).expect("UTXO must exist");
@@ -181,22 +256,22 @@ impl UtxoSet {
 
     /// Returns the UTXO of the given outpoint.
     pub fn get_utxo(&self, outpoint: &OutPoint) -> Option<(TxOut, Height)> {
-        // Revert any changes to the UTXOs that were done by the ingesting block.
-        if let Some(b) = &self.ingesting_block {
-            if b.utxos_delta.is_outpoint_removed(outpoint) {
-                // The UTXO was removed by the ingesting block.
+        // Revert any changes to the UTXOs that were done by the ingesting or undoing block.
+        if let Some(utxos_delta) = self.partial_utxos_delta() {
+            if utxos_delta.is_outpoint_removed(outpoint) {
+                // The UTXO was removed by the block.
                 // Revert that removal by returning the UTXO.
-                return b.utxos_delta.get_utxo(outpoint).cloned();
+                return utxos_delta.get_utxo(outpoint).cloned();
             }
 
-            if b.utxos_delta.is_outpoint_added(outpoint) {
-                // The UTXO was added by the ingesting block.
+            if utxos_delta.is_outpoint_added(outpoint) {
+                // The UTXO was added by the block.
                 // Revert that addition by returning `None`.
                 return None;
             }
         };
 
-        // No modifications done by the ingesting block. Return the UTXO from the stable set.
+        // No modifications done by a partial block. Return the UTXO from the stable set.
         self.utxos.get(outpoint)
     }
 
@@ -207,17 +282,17 @@ impl UtxoSet {
         address: &Address,
         offset: &Option<Utxo>,
     ) -> impl Iterator<Item = OutPoint> + '_ {
-        // If there is an ingesting block, retrieve all the outpoints it added/removed.
-        let (added_outpoints, removed_outpoints) = match &self.ingesting_block {
-            Some(b) => (
-                b.utxos_delta.get_added_outpoints(address),
-                b.utxos_delta.get_removed_outpoints(address),
+        // If there is an ingesting or undoing block, retrieve all the outpoints it added/removed.
+        let (added_outpoints, removed_outpoints) = match self.partial_utxos_delta() {
+            Some(utxos_delta) => (
+                utxos_delta.get_added_outpoints(address),
+                utxos_delta.get_removed_outpoints(address),
             ),
             None => (BTreeSet::new(), BTreeSet::new()),
         };
 
         // Retrieve all address's outpoints from the stable set, removing any outpoints
-        // that were added by the ingesting block.
+        // that were added by the ingesting or undoing block.
         let stable_outpoints = self
             .address_utxos
             .range(
@@ -229,7 +304,7 @@ impl UtxoSet {
             .map(|(address_utxo, _)| address_utxo.outpoint)
             .filter(move |outpoint| !added_outpoints.contains(outpoint));
 
-        // Return the stable outpoints along with the outpoints removed by the ingesting block.
+        // Return the stable outpoints along with the outpoints removed by the partial block.
         MultiIter::new(stable_outpoints, removed_outpoints.into_iter().cloned())
     }
 
@@ -238,11 +313,39 @@ impl UtxoSet {
         self.utxos.len()
     }
 
+    /// Returns the stats of the latest block that was fully ingested, if any.
+    pub fn last_ingestion_stats(&self) -> Option<&BlockIngestionStats> {
+        self.last_ingestion_stats.as_ref()
+    }
+
     /// Returns the number of UTXOs that are owned by supported addresses.
     pub fn address_utxos_len(&self) -> u64 {
         self.address_utxos.len()
     }
 
+    pub fn set_instructions_budget(&mut self, instructions_budget: u64) {
+        self.instructions_budget = instructions_budget;
+    }
+
+    /// Returns the maximum number of stable blocks that can be undone.
+    pub fn max_undo_log_size(&self) -> u32 {
+        self.max_undo_log_size
+    }
+
+    /// Sets the maximum number of stable blocks that can be undone, dropping the undo
+    /// information of the oldest blocks if there's more.
+    pub fn set_max_undo_log_size(&mut self, max_undo_log_size: u32) {
+        self.max_undo_log_size = max_undo_log_size;
+        self.truncate_undo_log();
+    }
+
+    // Drops the oldest entries of the undo log that exceed its maximum size.
+    fn truncate_undo_log(&mut self) {
+        while self.undo_log.len() > self.max_undo_log_size as usize {
+            self.undo_log.pop_front();
+        }
+    }
+
     pub fn network(&self) -> Network {
         self.network
     }
@@ -251,6 +354,63 @@ impl UtxoSet {
         self.next_height
     }
 
+    /// Returns true if the UTXO with the given outpoint was created by a coinbase transaction.
+    pub fn is_coinbase(&self, outpoint: &OutPoint) -> bool {
+        self.coinbase_utxos.get(outpoint).is_some()
+    }
+
+    /// Returns the hash of the UTXO set as of the latest block that was fully ingested, as
+    /// displayed by bitcoind's `gettxoutsetinfo muhash`.
+    ///
+    /// Returns `None` if no block has been ingested yet, or if the hash is unknown because the
+    /// UTXO set was created by a version of the canister that didn't maintain it.
+    ///
+    /// NOTE: Finalizing the hash is expensive, so this should only be called by queries.
+    pub fn muhash_hex(&self) -> Option<String> {
+        if self.next_height == 0 {
+            return None;
+        }
+        self.muhash
+            .as_ref()
+            .map(|muhash| muhash::to_hex(&muhash.finalize()))
+    }
+
+    #[cfg(test)]
+    pub fn muhash(&self) -> &MuHash3072 {
+        self.muhash.as_ref().expect("the hash must be maintained")
+    }
+
+    /// Marks the UTXO with the given outpoint as created by a coinbase transaction.
+    ///
+    /// Used when building a UTXO set from a dump of bitcoind's UTXO set, along with
+    /// `compute_muhash`.
+    pub fn insert_coinbase_outpoint(&mut self, outpoint: OutPoint) {
+        self.coinbase_utxos
+            .insert(outpoint, ())
+            .expect("insertion must succeed");
+    }
+
+    /// Computes the hash of the UTXO set from scratch, which requires iterating over all the
+    /// UTXOs. The outputs of the genesis block aren't part of the hash, as in bitcoind.
+    ///
+    /// Used when building a UTXO set from a dump of bitcoind's UTXO set, as the hash cannot be
+    /// maintained while inserting the UTXOs directly.
+    pub fn compute_muhash(&self) -> MuHash3072 {
+        let mut muhash = MuHash3072::default();
+        for (outpoint, (tx_out, height)) in self.utxos.iter() {
+            if height > 0 {
+                let is_coinbase = self.coinbase_utxos.get(&outpoint).is_some();
+                muhash.insert(&muhash_element(&outpoint, &tx_out, height, is_coinbase));
+            }
+        }
+        muhash
+    }
+
+    /// Sets the hash of the UTXO set, e.g. one computed with `compute_muhash`.
+    pub fn set_muhash(&mut self, muhash: MuHash3072) {
+        self.muhash = Some(muhash);
+    }
+
     // Ingests a transaction into the given UTXO set.
     //
     // NOTE: This method does a form of time-slicing to stay within the instruction limit, and
@@ -294,7 +454,7 @@ impl UtxoSet {
         }
 
         for (input_idx, input) in tx.input().iter().enumerate().skip(start_idx) {
-            if (self.should_time_slice)() {
+            if (self.should_time_slice)(self.instructions_budget) {
                 return Slicing::Paused(input_idx);
             }
 
@@ -302,39 +462,29 @@ impl UtxoSet {
             let outpoint = (&input.previous_output).into();
             match self.utxos.remove(&outpoint) {
                 Some((txout, height)) => {
+                    let is_coinbase = self.coinbase_utxos.remove(&outpoint).is_some();
+                    // The outputs of the genesis block were never added to the hash.
+                    if height > 0 {
+                        utxos_delta.remove_from_muhash(&muhash_element(
+                            &outpoint,
+                            &txout,
+                            height,
+                            is_coinbase,
+                        ));
+                    }
+
+                    utxos_delta.record_spent(SpentUtxo {
+                        outpoint: outpoint.clone(),
+                        tx_out: txout.clone(),
+                        height,
+                        is_coinbase,
+                    });
+
                     if let Ok(address) = Address::from_script(
                         &Script::from(txout.script_pubkey.clone()),
                         self.network,
                     ) {
-                        let address_utxo = AddressUtxo {
-                            address: address.clone(),
-                            height,
-                            outpoint: outpoint.clone(),
-                        };
-
-                        let found = self.address_utxos.remove(&address_utxo);
-
-                        assert!(
-                            found.is_some(),
-                            "Outpoint {:?} not found in the index.",
-                            input.previous_output
-                        );
-
-                        // Update the balance of the address.
-                        if txout.value != 0 {
-                            let address_balance =
-                                self.balances.get(&address).unwrap_or_else(|| {
-                                    panic!("Address {} must exist in the balances map (trying to remove outpoint {:?})", address, input.previous_output);
-                                });
-
-                            match address_balance - txout.value {
-                                // Remove the address from the map if balance is zero.
-                                0 => self.balances.remove(&address),
-                                // Update the balance in the map.
-                                balance => self.balances.insert(address.clone(), balance).unwrap(),
-                            };
-                        }
-
+                        self.remove_from_address_index(&address, &outpoint, txout.value, height);
                         utxos_delta.remove(address, outpoint, txout, height);
                     }
                 }
@@ -356,7 +506,7 @@ impl UtxoSet {
         stats: &mut BlockIngestionStats,
     ) -> Slicing<usize, ()> {
         for (vout, output) in tx.output().iter().enumerate().skip(start_idx) {
-            if (self.should_time_slice)() {
+            if (self.should_time_slice)(self.instructions_budget) {
                 return Slicing::Paused(vout);
             }
 
@@ -369,6 +519,7 @@ impl UtxoSet {
                 self.insert_utxo(
                     OutPoint::new(txid, vout as u32),
                     output.clone(),
+                    tx.is_coin_base(),
                     utxos_delta,
                 );
                 stats.ins_insert_utxos += performance_counter() - ins_start;
@@ -384,29 +535,53 @@ impl UtxoSet {
         &mut self,
         outpoint: OutPoint,
         output: BitcoinTxOut,
+        is_coinbase: bool,
         utxos_delta: &mut UtxosDelta,
     ) {
         // Insert the outpoint.
         let tx_out: TxOut = (&output).into();
-        if let Ok(address) = Address::from_script(&output.script_pubkey, self.network) {
-            // Add the address to the index if we can parse it.
-            self.address_utxos
-                .insert(
-                    AddressUtxo {
-                        address: address.clone(),
-                        height: self.next_height,
-                        outpoint: outpoint.clone(),
-                    },
-                    (),
-                )
-                .expect("insertion must succeed");
 
-            // Update the balance of the address.
-            let address_balance = self.balances.get(&address).unwrap_or(0);
-            self.balances
-                .insert(address.clone(), address_balance + output.value)
+        // A duplicate transaction overwrites the UTXO of its predecessor (see below), so
+        // the overwritten UTXO is removed from the hash.
+        if DUPLICATE_TX_IDS.contains(&outpoint.txid) {
+            if let Some((old_tx_out, old_height)) = self.utxos.get(&outpoint) {
+                let old_is_coinbase = self.coinbase_utxos.get(&outpoint).is_some();
+                utxos_delta.remove_from_muhash(&muhash_element(
+                    &outpoint,
+                    &old_tx_out,
+                    old_height,
+                    old_is_coinbase,
+                ));
+                utxos_delta.record_spent(SpentUtxo {
+                    outpoint: outpoint.clone(),
+                    tx_out: old_tx_out,
+                    height: old_height,
+                    is_coinbase: old_is_coinbase,
+                });
+            }
+        }
+        utxos_delta.record_created(outpoint.clone());
+
+        // bitcoind never adds the outputs of the genesis block to its UTXO set, so they're
+        // left out of the hash as well.
+        if self.next_height > 0 {
+            utxos_delta.insert_into_muhash(&muhash_element(
+                &outpoint,
+                &tx_out,
+                self.next_height,
+                is_coinbase,
+            ));
+        }
+
+        if is_coinbase {
+            self.coinbase_utxos
+                .insert(outpoint.clone(), ())
                 .expect("insertion must succeed");
+        }
 
+        if let Ok(address) = Address::from_script(&output.script_pubkey, self.network) {
+            // Add the address to the index if we can parse it.
+            self.add_to_address_index(&address, &outpoint, output.value, self.next_height);
             utxos_delta.insert(address, outpoint.clone(), tx_out.clone(), self.next_height);
         }
 
@@ -429,6 +604,226 @@ impl UtxoSet {
         }
     }
 
+    // Adds a UTXO to the index of the given address and updates the address's balance.
+    fn add_to_address_index(
+        &mut self,
+        address: &Address,
+        outpoint: &OutPoint,
+        value: Satoshi,
+        height: Height,
+    ) {
+        self.address_utxos
+            .insert(
+                AddressUtxo {
+                    address: address.clone(),
+                    height,
+                    outpoint: outpoint.clone(),
+                },
+                (),
+            )
+            .expect("insertion must succeed");
+
+        // Update the balance of the address.
+        let address_balance = self.balances.get(address).unwrap_or(0);
+        self.balances
+            .insert(address.clone(), address_balance + value)
+            .expect("insertion must succeed");
+    }
+
+    // Removes a UTXO from the index of the given address and updates the address's balance.
+    fn remove_from_address_index(
+        &mut self,
+        address: &Address,
+        outpoint: &OutPoint,
+        value: Satoshi,
+        height: Height,
+    ) {
+        let found = self.address_utxos.remove(&AddressUtxo {
+            address: address.clone(),
+            height,
+            outpoint: outpoint.clone(),
+        });
+
+        assert!(
+            found.is_some(),
+            "Outpoint {:?} not found in the index.",
+            outpoint
+        );
+
+        // Update the balance of the address.
+        if value != 0 {
+            let address_balance = self.balances.get(address).unwrap_or_else(|| {
+                panic!(
+                    "Address {} must exist in the balances map (trying to remove outpoint {:?})",
+                    address, outpoint
+                );
+            });
+
+            match address_balance - value {
+                // Remove the address from the map if balance is zero.
+                0 => self.balances.remove(address),
+                // Update the balance in the map.
+                balance => self.balances.insert(address.clone(), balance).unwrap(),
+            };
+        }
+    }
+
+    /// Returns the blocks that can be undone, from the latest to the oldest.
+    pub fn undoable_blocks(&self) -> impl Iterator<Item = &Block> + '_ {
+        self.undo_log.iter().rev().map(|u| &u.block)
+    }
+
+    /// Returns the hashes of the blocks that can be undone, from the latest to the oldest.
+    pub fn undoable_block_hashes(&self) -> impl Iterator<Item = BlockHash> + '_ {
+        self.undoable_blocks().map(|block| block.block_hash())
+    }
+
+    /// Undoes the ingestion of the latest stable block.
+    ///
+    /// Returns `None` if there is no block to undo, either because the undo log is empty, or
+    /// because a block is currently being ingested or undone.
+    ///
+    /// NOTE: Like ingestion, undoing a block is time-sliced. Returns `Slicing::Done` with the
+    /// block once it's fully undone, or `Slicing::Paused` if one or more calls to
+    /// `undo_block_continue` are necessary to finish it. In the meantime, the UTXO set is
+    /// queried as if the block wasn't undone.
+    pub fn undo_block(&mut self) -> Option<Slicing<(), Block>> {
+        if self.ingesting_block.is_some() || self.undoing_block.is_some() {
+            return None;
+        }
+
+        let undo = self.undo_log.pop_back()?;
+        self.undoing_block = Some(UndoingBlock {
+            undo,
+            next_created_idx: 0,
+            next_spent_idx: 0,
+            utxos_delta: UtxosDelta::default(),
+        });
+        self.undo_block_continue()
+    }
+
+    /// Continues undoing a block that is partially undone.
+    /// Returns `None` if there was no block to continue undoing.
+    pub fn undo_block_continue(&mut self) -> Option<Slicing<(), Block>> {
+        let UndoingBlock {
+            undo,
+            mut next_created_idx,
+            mut next_spent_idx,
+            mut utxos_delta,
+        } = self.undoing_block.take()?;
+
+        // The height of the block being undone.
+        let height = self.next_height - 1;
+
+        // Remove the UTXOs that were created by the block.
+        while next_created_idx < undo.created_outpoints.len() {
+            if (self.should_time_slice)(self.instructions_budget) {
+                self.undoing_block = Some(UndoingBlock {
+                    undo,
+                    next_created_idx,
+                    next_spent_idx,
+                    utxos_delta,
+                });
+                return Some(Slicing::Paused(()));
+            }
+
+            let outpoint = &undo.created_outpoints[next_created_idx];
+            let (tx_out, utxo_height) = self
+                .utxos
+                .remove(outpoint)
+                .expect("a UTXO created by the block must exist");
+            assert_eq!(utxo_height, height);
+
+            let is_coinbase = self.coinbase_utxos.remove(outpoint).is_some();
+            if height > 0 {
+                utxos_delta.remove_from_muhash(&muhash_element(
+                    outpoint,
+                    &tx_out,
+                    height,
+                    is_coinbase,
+                ));
+            }
+
+            if let Ok(address) =
+                Address::from_script(&Script::from(tx_out.script_pubkey.clone()), self.network)
+            {
+                self.remove_from_address_index(&address, outpoint, tx_out.value, height);
+                utxos_delta.remove(address, outpoint.clone(), tx_out, height);
+            }
+
+            next_created_idx += 1;
+        }
+
+        // Restore the UTXOs that were spent by the block, the latest spent first.
+        while next_spent_idx < undo.spent_utxos.len() {
+            if (self.should_time_slice)(self.instructions_budget) {
+                self.undoing_block = Some(UndoingBlock {
+                    undo,
+                    next_created_idx,
+                    next_spent_idx,
+                    utxos_delta,
+                });
+                return Some(Slicing::Paused(()));
+            }
+
+            let SpentUtxo {
+                outpoint,
+                tx_out,
+                height,
+                is_coinbase,
+            } = undo.spent_utxos[undo.spent_utxos.len() - 1 - next_spent_idx].clone();
+
+            if is_coinbase {
+                self.coinbase_utxos
+                    .insert(outpoint.clone(), ())
+                    .expect("insertion must succeed");
+            }
+
+            if height > 0 {
+                utxos_delta.insert_into_muhash(&muhash_element(
+                    &outpoint,
+                    &tx_out,
+                    height,
+                    is_coinbase,
+                ));
+            }
+
+            if let Ok(address) =
+                Address::from_script(&Script::from(tx_out.script_pubkey.clone()), self.network)
+            {
+                self.add_to_address_index(&address, &outpoint, tx_out.value, height);
+                utxos_delta.insert(address, outpoint.clone(), tx_out.clone(), height);
+            }
+
+            self.utxos.insert(outpoint, (tx_out, height));
+            next_spent_idx += 1;
+        }
+
+        // Block undo complete.
+        if let Some(muhash) = self.muhash.as_mut() {
+            muhash.combine(utxos_delta.muhash());
+        }
+        self.next_height -= 1;
+
+        Some(Slicing::Done(undo.block))
+    }
+
+    /// Returns true if a block is partially ingested or undone, in which case the UTXOs stored
+    /// in the set are a mix of the UTXOs before and after the block.
+    pub fn has_partial_block(&self) -> bool {
+        self.ingesting_block.is_some() || self.undoing_block.is_some()
+    }
+
+    // Returns the changes made so far by the block that is partially ingested or undone, if any.
+    // Queries revert these changes, so that the UTXO set is seen as it was before the block.
+    fn partial_utxos_delta(&self) -> Option<&UtxosDelta> {
+        match (&self.ingesting_block, &self.undoing_block) {
+            (Some(ingesting_block), _) => Some(&ingesting_block.utxos_delta),
+            (None, Some(undoing_block)) => Some(&undoing_block.utxos_delta),
+            (None, None) => None,
+        }
+    }
+
     #[cfg(test)]
     pub fn get_total_supply(&self) -> Satoshi {
         self.utxos.iter().map(|(_, (v, _))| v.value).sum()
@@ -443,6 +838,58 @@ fn init_balances() -> StableBTreeMap<Memory, Address, u64> {
     StableBTreeMap::init(crate::memory::get_balances_memory())
 }
 
+fn init_coinbase_utxos() -> StableBTreeMap<Memory, OutPoint, ()> {
+    StableBTreeMap::init(crate::memory::get_coinbase_utxos_memory())
+}
+
+// Serializes a UTXO the same way bitcoind does when computing the UTXO set's hash.
+//
+// See `TxOutSer` in bitcoind's `kernel/coinstats.cpp`.
+fn muhash_element(
+    outpoint: &OutPoint,
+    tx_out: &TxOut,
+    height: Height,
+    is_coinbase: bool,
+) -> Vec<u8> {
+    use bitcoin::consensus::Encodable;
+
+    let mut bytes = outpoint.txid.as_bytes().to_vec();
+    bytes.extend_from_slice(&outpoint.vout.to_le_bytes());
+    bytes.extend_from_slice(&(height * 2 + is_coinbase as u32).to_le_bytes());
+    bytes.extend_from_slice(&tx_out.value.to_le_bytes());
+    Script::from(tx_out.script_pubkey.clone())
+        .consensus_encode(&mut bytes)
+        .expect("encoding a script must succeed");
+    bytes
+}
+
+/// The information needed to undo the ingestion of a stable block.
+#[derive(Serialize, Deserialize, PartialEq, Clone, Debug, Eq)]
+struct BlockUndo {
+    block: Block,
+
+    // The UTXOs that the block spent.
+    spent_utxos: Vec<SpentUtxo>,
+
+    // The outpoints of the UTXOs that the block created.
+    created_outpoints: Vec<OutPoint>,
+}
+
+/// A stable block that is partially undone. Used for time slicing.
+#[derive(Serialize, Deserialize, PartialEq, Clone, Debug, Eq)]
+struct UndoingBlock {
+    undo: BlockUndo,
+
+    // The number of UTXOs created by the block that have been removed so far.
+    next_created_idx: usize,
+
+    // The number of UTXOs spent by the block that have been restored so far, the latest first.
+    next_spent_idx: usize,
+
+    // The changes made to the UTXO set so far.
+    utxos_delta: UtxosDelta,
+}
+
 /// A state for maintaining a stable block that is partially ingested into the UTXO set.
 /// Used for time slicing.
 #[derive(Serialize, Deserialize, PartialEq, Clone, Debug, Eq)]
@@ -485,26 +932,26 @@ impl IngestingBlock {
     }
 }
 
-// Various profiling stats for tracking the performance of block ingestion.
+/// Various profiling stats for tracking the performance of block ingestion.
 #[derive(Serialize, Deserialize, PartialEq, Clone, Debug, Eq, Default)]
-struct BlockIngestionStats {
-    // The number of rounds it took to ingest the block.
-    num_rounds: u32,
+pub struct BlockIngestionStats {
+    /// The number of rounds it took to ingest the block.
+    pub num_rounds: u32,
 
-    // The total number of instructions used to ingest the block.
-    ins_total: u64,
+    /// The total number of instructions used to ingest the block.
+    pub ins_total: u64,
 
-    // The number of instructions used to remove the transaction inputs.
-    ins_remove_inputs: u64,
+    /// The number of instructions used to remove the transaction inputs.
+    pub ins_remove_inputs: u64,
 
-    // The number of instructions used to insert the transaction outputs.
-    ins_insert_outputs: u64,
+    /// The number of instructions used to insert the transaction outputs.
+    pub ins_insert_outputs: u64,
 
-    // The number of instructions used to compute the txids.
-    ins_txids: u64,
+    /// The number of instructions used to compute the txids.
+    pub ins_txids: u64,
 
-    // The number of instructions used to insert new utxos.
-    ins_insert_utxos: u64,
+    /// The number of instructions used to insert new utxos.
+    pub ins_insert_utxos: u64,
 }
 
 // NOTE: `PartialEq` is only available in tests as it would be impractically
@@ -517,27 +964,36 @@ impl PartialEq for UtxoSet {
             && self.network == other.network
             && self.next_height == other.next_height
             && self.ingesting_block == other.ingesting_block
+            && self.undoing_block == other.undoing_block
             && is_stable_btreemap_equal(&self.address_utxos, &other.address_utxos)
             && is_stable_btreemap_equal(&self.balances, &other.balances)
+            && is_stable_btreemap_equal(&self.coinbase_utxos, &other.coinbase_utxos)
+            && self.muhash == other.muhash
+            && self.undo_log == other.undo_log
+            && self.max_undo_log_size == other.max_undo_log_size
     }
 }
 
-// The default predicate to use for time-slicing.
-// Checks that we're not approaching the instructions limit.
-fn default_should_time_slice() -> Box<dyn FnMut() -> bool> {
-    // The threshold at which time slicing kicks in.
-    // At the time of this writing it is equivalent to 80% of the maximum instructions limit.
-    const MAX_INSTRUCTIONS_THRESHOLD: u64 = 4_000_000_000;
+fn default_max_undo_log_size() -> u32 {
+    DEFAULT_MAX_UNDO_LOG_SIZE
+}
+
+fn default_instructions_budget() -> u64 {
+    HeartbeatLimits::default().ingestion_instructions
+}
 
+// The default predicate to use for time-slicing.
+// Checks that we're not exceeding the instructions budget.
+fn default_should_time_slice() -> Box<dyn FnMut(u64) -> bool> {
     // NOTE: We're using `inc_performance_counter` here to also increment the mock performance
     // counter in the unit tests.
-    Box::new(|| inc_performance_counter() >= MAX_INSTRUCTIONS_THRESHOLD)
+    Box::new(|instructions_budget| inc_performance_counter() >= instructions_budget)
 }
 
 #[cfg(test)]
 mod test {
     use super::*;
-    use crate::test_utils::{random_p2pkh_address, BlockBuilder, TransactionBuilder};
+    use crate::test_utils::{build_chain, random_p2pkh_address, BlockBuilder, TransactionBuilder};
     use crate::{
         address_utxoset::AddressUtxoSet,
         types::{Network, OutPoint, Txid},
@@ -1058,11 +1514,270 @@ mod test {
         }
     }
 
+    #[test]
+    fn undo_log_is_bounded_by_its_max_size() {
+        let network = Network::Regtest;
+        let blocks = build_chain(network, 6, 1);
+        let mut utxo_set = UtxoSet::new(network);
+        for block in blocks.iter() {
+            assert_eq!(
+                utxo_set.ingest_block(block.clone()),
+                Slicing::Done(block.block_hash())
+            );
+        }
+
+        let latest_hashes = |n: usize| -> Vec<BlockHash> {
+            blocks
+                .iter()
+                .rev()
+                .take(n)
+                .map(|b| b.block_hash())
+                .collect()
+        };
+        assert_eq!(
+            utxo_set.undoable_block_hashes().collect::<Vec<_>>(),
+            latest_hashes(DEFAULT_MAX_UNDO_LOG_SIZE as usize)
+        );
+
+        // Lowering the max size drops the oldest blocks of the undo log.
+        utxo_set.set_max_undo_log_size(1);
+        assert_eq!(
+            utxo_set.undoable_block_hashes().collect::<Vec<_>>(),
+            latest_hashes(1)
+        );
+
+        utxo_set.set_max_undo_log_size(0);
+        assert_eq!(utxo_set.undo_block(), None);
+    }
+
+    #[test]
+    fn undo_block_restores_the_utxo_set() {
+        let network = Network::Regtest;
+        let address_1 = random_p2pkh_address(network);
+        let address_2 = random_p2pkh_address(network);
+
+        let mut utxo_set = UtxoSet::new(network);
+
+        let coinbase_tx = TransactionBuilder::coinbase()
+            .with_output(&address_1, 1000)
+            .build();
+        let block_0 = BlockBuilder::genesis()
+            .with_transaction(coinbase_tx.clone())
+            .build();
+
+        // Block 1 spends the output of block 0, and also creates and spends a UTXO within
+        // the same block.
+        let tx_1 = TransactionBuilder::new()
+            .with_input(OutPoint::new(coinbase_tx.txid(), 0))
+            .with_output(&address_2, 600)
+            .with_output(&address_1, 400)
+            .build();
+        let tx_2 = TransactionBuilder::new()
+            .with_input(OutPoint::new(tx_1.txid(), 0))
+            .with_output(&address_2, 600)
+            .build();
+        let block_1 = BlockBuilder::with_prev_header(block_0.header())
+            .with_transaction(
+                TransactionBuilder::coinbase()
+                    .with_output(&address_2, 50)
+                    .build(),
+            )
+            .with_transaction(tx_1)
+            .with_transaction(tx_2)
+            .build();
+
+        assert_eq!(
+            utxo_set.ingest_block(block_0.clone()),
+            Slicing::Done(block_0.block_hash())
+        );
+        let utxos_len = utxo_set.utxos_len();
+        let address_utxos_len = utxo_set.address_utxos_len();
+        let muhash = utxo_set.muhash().finalize();
+
+        assert_eq!(
+            utxo_set.ingest_block(block_1.clone()),
+            Slicing::Done(block_1.block_hash())
+        );
+        assert_eq!(utxo_set.get_balance(&address_1), 400);
+        assert_eq!(utxo_set.get_balance(&address_2), 650);
+        assert_eq!(
+            utxo_set.undoable_block_hashes().collect::<Vec<_>>(),
+            vec![block_1.block_hash(), block_0.block_hash()]
+        );
+
+        assert_eq!(utxo_set.undo_block(), Some(Slicing::Done(block_1)));
+        assert_eq!(utxo_set.next_height(), 1);
+        assert_eq!(utxo_set.get_balance(&address_1), 1000);
+        assert_eq!(utxo_set.get_balance(&address_2), 0);
+        assert_eq!(utxo_set.utxos_len(), utxos_len);
+        assert_eq!(utxo_set.address_utxos_len(), address_utxos_len);
+        assert_eq!(utxo_set.muhash().finalize(), muhash);
+        assert_eq!(utxo_set.muhash_hex(), Some(muhash::to_hex(&muhash)));
+        assert_eq!(
+            utxo_set
+                .get_utxo(&OutPoint::new(coinbase_tx.txid(), 0))
+                .map(|(tx_out, height)| (tx_out.value, height)),
+            Some((1000, 0))
+        );
+
+        assert_eq!(utxo_set.undo_block(), Some(Slicing::Done(block_0)));
+        assert_eq!(utxo_set.undo_block(), None);
+        assert_eq!(utxo_set.next_height(), 0);
+        assert_eq!(utxo_set.utxos_len(), 0);
+        assert_eq!(utxo_set.get_balance(&address_1), 0);
+    }
+
+    #[test]
+    fn undo_block_with_time_slicing() {
+        let network = Network::Regtest;
+        let address_1 = random_p2pkh_address(network);
+        let address_2 = random_p2pkh_address(network);
+
+        let mut utxo_set = UtxoSet::new(network);
+
+        let coinbase_tx = TransactionBuilder::coinbase()
+            .with_output(&address_1, 1000)
+            .build();
+        let block_0 = BlockBuilder::genesis()
+            .with_transaction(coinbase_tx.clone())
+            .build();
+        let tx = TransactionBuilder::new()
+            .with_input(OutPoint::new(coinbase_tx.txid(), 0))
+            .with_output(&address_2, 400)
+            .with_output(&address_2, 600)
+            .build();
+        let block_1 = BlockBuilder::with_prev_header(block_0.header())
+            .with_transaction(TransactionBuilder::coinbase().build())
+            .with_transaction(tx)
+            .build();
+
+        assert_eq!(
+            utxo_set.ingest_block(block_0.clone()),
+            Slicing::Done(block_0.block_hash())
+        );
+        let muhash = utxo_set.muhash().finalize();
+        assert_eq!(
+            utxo_set.ingest_block(block_1.clone()),
+            Slicing::Done(block_1.block_hash())
+        );
+        let outpoints_2 = utxo_set
+            .get_address_outpoints(&address_2, &None)
+            .collect::<Vec<_>>();
+
+        // Time-slice after every UTXO that is removed or restored.
+        utxo_set.should_time_slice = ingestion_rate_predicate(1);
+        let mut res = utxo_set.undo_block();
+        while res == Some(Slicing::Paused(())) {
+            // The block isn't undone until it's fully undone.
+            assert!(utxo_set.has_partial_block());
+            assert_eq!(utxo_set.next_height(), 2);
+            assert_eq!(utxo_set.get_balance(&address_1), 0);
+            assert_eq!(utxo_set.get_balance(&address_2), 1000);
+            assert_eq!(
+                utxo_set
+                    .get_address_outpoints(&address_2, &None)
+                    .collect::<Vec<_>>(),
+                outpoints_2
+            );
+            assert_eq!(
+                utxo_set.get_utxo(&OutPoint::new(coinbase_tx.txid(), 0)),
+                None
+            );
+
+            // Blocks can't be ingested or undone while a block is being undone.
+            assert_eq!(utxo_set.undo_block(), None);
+            res = utxo_set.undo_block_continue();
+        }
+
+        assert_eq!(res, Some(Slicing::Done(block_1)));
+        assert!(!utxo_set.has_partial_block());
+        assert_eq!(utxo_set.next_height(), 1);
+        assert_eq!(utxo_set.get_balance(&address_1), 1000);
+        assert_eq!(utxo_set.get_balance(&address_2), 0);
+        assert_eq!(utxo_set.muhash().finalize(), muhash);
+    }
+
+    #[test]
+    fn muhash_is_updated_on_ingestion() {
+        let network = Network::Regtest;
+        let address_1 = random_p2pkh_address(network);
+        let address_2 = random_p2pkh_address(network);
+
+        let mut utxo_set = UtxoSet::new(network);
+        // Time-slice after every input/output.
+        utxo_set.should_time_slice = ingestion_rate_predicate(1);
+
+        let block_0 = BlockBuilder::genesis()
+            .with_transaction(
+                TransactionBuilder::coinbase()
+                    .with_output(&address_1, 1)
+                    .build(),
+            )
+            .build();
+
+        let coinbase_tx = TransactionBuilder::coinbase()
+            .with_output(&address_1, 1000)
+            .with_output(&address_1, 2000)
+            .build();
+        let block_1 = BlockBuilder::with_prev_header(block_0.header())
+            .with_transaction(coinbase_tx.clone())
+            .build();
+
+        let tx = TransactionBuilder::new()
+            .with_input(OutPoint::new(coinbase_tx.txid(), 0))
+            .with_output(&address_2, 500)
+            .with_output(&address_2, 500)
+            .build();
+        let coinbase_tx_2 = TransactionBuilder::coinbase()
+            .with_output(&address_2, 3000)
+            .build();
+        let block_2 = BlockBuilder::with_prev_header(block_1.header())
+            .with_transaction(coinbase_tx_2.clone())
+            .with_transaction(tx.clone())
+            .build();
+
+        for block in [block_0, block_1, block_2].iter() {
+            let muhash_before = utxo_set.muhash().clone();
+            let mut res = utxo_set.ingest_block(block.clone());
+            while res == Slicing::Paused(()) {
+                // The hash isn't updated until the block is fully ingested.
+                assert_eq!(utxo_set.muhash(), &muhash_before);
+                res = utxo_set.ingest_block_continue().unwrap();
+            }
+
+            assert_eq!(
+                utxo_set.muhash().finalize(),
+                utxo_set.compute_muhash().finalize()
+            );
+            assert_eq!(
+                utxo_set.muhash_hex(),
+                Some(muhash::to_hex(&utxo_set.muhash().finalize()))
+            );
+        }
+
+        // The outputs of the genesis block aren't included in the hash.
+        let mut expected = MuHash3072::default();
+        for (transaction, vout, height, is_coinbase) in [
+            (&coinbase_tx, 1, 1, true),
+            (&coinbase_tx_2, 0, 2, true),
+            (&tx, 0, 2, false),
+            (&tx, 1, 2, false),
+        ] {
+            expected.insert(&muhash_element(
+                &OutPoint::new(transaction.txid(), vout),
+                &(&transaction.output()[vout as usize]).into(),
+                height,
+                is_coinbase,
+            ));
+        }
+        assert_eq!(utxo_set.muhash().finalize(), expected.finalize());
+    }
+
     // A predicate that allows the Utxo Set to ingest `ingestion_rate` inputs/outputs,
     // then triggers time-slicing.
-    fn ingestion_rate_predicate(ingestion_rate: u32) -> Box<dyn FnMut() -> bool> {
+    fn ingestion_rate_predicate(ingestion_rate: u32) -> Box<dyn FnMut(u64) -> bool> {
         let mut count = ingestion_rate + 1;
-        Box::new(move || {
+        Box::new(move |_| {
             count -= 1;
             if count == 0 {
                 // Trigger time-slicing, but reset the counter before doing so.
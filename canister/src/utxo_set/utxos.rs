@@ -1,12 +1,11 @@
 use crate::{
     memory::Memory,
+    multi_iter::MultiIter,
     state::{UTXO_KEY_SIZE, UTXO_VALUE_MAX_SIZE_MEDIUM, UTXO_VALUE_MAX_SIZE_SMALL},
     types::{OutPoint, Storable, TxOut},
 };
 use ic_btc_types::Height;
-#[cfg(test)]
-use ic_stable_structures::{btreemap, Memory as MemoryTrait};
-use ic_stable_structures::{StableBTreeMap, Storable as StableStructuresStorable};
+use ic_stable_structures::{btreemap, StableBTreeMap, Storable as StableStructuresStorable};
 use serde::{Deserialize, Serialize};
 use std::collections::BTreeMap;
 
@@ -137,10 +136,15 @@ impl Utxos {
     }
 
     /// Gets an iterator over the entries of the map.
-    /// NOTE: The entries are not guaranteed to be sorted in any particular way.
-    #[cfg(test)]
-    pub fn iter(&self) -> Iter<Memory> {
-        Iter::new(self)
+    /// The entries are sorted by the byte representation of their outpoints.
+    pub fn iter(&self) -> Iter {
+        Iter::new(self, None)
+    }
+
+    /// Gets an iterator over the entries of the map that come strictly after the given outpoint.
+    /// The entries are sorted by the byte representation of their outpoints.
+    pub fn iter_after(&self, offset: &OutPoint) -> Iter {
+        Iter::new(self, Some(offset))
     }
 
     pub fn len(&self) -> u64 {
@@ -153,51 +157,66 @@ impl Utxos {
     }
 }
 
+// The items of the underlying maps, represented as (key bytes, value bytes).
+type RawUtxo = (Vec<u8>, Vec<u8>);
+
 /// An iterator over the entries in [`Utxos`].
-#[cfg(test)]
 #[must_use = "iterators are lazy and do nothing unless consumed"]
-pub struct Iter<'a, M: MemoryTrait> {
-    small_utxos_iter: btreemap::Iter<'a, M, Vec<u8>, Vec<u8>>,
-    medium_utxos_iter: btreemap::Iter<'a, M, Vec<u8>, Vec<u8>>,
-    large_utxos_iter: std::collections::btree_map::Iter<'a, OutPoint, (TxOut, Height)>,
+pub struct Iter<'a> {
+    inner: MultiIter<
+        RawUtxo,
+        MultiIter<
+            RawUtxo,
+            btreemap::Iter<'a, Memory, Vec<u8>, Vec<u8>>,
+            btreemap::Iter<'a, Memory, Vec<u8>, Vec<u8>>,
+        >,
+        std::vec::IntoIter<RawUtxo>,
+    >,
+    offset: Option<Vec<u8>>,
 }
 
-#[cfg(test)]
-impl<'a> Iter<'a, Memory> {
-    fn new(utxos: &'a Utxos) -> Self {
+impl<'a> Iter<'a> {
+    fn new(utxos: &'a Utxos, offset: Option<&OutPoint>) -> Self {
+        let offset = offset.map(|o| o.to_bytes().to_vec());
+
+        // The large UTXOs are sorted by `OutPoint`, which doesn't necessarily match the order
+        // of their bytes. There are very few of them, so they're collected and sorted here.
+        let mut large_utxos: Vec<RawUtxo> = utxos
+            .large_utxos
+            .iter()
+            .map(|(k, v)| (k.to_bytes().to_vec(), v.to_bytes()))
+            .filter(|(k, _)| offset.as_ref().map_or(true, |offset| k > offset))
+            .collect();
+        large_utxos.sort();
+
         Self {
-            small_utxos_iter: utxos.small_utxos.iter(),
-            medium_utxos_iter: utxos.medium_utxos.iter(),
-            large_utxos_iter: utxos.large_utxos.iter(),
+            inner: MultiIter::new(
+                MultiIter::new(
+                    utxos.small_utxos.range(vec![], offset.clone()),
+                    utxos.medium_utxos.range(vec![], offset.clone()),
+                ),
+                large_utxos.into_iter(),
+            ),
+            offset,
         }
     }
 }
 
-#[cfg(test)]
-impl<M: MemoryTrait + Clone> Iterator for Iter<'_, M> {
+impl Iterator for Iter<'_> {
     type Item = (OutPoint, (TxOut, Height));
 
     fn next(&mut self) -> Option<Self::Item> {
-        // First, iterate over the small utxos.
-        if let Some((key_bytes, value_bytes)) = self.small_utxos_iter.next() {
-            return Some((
-                OutPoint::from_bytes(key_bytes),
-                <(TxOut, Height)>::from_bytes(value_bytes),
-            ));
-        }
+        let (key_bytes, value_bytes) = self.inner.next()?;
 
-        // Second, iterate over the medium utxos.
-        if let Some((key_bytes, value_bytes)) = self.medium_utxos_iter.next() {
-            return Some((
-                OutPoint::from_bytes(key_bytes),
-                <(TxOut, Height)>::from_bytes(value_bytes),
-            ));
+        // Ranges in stable maps are inclusive of the offset, so it's skipped here.
+        if Some(&key_bytes) == self.offset.as_ref() {
+            return self.next();
         }
 
-        // Finally, iterate over the large utxos.
-        self.large_utxos_iter
-            .next()
-            .map(|(k, v)| (k.clone(), v.clone()))
+        Some((
+            OutPoint::from_bytes(key_bytes),
+            <(TxOut, Height)>::from_bytes(value_bytes),
+        ))
     }
 }
 
@@ -216,3 +235,56 @@ fn init_medium_utxos() -> StableBTreeMap<Memory, Vec<u8>, Vec<u8>> {
         UTXO_VALUE_MAX_SIZE_MEDIUM,
     )
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::types::Txid;
+    use proptest::prelude::*;
+
+    proptest! {
+        #[test]
+        fn iter_is_sorted_across_buckets(
+            utxos in proptest::collection::btree_map(
+                (proptest::collection::vec(any::<u8>(), 32), any::<u32>()),
+                (any::<u64>(), 0..300usize, any::<u32>()),
+                1..50,
+            ),
+            offset_idx in any::<prop::sample::Index>(),
+        ) {
+            let mut map = Utxos::default();
+            for ((txid, vout), (value, script_size, height)) in utxos {
+                map.insert(
+                    OutPoint::new(Txid::from(txid), vout),
+                    (
+                        TxOut {
+                            value,
+                            script_pubkey: vec![0; script_size],
+                        },
+                        height,
+                    ),
+                );
+            }
+
+            let mut expected: Vec<_> = map
+                .small_utxos
+                .iter()
+                .chain(map.medium_utxos.iter())
+                .map(|(k, _)| k)
+                .chain(map.large_utxos.keys().map(|k| k.to_bytes().to_vec()))
+                .collect();
+            expected.sort();
+
+            let actual: Vec<_> = map.iter().map(|(k, _)| k.to_bytes().to_vec()).collect();
+            prop_assert_eq!(&actual, &expected);
+
+            // Iterating after an offset returns the remaining entries.
+            let offset = offset_idx.index(expected.len());
+            let actual: Vec<_> = map
+                .iter_after(&OutPoint::from_bytes(expected[offset].clone()))
+                .map(|(k, _)| k.to_bytes().to_vec())
+                .collect();
+            prop_assert_eq!(&actual, &expected[offset + 1..].to_vec());
+        }
+    }
+}
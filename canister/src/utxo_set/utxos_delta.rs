@@ -1,4 +1,7 @@
-use crate::types::{Address, OutPoint, TxOut};
+use crate::{
+    muhash::MuHash3072,
+    types::{Address, OutPoint, TxOut},
+};
 use ic_btc_types::Height;
 use serde::{Deserialize, Serialize};
 use std::{
@@ -25,6 +28,27 @@ pub struct UtxosDelta {
 
     // UTXOs that are added/removed.
     utxos: BTreeMap<OutPoint, (TxOut, Height)>,
+
+    // The changes made to the hash of the UTXO set. Unlike the fields above, this tracks all
+    // the UTXOs, including the ones that don't belong to a supported address.
+    muhash: MuHash3072,
+
+    // UTXOs that existed before the block and were spent by it, in the order they were spent.
+    // Like `muhash`, this tracks all the UTXOs and is used for undoing the block.
+    spent_utxos: Vec<SpentUtxo>,
+
+    // Outpoints that were created by the block and weren't spent by it.
+    // Like `muhash`, this tracks all the UTXOs and is used for undoing the block.
+    created_outpoints: BTreeSet<OutPoint>,
+}
+
+/// A UTXO that was spent by a block.
+#[derive(Serialize, Deserialize, PartialEq, Clone, Debug, Eq)]
+pub struct SpentUtxo {
+    pub outpoint: OutPoint,
+    pub tx_out: TxOut,
+    pub height: Height,
+    pub is_coinbase: bool,
 }
 
 impl UtxosDelta {
@@ -98,4 +122,39 @@ impl UtxosDelta {
     pub fn get_utxo(&self, outpoint: &OutPoint) -> Option<&(TxOut, Height)> {
         self.utxos.get(outpoint)
     }
+
+    /// Records the insertion of a UTXO into the UTXO set's hash.
+    pub fn insert_into_muhash(&mut self, element: &[u8]) {
+        self.muhash.insert(element);
+    }
+
+    /// Records the removal of a UTXO from the UTXO set's hash.
+    pub fn remove_from_muhash(&mut self, element: &[u8]) {
+        self.muhash.remove(element);
+    }
+
+    pub fn muhash(&self) -> &MuHash3072 {
+        &self.muhash
+    }
+
+    /// Records that a UTXO was created by the block.
+    pub fn record_created(&mut self, outpoint: OutPoint) {
+        self.created_outpoints.insert(outpoint);
+    }
+
+    /// Records that a UTXO was spent by the block.
+    pub fn record_spent(&mut self, spent_utxo: SpentUtxo) {
+        // A UTXO that is both created and spent by the block doesn't need to be undone.
+        if !self.created_outpoints.remove(&spent_utxo.outpoint) {
+            self.spent_utxos.push(spent_utxo);
+        }
+    }
+
+    /// Consumes the delta, returning the UTXOs spent by the block and the outpoints it created.
+    pub fn into_undo_data(self) -> (Vec<SpentUtxo>, Vec<OutPoint>) {
+        (
+            self.spent_utxos,
+            self.created_outpoints.into_iter().collect(),
+        )
+    }
 }
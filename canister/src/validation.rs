@@ -0,0 +1,190 @@
+use crate::{
+    blocktree,
+    state::{self, State},
+    types::BlockHash,
+    unstable_blocks,
+};
+use bitcoin::{BlockHash as BitcoinBlockHash, BlockHeader};
+use ic_btc_types::Height;
+use ic_btc_validation::{HeaderStore, ValidateHeaderError};
+use std::{cell::RefCell, collections::BTreeMap};
+
+/// Validates the given header against the headers known to the canister.
+pub fn validate_header(state: &State, header: &BlockHeader) -> Result<(), ValidateHeaderError> {
+    ic_btc_validation::validate_header(
+        &state.network().into(),
+        &ValidationContext::new(state),
+        header,
+    )
+}
+
+/// Returns true if the header is off the header chain, i.e. if the header chain has a header at
+/// the height of the given header but the header isn't that one.
+///
+/// Headers that compete with the header chain, e.g. after a reorg, are kept in a separate branch
+/// that replaces the header chain once it has more work. See `HeaderChain::insert`. Headers that
+/// extend an unknown block aren't considered to be off the header chain, as their height is
+/// unknown.
+pub fn is_off_header_chain(state: &State, header: &BlockHeader) -> bool {
+    let block_hash = BlockHash::from(header.block_hash());
+    if state.header_chain.contains(&block_hash) {
+        return false;
+    }
+
+    match ValidationContext::new(state).get_header(&header.prev_blockhash) {
+        Some((_, prev_height)) => state.header_chain.header_at(prev_height + 1).is_some(),
+        None => false,
+    }
+}
+
+/// A `HeaderStore` of all the headers known to the canister, i.e. the headers of the stable
+/// blocks, of the unstable blocks, and of the header chain.
+pub struct ValidationContext<'a> {
+    state: &'a State,
+
+    // The heights of the headers returned so far, indexed by the hash of their parents.
+    //
+    // Stable headers can only be looked up by height, so their heights are inferred from their
+    // children, as the validation traverses the chain backwards one header at a time.
+    child_heights: RefCell<BTreeMap<BlockHash, Height>>,
+}
+
+impl<'a> ValidationContext<'a> {
+    pub fn new(state: &'a State) -> Self {
+        Self {
+            state,
+            child_heights: RefCell::new(BTreeMap::new()),
+        }
+    }
+
+    fn get_stable_header(&self, block_hash: &BlockHash) -> Option<(BlockHeader, Height)> {
+        let is_header_at = |height: Height| {
+            self.state
+                .stable_block_headers
+                .get_with_height(height)
+                .filter(|header| BlockHash::from(header.block_hash()) == *block_hash)
+                .map(|header| (header, height))
+        };
+
+        if let Some(child_height) = self.child_heights.borrow().get(block_hash) {
+            return child_height.checked_sub(1).and_then(is_header_at);
+        }
+
+        // Otherwise, the header must be one that new blocks can extend, i.e. either the latest
+        // stable block or one of the stable blocks that can still be undone.
+        let stable_height = self.state.stable_height();
+        let num_extendable = self.state.utxos.undoable_block_hashes().count() as Height + 1;
+        (stable_height.saturating_sub(num_extendable)..stable_height)
+            .rev()
+            .find_map(is_header_at)
+    }
+
+    // Returns the header of a block in one of the forks from the stable blocks that are pending.
+    fn get_fork_header(&self, block_hash: &BlockHash) -> Option<(BlockHeader, Height)> {
+        self.state.forks.iter().find_map(|fork| {
+            let chain = blocktree::get_chain_with_tip(fork, block_hash)?;
+
+            // The fork starts from a stable block that can be undone, the latest one first.
+            let parent_hash: BlockHash = fork.root.header().prev_blockhash.into();
+            let parent_depth = self
+                .state
+                .utxos
+                .undoable_block_hashes()
+                .position(|h| h == parent_hash)?;
+            let parent_height = self.state.stable_height() - 1 - parent_depth as Height;
+
+            Some((*chain.tip().header(), parent_height + chain.len() as Height))
+        })
+    }
+}
+
+impl HeaderStore for ValidationContext<'_> {
+    fn get_header(&self, hash: &BitcoinBlockHash) -> Option<(BlockHeader, Height)> {
+        let block_hash = BlockHash::from(*hash);
+
+        let (header, height) = match self.state.header_chain.get(&block_hash) {
+            Some((header, height)) => (*header, height),
+            None => {
+                match unstable_blocks::get_chain_with_tip(&self.state.unstable_blocks, &block_hash)
+                {
+                    Some(chain) => (
+                        *chain.tip().header(),
+                        self.state.stable_height() + chain.len() as Height - 1,
+                    ),
+                    None => match self.get_fork_header(&block_hash) {
+                        Some(header) => header,
+                        None => self.get_stable_header(&block_hash)?,
+                    },
+                }
+            }
+        };
+
+        self.child_heights
+            .borrow_mut()
+            .insert(header.prev_blockhash.into(), height);
+        Some((header, height))
+    }
+
+    fn get_height(&self) -> Height {
+        // The height of the blocks rather than that of the best header, as headers beyond the
+        // checkpoints would otherwise invalidate the blocks that are yet to be received.
+        state::main_chain_height(self.state)
+    }
+
+    fn get_initial_hash(&self) -> BitcoinBlockHash {
+        // The first header known to the canister.
+        match self.state.stable_block_headers.get_with_height(0) {
+            Some(header) => header.block_hash(),
+            None => unstable_blocks::get_main_chain(&self.state.unstable_blocks)
+                .first()
+                .header()
+                .block_hash(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::{
+        test_utils::{build_regtest_chain, BlockBuilder},
+        types::Network,
+    };
+
+    #[test]
+    fn finds_stable_and_unstable_headers() {
+        let network = Network::Regtest;
+        let blocks = build_regtest_chain(5, 1);
+        let mut state = State::new(2, network, blocks[0].clone());
+        for block in blocks[1..].iter() {
+            state::insert_block(&mut state, block.clone()).unwrap();
+        }
+        state::ingest_stable_blocks_into_utxoset(&mut state);
+        assert_eq!(state.stable_height(), 2);
+
+        // Traverse the chain backwards from the tip, across the stable headers.
+        let context = ValidationContext::new(&state);
+        let mut header = *blocks[4].header();
+        for height in (0..5).rev() {
+            assert_eq!(
+                context.get_header(&header.block_hash()),
+                Some((header, height))
+            );
+            if height > 0 {
+                header = *blocks[height as usize - 1].header();
+            }
+        }
+        assert_eq!(context.get_initial_hash(), blocks[0].header().block_hash());
+
+        // A block extending the latest stable block is valid.
+        let fork = BlockBuilder::with_prev_header(blocks[1].header()).build();
+        assert!(validate_header(&state, fork.header()).is_ok());
+
+        // A block extending an unknown block isn't.
+        let orphan = BlockBuilder::with_prev_header(fork.header()).build();
+        assert!(matches!(
+            validate_header(&state, orphan.header()),
+            Err(ValidateHeaderError::PrevHeaderNotFound)
+        ));
+    }
+}
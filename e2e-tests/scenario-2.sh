@@ -16,6 +16,8 @@ dfx start --background --clean
 # Deploy the canister that returns the blocks for scenario 2.
 dfx deploy --no-wallet e2e-scenario-2
 
+START_TIME=$(date +%s)
+
 # Deploy the bitcoin canister, setting the blocks_source to be the source above.
 dfx deploy --no-wallet bitcoin --argument "(record {
   stability_threshold = 1;
@@ -32,13 +34,21 @@ dfx deploy --no-wallet bitcoin --argument "(record {
 })"
 
 # Wait until the ingestion of stable blocks is complete.
-wait_until_main_chain_height 4 60
+NUM_BLOCKS=4
+wait_until_main_chain_height $NUM_BLOCKS 60
+ELAPSED=$(( $(date +%s) - START_TIME ))
 
 BALANCE=$(dfx canister call bitcoin bitcoin_get_balance '(record {
   network = variant { regtest };
   address = "bcrt1qg4cvn305es3k8j69x06t9hf4v5yx4mxdaeazl8"
 })')
 
+# Report the sync throughput. The canister fast syncs all blocks except for the last one.
+# Every generated transaction pays 1 satoshi to the address, so the balance is the number
+# of transactions that were synced.
+NUM_TRANSACTIONS=$(echo "$BALANCE" | sed -E 's/^\(([0-9_]+) : nat64\)$/\1/' | tr -d _)
+echo "Synced ${NUM_BLOCKS} blocks in ${ELAPSED}s ($(( NUM_TRANSACTIONS / (ELAPSED > 0 ? ELAPSED : 1) )) transactions/s)"
+
 if ! [[ $BALANCE = "(40_000 : nat64)" ]]; then
   echo "FAIL"
   exit 1
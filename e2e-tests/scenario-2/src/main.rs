@@ -65,6 +65,8 @@ struct GetSuccessorsPartialResponse {
 thread_local! {
     static BLOCKS: RefCell<Vec<BlockBlob>> = RefCell::new(Vec::new());
 
+    static HEADERS: RefCell<Vec<BlockHeaderBlob>> = RefCell::new(Vec::new());
+
     static COUNT: Cell<u64> = Cell::new(0);
 }
 
@@ -115,7 +117,9 @@ fn bitcoin_get_successors(request: GetSuccessorsRequest) -> GetSuccessorsRespons
     let res = if count < BLOCKS.with(|b| b.borrow().len()) {
         GetSuccessorsResponse::Complete(GetSuccessorsCompleteResponse {
             blocks: vec![BLOCKS.with(|b| b.borrow()[count].clone())],
-            next: vec![],
+            // The headers of the blocks that come after, so that the canister knows how far
+            // it is from the tip.
+            next: HEADERS.with(|h| h.borrow()[count + 1..].to_vec()),
         })
     } else {
         // Empty response
@@ -133,6 +137,10 @@ fn append_block(block: &Block) {
     let mut block_bytes = vec![];
     block.consensus_encode(&mut block_bytes).unwrap();
     BLOCKS.with(|b| b.borrow_mut().push(block_bytes));
+
+    let mut header_bytes = vec![];
+    block.header.consensus_encode(&mut header_bytes).unwrap();
+    HEADERS.with(|h| h.borrow_mut().push(header_bytes));
 }
 
 fn main() {}